@@ -0,0 +1,196 @@
+/* Benchmark of the extraction of a generated archive through the different
+ * ways of reading it, run it with `cargo bench --bench extraction`:
+ *
+ * - serial: extract_archive() on an archive read with file handles,
+ *   copy_data() allocating a buffer per file
+ * - reused buffer: the same, files being copied through one buffer
+ * - mmap: extract_archive() on an archive mapped in memory
+ * - parallel: files shared among threads, each with its own deep clone of
+ *   the archive
+ *
+ * Before being measured, each backend extracts the archive once, which is
+ * checked file by file against the archive, and the number of allocations
+ * it made is printed. */
+
+#[macro_use]
+extern crate criterion;
+extern crate sha2;
+extern crate tempfile;
+extern crate tropico5_hpk_unpacker;
+
+#[allow(dead_code)]
+#[path = "../src/testutil.rs"]
+mod testutil;
+
+use criterion::{BatchSize, Criterion, SamplingMode, Throughput};
+use sha2::{Digest, Sha256};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use testutil::*;
+use tropico5_hpk_unpacker::errors::*;
+use tropico5_hpk_unpacker::extract::{
+    extract_archive, read_all_files, write_atomically, ExtractOptions,
+};
+use tropico5_hpk_unpacker::hpk::Archive;
+// Modules testutil refers to from the crate root
+use tropico5_hpk_unpacker::{builder, hpk};
+
+const THREADS: usize = 4;
+
+/* Allocator of the benchmark, counting allocations */
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/* Paths of the files of the archive */
+fn file_paths(archive: &Archive) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    archive.walk(|path, _file| {
+        paths.push(path.to_string());
+        Ok(())
+    })?;
+    Ok(paths)
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+fn serial(archive: &Archive, outpath: &str) -> Result<()> {
+    extract_archive(archive, outpath, &mut ExtractOptions::default()).map(|_| ())
+}
+
+fn reused_buffer(archive: &Archive, outpath: &str) -> Result<()> {
+    let mut buf = vec![0u8; 0x100000];
+    archive.walk(|path, file| {
+        let mut data = archive.file_data(file)?;
+        let outfile = Path::new(outpath).join(path);
+        create_parent(&outfile)?;
+        let mut out = fs::File::create(outfile)?;
+        loop {
+            let len = data.read(&mut buf)?;
+            if len == 0 {
+                return Ok(());
+            }
+            out.write_all(&buf[..len])?;
+        }
+    })
+}
+
+fn parallel(archive: &Archive, outpath: &str) -> Result<()> {
+    let paths = Arc::new(file_paths(archive)?);
+    let mut handles = Vec::new();
+    for t in 0..THREADS {
+        let clone = archive.deep_clone()?;
+        let paths = paths.clone();
+        let outpath = outpath.to_string();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for path in paths.iter().skip(t).step_by(THREADS) {
+                let mut data = clone.file_data_at(path)?;
+                let size = data.size();
+                let outfile = Path::new(&outpath).join(path);
+                create_parent(&outfile)?;
+                write_atomically(&mut data, size, &outfile)?;
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+/* Panic unless each file of the archive was extracted under outpath with
+ * the right contents. */
+fn check_contents(archive: &Archive, outpath: &str) {
+    for path in file_paths(archive).unwrap() {
+        let expected = archive.file_data_at(&path).unwrap().sha256().unwrap();
+        let extracted = fs::read(Path::new(outpath).join(&path)).unwrap();
+        assert!(
+            Sha256::digest(&extracted)[..] == expected[..],
+            "{} was not extracted right",
+            path
+        );
+    }
+}
+
+fn extraction_backends(c: &mut Criterion) {
+    let mut dirs = Vec::new();
+    for &dir in &[&b"d0"[..], b"d1", b"d2", b"d3"] {
+        let mut files = Vec::new();
+        for &name in &[&b"f0"[..], b"f1", b"f2", b"f3", b"f4", b"f5", b"f6", b"f7"] {
+            let data = sample_data(0x40000);
+            files.push(TestEntry::Zlib(name, data.clone(), 0x8000));
+            files.push(TestEntry::File(&name[1..], data));
+        }
+        files.push(TestEntry::File(b"tiny", b"tiny".to_vec()));
+        dirs.push(TestEntry::Dir(dir, files));
+    }
+    let fixture = archive_file(&dirs);
+    let path = fixture.path().to_str().unwrap();
+    let archive = Archive::open(path).unwrap();
+    let mmap = Archive::open_mmap(path).unwrap();
+    let (_, total_bytes) = read_all_files(&archive).unwrap();
+
+    type Extract = fn(&Archive, &str) -> Result<()>;
+    let backends: Vec<(&str, &Archive, Extract)> = vec![
+        ("serial", &archive, serial),
+        ("reused buffer", &archive, reused_buffer),
+        ("mmap", &mmap, serial),
+        ("parallel", &archive, parallel),
+    ];
+
+    let mut group = c.benchmark_group("extraction");
+    group.sample_size(10);
+    group.sampling_mode(SamplingMode::Flat);
+    group.throughput(Throughput::Bytes(total_bytes));
+    for (name, archive, extract) in backends {
+        let outdir = tempfile::tempdir().unwrap();
+        let outpath = outdir.path().to_str().unwrap();
+        let allocated = ALLOCATIONS.load(Ordering::Relaxed);
+        extract(archive, outpath).unwrap();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocated;
+        check_contents(archive, outpath);
+        println!("{}: {} allocations", name, allocations);
+
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || tempfile::tempdir().unwrap(),
+                |outdir| extract(archive, outdir.path().to_str().unwrap()).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, extraction_backends);
+criterion_main!(benches);
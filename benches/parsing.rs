@@ -0,0 +1,99 @@
+/* Benchmark of the parsing of a generated archive of 100k entries, run it
+ * with `cargo bench --bench parsing`:
+ *
+ * - open: Archive::open(), parsing the whole index
+ * - entry by index: Archive::entry_by_index() on the last entry of the file
+ *   table
+ *
+ * Before being measured, the archive is opened once, and the peak amount of
+ * memory allocated while parsing it is printed along with the amount the
+ * parsed archive keeps. */
+
+#[macro_use]
+extern crate criterion;
+extern crate tempfile;
+extern crate tropico5_hpk_unpacker;
+
+use criterion::Criterion;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tropico5_hpk_unpacker::builder::ArchiveBuilder;
+use tropico5_hpk_unpacker::hpk::{Archive, EntryRef};
+
+const DIRECTORIES: usize = 100;
+const FILES_PER_DIRECTORY: usize = 1000;
+
+/* Allocator of the benchmark, keeping track of the allocated bytes */
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn add_allocated(size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(allocated, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        add_allocated(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        add_allocated(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn parsing(c: &mut Criterion) {
+    let mut builder = ArchiveBuilder::new();
+    for d in 0..DIRECTORIES {
+        let dir = format!("directory_{:04}", d);
+        builder.add_directory("", &dir).unwrap();
+        for f in 0..FILES_PER_DIRECTORY {
+            let name = format!("some_file_name_{:06}.dat", f);
+            builder.add_file(&dir, &name, Vec::new()).unwrap();
+        }
+    }
+    let outdir = tempfile::tempdir().unwrap();
+    let output = outdir.path().join("large.hpk");
+    let output = output.to_str().unwrap();
+    builder.finish(output).unwrap();
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let archive = Archive::open(output).unwrap();
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    let kept = ALLOCATED.load(Ordering::Relaxed) - before;
+    println!(
+        "open: {} KiB allocated at most, {} KiB kept by the archive",
+        peak / 1024,
+        kept / 1024
+    );
+    let last = archive.entry_count().unwrap() as u32;
+    match archive.entry_by_index(last) {
+        Some(EntryRef::File(..)) => {}
+        _ => panic!("index {} should be a file", last),
+    }
+
+    let mut group = c.benchmark_group("parsing");
+    group.sample_size(20);
+    group.bench_function("open", |b| b.iter(|| Archive::open(output).unwrap()));
+    group.bench_function("entry by index", |b| {
+        b.iter(|| archive.entry_by_index(last).is_some())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parsing);
+criterion_main!(benches);
@@ -0,0 +1,22 @@
+/* Generate the C header of the library into OUT_DIR. The tests check that
+ * include/hpk.h, which is distributed, matches it. */
+
+extern crate cbindgen;
+
+use std::env;
+use std::path::Path;
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let dir = Path::new(&dir);
+    let out_dir = env::var("OUT_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let config = cbindgen::Config::from_file(dir.join("cbindgen.toml")).unwrap();
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(dir.join("src/lib.rs"))
+        .generate()
+        .expect("Unable to generate the C header")
+        .write_to_file(Path::new(&out_dir).join("hpk.h"));
+}
@@ -0,0 +1,129 @@
+#ifndef HPK_H
+#define HPK_H
+
+/* Generated by build.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ An opened archive. It can be used by several threads at once, and must
+ only be closed once none uses it.
+ */
+typedef struct HpkArchive HpkArchive;
+
+/*
+ Called by hpk_list() for each file, with the user data given to
+ hpk_list() and the '/' separated path of the file. The path is only valid
+ during the call. Returning a value other than 0 stops the listing.
+ */
+typedef int32_t (*HpkListCallback)(void *user_data, const uint8_t *path, size_t path_len);
+
+/*
+ Metadata of an entry, filled by hpk_stat().
+ */
+typedef struct HpkStat {
+  /*
+   1 for a directory, 0 for a file
+   */
+  uint8_t is_directory;
+  /*
+   1 for a file stored ZLIB compressed, 0 otherwise
+   */
+  uint8_t is_compressed;
+  /*
+   Size of the file once decompressed, 0 for a directory
+   */
+  uint64_t size;
+  /*
+   Size of the data in the archive
+   */
+  uint64_t stored_size;
+  /*
+   Offset of the data in the archive
+   */
+  uint64_t offset;
+  /*
+   File table index
+   */
+  uint32_t index;
+} HpkStat;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Open the archive at path. Returns NULL on error.
+
+ # Safety
+ path must point to path_len readable bytes.
+ */
+struct HpkArchive *hpk_open(const uint8_t *path, size_t path_len);
+
+/*
+ Release an archive returned by hpk_open(). Passing NULL does nothing.
+
+ # Safety
+ handle must come from hpk_open() and not have been closed already.
+ */
+void hpk_close(struct HpkArchive *handle);
+
+/*
+ Call callback for each file of the archive, in lexicographical order of
+ the paths. Returns 0 once all files were listed or the callback stopped
+ the listing, -1 on error.
+
+ # Safety
+ handle must be an open archive.
+ */
+int32_t hpk_list(const struct HpkArchive *handle, HpkListCallback callback, void *user_data);
+
+/*
+ Fill out with the metadata of the file or directory at path. Returns 0
+ on success, -1 on error.
+
+ # Safety
+ handle must be an open archive, path must point to path_len readable
+ bytes and out to a writable HpkStat.
+ */
+int32_t hpk_stat(const struct HpkArchive *handle,
+                 const uint8_t *path,
+                 size_t path_len,
+                 struct HpkStat *out);
+
+/*
+ Read up to len bytes of the decompressed contents of the file at path,
+ starting at offset, into buf. Returns the number of bytes read, which is
+ less than len only at the end of the file, or -1 on error.
+
+ # Safety
+ handle must be an open archive, path must point to path_len readable
+ bytes and buf to len writable bytes.
+ */
+int64_t hpk_read(const struct HpkArchive *handle,
+                 const uint8_t *path,
+                 size_t path_len,
+                 uint8_t *buf,
+                 uint64_t offset,
+                 size_t len);
+
+/*
+ Message of the last error which happened in the calling thread, with its
+ length in bytes stored in len. Returns NULL, and sets len to 0, if no
+ error happened yet. The message stays valid until the next call to the
+ library from the same thread.
+
+ # Safety
+ len must be NULL or point to a writable size_t.
+ */
+const uint8_t *hpk_last_error_message(size_t *len);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* HPK_H */
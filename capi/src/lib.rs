@@ -0,0 +1,427 @@
+/* C API of the archive reader, for programs which cannot use the Rust code
+ * directly. The header, include/hpk.h, is generated by build.rs, and the
+ * tests check that the distributed copy is up to date.
+ *
+ * Ownership rules:
+ * - Strings passed to the library are UTF-8, not NUL terminated, and given
+ *   as a pointer along with a length in bytes. They are only borrowed for
+ *   the duration of the call.
+ * - Strings returned by the library are also UTF-8 with a length. They are
+ *   owned by the library and only valid for the documented duration.
+ * - A handle returned by hpk_open() is owned by the caller, and must be
+ *   released exactly once with hpk_close().
+ *
+ * Threads: a handle can be used by several threads at once, the reads of
+ * each call having their own position in the archive. It must only be
+ * closed once no other thread uses it. Errors are kept per thread.
+ *
+ * No panic unwinds across the API: a panic is reported like an error, with
+ * its message available from hpk_last_error_message().
+ *
+ * Items exported to the header are documented with /// comments, which
+ * cbindgen carries over to the header. */
+
+#![allow(dead_code)]
+#![recursion_limit = "1024"]
+
+#[macro_use]
+extern crate error_chain;
+
+#[path = "../../src/errors.rs"]
+mod errors;
+// The parser also has code for the features of the unpacker and for
+// fuzzing, which this library does not build
+#[allow(unexpected_cfgs)]
+#[path = "../../src/hpk.rs"]
+mod hpk;
+#[cfg(test)]
+#[path = "../../src/builder.rs"]
+mod builder;
+#[cfg(test)]
+#[path = "../../src/testutil.rs"]
+mod testutil;
+
+use errors::*;
+use std::cell::RefCell;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::raw::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice, str};
+
+/// An opened archive. It can be used by several threads at once, and must
+/// only be closed once none uses it.
+pub struct HpkArchive {
+    archive: hpk::Archive,
+}
+
+/// Metadata of an entry, filled by hpk_stat().
+#[repr(C)]
+pub struct HpkStat {
+    /// 1 for a directory, 0 for a file
+    pub is_directory: u8,
+    /// 1 for a file stored ZLIB compressed, 0 otherwise
+    pub is_compressed: u8,
+    /// Size of the file once decompressed, 0 for a directory
+    pub size: u64,
+    /// Size of the data in the archive
+    pub stored_size: u64,
+    /// Offset of the data in the archive
+    pub offset: u64,
+    /// File table index
+    pub index: u32,
+}
+
+/// Called by hpk_list() for each file, with the user data given to
+/// hpk_list() and the '/' separated path of the file. The path is only valid
+/// during the call. Returning a value other than 0 stops the listing.
+pub type HpkListCallback =
+    Option<extern "C" fn(user_data: *mut c_void, path: *const u8, path_len: usize) -> i32>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/* Run f, turning its errors and panics into the last error of the thread.
+ * Returns None in that case. */
+fn guard<T, F: FnOnce() -> Result<T>>(f: F) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(e)) => {
+            let messages: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+            set_last_error(messages.join(": "));
+            None
+        }
+        Err(payload) => {
+            let message = if let Some(s) = payload.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            set_last_error(format!("panic: {}", message));
+            None
+        }
+    }
+}
+
+/* Borrow a string given by the caller. */
+unsafe fn str_arg<'a>(ptr: *const u8, len: usize) -> Result<&'a str> {
+    if ptr.is_null() {
+        if len == 0 {
+            return Ok("");
+        }
+        bail!("Null string pointer");
+    }
+    str::from_utf8(slice::from_raw_parts(ptr, len)).chain_err(|| "String is not valid UTF-8")
+}
+
+unsafe fn archive_arg<'a>(handle: *const HpkArchive) -> Result<&'a hpk::Archive> {
+    match handle.as_ref() {
+        Some(h) => Ok(&h.archive),
+        None => bail!("Null archive handle"),
+    }
+}
+
+/// Open the archive at path. Returns NULL on error.
+///
+/// # Safety
+/// path must point to path_len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn hpk_open(path: *const u8, path_len: usize) -> *mut HpkArchive {
+    guard(|| {
+        let path = str_arg(path, path_len)?;
+        let archive = hpk::Archive::open(path).chain_err(|| "Unable to open archive")?;
+        Ok(Box::into_raw(Box::new(HpkArchive { archive: archive })))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Release an archive returned by hpk_open(). Passing NULL does nothing.
+///
+/// # Safety
+/// handle must come from hpk_open() and not have been closed already.
+#[no_mangle]
+pub unsafe extern "C" fn hpk_close(handle: *mut HpkArchive) {
+    if !handle.is_null() {
+        guard(|| {
+            drop(Box::from_raw(handle));
+            Ok(())
+        });
+    }
+}
+
+/// Call callback for each file of the archive, in lexicographical order of
+/// the paths. Returns 0 once all files were listed or the callback stopped
+/// the listing, -1 on error.
+///
+/// # Safety
+/// handle must be an open archive.
+#[no_mangle]
+pub unsafe extern "C" fn hpk_list(
+    handle: *const HpkArchive,
+    callback: HpkListCallback,
+    user_data: *mut c_void,
+) -> i32 {
+    guard(|| {
+        let archive = archive_arg(handle)?;
+        let callback = match callback {
+            Some(c) => c,
+            None => bail!("Null callback"),
+        };
+        let mut paths = Vec::new();
+        archive.walk(|path, _| {
+            paths.push(path.to_string());
+            Ok(())
+        })?;
+        paths.sort();
+        for path in &paths {
+            if callback(user_data, path.as_ptr(), path.len()) != 0 {
+                break;
+            }
+        }
+        Ok(0)
+    })
+    .unwrap_or(-1)
+}
+
+/// Fill out with the metadata of the file or directory at path. Returns 0
+/// on success, -1 on error.
+///
+/// # Safety
+/// handle must be an open archive, path must point to path_len readable
+/// bytes and out to a writable HpkStat.
+#[no_mangle]
+pub unsafe extern "C" fn hpk_stat(
+    handle: *const HpkArchive,
+    path: *const u8,
+    path_len: usize,
+    out: *mut HpkStat,
+) -> i32 {
+    guard(|| {
+        let archive = archive_arg(handle)?;
+        let path = str_arg(path, path_len)?;
+        if out.is_null() {
+            bail!("Null output pointer");
+        }
+        let stat = archive.stat(path, true)?;
+        let is_compressed = match archive.find(path)? {
+            Some(file) => archive.is_compressed(file)?,
+            None => false,
+        };
+        *out = HpkStat {
+            is_directory: stat.kind.is_directory() as u8,
+            is_compressed: is_compressed as u8,
+            size: stat.unpacked_size.unwrap_or(0),
+            stored_size: stat.stored_size as u64,
+            offset: stat.offset as u64,
+            index: stat.index,
+        };
+        Ok(0)
+    })
+    .unwrap_or(-1)
+}
+
+/// Read up to len bytes of the decompressed contents of the file at path,
+/// starting at offset, into buf. Returns the number of bytes read, which is
+/// less than len only at the end of the file, or -1 on error.
+///
+/// # Safety
+/// handle must be an open archive, path must point to path_len readable
+/// bytes and buf to len writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn hpk_read(
+    handle: *const HpkArchive,
+    path: *const u8,
+    path_len: usize,
+    buf: *mut u8,
+    offset: u64,
+    len: usize,
+) -> i64 {
+    guard(|| {
+        let archive = archive_arg(handle)?;
+        let path = str_arg(path, path_len)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        if buf.is_null() {
+            bail!("Null buffer");
+        }
+        let buf = slice::from_raw_parts_mut(buf, len);
+        let mut data = archive.file_data_at(path)?;
+        if offset >= data.size() {
+            return Ok(0);
+        }
+        data.seek(SeekFrom::Start(offset))?;
+        let mut total = 0;
+        while total < len {
+            let n = data.read(&mut buf[total..])?;
+            if n == 0 {
+                break;
+            }
+            total += n;
+        }
+        Ok(total as i64)
+    })
+    .unwrap_or(-1)
+}
+
+/// Message of the last error which happened in the calling thread, with its
+/// length in bytes stored in len. Returns NULL, and sets len to 0, if no
+/// error happened yet. The message stays valid until the next call to the
+/// library from the same thread.
+///
+/// # Safety
+/// len must be NULL or point to a writable size_t.
+#[no_mangle]
+pub unsafe extern "C" fn hpk_last_error_message(len: *mut usize) -> *const u8 {
+    let (message, message_len) = LAST_ERROR.with(|e| match *e.borrow() {
+        Some(ref m) => (m.as_ptr(), m.len()),
+        None => (ptr::null(), 0),
+    });
+    if !len.is_null() {
+        *len = message_len;
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use testutil::*;
+
+    extern "C" fn collect_path(user_data: *mut c_void, path: *const u8, path_len: usize) -> i32 {
+        let paths = unsafe { &mut *(user_data as *mut Vec<String>) };
+        let path = unsafe { slice::from_raw_parts(path, path_len) };
+        paths.push(String::from_utf8(path.to_vec()).unwrap());
+        0
+    }
+
+    fn last_error() -> String {
+        let mut len = 0;
+        let message = unsafe { hpk_last_error_message(&mut len) };
+        assert!(!message.is_null());
+        String::from_utf8(unsafe { slice::from_raw_parts(message, len) }.to_vec()).unwrap()
+    }
+
+    fn open(path: &Path) -> *mut HpkArchive {
+        let path = path.to_str().unwrap();
+        unsafe { hpk_open(path.as_ptr(), path.len()) }
+    }
+
+    fn read(handle: *const HpkArchive, path: &str, offset: u64, buf: &mut [u8]) -> i64 {
+        unsafe {
+            hpk_read(
+                handle,
+                path.as_ptr(),
+                path.len(),
+                buf.as_mut_ptr(),
+                offset,
+                buf.len(),
+            )
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample_data(0x3000);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::Dir(
+                b"d",
+                vec![TestEntry::Zlib(b"zlib", data.clone(), 0x1000)],
+            ),
+        ]);
+        let handle = open(file.path());
+        assert!(!handle.is_null());
+
+        let mut paths: Vec<String> = Vec::new();
+        let user_data = &mut paths as *mut Vec<String> as *mut c_void;
+        assert_eq!(unsafe { hpk_list(handle, Some(collect_path), user_data) }, 0);
+        assert_eq!(paths, vec!["d/zlib", "plain"]);
+
+        let mut stat: HpkStat = unsafe { ::std::mem::zeroed() };
+        let path = "d/zlib";
+        assert_eq!(
+            unsafe { hpk_stat(handle, path.as_ptr(), path.len(), &mut stat) },
+            0
+        );
+        assert_eq!(stat.is_directory, 0);
+        assert_eq!(stat.is_compressed, 1);
+        assert_eq!(stat.size, data.len() as u64);
+        let path = "d";
+        assert_eq!(
+            unsafe { hpk_stat(handle, path.as_ptr(), path.len(), &mut stat) },
+            0
+        );
+        assert_eq!(stat.is_directory, 1);
+
+        for path in &["plain", "d/zlib"] {
+            let mut buf = vec![0u8; 0x100];
+            assert_eq!(read(handle, path, 0x1f80, &mut buf), 0x100);
+            assert!(buf[..] == data[0x1f80..0x2080]);
+            assert_eq!(read(handle, path, 0x2f80, &mut buf), 0x80);
+            assert!(buf[..0x80] == data[0x2f80..]);
+            assert_eq!(read(handle, path, 0x3000, &mut buf), 0);
+        }
+
+        let mut buf = [0u8; 0x10];
+        assert_eq!(read(handle, "missing", 0, &mut buf), -1);
+        assert!(last_error().contains("missing"), "{}", last_error());
+        assert_eq!(read(ptr::null(), "plain", 0, &mut buf), -1);
+        assert_eq!(last_error(), "Null archive handle");
+        unsafe { hpk_close(handle) };
+    }
+
+    #[test]
+    fn open_failure_sets_error() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(open(&dir.path().join("missing.hpk")).is_null());
+        assert!(last_error().starts_with("Unable to open archive"));
+    }
+
+    #[test]
+    fn reads_from_several_threads() {
+        let data = sample_data(0x10000);
+        let file = archive_file(&[TestEntry::File(b"plain", data.clone())]);
+        let handle = open(file.path()) as usize;
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let expected = data.clone();
+                ::std::thread::spawn(move || {
+                    let mut buf = [0u8; 0x40];
+                    for i in 0..2000 {
+                        let offset = (t * 0x3d1 + i * 0x83) % (expected.len() - buf.len());
+                        let len = read(handle as *const HpkArchive, "plain", offset as u64, &mut buf);
+                        assert_eq!(len, buf.len() as i64);
+                        assert!(buf[..] == expected[offset..offset + buf.len()]);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        unsafe { hpk_close(handle as *mut HpkArchive) };
+    }
+
+    #[test]
+    fn header_is_up_to_date() {
+        let generated = concat!(env!("OUT_DIR"), "/hpk.h");
+        let distributed = Path::new(env!("CARGO_MANIFEST_DIR")).join("include/hpk.h");
+        assert!(
+            fs::read(generated).unwrap() == fs::read(&distributed).unwrap(),
+            "{} is outdated, update it from {}",
+            distributed.display(),
+            generated
+        );
+    }
+}
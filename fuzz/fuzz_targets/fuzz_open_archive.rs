@@ -0,0 +1,28 @@
+/* Parse arbitrary bytes as a whole archive, and read all of its files. Any
+ * panic is a bug: malformed input must be rejected with an error. */
+
+#![no_main]
+#![allow(dead_code)]
+#![recursion_limit = "1024"]
+
+#[macro_use]
+extern crate error_chain;
+#[macro_use]
+extern crate libfuzzer_sys;
+
+#[path = "../../src/errors.rs"]
+mod errors;
+#[path = "../../src/hpk.rs"]
+mod hpk;
+
+use std::io::Read;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(archive) = hpk::Archive::open_from_reader(data) {
+        let _ = archive.walk(|_, file| {
+            let mut contents = Vec::new();
+            let _ = archive.file_data(file)?.read_to_end(&mut contents);
+            Ok(())
+        });
+    }
+});
@@ -0,0 +1,20 @@
+/* Feed arbitrary bytes to the header parser of ZLIB compressed files. Any
+ * panic is a bug: malformed input must be rejected with an error. */
+
+#![no_main]
+#![allow(dead_code)]
+#![recursion_limit = "1024"]
+
+#[macro_use]
+extern crate error_chain;
+#[macro_use]
+extern crate libfuzzer_sys;
+
+#[path = "../../src/errors.rs"]
+mod errors;
+#[path = "../../src/hpk.rs"]
+mod hpk;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = hpk::fuzzing::parse_zlib_header(data);
+});
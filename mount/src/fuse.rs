@@ -0,0 +1,500 @@
+/* Server side of the Linux FUSE protocol, see include/uapi/linux/fuse.h in
+ * the kernel sources, limited to what a read-only filesystem needs. Requests
+ * are read from /dev/fuse and answered one at a time.
+ *
+ * The filesystem is mounted with mount(2) when permitted, else through the
+ * fusermount helper of libfuse, which passes back the /dev/fuse descriptor
+ * over a socket. */
+
+use errors::*;
+use libc;
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/** Inode of the root directory */
+pub const ROOT_ID: u64 = 1;
+
+/* Version of the protocol spoken, which defines the layout of the
+ * structures below */
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+
+/* Room for any request: a header, its arguments, and the data of a write,
+ * which are at most 128 KiB unless the max_pages option says otherwise */
+const BUFFER_SIZE: usize = 0x21000;
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+/* The archive does not change while it is mounted */
+const TTL: Duration = Duration::from_secs(3600);
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_ACCESS: u32 = 34;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+/** Type of an entry of the filesystem */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Directory,
+    RegularFile,
+}
+
+/** Attributes of an entry, as returned by stat(2) */
+#[derive(Clone, Debug)]
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub kind: FileType,
+    /** Permission bits, such as 0o444 */
+    pub perm: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /** Given as access, modification and change time */
+    pub mtime: SystemTime,
+}
+
+/** Entry of a directory listing */
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: String,
+}
+
+/** Result of an operation, the error being an errno value */
+pub type Reply<T> = ::std::result::Result<T, i32>;
+
+/** Read-only filesystem served by `Session::serve()`. Inodes are chosen by
+ * the filesystem, `ROOT_ID` being the one of the root directory. */
+pub trait Filesystem {
+    fn lookup(&mut self, parent: u64, name: &OsStr) -> Reply<Attr>;
+    fn getattr(&mut self, ino: u64) -> Reply<Attr>;
+    /** Open a file with the flags of open(2), returning a file handle */
+    fn open(&mut self, ino: u64, flags: i32) -> Reply<u64>;
+    /** Read up to size bytes at offset, fewer only at the end of the file */
+    fn read(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Reply<Vec<u8>>;
+    fn release(&mut self, fh: u64);
+    /** Entries of a directory, including . and .. */
+    fn readdir(&mut self, ino: u64) -> Reply<Vec<DirEntry>>;
+}
+
+/** Connection to the kernel of a mounted filesystem. */
+pub struct Session {
+    fd: File,
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_ne_bytes(bytes)
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn push_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+/* Append a struct fuse_attr */
+fn push_attr(out: &mut Vec<u8>, attr: &Attr) {
+    let time = attr
+        .mtime
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    let mode = match attr.kind {
+        FileType::Directory => libc::S_IFDIR,
+        FileType::RegularFile => libc::S_IFREG,
+    };
+    push_u64(out, attr.ino);
+    push_u64(out, attr.size);
+    push_u64(out, attr.size.div_ceil(512));
+    for _ in 0..3 {
+        push_u64(out, time.as_secs());
+    }
+    for _ in 0..3 {
+        push_u32(out, time.subsec_nanos());
+    }
+    push_u32(out, mode | attr.perm);
+    push_u32(out, attr.nlink);
+    push_u32(out, attr.uid);
+    push_u32(out, attr.gid);
+    // rdev, blksize and flags
+    push_u32(out, 0);
+    push_u32(out, 4096);
+    push_u32(out, 0);
+}
+
+/* Append a struct fuse_entry_out */
+fn push_entry(out: &mut Vec<u8>, attr: &Attr) {
+    push_u64(out, attr.ino);
+    // Generation, the same inode never refers to another entry
+    push_u64(out, 0);
+    push_u64(out, TTL.as_secs());
+    push_u64(out, TTL.as_secs());
+    push_u32(out, 0);
+    push_u32(out, 0);
+    push_attr(out, attr);
+}
+
+/* Append a struct fuse_open_out */
+fn push_open(out: &mut Vec<u8>, fh: u64) {
+    push_u64(out, fh);
+    push_u32(out, 0);
+    push_u32(out, 0);
+}
+
+/* Append as many struct fuse_dirent as fit in size bytes, starting with
+ * entry offset. The offset of an entry is the one of the next entry. */
+fn push_dir_entries(out: &mut Vec<u8>, entries: &[DirEntry], offset: u64, size: usize) {
+    for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+        let name = entry.name.as_bytes();
+        let len = (24 + name.len() + 7) & !7;
+        if out.len() + len > size {
+            break;
+        }
+        let kind = match entry.kind {
+            FileType::Directory => libc::DT_DIR,
+            FileType::RegularFile => libc::DT_REG,
+        };
+        push_u64(out, entry.ino);
+        push_u64(out, i as u64 + 1);
+        push_u32(out, name.len() as u32);
+        push_u32(out, kind as u32);
+        out.extend_from_slice(name);
+        out.resize(out.len() + len - 24 - name.len(), 0);
+    }
+}
+
+/* Append a struct fuse_kstatfs */
+fn push_statfs(out: &mut Vec<u8>) {
+    // Blocks and files, all in use
+    for _ in 0..5 {
+        push_u64(out, 0);
+    }
+    // Block size, longest name, fragment size and padding
+    push_u32(out, 4096);
+    push_u32(out, u16::MAX as u32);
+    push_u32(out, 4096);
+    push_u32(out, 0);
+    for _ in 0..6 {
+        push_u32(out, 0);
+    }
+}
+
+/* Append a struct fuse_init_out answering a struct fuse_init_in */
+fn push_init(out: &mut Vec<u8>, init_in: &[u8]) {
+    push_u32(out, KERNEL_VERSION);
+    push_u32(out, KERNEL_MINOR_VERSION);
+    // Readahead as wanted by the kernel, and no optional feature
+    push_u32(out, u32_at(init_in, 8));
+    push_u32(out, 0);
+    // Background requests and congestion threshold
+    push_u16(out, 16);
+    push_u16(out, 12);
+    // Largest write, time granularity in ns, max pages, map alignment
+    push_u32(out, 4096);
+    push_u32(out, 1);
+    push_u16(out, 0);
+    push_u16(out, 0);
+    for _ in 0..8 {
+        push_u32(out, 0);
+    }
+}
+
+fn errno() -> i32 {
+    io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EIO)
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).chain_err(|| "Path holds a NUL byte")
+}
+
+/* Escape the commas and backslashes of a mount option value */
+fn escape_option(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,")
+}
+
+/* Receive a file descriptor sent by fusermount over socket */
+fn receive_fd(socket: RawFd) -> Result<File> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    loop {
+        let res = unsafe { libc::recvmsg(socket, &mut msg, 0) };
+        if res > 0 {
+            break;
+        }
+        if res == 0 {
+            bail!("fusermount did not send the FUSE device");
+        }
+        if errno() != libc::EINTR {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            bail!("fusermount sent no file descriptor");
+        }
+        let fd = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+/* Run fusermount, or fusermount3, with args */
+fn fusermount(args: &[&OsStr], commfd: Option<RawFd>) -> Result<()> {
+    let mut last_error = None;
+    for program in &["fusermount3", "fusermount"] {
+        let mut command = Command::new(program);
+        command.args(args);
+        if let Some(fd) = commfd {
+            command.env("_FUSE_COMMFD", fd.to_string());
+        }
+        match command.status() {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => bail!("{} failed with {}", program, status),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(Error::with_chain(
+        last_error.unwrap(),
+        "Unable to run fusermount",
+    ))
+}
+
+/* Mount through fusermount, for users not allowed to call mount(2) */
+fn mount_with_fusermount(source: &str, mountpoint: &Path) -> Result<File> {
+    let mut fds = [0 as RawFd; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let (ours, theirs) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    let options = format!(
+        "ro,nosuid,nodev,default_permissions,fsname={},subtype=hpk",
+        escape_option(source)
+    );
+    fusermount(
+        &[
+            OsStr::new("-o"),
+            OsStr::new(&options),
+            OsStr::new("--"),
+            mountpoint.as_os_str(),
+        ],
+        Some(theirs.as_raw_fd()),
+    )?;
+    drop(theirs);
+    receive_fd(ours.as_raw_fd())
+}
+
+/** Unmount the filesystem at mountpoint, lazily if it is busy. The session
+ * serving it then returns. */
+pub fn unmount(mountpoint: &Path) -> Result<()> {
+    let target = c_path(mountpoint)?;
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } == 0 {
+        return Ok(());
+    }
+    if errno() != libc::EPERM {
+        return Err(io::Error::last_os_error().into());
+    }
+    fusermount(
+        &[OsStr::new("-u"), OsStr::new("-z"), mountpoint.as_os_str()],
+        None,
+    )
+}
+
+impl Session {
+    /** Mount a read-only filesystem on mountpoint, source being shown as
+     * the mounted device. It is served by `serve()`, until unmounted. */
+    pub fn mount(source: &str, mountpoint: &Path) -> Result<Session> {
+        let device = File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/fuse")
+            .chain_err(|| "Unable to open /dev/fuse")?;
+        let options = format!(
+            "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+            device.as_raw_fd(),
+            unsafe { libc::getuid() },
+            unsafe { libc::getgid() }
+        );
+        let source_c = CString::new(source).chain_err(|| "Source holds a NUL byte")?;
+        let target = c_path(mountpoint)?;
+        let fstype = CString::new("fuse.hpk").unwrap();
+        let options = CString::new(options).unwrap();
+        let res = unsafe {
+            libc::mount(
+                source_c.as_ptr(),
+                target.as_ptr(),
+                fstype.as_ptr(),
+                libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                options.as_ptr() as *const libc::c_void,
+            )
+        };
+        if res == 0 {
+            return Ok(Session { fd: device });
+        }
+        if errno() != libc::EPERM {
+            return Err(io::Error::last_os_error()).chain_err(|| "Unable to mount");
+        }
+        drop(device);
+        let device = mount_with_fusermount(source, mountpoint).chain_err(|| "Unable to mount")?;
+        Ok(Session { fd: device })
+    }
+
+    /* Send the reply to request unique: an error if errno is not 0, else
+     * data */
+    fn reply(&self, unique: u64, errno: i32, data: &[u8]) -> Result<()> {
+        let mut out = Vec::with_capacity(OUT_HEADER_SIZE + data.len());
+        push_u32(&mut out, (OUT_HEADER_SIZE + data.len()) as u32);
+        out.extend_from_slice(&(-errno).to_ne_bytes());
+        push_u64(&mut out, unique);
+        out.extend_from_slice(data);
+        match (&self.fd).write(&out) {
+            Ok(_) => Ok(()),
+            // The request was interrupted, nobody waits for the reply
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => Err(Error::with_chain(e, "Unable to reply to FUSE")),
+        }
+    }
+
+    /* Handle a request, returning the reply data, or None if there is no
+     * reply to send */
+    fn dispatch<F: Filesystem>(
+        fs: &mut F,
+        opcode: u32,
+        nodeid: u64,
+        arg: &[u8],
+    ) -> Option<Reply<Vec<u8>>> {
+        let mut out = Vec::new();
+        let res = match opcode {
+            FUSE_INIT => {
+                if u32_at(arg, 0) < KERNEL_VERSION {
+                    return Some(Err(libc::EPROTO));
+                }
+                push_init(&mut out, arg);
+                Ok(())
+            }
+            FUSE_LOOKUP => {
+                let name = arg.split(|&b| b == 0).next().unwrap_or(&[]);
+                fs.lookup(nodeid, OsStr::from_bytes(name))
+                    .map(|attr| push_entry(&mut out, &attr))
+            }
+            FUSE_GETATTR => fs.getattr(nodeid).map(|attr| {
+                push_u64(&mut out, TTL.as_secs());
+                push_u32(&mut out, 0);
+                push_u32(&mut out, 0);
+                push_attr(&mut out, &attr);
+            }),
+            FUSE_OPEN => fs
+                .open(nodeid, u32_at(arg, 0) as i32)
+                .map(|fh| push_open(&mut out, fh)),
+            FUSE_READ => fs
+                .read(nodeid, u64_at(arg, 0), u64_at(arg, 8), u32_at(arg, 16))
+                .map(|data| out = data),
+            FUSE_RELEASE => {
+                fs.release(u64_at(arg, 0));
+                Ok(())
+            }
+            FUSE_OPENDIR => fs.getattr(nodeid).and_then(|attr| match attr.kind {
+                FileType::Directory => {
+                    push_open(&mut out, 0);
+                    Ok(())
+                }
+                FileType::RegularFile => Err(libc::ENOTDIR),
+            }),
+            FUSE_READDIR => fs.readdir(nodeid).map(|entries| {
+                push_dir_entries(&mut out, &entries, u64_at(arg, 8), u32_at(arg, 16) as usize)
+            }),
+            FUSE_STATFS => {
+                push_statfs(&mut out);
+                Ok(())
+            }
+            // Permissions are checked by the kernel, see default_permissions
+            FUSE_RELEASEDIR | FUSE_FLUSH | FUSE_ACCESS | FUSE_DESTROY => Ok(()),
+            // Inodes stay valid, and requests are not long enough to bother
+            // interrupting them
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return None,
+            _ => Err(libc::ENOSYS),
+        };
+        Some(res.map(|_| out))
+    }
+
+    /** Answer the requests of the kernel with fs until the filesystem is
+     * unmounted. */
+    pub fn serve<F: Filesystem>(&self, fs: &mut F) -> Result<()> {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let len = match (&self.fd).read(&mut buf) {
+                Ok(len) => len,
+                Err(e) => match e.raw_os_error() {
+                    // Interrupted requests, or signals
+                    Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                    Some(libc::ENODEV) => return Ok(()),
+                    _ => return Err(Error::with_chain(e, "Unable to read from FUSE")),
+                },
+            };
+            if len < IN_HEADER_SIZE || u32_at(&buf, 0) as usize != len {
+                bail!("Malformed FUSE request of {} bytes", len);
+            }
+            let opcode = u32_at(&buf, 4);
+            let unique = u64_at(&buf, 8);
+            let nodeid = u64_at(&buf, 16);
+            match Self::dispatch(fs, opcode, nodeid, &buf[IN_HEADER_SIZE..len]) {
+                None => {}
+                Some(Ok(data)) => self.reply(unique, 0, &data)?,
+                Some(Err(errno)) => self.reply(unique, errno, &[])?,
+            }
+            if opcode == FUSE_DESTROY {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,351 @@
+/* Mount an archive as a read-only filesystem with FUSE, so that tools can
+ * use its contents without extracting them first:
+ *
+ *   hpk-mount ARCHIVE MOUNTPOINT
+ *
+ * The filesystem is served until it is unmounted, with umount, or
+ * fusermount -u when mounted by another user than root. Inodes are the file
+ * table indices of the entries, the root directory having index 1 like the
+ * FUSE root. Files have their decompressed size, and are read through
+ * FileData readers of the archive mapped in memory, which share a cache of
+ * decompressed blocks.
+ *
+ * The FUSE protocol is spoken directly, see src/fuse.rs, and only on
+ * Linux. */
+
+#![allow(dead_code)]
+#![recursion_limit = "1024"]
+
+#[macro_use]
+extern crate error_chain;
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+#[path = "../../src/errors.rs"]
+mod errors;
+// The parser also has code for the features of the unpacker and for
+// fuzzing, which this binary does not build
+#[allow(unexpected_cfgs)]
+#[path = "../../src/hpk.rs"]
+mod hpk;
+#[cfg(test)]
+#[path = "../../src/builder.rs"]
+mod builder;
+#[cfg(test)]
+#[path = "../../src/testutil.rs"]
+mod testutil;
+#[cfg(target_os = "linux")]
+mod fuse;
+
+use errors::*;
+
+#[cfg(target_os = "linux")]
+mod mount {
+    use errors::*;
+    use fuse::{Attr, DirEntry, FileType, Filesystem, Reply, Session, ROOT_ID};
+    use hpk::{Archive, ArchiveOptions, Directory, EntryRef, FileData};
+    use libc;
+    use std::collections::HashMap;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::path::Path;
+    use std::time::SystemTime;
+
+    const SHARED_CACHE_BYTES: usize = 64 << 20;
+
+    pub struct HpkMount {
+        archive: Archive,
+        /* Modification time of the archive, given to all the entries */
+        mtime: SystemTime,
+        uid: u32,
+        gid: u32,
+        /* Readers of the open files, by file handle */
+        open_files: HashMap<u64, FileData>,
+        next_fh: u64,
+    }
+
+    impl HpkMount {
+        /** Open the archive at path to be served by a `Session` */
+        pub fn open(path: &str) -> Result<HpkMount> {
+            let options = ArchiveOptions::new().shared_cache_bytes(Some(SHARED_CACHE_BYTES));
+            let archive =
+                Archive::open_mmap_with(path, &options).chain_err(|| "Unable to open archive")?;
+            let mtime = fs::metadata(path)?.modified()?;
+            Ok(HpkMount {
+                archive: archive,
+                mtime: mtime,
+                uid: unsafe { libc::getuid() },
+                gid: unsafe { libc::getgid() },
+                open_files: HashMap::new(),
+                next_fh: 1,
+            })
+        }
+
+        fn entry(&self, ino: u64) -> Option<EntryRef<'_>> {
+            if ino > u32::max_value() as u64 {
+                return None;
+            }
+            match self.archive.entry_by_index(ino as u32) {
+                Some(EntryRef::Unreferenced) | None => None,
+                entry => entry,
+            }
+        }
+
+        /* Directory at a '/' separated path, as given by EntryRef */
+        fn directory_at(&self, path: &str) -> Result<Option<&Directory>> {
+            let mut dir = self.archive.root_directory();
+            for component in path.split('/').filter(|c| !c.is_empty()) {
+                dir.load_children()?;
+                dir = match dir
+                    .directories()
+                    .iter()
+                    .find(|d| d.name() == Some(component))
+                {
+                    Some(d) => d,
+                    None => return Ok(None),
+                };
+            }
+            Ok(Some(dir))
+        }
+
+        fn attr(&self, ino: u64) -> Result<Option<Attr>> {
+            let (kind, size, perm, nlink) = match self.entry(ino) {
+                Some(EntryRef::File(_, file)) => {
+                    let size = self.archive.file_data(file)?.size();
+                    (FileType::RegularFile, size, 0o444, 1)
+                }
+                Some(EntryRef::Directory(..)) => (FileType::Directory, 0, 0o555, 2),
+                _ => return Ok(None),
+            };
+            Ok(Some(Attr {
+                ino: ino,
+                size: size,
+                kind: kind,
+                perm: perm,
+                nlink: nlink,
+                uid: self.uid,
+                gid: self.gid,
+                mtime: self.mtime,
+            }))
+        }
+
+        /* Entries of a directory, including . and .. */
+        fn dir_entries(&self, ino: u64) -> Result<Option<Vec<DirEntry>>> {
+            let (path, dir) = match self.entry(ino) {
+                Some(EntryRef::Directory(path, dir)) => (path, dir),
+                _ => return Ok(None),
+            };
+            let parent_path = match path.trim_end_matches('/').rfind('/') {
+                Some(pos) => &path[..pos],
+                None => "",
+            };
+            let parent = self
+                .directory_at(parent_path)?
+                .map_or(ROOT_ID, |d| d.index() as u64);
+            let dir_entry = |ino, kind, name: &str| DirEntry {
+                ino: ino,
+                kind: kind,
+                name: name.to_string(),
+            };
+            let mut entries = vec![
+                dir_entry(ino, FileType::Directory, "."),
+                dir_entry(parent, FileType::Directory, ".."),
+            ];
+            dir.load_children()?;
+            for d in dir.directories() {
+                let name = d.name().unwrap_or("");
+                entries.push(dir_entry(d.index() as u64, FileType::Directory, name));
+            }
+            for f in dir.files() {
+                entries.push(dir_entry(f.index() as u64, FileType::RegularFile, f.name()));
+            }
+            Ok(Some(entries))
+        }
+
+        fn child_index(&self, parent: u64, name: &OsStr) -> Result<Option<u64>> {
+            let (name, dir) = match (name.to_str(), self.entry(parent)) {
+                (Some(name), Some(EntryRef::Directory(_, dir))) => (name, dir),
+                _ => return Ok(None),
+            };
+            dir.load_children()?;
+            if let Some(d) = dir.directories().iter().find(|d| d.name() == Some(name)) {
+                return Ok(Some(d.index() as u64));
+            }
+            let file = dir.files().iter().find(|f| f.name() == name);
+            Ok(file.map(|f| f.index() as u64))
+        }
+
+        fn read_at(data: &mut FileData, offset: u64, size: usize) -> Result<Vec<u8>> {
+            let len = data.size().saturating_sub(offset).min(size as u64) as usize;
+            let mut buf = vec![0u8; len];
+            if len > 0 {
+                data.seek(SeekFrom::Start(offset))?;
+                data.read_exact(&mut buf)?;
+            }
+            Ok(buf)
+        }
+    }
+
+    /* Log an error, which FUSE can only report as an errno */
+    fn log_error(operation: &str, ino: u64, e: &Error) -> i32 {
+        let messages: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+        eprintln!(
+            "error: {} of inode {}: {}",
+            operation,
+            ino,
+            messages.join(": ")
+        );
+        libc::EIO
+    }
+
+    impl Filesystem for HpkMount {
+        fn lookup(&mut self, parent: u64, name: &OsStr) -> Reply<Attr> {
+            match self.child_index(parent, name) {
+                Ok(Some(ino)) => self.getattr(ino),
+                Ok(None) => Err(libc::ENOENT),
+                Err(e) => Err(log_error("lookup", parent, &e)),
+            }
+        }
+
+        fn getattr(&mut self, ino: u64) -> Reply<Attr> {
+            match self.attr(ino) {
+                Ok(attr) => attr.ok_or(libc::ENOENT),
+                Err(e) => Err(log_error("getattr", ino, &e)),
+            }
+        }
+
+        fn open(&mut self, ino: u64, flags: i32) -> Reply<u64> {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                return Err(libc::EROFS);
+            }
+            let data = match self.entry(ino) {
+                Some(EntryRef::File(_, file)) => self.archive.file_data(file),
+                Some(_) => return Err(libc::EISDIR),
+                None => return Err(libc::ENOENT),
+            };
+            let data = data.map_err(|e| log_error("open", ino, &e))?;
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            self.open_files.insert(fh, data);
+            Ok(fh)
+        }
+
+        fn read(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Reply<Vec<u8>> {
+            let data = self.open_files.get_mut(&fh).ok_or(libc::EBADF)?;
+            Self::read_at(data, offset, size as usize).map_err(|e| log_error("read", ino, &e))
+        }
+
+        fn release(&mut self, fh: u64) {
+            self.open_files.remove(&fh);
+        }
+
+        fn readdir(&mut self, ino: u64) -> Reply<Vec<DirEntry>> {
+            match self.dir_entries(ino) {
+                Ok(entries) => entries.ok_or(libc::ENOTDIR),
+                Err(e) => Err(log_error("readdir", ino, &e)),
+            }
+        }
+    }
+
+    /** Serve the archive at path on mountpoint until it is unmounted. */
+    pub fn mount(path: &str, mountpoint: &str) -> Result<()> {
+        let mut filesystem = HpkMount::open(path)?;
+        let session = Session::mount(path, Path::new(mountpoint))?;
+        session.serve(&mut filesystem)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        extern crate tempfile;
+
+        use super::*;
+        use std::path::PathBuf;
+        use std::thread;
+        use testutil::*;
+
+        /* Unmounts when dropped, even if the test fails */
+        struct Mounted(PathBuf);
+
+        impl Drop for Mounted {
+            fn drop(&mut self) {
+                ::fuse::unmount(&self.0).unwrap();
+            }
+        }
+
+        #[test]
+        fn mount_and_read() {
+            // Mounting needs the FUSE device, and the permission to mount,
+            // which containers may not give
+            if !Path::new("/dev/fuse").exists() {
+                eprintln!("skipped: no /dev/fuse");
+                return;
+            }
+            let data = sample_data(0x30000);
+            let file = archive_file(&[
+                TestEntry::File(b"plain", data.clone()),
+                TestEntry::Dir(b"d", vec![TestEntry::Zlib(b"zlib", data.clone(), 0x8000)]),
+            ]);
+            let path = file.path().to_str().unwrap();
+            let mut filesystem = HpkMount::open(path).unwrap();
+            let mountpoint = tempfile::tempdir().unwrap();
+            let session = match Session::mount(path, mountpoint.path()) {
+                Ok(session) => session,
+                Err(e) => {
+                    eprintln!("skipped: {}", e);
+                    return;
+                }
+            };
+            let server = thread::spawn(move || session.serve(&mut filesystem));
+            let mounted = Mounted(mountpoint.path().to_path_buf());
+
+            let mut names: Vec<_> = fs::read_dir(mountpoint.path())
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            assert_eq!(names, ["d", "plain"]);
+            let zlib = mountpoint.path().join("d/zlib");
+            assert!(fs::metadata(mountpoint.path().join("d")).unwrap().is_dir());
+            assert_eq!(fs::metadata(&zlib).unwrap().len(), data.len() as u64);
+            assert!(fs::read(mountpoint.path().join("plain")).unwrap() == data);
+            assert!(fs::read(&zlib).unwrap() == data);
+            // Reads at an offset, with another file open
+            let mut plain = fs::File::open(mountpoint.path().join("plain")).unwrap();
+            let mut zlib = fs::File::open(&zlib).unwrap();
+            let mut buf = [0u8; 100];
+            zlib.seek(SeekFrom::Start(0x12345)).unwrap();
+            zlib.read_exact(&mut buf).unwrap();
+            assert!(buf[..] == data[0x12345..0x12345 + 100]);
+            plain.read_exact(&mut buf).unwrap();
+            assert!(buf[..] == data[..100]);
+            assert!(fs::File::open(mountpoint.path().join("missing")).is_err());
+            assert!(fs::write(mountpoint.path().join("plain"), b"x").is_err());
+
+            drop((plain, zlib));
+            drop(mounted);
+            server.join().unwrap().unwrap();
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 3 {
+        bail!("Usage: {} ARCHIVE MOUNTPOINT", args[0]);
+    }
+    #[cfg(target_os = "linux")]
+    return mount::mount(&args[1], &args[2]);
+    #[cfg(not(target_os = "linux"))]
+    bail!("Mounting archives is only supported on Linux");
+}
+
+fn main() {
+    if let Err(ref e) = run() {
+        eprintln!("error: {}", e);
+        for e in e.iter().skip(1) {
+            eprintln!("caused by: {}", e);
+        }
+        ::std::process::exit(1);
+    }
+}
@@ -0,0 +1,276 @@
+/* Adapter reading the files of an archive from asynchronous code. */
+
+use ::errors::*;
+use hpk::{Archive, File, FileData};
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::task::{spawn_blocking, JoinHandle};
+
+/* Most bytes read by a single blocking task, which for a compressed file
+ * means a few blocks decompressed at a time */
+const ASYNC_READ_CHUNK_SIZE: usize = 0x10000;
+
+/* Read done by a blocking task, returning the reader it was given back along
+ * with the bytes read */
+type ReadJob = JoinHandle<(FileData, io::Result<Vec<u8>>)>;
+
+/** Reader over a file of an archive implementing tokio's `AsyncRead` and
+ * `AsyncSeek`, see `Archive::async_file_data()`. Reads, and the
+ * decompression they involve, are done by tasks of tokio's blocking thread
+ * pool, so it must be used from within a tokio runtime. Readers over the
+ * same archive, or the same file, do not share any position and can be used
+ * by concurrent tasks.
+ *
+ * A read whose future is dropped before completion is not lost: its bytes
+ * are returned by the next read, and seeks take them into account. */
+pub struct AsyncFileData {
+    /* None while a read is in progress */
+    data: Option<FileData>,
+    job: Option<ReadJob>,
+    /* Bytes read by the last job and not returned yet, from pending_pos */
+    pending: Vec<u8>,
+    pending_pos: usize,
+    /* Seek started with start_seek() */
+    seek: Option<SeekFrom>,
+}
+
+fn reader_lost() -> io::Error {
+    io::Error::other("Reader was lost by a failed read")
+}
+
+impl AsyncFileData {
+    pub fn new(data: FileData) -> AsyncFileData {
+        AsyncFileData {
+            data: Some(data),
+            job: None,
+            pending: Vec::new(),
+            pending_pos: 0,
+            seek: None,
+        }
+    }
+
+    fn unread(&self) -> usize {
+        self.pending.len() - self.pending_pos
+    }
+
+    /* Wait for the read in progress, if any, to complete. Returns whether
+     * there was one. */
+    fn poll_job(&mut self, cx: &mut Context) -> Poll<io::Result<bool>> {
+        let (data, res) = match self.job {
+            None => return Poll::Ready(Ok(false)),
+            Some(ref mut job) => match Pin::new(job).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(r)) => r,
+                Poll::Ready(Err(e)) => {
+                    self.job = None;
+                    return Poll::Ready(Err(io::Error::other(e)));
+                }
+            },
+        };
+        self.job = None;
+        self.data = Some(data);
+        self.pending = res?;
+        self.pending_pos = 0;
+        Poll::Ready(Ok(true))
+    }
+
+    fn start_read(&mut self, len: usize) -> io::Result<()> {
+        let mut data = self.data.take().ok_or_else(reader_lost)?;
+        let len = cmp::min(len, ASYNC_READ_CHUNK_SIZE);
+        self.job = Some(spawn_blocking(move || {
+            let mut chunk = vec![0u8; len];
+            let res = data.read(&mut chunk).map(|n| {
+                chunk.truncate(n);
+                chunk
+            });
+            (data, res)
+        }));
+        Ok(())
+    }
+}
+
+impl AsyncRead for AsyncFileData {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut completed = match this.poll_job(cx) {
+            Poll::Ready(res) => res?,
+            Poll::Pending => return Poll::Pending,
+        };
+        if this.unread() == 0 && !completed {
+            if buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            this.start_read(buf.remaining())?;
+            completed = match this.poll_job(cx) {
+                Poll::Ready(res) => res?,
+                Poll::Pending => return Poll::Pending,
+            };
+        }
+        // Either bytes are left, or a read just ended at the end of the file
+        debug_assert!(completed || this.unread() > 0);
+        let len = cmp::min(buf.remaining(), this.unread());
+        buf.put_slice(&this.pending[this.pending_pos..this.pending_pos + len]);
+        this.pending_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for AsyncFileData {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        if this.seek.is_some() {
+            return Err(io::Error::other("Another seek is in progress"));
+        }
+        this.seek = Some(position);
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        // The bytes of a read in progress are accounted for as unread ones
+        if this.poll_job(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        let unread = this.unread() as u64;
+        let data = this.data.as_mut().ok_or_else(reader_lost)?;
+        let res = match this.seek.take() {
+            None => return Poll::Ready(data.stream_position().map(|p| p - unread)),
+            Some(SeekFrom::Current(offset)) => data.seek(SeekFrom::Current(offset - unread as i64)),
+            Some(position) => data.seek(position),
+        };
+        if res.is_ok() {
+            this.pending.clear();
+            this.pending_pos = 0;
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl Archive {
+    /** Open the data of a file for reading from asynchronous code. */
+    pub fn async_file_data(&self, file: &File) -> Result<AsyncFileData> {
+        Ok(AsyncFileData::new(self.file_data(file)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::*;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn async_reads_and_seeks() {
+        let data = sample_data(0x25000);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::File(b"zlib", write_zlib_file_data(&data, 0x1000)),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let rt = runtime();
+        for f in archive.root_directory().files() {
+            let mut reader = archive.async_file_data(f).unwrap();
+            let mut contents = Vec::new();
+            rt.block_on(reader.read_to_end(&mut contents)).unwrap();
+            assert!(contents == data, "{}", f.name());
+
+            let pos = rt.block_on(reader.seek(SeekFrom::Start(0x1234))).unwrap();
+            assert_eq!(pos, 0x1234);
+            let mut buf = [0u8; 0x100];
+            rt.block_on(reader.read_exact(&mut buf)).unwrap();
+            assert_eq!(&buf[..], &data[0x1234..0x1334]);
+            let pos = rt.block_on(reader.seek(SeekFrom::Current(-0x10))).unwrap();
+            assert_eq!(pos, 0x1324);
+        }
+    }
+
+    #[test]
+    fn serve_files_concurrently() {
+        let plain = sample_data(0x20000);
+        let zlib: Vec<u8> = sample_data(0x20100).split_off(0x100);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", plain.clone()),
+            TestEntry::Zlib(b"zlib", zlib.clone(), 0x1000),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        // Send each file several times at once in small chunks, as a server
+        // would over as many connections, the blocking reads overlapping on
+        // several threads
+        let mut transfers: Vec<_> = (0..16)
+            .map(|i| {
+                let path = if i % 2 == 0 { "plain" } else { "zlib" };
+                let reader = AsyncFileData::new(archive.file_data_at(path).unwrap());
+                (path, reader, Vec::new(), false)
+            })
+            .collect();
+        runtime().block_on(::std::future::poll_fn(|cx| {
+            let mut done = true;
+            for &mut (_, ref mut reader, ref mut sent, ref mut eof) in &mut transfers {
+                while !*eof {
+                    let mut buf = [0u8; 0x100];
+                    let mut read_buf = ReadBuf::new(&mut buf);
+                    match Pin::new(&mut *reader).poll_read(cx, &mut read_buf) {
+                        Poll::Pending => break,
+                        Poll::Ready(res) => res.unwrap(),
+                    }
+                    *eof = read_buf.filled().is_empty();
+                    sent.extend_from_slice(read_buf.filled());
+                }
+                done &= *eof;
+            }
+            if done {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }));
+        for (path, _, sent, _) in transfers {
+            let expected = if path == "plain" { &plain } else { &zlib };
+            assert!(sent == *expected, "{} differs", path);
+        }
+    }
+
+    #[test]
+    fn cancelled_read_is_not_lost() {
+        let data = sample_data(0x3000);
+        let file = archive_file(&[TestEntry::File(
+            b"zlib",
+            write_zlib_file_data(&data, 0x1000),
+        )]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let f = &archive.root_directory().files()[0];
+        let rt = runtime();
+        let mut reader = archive.async_file_data(f).unwrap();
+        // Start a read, and give up on it right away
+        rt.block_on(::std::future::poll_fn(|cx| {
+            let mut buf = [0u8; 0x800];
+            let mut read_buf = ReadBuf::new(&mut buf);
+            let _ = Pin::new(&mut reader).poll_read(cx, &mut read_buf);
+            Poll::Ready(())
+        }));
+        assert_eq!(rt.block_on(reader.stream_position()).unwrap(), 0);
+        let mut buf = [0u8; 0x10];
+        rt.block_on(reader.read_exact(&mut buf)).unwrap();
+        assert_eq!(&buf[..], &data[..0x10]);
+        assert_eq!(rt.block_on(reader.stream_position()).unwrap(), 0x10);
+        let mut rest = Vec::new();
+        rt.block_on(reader.read_to_end(&mut rest)).unwrap();
+        assert!(rest[..] == data[0x10..]);
+    }
+}
@@ -0,0 +1,1005 @@
+extern crate byteorder;
+extern crate libflate;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+use self::libflate::lz77::DefaultLz77Encoder;
+use self::libflate::zlib::{EncodeOptions, Encoder};
+use ::errors::*;
+use hpk::{Archive, MAGIC, MAX_DIRECTORY_DEPTH, MAX_NAME_LENGTH, ZLIB_MAGIC};
+use std::cmp;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const HEADER_SIZE: usize = 0x24;
+
+/** Size of the blocks of the ZLIB compressed files written by
+ * `ArchiveBuilder` */
+pub const ZLIB_BLOCK_SIZE: usize = 0x8000;
+/** Compression level used when packing, unless specified otherwise */
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+const MAX_COMPRESSION_LEVEL: u32 = 9;
+/* Extensions of the files which are already compressed, and which
+ * `add_fs_directory()` stores as is */
+const STORED_EXTENSIONS: &[&str] = &["bik", "jpeg", "jpg", "ogg", "png", "zip"];
+
+/** What to do when adding a file at a path which is already taken. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePolicy {
+    /** Replace the existing file */
+    Overwrite,
+    /** Keep the existing file */
+    Skip,
+}
+
+/** Problem found by `ArchiveBuilder::validate()`. Each variant holds the
+ * '/' separated path of the offending entry. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuilderError {
+    /** Several entries of a directory have the same name */
+    DuplicatePath(String),
+    EmptyName(String),
+    /** The name is longer than `MAX_NAME_LENGTH` bytes */
+    NameTooLong(String),
+    /** The directory is more than `MAX_DIRECTORY_DEPTH` levels deep */
+    TooDeep(String),
+    /** The name contains a null byte */
+    NullByte(String),
+}
+
+impl fmt::Display for BuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuilderError::DuplicatePath(ref p) => write!(f, "duplicate path \"{}\"", p),
+            BuilderError::EmptyName(ref p) => write!(f, "empty name in \"{}\"", p),
+            BuilderError::NameTooLong(ref p) => write!(
+                f,
+                "name of \"{}\" is longer than {} bytes",
+                p, MAX_NAME_LENGTH
+            ),
+            BuilderError::TooDeep(ref p) => write!(
+                f,
+                "directory \"{}\" is more than {} levels deep",
+                p, MAX_DIRECTORY_DEPTH
+            ),
+            BuilderError::NullByte(ref p) => write!(f, "name of \"{}\" contains a null byte", p),
+        }
+    }
+}
+
+/* Where the data of a file comes from */
+enum FileSource {
+    Data(Vec<u8>),
+    /* File of the filesystem, only read when the archive is written */
+    Path(PathBuf),
+}
+
+enum BuilderEntry {
+    /* Data, and whether it may be compressed */
+    File(FileSource, bool),
+    Directory(BuilderDir),
+}
+
+struct BuilderDir {
+    /* Entries in the order in which they were added */
+    entries: Vec<(String, BuilderEntry)>,
+}
+
+/** Collects files and directories, then writes them as a new HPK archive.
+ * Paths are '/' separated and relative to the root directory, which is
+ * designated by an empty path. */
+pub struct ArchiveBuilder {
+    root: BuilderDir,
+    merge_policy: MergePolicy,
+    compression_level: Option<u32>,
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    let mut buf = [0u8; 4];
+    LittleEndian::write_u32(&mut buf, value);
+    out.extend_from_slice(&buf);
+}
+
+/* Deflate a block. Level 0 and blocks which deflating does not make smaller
+ * are stored as is, which the reader detects by their size being the block
+ * size. libflate has no notion of levels: lower levels use a smaller LZ77
+ * window, and the lowest ones fixed Huffman codes. */
+fn deflate_block(block: &[u8], level: u32) -> Result<Vec<u8>> {
+    if level == 0 {
+        return Ok(block.to_vec());
+    }
+    let window_size = cmp::min(1u32 << (7 + level), 0x8000) as u16;
+    let mut options = EncodeOptions::with_lz77(DefaultLz77Encoder::with_window_size(window_size));
+    if level <= 3 {
+        options = options.fixed_huffman_codes();
+    }
+    let mut encoder = Encoder::with_options(Vec::new(), options)?;
+    encoder.write_all(block)?;
+    let packed = encoder.finish().into_result()?;
+    if packed.len() < block.len() {
+        Ok(packed)
+    } else {
+        Ok(block.to_vec())
+    }
+}
+
+/** Build the data of a ZLIB compressed file: a header holding the size of
+ * the data and the block size, a table of the offsets of the blocks, then the
+ * blocks. */
+pub fn zlib_file_data(data: &[u8], blocksize: usize, level: u32) -> Result<Vec<u8>> {
+    let mut out = io::Cursor::new(Vec::new());
+    write_zlib_file(
+        &mut &data[..],
+        data.len() as u64,
+        blocksize,
+        level,
+        &mut out,
+    )?;
+    Ok(out.into_inner())
+}
+
+/** Same as `zlib_file_data()`, except that the size bytes of data are read
+ * from input and compressed one block at a time as they are read, then
+ * written to out at its current position. Only one block is held in memory.
+ * Returns the size of the compressed file.
+ *
+ * The offsets of the blocks are only known once they are compressed, while
+ * their table comes before them. Its size only depends on the size of the
+ * data though, so room is left for it, and out is seeked back to fill it in
+ * once the blocks are written, then to the end of the file. Reading input
+ * twice instead would compress every block twice. An error is returned if
+ * input does not hold exactly size bytes. */
+pub fn write_zlib_file<R: Read, W: Write + Seek>(
+    input: &mut R,
+    size: u64,
+    blocksize: usize,
+    level: u32,
+    out: &mut W,
+) -> Result<u64> {
+    if size > u32::MAX as u64 {
+        bail!("File is too large to be compressed");
+    }
+    if blocksize == 0 {
+        bail!("Invalid block size: 0");
+    }
+    let num_blocks = size.div_ceil(blocksize as u64) as usize;
+    let start = out.stream_position()?;
+    let mut header = Vec::new();
+    header.extend_from_slice(ZLIB_MAGIC);
+    push_u32(&mut header, size as u32);
+    push_u32(&mut header, blocksize as u32);
+    out.write_all(&header)?;
+    out.write_all(&vec![0u8; num_blocks * 4])?;
+
+    let mut offsets = Vec::new();
+    let mut offset = (header.len() + num_blocks * 4) as u64;
+    let mut block = vec![0u8; blocksize];
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = cmp::min(remaining, blocksize as u64) as usize;
+        input
+            .read_exact(&mut block[..len])
+            .chain_err(|| format!("Expected {} bytes of data", size))?;
+        let packed = deflate_block(&block[..len], level)?;
+        out.write_all(&packed)?;
+        push_u32(&mut offsets, offset as u32);
+        offset += packed.len() as u64;
+        if offset > u32::MAX as u64 {
+            bail!("Compressed file is too large");
+        }
+        remaining -= len as u64;
+    }
+    if input.read(&mut block[..1])? != 0 {
+        bail!("Expected {} bytes of data, got more", size);
+    }
+
+    out.seek(SeekFrom::Start(start + header.len() as u64))?;
+    out.write_all(&offsets)?;
+    out.seek(SeekFrom::Start(start + offset))?;
+    Ok(offset)
+}
+
+/* Copy the size bytes of data read from input to out. An error is returned
+ * if input does not hold exactly size bytes. */
+fn copy_exact<R: Read, W: Write>(input: &mut R, size: u64, out: &mut W) -> Result<()> {
+    let copied = io::copy(&mut input.by_ref().take(size), out)?;
+    if copied != size {
+        bail!("Expected {} bytes of data, got {}", size, copied);
+    }
+    if input.read(&mut [0u8; 1])? != 0 {
+        bail!("Expected {} bytes of data, got more", size);
+    }
+    Ok(())
+}
+
+/* Writer keeping track of its position, so that querying it with
+ * `SeekFrom::Current(0)` does not flush a `BufWriter`. */
+struct PositionTracker<W> {
+    inner: W,
+    pos: u64,
+}
+
+impl<W: Write> Write for PositionTracker<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for PositionTracker<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if let SeekFrom::Current(0) = pos {
+            return Ok(self.pos);
+        }
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+fn is_stored_format(name: &str) -> bool {
+    match name.rfind('.') {
+        Some(pos) => {
+            let ext = name[pos + 1..].to_lowercase();
+            STORED_EXTENSIONS.contains(&ext.as_str())
+        }
+        None => false,
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('/') {
+        bail!("Invalid entry name: \"{}\"", name);
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        bail!("Entry name is too long: \"{}\"", name);
+    }
+    Ok(())
+}
+
+impl BuilderDir {
+    fn new() -> BuilderDir {
+        BuilderDir {
+            entries: Vec::new(),
+        }
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|(n, _)| n == name)
+    }
+
+    fn subdir_mut(&mut self, path: &str) -> Result<&mut BuilderDir> {
+        let mut dir = self;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            let pos = match dir.position(component) {
+                Some(p) => p,
+                None => bail!("No such directory: \"{}\"", path),
+            };
+            dir = match dir.entries[pos].1 {
+                BuilderEntry::Directory(ref mut d) => d,
+                BuilderEntry::File(..) => bail!("\"{}\" is not a directory", path),
+            };
+        }
+        Ok(dir)
+    }
+
+    /* Number of entries in the file table needed for this directory, itself
+     * included. */
+    fn num_indices(&self) -> u32 {
+        1 + self
+            .entries
+            .iter()
+            .map(|(_, e)| match *e {
+                BuilderEntry::File(..) => 1,
+                BuilderEntry::Directory(ref d) => d.num_indices(),
+            })
+            .sum::<u32>()
+    }
+
+    /* Check the entries of the directory at path, which is at the specified
+     * level below the root directory, and of its subdirectories. */
+    fn validate(&self, path: &str, level: usize, errors: &mut Vec<BuilderError>) {
+        let mut names = HashSet::new();
+        for (name, entry) in &self.entries {
+            let entry_path = format!("{}{}", path, name);
+            if name.is_empty() {
+                errors.push(BuilderError::EmptyName(path.to_string()));
+            }
+            if name.len() > MAX_NAME_LENGTH {
+                errors.push(BuilderError::NameTooLong(entry_path.clone()));
+            }
+            if name.contains('\0') {
+                errors.push(BuilderError::NullByte(entry_path.clone()));
+            }
+            if !names.insert(name) {
+                errors.push(BuilderError::DuplicatePath(entry_path.clone()));
+            }
+            if let BuilderEntry::Directory(ref d) = *entry {
+                if level + 1 > MAX_DIRECTORY_DEPTH {
+                    errors.push(BuilderError::TooDeep(entry_path.clone()));
+                } else {
+                    d.validate(&format!("{}/", entry_path), level + 1, errors);
+                }
+            }
+        }
+    }
+
+    /* Write the data of all files and the name tables of all directories to
+     * out, from its current position, and fill in the file table. Directory
+     * contents are laid out before the name table of their parent. */
+    fn layout<W: Write + Seek>(
+        &self,
+        index: u32,
+        compression_level: Option<u32>,
+        out: &mut W,
+        table: &mut Vec<(u32, u32)>,
+    ) -> Result<()> {
+        let mut names = Vec::new();
+        let mut next_index = index + 1;
+        for (name, entry) in &self.entries {
+            let entry_type = match *entry {
+                BuilderEntry::File(ref source, compress) => {
+                    let offset = out.stream_position()?;
+                    let size = write_file_data(source, compression_level.filter(|_| compress), out)
+                        .chain_err(|| format!("Unable to write the data of \"{}\"", name))?;
+                    if offset + size > u32::MAX as u64 {
+                        bail!("Archive is too large");
+                    }
+                    table[next_index as usize - 1] = (offset as u32, size as u32);
+                    0
+                }
+                BuilderEntry::Directory(ref d) => {
+                    d.layout(next_index, compression_level, out, table)?;
+                    1
+                }
+            };
+            push_u32(&mut names, next_index);
+            push_u32(&mut names, entry_type);
+            let mut len = [0u8; 2];
+            LittleEndian::write_u16(&mut len, name.len() as u16);
+            names.extend_from_slice(&len);
+            names.extend_from_slice(name.as_bytes());
+            next_index += match *entry {
+                BuilderEntry::File(..) => 1,
+                BuilderEntry::Directory(ref d) => d.num_indices(),
+            };
+        }
+        let offset = out.stream_position()?;
+        if offset + names.len() as u64 > u32::MAX as u64 {
+            bail!("Archive is too large");
+        }
+        table[index as usize - 1] = (offset as u32, names.len() as u32);
+        out.write_all(&names)?;
+        Ok(())
+    }
+}
+
+/* Write the data of a file to out, ZLIB compressed if a level is given,
+ * returning its size in the archive. */
+fn write_file_data<W: Write + Seek>(
+    source: &FileSource,
+    compression_level: Option<u32>,
+    out: &mut W,
+) -> Result<u64> {
+    match (source, compression_level) {
+        /* Data in memory is compressed in memory too, so that out need not
+         * be seeked back to fill in the block table */
+        (FileSource::Data(data), Some(level)) => {
+            let packed = zlib_file_data(data, ZLIB_BLOCK_SIZE, level)?;
+            out.write_all(&packed)?;
+            Ok(packed.len() as u64)
+        }
+        (FileSource::Data(data), None) => {
+            out.write_all(data)?;
+            Ok(data.len() as u64)
+        }
+        (FileSource::Path(path), level) => {
+            let file =
+                fs::File::open(path).chain_err(|| format!("Unable to open {}", path.display()))?;
+            let size = file.metadata()?.len();
+            let mut input = io::BufReader::new(file);
+            let res = match level {
+                Some(level) => write_zlib_file(&mut input, size, ZLIB_BLOCK_SIZE, level, out),
+                None => copy_exact(&mut input, size, out).map(|_| size),
+            };
+            res.chain_err(|| format!("Unable to pack {}", path.display()))
+        }
+    }
+}
+
+impl Default for ArchiveBuilder {
+    fn default() -> ArchiveBuilder {
+        ArchiveBuilder {
+            root: BuilderDir::new(),
+            merge_policy: MergePolicy::Overwrite,
+            compression_level: None,
+        }
+    }
+}
+
+impl ArchiveBuilder {
+    pub fn new() -> ArchiveBuilder {
+        Self::default()
+    }
+
+    /** Set how files added at an existing path are handled. Defaults to
+     * `MergePolicy::Overwrite`. */
+    pub fn set_merge_policy(&mut self, policy: MergePolicy) {
+        self.merge_policy = policy;
+    }
+
+    /** Set the zlib compression level, from 0 to 9, of the files written by
+     * `finish()`. Level 0 still writes ZLIB files, with all blocks stored
+     * uncompressed. Files are written without a ZLIB header if no level is
+     * set, which is the default. */
+    pub fn set_compression_level(&mut self, level: Option<u32>) -> Result<()> {
+        if let Some(l) = level {
+            if l > MAX_COMPRESSION_LEVEL {
+                bail!(
+                    "Invalid compression level {}, expected 0 to {}",
+                    l,
+                    MAX_COMPRESSION_LEVEL
+                );
+            }
+        }
+        self.compression_level = level;
+        Ok(())
+    }
+
+    /** Add a directory named name in the existing directory parent. Adding a
+     * directory which already exists is not an error. */
+    pub fn add_directory(&mut self, parent: &str, name: &str) -> Result<()> {
+        check_name(name)?;
+        let dir = self.root.subdir_mut(parent)?;
+        match dir.position(name) {
+            Some(pos) => match dir.entries[pos].1 {
+                BuilderEntry::Directory(_) => Ok(()),
+                BuilderEntry::File(..) => bail!("A file named \"{}\" already exists", name),
+            },
+            None => {
+                dir.entries
+                    .push((name.to_string(), BuilderEntry::Directory(BuilderDir::new())));
+                Ok(())
+            }
+        }
+    }
+
+    /** Add a file named name in the existing directory parent. If a file
+     * with that name already exists, the merge policy decides which one is
+     * kept. */
+    pub fn add_file(&mut self, parent: &str, name: &str, data: Vec<u8>) -> Result<()> {
+        self.insert_file(parent, name, FileSource::Data(data), true)
+    }
+
+    /** Same as `add_file()` for the file at path in the filesystem, which is
+     * only read by `finish()`, and compressed as it is read. Large files are
+     * thus never held in memory. The file must not change until then. */
+    pub fn add_file_from_path(&mut self, parent: &str, name: &str, path: &Path) -> Result<()> {
+        self.insert_file(parent, name, FileSource::Path(path.to_path_buf()), true)
+    }
+
+    fn insert_file(
+        &mut self,
+        parent: &str,
+        name: &str,
+        data: FileSource,
+        compress: bool,
+    ) -> Result<()> {
+        check_name(name)?;
+        let policy = self.merge_policy;
+        let dir = self.root.subdir_mut(parent)?;
+        match dir.position(name) {
+            Some(pos) => match dir.entries[pos].1 {
+                BuilderEntry::File(ref mut existing, ref mut existing_compress) => {
+                    if policy == MergePolicy::Overwrite {
+                        *existing = data;
+                        *existing_compress = compress;
+                    }
+                    Ok(())
+                }
+                BuilderEntry::Directory(_) => {
+                    bail!("A directory named \"{}\" already exists", name)
+                }
+            },
+            None => {
+                dir.entries
+                    .push((name.to_string(), BuilderEntry::File(data, compress)));
+                Ok(())
+            }
+        }
+    }
+
+    /** Add a file at a '/' separated path, creating the directories leading
+     * to it which do not exist yet. */
+    pub fn add_file_at_path(&mut self, path: &str, data: Vec<u8>) -> Result<()> {
+        let mut components: Vec<&str> = path.split('/').collect();
+        let name = components.pop().unwrap();
+        let mut parent = String::new();
+        for component in components {
+            self.add_directory(&parent, component)?;
+            if !parent.is_empty() {
+                parent.push('/');
+            }
+            parent.push_str(component);
+        }
+        self.add_file(&parent, name, data)
+    }
+
+    /** Add the contents of the directory at path in the filesystem to the
+     * existing directory parent, recursively. Entries are added in
+     * lexicographical order of their names, files being added with
+     * `add_file_from_path()`, and so only read by `finish()`. Files with the
+     * extension of an already compressed format, such as Ogg or PNG, are
+     * stored as is. */
+    pub fn add_fs_directory(&mut self, parent: &str, path: &Path) -> Result<()> {
+        let mut entries = fs::read_dir(path)
+            .chain_err(|| format!("Unable to read directory {}", path.display()))?
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let name = match entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(n) => bail!("File name is not valid UTF-8: {}", n.to_string_lossy()),
+            };
+            if entry.file_type()?.is_dir() {
+                self.add_directory(parent, &name)?;
+                let subdir = if parent.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", parent, name)
+                };
+                self.add_fs_directory(&subdir, &entry.path())?;
+            } else {
+                let source = FileSource::Path(entry.path());
+                self.insert_file(parent, &name, source, !is_stored_format(&name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /** Add all the files of another archive, keeping their paths. Their data
+     * is decompressed, and compressed again by `finish()` according to the
+     * compression level. */
+    pub fn merge_archive(&mut self, other: &Archive) -> Result<()> {
+        other.walk(|path, file| {
+            let mut data = Vec::new();
+            other
+                .file_data(file)?
+                .read_to_end(&mut data)
+                .chain_err(|| format!("Unable to read \"{}\"", path))?;
+            self.add_file_at_path(path, data)
+        })
+    }
+
+    /** Check that the archive can be written and read back, returning all
+     * the problems found. `finish()` refuses to write an archive with
+     * problems. */
+    pub fn validate(&self) -> Result<Vec<BuilderError>> {
+        let mut errors = Vec::new();
+        self.root.validate("", 0, &mut errors);
+        Ok(errors)
+    }
+
+    /** Write the archive to the file at output. Data is written as files
+     * are compressed, the header last. The output file is removed if
+     * writing fails. */
+    pub fn finish(self, output: &str) -> Result<()> {
+        let errors = self.validate()?;
+        if !errors.is_empty() {
+            let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            bail!("Invalid archive contents: {}", messages.join(", "));
+        }
+        let mut out = PositionTracker {
+            inner: BufWriter::new(fs::File::create(output)?),
+            pos: 0,
+        };
+        let res = self.write(&mut out).and_then(|_| Ok(out.flush()?));
+        if res.is_err() {
+            drop(out);
+            let _ = fs::remove_file(output);
+        }
+        res
+    }
+
+    fn write<W: Write + Seek>(&self, out: &mut W) -> Result<()> {
+        out.write_all(&[0u8; HEADER_SIZE])?;
+        let mut table = vec![(0u32, 0u32); self.root.num_indices() as usize];
+        self.root
+            .layout(1, self.compression_level, out, &mut table)?;
+        let filetbl_offset = out.stream_position()? as u32;
+
+        let mut header = Vec::new();
+        header.extend_from_slice(MAGIC);
+        for &v in &[
+            HEADER_SIZE as u32,
+            1,
+            0xffffffff,
+            0,
+            0,
+            1,
+            filetbl_offset,
+            0,
+        ] {
+            push_u32(&mut header, v);
+        }
+        let mut filetbl = Vec::new();
+        for &(offset, size) in &table {
+            push_u32(&mut filetbl, offset);
+            push_u32(&mut filetbl, size);
+        }
+        out.write_all(&filetbl)?;
+        out.seek(SeekFrom::Start(0))?;
+        out.write_all(&header)?;
+        Ok(())
+    }
+}
+
+/** Write the archive output from the contents of the directory at path,
+ * the directory tree being kept as is, as `--pack` does. Files are ZLIB
+ * compressed at the specified level, except those of already compressed
+ * formats, see `ArchiveBuilder::add_fs_directory()`. */
+pub fn pack_directory(path: &Path, output: &str, compression_level: Option<u32>) -> Result<()> {
+    let mut builder = ArchiveBuilder::new();
+    builder.set_compression_level(compression_level)?;
+    builder.add_fs_directory("", path)?;
+    builder.finish(output)
+}
+
+impl Archive {
+    /** Write the archive output from the contents of extracted_dir, such as
+     * left by `extract_archive()`, with `pack_directory()` at the default
+     * compression level. */
+    pub fn rebuild_from_extracted(extracted_dir: &str, output: &str) -> Result<()> {
+        pack_directory(
+            Path::new(extracted_dir),
+            output,
+            Some(DEFAULT_COMPRESSION_LEVEL),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use testutil::*;
+
+    #[cfg(feature = "fs")]
+    fn read_file(archive: &Archive, path: &str) -> Vec<u8> {
+        let file = archive.find(path).unwrap().unwrap();
+        let mut data = Vec::new();
+        archive
+            .file_data(file)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn rebuild_from_extracted_tree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("in");
+        let script = b"print(\"El Presidente\")\n".repeat(100);
+        fs::create_dir_all(input.join("Scripts")).unwrap();
+        fs::create_dir_all(input.join("Sounds")).unwrap();
+        fs::write(input.join("Scripts/init.lua"), &script).unwrap();
+        fs::write(input.join("Sounds/music.OGG"), &script).unwrap();
+        fs::write(input.join("readme"), b"").unwrap();
+        let output = tmp.path().join("out.hpk");
+        let output = output.to_str().unwrap();
+
+        Archive::rebuild_from_extracted(input.to_str().unwrap(), output).unwrap();
+        let archive = Archive::open(output).unwrap();
+        let paths: Vec<String> = archive.files().map(|e| e.unwrap().0).collect();
+        assert_eq!(
+            paths,
+            vec!["Scripts/init.lua", "Sounds/music.OGG", "readme"]
+        );
+        assert_eq!(read_file(&archive, "Scripts/init.lua"), script);
+        assert_eq!(read_file(&archive, "Sounds/music.OGG"), script);
+        assert!(archive
+            .file_data_at("Scripts/init.lua")
+            .unwrap()
+            .is_compressed());
+        assert!(!archive
+            .file_data_at("Sounds/music.OGG")
+            .unwrap()
+            .is_compressed());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn add_file_at_path_creates_directories() {
+        let mut builder = ArchiveBuilder::new();
+        builder
+            .add_file_at_path("data/ui/a.lua", b"a".to_vec())
+            .unwrap();
+        builder
+            .add_file_at_path("data/ui/textures/logo.dds", b"logo".to_vec())
+            .unwrap();
+        builder
+            .add_file_at_path("data/ui/b.lua", b"b".to_vec())
+            .unwrap();
+        assert!(builder.add_file_at_path("data/ui/", Vec::new()).is_err());
+        assert!(builder
+            .add_file_at_path("data/ui/a.lua/c", Vec::new())
+            .is_err());
+        let tmp = tempfile::tempdir().unwrap();
+        let output = tmp.path().join("out.hpk");
+        let output = output.to_str().unwrap();
+        builder.finish(output).unwrap();
+
+        let archive = Archive::open(output).unwrap();
+        let root = archive.root_directory();
+        assert!(root.files().is_empty());
+        assert_eq!(root.directories().len(), 1);
+        let data = &root.directories()[0];
+        assert_eq!(data.name(), Some("data"));
+        assert!(data.files().is_empty());
+        assert_eq!(data.directories().len(), 1);
+        let ui = &data.directories()[0];
+        assert_eq!(ui.name(), Some("ui"));
+        let names: Vec<&str> = ui.files().iter().map(|f| f.name()).collect();
+        assert_eq!(names, vec!["a.lua", "b.lua"]);
+        assert_eq!(ui.directories().len(), 1);
+        assert_eq!(ui.directories()[0].name(), Some("textures"));
+        assert_eq!(read_file(&archive, "data/ui/textures/logo.dds"), b"logo");
+    }
+
+    #[test]
+    fn validate_reports_all_errors() {
+        let mut builder = ArchiveBuilder::new();
+        builder.add_file("", "a\0", Vec::new()).unwrap();
+        let longest = "n".repeat(MAX_NAME_LENGTH);
+        builder.add_file("", &longest, Vec::new()).unwrap();
+        assert!(builder
+            .add_file("", &format!("{}n", longest), Vec::new())
+            .is_err());
+        let mut parent = String::new();
+        for _ in 0..MAX_DIRECTORY_DEPTH + 1 {
+            builder.add_directory(&parent, "d").unwrap();
+            if !parent.is_empty() {
+                parent.push('/');
+            }
+            parent.push('d');
+        }
+        // Not possible through add_file(), but what a bug could lead to
+        builder.root.entries.push((
+            "a\0".to_string(),
+            BuilderEntry::File(FileSource::Data(Vec::new()), true),
+        ));
+        builder.root.entries.push((
+            format!("{}n", longest),
+            BuilderEntry::File(FileSource::Data(Vec::new()), true),
+        ));
+
+        let mut too_deep = String::from("d");
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            too_deep.push_str("/d");
+        }
+        assert_eq!(
+            builder.validate().unwrap(),
+            vec![
+                BuilderError::NullByte("a\0".to_string()),
+                BuilderError::TooDeep(too_deep),
+                BuilderError::NullByte("a\0".to_string()),
+                BuilderError::DuplicatePath("a\0".to_string()),
+                BuilderError::NameTooLong(format!("{}n", longest)),
+            ]
+        );
+        let outdir = tempfile::tempdir().unwrap();
+        let output = outdir.path().join("invalid.hpk");
+        assert!(builder.finish(output.to_str().unwrap()).is_err());
+        assert!(!output.exists());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn compression_levels_round_trip() {
+        let data: Vec<u8> = b"El Presidente "
+            .iter()
+            .cycle()
+            .take(ZLIB_BLOCK_SIZE * 2 + 0x123)
+            .cloned()
+            .collect();
+        let outdir = tempfile::tempdir().unwrap();
+        let output = outdir.path().join("packed.hpk");
+        let output = output.to_str().unwrap();
+        for &level in &[0, 1, 4, DEFAULT_COMPRESSION_LEVEL, 9] {
+            let mut builder = ArchiveBuilder::new();
+            builder.set_compression_level(Some(level)).unwrap();
+            builder.add_directory("", "d").unwrap();
+            builder.add_file("d", "f", data.clone()).unwrap();
+            builder.add_file("", "empty", Vec::new()).unwrap();
+            builder.finish(output).unwrap();
+
+            let archive = Archive::open(output).unwrap();
+            assert_eq!(read_file(&archive, "d/f"), data);
+            assert_eq!(read_file(&archive, "empty"), b"");
+            let file = archive.find("d/f").unwrap().unwrap();
+            let mut fdata = archive.file_data(file).unwrap();
+            assert_eq!(fdata.num_blocks(), Some(3));
+            let block = fdata.raw_block(0).unwrap();
+            assert_eq!(block.is_compressed(), level != 0);
+            if level == 0 {
+                assert_eq!(block.data().len(), ZLIB_BLOCK_SIZE);
+            }
+        }
+        assert!(ArchiveBuilder::new()
+            .set_compression_level(Some(10))
+            .is_err());
+    }
+
+    #[test]
+    fn streaming_compression() {
+        let data = sample_data(0x2345);
+        // Reads return a few bytes at a time, as from a pipe
+        struct Trickle<'a>(&'a [u8]);
+        impl<'a> Read for Trickle<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = cmp::min(cmp::min(buf.len(), self.0.len()), 0x7f);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+        let mut out = io::Cursor::new(b"before".to_vec());
+        out.seek(SeekFrom::End(0)).unwrap();
+        let size =
+            write_zlib_file(&mut Trickle(&data), data.len() as u64, 0x1000, 6, &mut out).unwrap();
+        assert_eq!(out.position(), 6 + size);
+        let out = out.into_inner();
+        assert_eq!(&out[..6], b"before");
+        assert_eq!(&out[6..], &zlib_file_data(&data, 0x1000, 6).unwrap()[..]);
+
+        let mut out = io::Cursor::new(Vec::new());
+        assert!(
+            write_zlib_file(&mut &data[..], data.len() as u64 + 1, 0x1000, 6, &mut out).is_err()
+        );
+        assert!(
+            write_zlib_file(&mut &data[..], data.len() as u64 - 1, 0x1000, 6, &mut out).is_err()
+        );
+    }
+
+    #[test]
+    fn stored_copy_checks_size() {
+        let data = sample_data(0x100);
+        let mut out = Vec::new();
+        copy_exact(&mut &data[..], 0x100, &mut out).unwrap();
+        assert_eq!(out, data);
+        // As when a file grows or shrinks after its size was taken
+        assert!(copy_exact(&mut &data[..], 0xff, &mut Vec::new()).is_err());
+        assert!(copy_exact(&mut &data[..], 0x101, &mut Vec::new()).is_err());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn add_file_from_path_reads_on_finish() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("input.bin");
+        let output = tmp.path().join("out.hpk");
+        let output = output.to_str().unwrap();
+        let mut builder = ArchiveBuilder::new();
+        builder
+            .set_compression_level(Some(DEFAULT_COMPRESSION_LEVEL))
+            .unwrap();
+        builder.add_file_from_path("", "f", &input).unwrap();
+        // Only read now
+        let data = sample_data(ZLIB_BLOCK_SIZE * 3 + 0x10);
+        fs::write(&input, &data).unwrap();
+        builder.finish(output).unwrap();
+        let archive = Archive::open(output).unwrap();
+        assert_eq!(read_file(&archive, "f"), data);
+        assert!(archive.file_data_at("f").unwrap().is_compressed());
+
+        let mut builder = ArchiveBuilder::new();
+        builder.add_file("", "a", b"a".to_vec()).unwrap();
+        builder
+            .add_file_from_path("", "missing", &tmp.path().join("missing"))
+            .unwrap();
+        fs::remove_file(output).unwrap();
+        assert!(builder.finish(output).is_err());
+        assert!(!Path::new(output).exists());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn merge_two_archives() {
+        let first = archive_file(&[
+            TestEntry::File(b"a", b"first a".to_vec()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"b", b"first b".to_vec())]),
+        ]);
+        let second = archive_file(&[TestEntry::Dir(
+            b"d",
+            vec![
+                TestEntry::File(b"b", b"second b".to_vec()),
+                TestEntry::Dir(
+                    b"e",
+                    vec![TestEntry::File(
+                        b"c",
+                        write_zlib_file_data(&sample_data(0x300), 0x100),
+                    )],
+                ),
+            ],
+        )]);
+        let first = Archive::open(first.path().to_str().unwrap()).unwrap();
+        let second = Archive::open(second.path().to_str().unwrap()).unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+
+        for &policy in &[MergePolicy::Overwrite, MergePolicy::Skip] {
+            let output = outdir.path().join("merged.hpk");
+            let output = output.to_str().unwrap();
+            let mut builder = ArchiveBuilder::new();
+            builder.set_merge_policy(policy);
+            builder.merge_archive(&first).unwrap();
+            builder.merge_archive(&second).unwrap();
+            builder.finish(output).unwrap();
+
+            let merged = Archive::open(output).unwrap();
+            let paths: Vec<String> = merged.files().map(|e| e.unwrap().0).collect();
+            assert_eq!(paths, vec!["a", "d/b", "d/e/c"]);
+            assert_eq!(read_file(&merged, "a"), b"first a");
+            assert_eq!(read_file(&merged, "d/e/c"), sample_data(0x300));
+            let expected_b: &[u8] = match policy {
+                MergePolicy::Overwrite => b"second b",
+                MergePolicy::Skip => b"first b",
+            };
+            assert_eq!(read_file(&merged, "d/b"), expected_b);
+        }
+    }
+
+    /* Write a seed corpus for the fuzz targets of fuzz/, made of archives and
+     * compressed files written by the builder. Run with
+     * `cargo test write_fuzz_corpus -- --ignored`. */
+    #[test]
+    #[ignore]
+    fn write_fuzz_corpus() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz")
+            .join("corpus");
+        let write = |target: &str, name: &str, contents: &[u8]| {
+            let dir = corpus.join(target);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(name), contents).unwrap();
+        };
+        let data = sample_data(0x5000);
+        let tmp = tempfile::tempdir().unwrap();
+        for &(name, level) in &[
+            ("plain", None),
+            ("stored", Some(0)),
+            ("compressed", Some(9)),
+        ] {
+            let mut builder = ArchiveBuilder::new();
+            builder.set_compression_level(level).unwrap();
+            builder.add_file("", "empty", Vec::new()).unwrap();
+            builder
+                .add_file("", "small.txt", b"hello".to_vec())
+                .unwrap();
+            builder.add_directory("", "dir").unwrap();
+            builder.add_file("dir", "data.bin", data.clone()).unwrap();
+            builder.add_directory("dir", "sub").unwrap();
+            let path = tmp.path().join(name);
+            builder.finish(path.to_str().unwrap()).unwrap();
+            let archive = fs::read(&path).unwrap();
+            write("fuzz_open_archive", name, &archive);
+            write("fuzz_read_header", name, &archive[..HEADER_SIZE]);
+        }
+        for &(blocksize, level) in &[(0x800, 9), (0x1000, 0), (ZLIB_BLOCK_SIZE, 6)] {
+            let packed = zlib_file_data(&data, blocksize, level).unwrap();
+            let name = format!("blocks_{:x}_level_{}", blocksize, level);
+            write("fuzz_zlib_header", &name, &packed[..0xc]);
+            write("fuzz_zlib_read", &name, &packed);
+        }
+    }
+}
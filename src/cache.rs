@@ -0,0 +1,203 @@
+use ::errors::*;
+use hpk;
+use std::collections::HashMap;
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const INDEX_FILENAME: &str = "index";
+
+/* Identifies a file within an archive */
+#[derive(PartialEq, Eq, Hash)]
+struct CacheKey {
+    archive: String,
+    offset: u32,
+    size: u32,
+}
+
+/* Where a file was extracted, and the state of its archive at that time */
+struct CacheEntry {
+    archive_len: u64,
+    archive_mtime: u64,
+    extracted_size: u64,
+    path: PathBuf,
+}
+
+/** Index of the files extracted during previous runs, so that extracting
+ * them again can be avoided.
+ *
+ * The index is a text file in the cache directory, with one tab separated
+ * entry per line: archive path, file offset, file size, archive length,
+ * archive modification time, extracted size and extracted file path. */
+pub struct ExtractionCache {
+    dir: PathBuf,
+    entries: HashMap<CacheKey, CacheEntry>,
+    archive: String,
+    archive_len: u64,
+    archive_mtime: u64,
+}
+
+fn mtime_secs(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn parse_line(line: &str) -> Option<(CacheKey, CacheEntry)> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 7 {
+        return None;
+    }
+    let key = CacheKey {
+        archive: fields[0].to_string(),
+        offset: fields[1].parse().ok()?,
+        size: fields[2].parse().ok()?,
+    };
+    let entry = CacheEntry {
+        archive_len: fields[3].parse().ok()?,
+        archive_mtime: fields[4].parse().ok()?,
+        extracted_size: fields[5].parse().ok()?,
+        path: PathBuf::from(fields[6]),
+    };
+    Some((key, entry))
+}
+
+impl ExtractionCache {
+    /** Open the cache stored in dir, creating it if needed, for extracting
+     * files from the archive at archive_path. Malformed index lines are
+     * ignored. */
+    pub fn open(dir: &str, archive_path: &str) -> Result<ExtractionCache> {
+        fs::create_dir_all(dir).chain_err(|| "Unable to create cache directory")?;
+        let dir = fs::canonicalize(dir)?;
+        let archive = fs::canonicalize(archive_path)?;
+        let metadata = fs::metadata(&archive)?;
+        let mut entries = HashMap::new();
+        match fs::File::open(dir.join(INDEX_FILENAME)) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    if let Some((key, entry)) = parse_line(&line?) {
+                        entries.insert(key, entry);
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).chain_err(|| "Unable to read cache index"),
+        }
+        Ok(ExtractionCache {
+            dir: dir,
+            entries: entries,
+            archive: archive.to_string_lossy().into_owned(),
+            archive_len: metadata.len(),
+            archive_mtime: mtime_secs(&metadata),
+        })
+    }
+
+    fn key(&self, file: &hpk::File) -> CacheKey {
+        CacheKey {
+            archive: self.archive.clone(),
+            offset: file.offset(),
+            size: file.size(),
+        }
+    }
+
+    /** Path where a file of the archive was previously extracted. Entries
+     * are only trusted if the archive did not change since, and the
+     * extracted file still has the expected size. */
+    pub fn lookup(&self, file: &hpk::File) -> Option<&Path> {
+        let entry = self.entries.get(&self.key(file))?;
+        if entry.archive_len != self.archive_len || entry.archive_mtime != self.archive_mtime {
+            return None;
+        }
+        match fs::metadata(&entry.path) {
+            Ok(ref m) if m.is_file() && m.len() == entry.extracted_size => Some(&entry.path),
+            _ => None,
+        }
+    }
+
+    /** Record that a file of the archive was extracted at path. */
+    pub fn record(&mut self, file: &hpk::File, path: &Path) -> Result<()> {
+        let path = fs::canonicalize(path)?;
+        let extracted_size = fs::metadata(&path)?.len();
+        let key = self.key(file);
+        self.entries.insert(
+            key,
+            CacheEntry {
+                archive_len: self.archive_len,
+                archive_mtime: self.archive_mtime,
+                extracted_size: extracted_size,
+                path: path,
+            },
+        );
+        Ok(())
+    }
+
+    /** Write the index back to the cache directory. */
+    pub fn save(&self) -> Result<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", INDEX_FILENAME));
+        {
+            let mut out = ::std::io::BufWriter::new(fs::File::create(&tmp_path)?);
+            for (key, entry) in &self.entries {
+                let path = entry.path.to_string_lossy();
+                // Such paths cannot be represented in the index
+                if key.archive.contains(['\t', '\n']) || path.contains(['\t', '\n']) {
+                    continue;
+                }
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    key.archive,
+                    key.offset,
+                    key.size,
+                    entry.archive_len,
+                    entry.archive_mtime,
+                    entry.extracted_size,
+                    path
+                )?;
+            }
+            out.flush()?;
+        }
+        fs::rename(&tmp_path, self.dir.join(INDEX_FILENAME))?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use hpk::Archive;
+    use testutil::*;
+
+    #[test]
+    fn entries_survive_reload_and_are_validated() {
+        let archive_file = archive_file(&[TestEntry::File(b"f", b"contents".to_vec())]);
+        let archive_path = archive_file.path().to_str().unwrap();
+        let archive = Archive::open(archive_path).unwrap();
+        let file = &archive.root_directory().files()[0];
+        let tmp = tempfile::tempdir().unwrap();
+        let cache_dir = tmp.path().join("cache");
+        let cache_dir = cache_dir.to_str().unwrap();
+        let extracted = tmp.path().join("f");
+        fs::write(&extracted, b"contents").unwrap();
+
+        let mut cache = ExtractionCache::open(cache_dir, archive_path).unwrap();
+        assert!(cache.lookup(file).is_none());
+        cache.record(file, &extracted).unwrap();
+        cache.save().unwrap();
+
+        let cache = ExtractionCache::open(cache_dir, archive_path).unwrap();
+        assert_eq!(
+            cache.lookup(file).unwrap(),
+            fs::canonicalize(&extracted).unwrap()
+        );
+
+        fs::write(&extracted, b"truncated").unwrap();
+        assert!(cache.lookup(file).is_none());
+    }
+}
@@ -0,0 +1,214 @@
+use ::errors::*;
+use hpk::{Archive, File};
+use json::json_string;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::prelude::*;
+
+const COMPARE_CHUNK_SIZE: usize = 0x10000;
+
+/** Files which differ between two archives, see `Archive::diff()`. Each
+ * list holds '/' separated paths, sorted. */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveDiff {
+    /** Files only present in the new archive */
+    pub added: Vec<String>,
+    /** Files only present in the old archive */
+    pub removed: Vec<String>,
+    /** Files present in both archives, with different contents */
+    pub changed: Vec<String>,
+}
+
+/** How `write_diff()` formats the differences. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffFormat {
+    /** One path per line, prefixed with '+', '-' or '~' */
+    Text,
+    /** An object with "added", "removed" and "changed" arrays */
+    Json,
+    /** "status,path" records, status being added, removed or changed */
+    Csv,
+}
+
+impl DiffFormat {
+    pub fn parse(s: &str) -> Result<DiffFormat> {
+        match s {
+            "text" => Ok(DiffFormat::Text),
+            "json" => Ok(DiffFormat::Json),
+            "csv" => Ok(DiffFormat::Csv),
+            _ => bail!("Invalid diff format \"{}\", expected text, json or csv", s),
+        }
+    }
+}
+
+impl ArchiveDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /* All the differences, sorted by path, along with their status. */
+    fn entries(&self) -> Vec<(&'static str, &str)> {
+        let mut entries: Vec<(&'static str, &str)> = Vec::new();
+        entries.extend(self.added.iter().map(|p| ("added", &p[..])));
+        entries.extend(self.removed.iter().map(|p| ("removed", &p[..])));
+        entries.extend(self.changed.iter().map(|p| ("changed", &p[..])));
+        entries.sort_by(|a, b| a.1.cmp(b.1));
+        entries
+    }
+}
+
+/* Read into buf until it is full or the end of the file is reached. */
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+/* Whether two files have the same contents once decompressed. */
+fn same_contents(old: &Archive, old_file: &File, new: &Archive, new_file: &File) -> Result<bool> {
+    let mut old_data = old.file_data(old_file)?;
+    let mut new_data = new.file_data(new_file)?;
+    if old_data.size() != new_data.size() {
+        return Ok(false);
+    }
+    let mut old_buf = vec![0u8; COMPARE_CHUNK_SIZE];
+    let mut new_buf = vec![0u8; COMPARE_CHUNK_SIZE];
+    loop {
+        let old_len = read_chunk(&mut old_data, &mut old_buf)?;
+        let new_len = read_chunk(&mut new_data, &mut new_buf)?;
+        if old_buf[..old_len] != new_buf[..new_len] {
+            return Ok(false);
+        }
+        if old_len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+impl Archive {
+    /** Compare the files of this archive with those of a newer version of
+     * it. Files are considered changed when their decompressed contents
+     * differ, regardless of how they are stored. */
+    pub fn diff(&self, new: &Archive) -> Result<ArchiveDiff> {
+        let old_files: BTreeMap<String, &File> = self.files().collect::<Result<_>>()?;
+        let new_files: BTreeMap<String, &File> = new.files().collect::<Result<_>>()?;
+        let mut diff = ArchiveDiff::default();
+        for (path, old_file) in &old_files {
+            match new_files.get(path) {
+                None => diff.removed.push(path.clone()),
+                Some(new_file) => {
+                    let same = same_contents(self, old_file, new, new_file)
+                        .chain_err(|| format!("Unable to compare {}", path))?;
+                    if !same {
+                        diff.changed.push(path.clone());
+                    }
+                }
+            }
+        }
+        for path in new_files.keys() {
+            if !old_files.contains_key(path) {
+                diff.added.push(path.clone());
+            }
+        }
+        Ok(diff)
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn json_array(paths: &[String]) -> String {
+    let items: Vec<String> = paths.iter().map(|p| json_string(p)).collect();
+    format!("[{}]", items.join(","))
+}
+
+/** Write the differences between two archives to out. */
+pub fn write_diff<W: Write>(diff: &ArchiveDiff, format: DiffFormat, out: &mut W) -> Result<()> {
+    match format {
+        DiffFormat::Text => {
+            for (status, path) in diff.entries() {
+                let prefix = match status {
+                    "added" => '+',
+                    "removed" => '-',
+                    _ => '~',
+                };
+                writeln!(out, "{} {}", prefix, path)?;
+            }
+        }
+        DiffFormat::Json => {
+            writeln!(
+                out,
+                "{{\"added\":{},\"removed\":{},\"changed\":{}}}",
+                json_array(&diff.added),
+                json_array(&diff.removed),
+                json_array(&diff.changed)
+            )?;
+        }
+        DiffFormat::Csv => {
+            writeln!(out, "status,path")?;
+            for (status, path) in diff.entries() {
+                writeln!(out, "{},{}", status, csv_field(path))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use super::*;
+    use testutil::*;
+
+    #[test]
+    fn diff_archives_in_all_formats() {
+        let old = archive_file(&[
+            TestEntry::File(b"same", b"contents".to_vec()),
+            TestEntry::File(b"b,\"q\"", b"old".to_vec()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"c", b"c".to_vec())]),
+        ]);
+        let new = archive_file(&[
+            TestEntry::File(b"same", write_zlib_file_data(b"contents", 0x100)),
+            TestEntry::File(b"b,\"q\"", b"new".to_vec()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"e", b"e".to_vec())]),
+        ]);
+        let old = Archive::open(old.path().to_str().unwrap()).unwrap();
+        let new = Archive::open(new.path().to_str().unwrap()).unwrap();
+        let diff = old.diff(&new).unwrap();
+        assert_eq!(
+            diff,
+            ArchiveDiff {
+                added: vec!["d/e".to_string()],
+                removed: vec!["d/c".to_string()],
+                changed: vec!["b,\"q\"".to_string()],
+            }
+        );
+        assert!(old.diff(&old).unwrap().is_empty());
+
+        let render = |format| {
+            let mut out = Vec::new();
+            write_diff(&diff, format, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(render(DiffFormat::Text), "~ b,\"q\"\n- d/c\n+ d/e\n");
+        assert_eq!(
+            render(DiffFormat::Csv),
+            "status,path\nchanged,\"b,\"\"q\"\"\"\nremoved,d/c\nadded,d/e\n"
+        );
+        assert_eq!(
+            render(DiffFormat::Json),
+            "{\"added\":[\"d/e\"],\"removed\":[\"d/c\"],\"changed\":[\"b,\\\"q\\\"\"]}\n"
+        );
+        assert_eq!(DiffFormat::parse("csv").unwrap(), DiffFormat::Csv);
+        assert!(DiffFormat::parse("xml").is_err());
+    }
+}
@@ -0,0 +1,31 @@
+// Create the Error, ErrorKind, ResultExt, and Result types
+error_chain! {
+    foreign_links {
+        Fmt(::std::fmt::Error);
+        Io(::std::io::Error) #[cfg(unix)];
+    }
+
+    errors {
+        TooManyEntries(max: usize) {
+            description("archive has too many entries")
+            display("Archive has more than {} entries", max)
+        }
+        Cancelled {
+            description("cancelled")
+            display("Cancelled")
+        }
+        NoSuchPath(path: String) {
+            description("no such path in archive")
+            display("No such path in archive: {}", path)
+        }
+        IsADirectory(path: String) {
+            description("path is a directory")
+            display("Path is a directory: {}", path)
+        }
+        ArchiveTruncated(len: u64, filetbl_offset: u64) {
+            description("archive is truncated")
+            display("Archive is truncated: its size is 0x{:x} bytes, but its file table \
+                     starts at offset 0x{:x}", len, filetbl_offset)
+        }
+    }
+}
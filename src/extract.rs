@@ -0,0 +1,997 @@
+use cache::ExtractionCache;
+use ::errors::*;
+use glob::GlobPattern;
+use hpk;
+use hpk::Archive;
+use hpk::ArchiveStream;
+use hpk::Directory;
+use hpk::EntryRef;
+use hpk::WalkOrder;
+use resume::ResumeState;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::slice::Iter;
+
+struct DirCtx<'a> {
+    dir: &'a Directory,
+    iter: Peekable<Iter<'a, Directory>>,
+}
+
+fn build_path(dir: &Directory, dirstack: &Vec<DirCtx>) -> String {
+    let mut path = String::new();
+    for ctx in dirstack {
+        if let Some(n) = ctx.dir.name() {
+            path.push_str(n);
+            path.push(::std::path::MAIN_SEPARATOR);
+        };
+    }
+    if let Some(n) = dir.name() {
+        path.push_str(n);
+        path.push(::std::path::MAIN_SEPARATOR);
+    };
+    path
+}
+
+pub fn foreach_dir_in_dir<F>(_archive: &Archive, dir: &Directory, mut closure: F) -> Result<()>
+where
+    F: FnMut(&Directory, &str, u16) -> Result<()>,
+{
+    // Initial state
+    let mut dirstack: Vec<DirCtx> = Vec::new();
+    dir.load_children()?;
+    let mut ctx = DirCtx {
+        dir: dir,
+        iter: dir.directories().iter().peekable(),
+    };
+
+    // Process root directory
+    closure(
+        ctx.dir,
+        &build_path(ctx.dir, &dirstack),
+        dirstack.len() as u16,
+    )?;
+
+    while !dirstack.is_empty() || ctx.iter.peek().is_some() {
+        let next_dir = ctx.iter.next();
+        match next_dir {
+            None => {
+                /* Last directory for this level processed, resume to where we left off in
+                 * the parent directory. */
+                ctx = dirstack.pop().unwrap();
+            }
+            Some(d) => {
+                dirstack.push(ctx);
+                d.load_children()?;
+                ctx = DirCtx {
+                    dir: d,
+                    iter: d.directories().iter().peekable(),
+                };
+                closure(
+                    ctx.dir,
+                    &build_path(ctx.dir, &dirstack),
+                    dirstack.len() as u16,
+                )?;
+            }
+        };
+    }
+    Ok(())
+}
+
+pub fn foreach_file_in_dir<F>(archive: &Archive, dir: &Directory, mut closure: F) -> Result<()>
+where
+    F: FnMut(&hpk::File, &str, u16) -> Result<()>,
+{
+    foreach_dir_in_dir(archive, dir, |dir, path, level| {
+        // Loaded by foreach_dir_in_dir
+        for f in dir.files() {
+            closure(f, path, level)?;
+        }
+        Ok(())
+    })
+}
+
+/* Windows refuses paths longer than MAX_PATH, unless they are absolute and
+ * start with \\?\, which passes them to the filesystem as is. Such paths
+ * are not normalized by Windows, so it is done here first. */
+#[cfg(windows)]
+fn long_path(path: &str) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::Component;
+    const MAX_PATH: usize = 260;
+    if path.len() < MAX_PATH || path.starts_with(r"\\") {
+        return PathBuf::from(path);
+    }
+    let absolute = match ::std::env::current_dir() {
+        Ok(cwd) => cwd.join(path),
+        Err(_) => return PathBuf::from(path),
+    };
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c.as_os_str()),
+        }
+    }
+    let mut prefixed = OsString::from(r"\\?\");
+    prefixed.push(normalized.as_os_str());
+    PathBuf::from(prefixed)
+}
+
+#[cfg(not(windows))]
+fn long_path(path: &str) -> PathBuf {
+    PathBuf::from(path)
+}
+
+/* Create all the output directory hiererchy under a specified path. */
+fn create_dirs(archive: &Archive, directory: &Directory, outpath: &str) -> Result<()> {
+    use std::fs::DirBuilder;
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    foreach_dir_in_dir(archive, directory, |_dir, path, _level| {
+        let mut dirpath = String::from(outpath);
+        dirpath.push(std::path::MAIN_SEPARATOR);
+        dirpath.push_str(path);
+        builder.create(long_path(&dirpath))?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/* Copy exactly size bytes from data to out. */
+fn copy_data<R: Read, W: Write>(data: &mut R, size: u64, out: &mut W) -> Result<()> {
+    let mut remain = size as usize;
+    // XXX: There must be a faster way
+    let mut buf = vec![0; 0x100000];
+    while remain > 0 {
+        let buflen = buf.len();
+        let size = if remain > buflen { buflen } else { remain };
+        data.read_exact(&mut buf[0..size])?;
+        out.write_all(&buf[0..size])?;
+        remain -= size;
+    }
+    Ok(())
+}
+
+/* Path of the temporary file a file is written to before being renamed to
+ * its final path. It is in the same directory, so that renaming does not
+ * need to copy anything. */
+fn temporary_path(filepath: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(filepath.file_name().unwrap_or_default());
+    name.push(".part");
+    filepath.with_file_name(name)
+}
+
+/** Write size bytes from data to filepath through a temporary file, which is
+ * renamed to filepath once completely written. On failure the temporary
+ * file is removed, so that a file found at filepath is always complete. */
+pub fn write_atomically<R: Read>(data: &mut R, size: u64, filepath: &Path) -> Result<()> {
+    let tmp_path = temporary_path(filepath);
+    let res = (|| -> Result<()> {
+        let mut out = fs::File::create(&tmp_path)?;
+        copy_data(data, size, &mut out)?;
+        fs::rename(&tmp_path, filepath)?;
+        Ok(())
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+/* Extract a single file to a specified output directory. Unless in_place is
+ * set, the file only appears at its final path once completely extracted. */
+fn extract_file(archive: &Archive, file: &hpk::File, outpath: &str, in_place: bool) -> Result<()> {
+    #[cfg(feature = "tracing")]
+    let _span = ::tracing::debug_span!(
+        "extract_file",
+        index = file.index(),
+        name = file.name(),
+        size = file.size()
+    )
+    .entered();
+    let mut data = archive.file_data(file)?;
+    let size = data.size();
+    let mut filepath = String::new();
+    filepath.push_str(outpath);
+    filepath.push_str(file.name());
+    let filepath = long_path(&filepath);
+    if in_place {
+        let mut out = fs::File::create(&filepath)?;
+        copy_data(&mut data, size, &mut out)
+    } else {
+        write_atomically(&mut data, size, &filepath)
+    }
+}
+
+/* Extract a single file, reusing a copy extracted by a previous run if the
+ * cache knows about one. */
+fn extract_file_cached(
+    archive: &Archive,
+    file: &hpk::File,
+    outpath: &str,
+    cache: &mut ExtractionCache,
+    in_place: bool,
+) -> Result<()> {
+    let mut filepath = String::from(outpath);
+    filepath.push_str(file.name());
+    let filepath = long_path(&filepath);
+    let filepath = filepath.as_path();
+    if let Some(cached) = cache.lookup(file) {
+        if fs::canonicalize(filepath).ok().as_deref() == Some(cached) {
+            return Ok(());
+        }
+        let _ = fs::remove_file(filepath);
+        if fs::hard_link(cached, filepath).is_ok() {
+            return Ok(());
+        }
+    }
+    extract_file(archive, file, outpath, in_place)?;
+    cache.record(file, filepath)
+}
+
+impl Archive {
+    /** Extract the subtree rooted at dir, which is found in the archive at
+     * the '/' separated path dir_path followed by the name of dir. Files are
+     * written under outpath, keeping that whole path. */
+    pub fn extract_directory(&self, dir: &Directory, dir_path: &str, outpath: &str) -> Result<()> {
+        let mut base = String::from(outpath);
+        for component in dir_path.split('/').filter(|c| !c.is_empty()) {
+            base.push(::std::path::MAIN_SEPARATOR);
+            base.push_str(component);
+        }
+        create_dirs(self, dir, &base)?;
+        foreach_file_in_dir(self, dir, |file, path, _level| {
+            let mut filepath = base.clone();
+            filepath.push(::std::path::MAIN_SEPARATOR);
+            filepath.push_str(path);
+            extract_file(self, file, &filepath, false)
+        })
+    }
+
+    /** Extract the file with the specified 1-based file table index under
+     * outpath, keeping its whole path in the archive. */
+    pub fn extract_single_by_index(&self, index: u32, outpath: &str) -> Result<()> {
+        let (path, file) = match self.entry_by_index(index) {
+            Some(EntryRef::File(path, file)) => (path, file),
+            Some(EntryRef::Directory(path, _)) => bail!(ErrorKind::IsADirectory(path)),
+            Some(EntryRef::Unreferenced) => {
+                bail!("Index {} is not referenced by any directory", index)
+            }
+            None => bail!("Index {} is outside of the file table", index),
+        };
+        let mut dirpath = String::from(outpath);
+        let mut components: Vec<&str> = path.split('/').collect();
+        components.pop();
+        for component in components {
+            dirpath.push(::std::path::MAIN_SEPARATOR);
+            dirpath.push_str(component);
+        }
+        dirpath.push(::std::path::MAIN_SEPARATOR);
+        fs::create_dir_all(long_path(&dirpath))?;
+        extract_file(self, file, &dirpath, false)
+            .chain_err(|| format!("Unable to extract {}", path))
+    }
+}
+
+/* Change the permissions of an extracted file. */
+#[cfg(unix)]
+fn set_mode(filepath: &str, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(filepath, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_filepath: &str, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/** Parse a Unix file mode, written in octal as for chmod. */
+pub fn parse_mode(s: &str) -> Result<u32> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => bail!(
+            "Invalid mode \"{}\", expected an octal number up to 7777",
+            s
+        ),
+    }
+}
+
+/** Parse a size in bytes, optionally followed by a K, M or G suffix for
+ * multiples of 1024. */
+pub fn parse_size(s: &str) -> Result<u64> {
+    let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let value: u64 = digits
+        .parse()
+        .chain_err(|| format!("Invalid size \"{}\"", s))?;
+    match value.checked_mul(1 << shift) {
+        Some(v) => Ok(v),
+        None => bail!("Size is too large: \"{}\"", s),
+    }
+}
+
+/** Settings for `extract_archive()`. The default settings stop at the first
+ * error, and do not output anything. */
+#[derive(Default)]
+pub struct ExtractOptions<'a> {
+    /** Report failures to extract a file and go on with the next one */
+    pub skip_errors: bool,
+    /** Extract files in the order in which their data is stored */
+    pub sequential: bool,
+    pub cache: Option<&'a mut ExtractionCache>,
+    /** Write files directly at their final path, instead of through a
+     * temporary file renamed once the file is complete */
+    pub in_place: bool,
+    /** Permissions given to the extracted files, as an octal Unix mode.
+     * Ignored on other platforms. */
+    pub mode: Option<u32>,
+    /** Only extract the files at least this large once decompressed, per
+     * `FileData::size()` */
+    pub min_size: Option<u64>,
+    /** Only extract the files at most this large once decompressed, per
+     * `FileData::size()` */
+    pub max_size: Option<u64>,
+    /** Do not extract the files of 0 bytes, which would otherwise be
+     * created empty */
+    pub exclude_empty: bool,
+    /** Only extract the files whose '/' separated path matches this
+     * pattern */
+    pub include: Option<GlobPattern>,
+    /** Receives the path of each file being extracted */
+    pub output: Option<&'a mut dyn Write>,
+    /** Receives the errors skipped because of skip_errors */
+    pub errors: Option<&'a mut dyn Write>,
+    /** Skip the files completed by a previous extraction to the same
+     * directory, and record the files completed by this one */
+    pub resume: Option<&'a mut ResumeState>,
+}
+
+/** Extract the whole archive. Returns the number of files which could not be
+ * extracted. */
+pub fn extract_archive(
+    archive: &Archive,
+    outpath: &str,
+    options: &mut ExtractOptions,
+) -> Result<u32> {
+    let rootdir = archive.root_directory();
+    let mut num_errors = 0u32;
+    let sequential = options.sequential;
+    create_dirs(archive, rootdir, outpath)?;
+    let needs_size = options.min_size.is_some()
+        || options.max_size.is_some()
+        || options.exclude_empty
+        || options.resume.is_some();
+    let mut extract_one = |file: &hpk::File, path: &str| -> Result<()> {
+        /* Errors getting the size are left to the extraction to report */
+        let unpacked_size = if needs_size {
+            archive.file_data(file).ok().map(|data| data.size())
+        } else {
+            None
+        };
+        if let Some(size) = unpacked_size {
+            if options.min_size.is_some_and(|min| size < min)
+                || options.max_size.is_some_and(|max| size > max)
+                || (options.exclude_empty && size == 0)
+            {
+                return Ok(());
+            }
+        }
+        let relpath = format!("{}{}", path, file.name());
+        if let Some(ref pattern) = options.include {
+            if !pattern.matches(&relpath.replace(::std::path::MAIN_SEPARATOR, "/")) {
+                return Ok(());
+            }
+        }
+        if let (Some(state), Some(size)) = (options.resume.as_ref(), unpacked_size) {
+            if state.is_done(&relpath, size) {
+                return Ok(());
+            }
+        }
+        let mut filepath = String::new();
+        filepath.push_str(outpath);
+        filepath.push(::std::path::MAIN_SEPARATOR);
+        filepath.push_str(path);
+        if let Some(ref mut out) = options.output {
+            writeln!(out, "{}{}", filepath, file.name())?;
+        }
+        let res = match options.cache {
+            Some(ref mut c) => extract_file_cached(archive, file, &filepath, c, options.in_place),
+            None => extract_file(archive, file, &filepath, options.in_place),
+        };
+        let res = match (res, options.mode) {
+            (Ok(()), Some(mode)) => set_mode(&format!("{}{}", filepath, file.name()), mode),
+            (res, _) => res,
+        };
+        let res = match (res, &mut options.resume, unpacked_size) {
+            (Ok(()), &mut Some(ref mut state), Some(size)) => state.record(&relpath, size),
+            (res, _, _) => res,
+        };
+        if let Err(e) = res {
+            if !options.skip_errors {
+                return Err(e);
+            }
+            if let Some(ref mut err) = options.errors {
+                writeln!(
+                    err,
+                    "error: failed to extract {}{}: {}",
+                    filepath,
+                    file.name(),
+                    e
+                )?;
+                for e in e.iter().skip(1) {
+                    writeln!(err, "caused by: {}", e)?;
+                }
+            }
+            num_errors += 1;
+        }
+        Ok(())
+    };
+    if sequential {
+        archive.walk_with(WalkOrder::DataOffset, |path, file| {
+            let dirpath = path[..path.len() - file.name().len()]
+                .replace('/', ::std::path::MAIN_SEPARATOR_STR);
+            extract_one(file, &dirpath)
+        })?;
+    } else {
+        foreach_file_in_dir(archive, rootdir, |file, path, _level| {
+            extract_one(file, path)
+        })?;
+    }
+    Ok(num_errors)
+}
+
+/** Extract the files of a stream as the directory tree is read, so that
+ * huge archives can be extracted without holding their tree in memory. The
+ * directories are created along with the first file they hold, so empty
+ * directories are not extracted. */
+pub fn extract_stream(stream: &mut ArchiveStream, outpath: &str) -> Result<()> {
+    while let Some(entry) = stream.next() {
+        let (path, file) = entry?;
+        let mut filepath = PathBuf::from(outpath);
+        filepath.extend(path.split('/'));
+        let filepath = long_path(filepath.to_str().unwrap());
+        let res = (|| -> Result<()> {
+            if let Some(parent) = filepath.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut data = stream.file_data(&file)?;
+            let size = data.size();
+            write_atomically(&mut data, size, &filepath)
+        })();
+        res.chain_err(|| format!("Unable to extract {}", path))?;
+    }
+    Ok(())
+}
+
+/** Read and decompress the data of all the files of the archive, discarding
+ * it, as an extraction without output would. Returns the number of files
+ * and the total number of bytes they decompress to. */
+pub fn read_all_files(archive: &Archive) -> Result<(u64, u64)> {
+    let mut num_files = 0u64;
+    let mut num_bytes = 0u64;
+    for entry in archive.files() {
+        let (path, file) = entry?;
+        let mut data = archive.file_data(file)?;
+        let size = data.size();
+        copy_data(&mut data, size, &mut ::std::io::sink())
+            .chain_err(|| format!("Unable to read {}", path))?;
+        num_files += 1;
+        num_bytes += size;
+    }
+    Ok((num_files, num_bytes))
+}
+
+/** Difference between an archive and a directory it was extracted to,
+ * found by `check_extracted()`. Each variant holds a '/' separated path. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckIssue {
+    /** File of the archive absent from the directory */
+    Missing(String),
+    /** File of the directory absent from the archive */
+    Extra(String),
+    /** File whose size differs: expected size, then size on disk */
+    SizeMismatch(String, u64, u64),
+}
+
+impl CheckIssue {
+    pub fn path(&self) -> &str {
+        match *self {
+            CheckIssue::Missing(ref p)
+            | CheckIssue::Extra(ref p)
+            | CheckIssue::SizeMismatch(ref p, _, _) => p,
+        }
+    }
+}
+
+impl fmt::Display for CheckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheckIssue::Missing(ref p) => write!(f, "missing: {}", p),
+            CheckIssue::Extra(ref p) => write!(f, "extra: {}", p),
+            CheckIssue::SizeMismatch(ref p, expected, actual) => write!(
+                f,
+                "size mismatch: {} ({} bytes expected, {} found)",
+                p, expected, actual
+            ),
+        }
+    }
+}
+
+/* Add the '/' separated paths of the files below dir to paths. */
+fn list_fs_files(dir: &Path, prefix: &str, paths: &mut Vec<String>) -> Result<()> {
+    for entry in ::std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            list_fs_files(&entry.path(), &format!("{}/", path), paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/** Compare the files of the archive with those of the directory at outpath,
+ * where it was supposedly extracted. Files must have their unpacked size.
+ * Issues are returned sorted by path. */
+pub fn check_extracted(archive: &Archive, outpath: &str) -> Result<Vec<CheckIssue>> {
+    let base = Path::new(outpath);
+    let mut issues = Vec::new();
+    let mut expected = HashSet::new();
+    archive.walk(|path, file| {
+        expected.insert(path.to_string());
+        let size = archive.file_data(file)?.size();
+        match ::std::fs::metadata(long_path(&base.join(path).to_string_lossy())) {
+            Ok(ref m) if m.is_file() => {
+                if m.len() != size {
+                    issues.push(CheckIssue::SizeMismatch(path.to_string(), size, m.len()));
+                }
+            }
+            _ => issues.push(CheckIssue::Missing(path.to_string())),
+        }
+        Ok(())
+    })?;
+    let mut found = Vec::new();
+    list_fs_files(base, "", &mut found)
+        .chain_err(|| format!("Unable to list the files of {}", outpath))?;
+    for path in found {
+        if !expected.contains(&path) {
+            issues.push(CheckIssue::Extra(path));
+        }
+    }
+    issues.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    #[cfg(feature = "fs")]
+    use testutil::*;
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn extraction_output_goes_to_sinks() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"a".to_vec()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"b", b"b".to_vec())]),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let outpath = outdir.path().to_str().unwrap();
+
+        assert_eq!(
+            extract_archive(&archive, outpath, &mut ExtractOptions::default()).unwrap(),
+            0
+        );
+
+        let mut output = Vec::new();
+        {
+            let mut options = ExtractOptions {
+                output: Some(&mut output),
+                ..Default::default()
+            };
+            extract_archive(&archive, outpath, &mut options).unwrap();
+        }
+        let sep = ::std::path::MAIN_SEPARATOR;
+        let expected = format!("{out}{sep}a\n{out}{sep}d{sep}b\n", out = outpath, sep = sep);
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    /* Reader failing after having returned some data */
+    struct FailingReader {
+        remain: usize,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            if self.remain == 0 {
+                return Err(::std::io::Error::other("read failure"));
+            }
+            let len = buf.len().min(self.remain);
+            self.remain -= len;
+            Ok(len)
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn extract_while_streaming() {
+        let data = sample_data(0x3000);
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"a".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![
+                    TestEntry::Dir(b"e", vec![TestEntry::File(b"b", b"b".to_vec())]),
+                    TestEntry::File(b"z", write_zlib_file_data(&data, 0x1000)),
+                ],
+            ),
+        ]);
+        let outdir = tempfile::tempdir().unwrap();
+        let mut stream = ArchiveStream::open(file.path().to_str().unwrap()).unwrap();
+        extract_stream(&mut stream, outdir.path().to_str().unwrap()).unwrap();
+        let out = outdir.path();
+        assert_eq!(::std::fs::read(out.join("a")).unwrap(), b"a");
+        assert_eq!(::std::fs::read(out.join("d/e/b")).unwrap(), b"b");
+        assert!(::std::fs::read(out.join("d/z")).unwrap() == data);
+    }
+
+    #[test]
+    fn interrupted_write_leaves_no_partial_file() {
+        let outdir = tempfile::tempdir().unwrap();
+        let filepath = outdir.path().join("f");
+        let mut reader = FailingReader { remain: 100 };
+        assert!(write_atomically(&mut reader, 1000, &filepath).is_err());
+        assert_eq!(::std::fs::read_dir(outdir.path()).unwrap().count(), 0);
+
+        // A previous complete copy is kept as is
+        ::std::fs::write(&filepath, b"old").unwrap();
+        let mut reader = FailingReader { remain: 100 };
+        assert!(write_atomically(&mut reader, 1000, &filepath).is_err());
+        assert_eq!(::std::fs::read(&filepath).unwrap(), b"old");
+        assert_eq!(::std::fs::read_dir(outdir.path()).unwrap().count(), 1);
+
+        let mut reader = FailingReader { remain: 1000 };
+        write_atomically(&mut reader, 1000, &filepath).unwrap();
+        assert_eq!(::std::fs::read(&filepath).unwrap().len(), 1000);
+        assert_eq!(::std::fs::read_dir(outdir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn resume_interrupted_extraction() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"first".to_vec()),
+            TestEntry::File(b"b", b"second".to_vec()),
+            TestEntry::File(b"c", b"third".to_vec()),
+            TestEntry::File(b"d", b"fourth".to_vec()),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let outpath = outdir.path().to_str().unwrap();
+
+        // A directory in the way of c interrupts the extraction after 2 files
+        ::std::fs::create_dir(outdir.path().join("c")).unwrap();
+        {
+            let mut state = ResumeState::open(outpath).unwrap();
+            let mut options = ExtractOptions {
+                resume: Some(&mut state),
+                ..Default::default()
+            };
+            assert!(extract_archive(&archive, outpath, &mut options).is_err());
+        }
+        ::std::fs::remove_dir(outdir.path().join("c")).unwrap();
+        // A completed file modified since is extracted again
+        ::std::fs::write(outdir.path().join("b"), b"2nd").unwrap();
+
+        let mut state = ResumeState::open(outpath).unwrap();
+        let mut output = Vec::new();
+        {
+            let mut options = ExtractOptions {
+                output: Some(&mut output),
+                resume: Some(&mut state),
+                ..Default::default()
+            };
+            assert_eq!(extract_archive(&archive, outpath, &mut options).unwrap(), 0);
+        }
+        let sep = ::std::path::MAIN_SEPARATOR;
+        let expected = format!(
+            "{out}{sep}b\n{out}{sep}c\n{out}{sep}d\n",
+            out = outpath,
+            sep = sep
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+        state.finish().unwrap();
+        assert_eq!(
+            check_extracted(&archive, outpath).unwrap(),
+            Vec::<CheckIssue>::new()
+        );
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(parse_mode("644").unwrap(), 0o644);
+        assert_eq!(parse_mode("0755").unwrap(), 0o755);
+        for s in &["", "8", "-1", "u+x", "17777"] {
+            assert!(parse_mode(s).is_err(), "{}", s);
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[cfg(unix)]
+    #[test]
+    fn extract_with_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"a".to_vec()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"b", b"b".to_vec())]),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let mut options = ExtractOptions {
+            mode: Some(0o751),
+            ..Default::default()
+        };
+        extract_archive(&archive, outdir.path().to_str().unwrap(), &mut options).unwrap();
+        for path in &["a", "d/b"] {
+            let metadata = ::std::fs::metadata(outdir.path().join(path)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o7777, 0o751, "{}", path);
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn read_all_files_without_output() {
+        let data = sample_data(0x1800);
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"abc".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![TestEntry::File(b"z", write_zlib_file_data(&data, 0x800))],
+            ),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(read_all_files(&archive).unwrap(), (2, 3 + 0x1800));
+    }
+
+    #[test]
+    fn size_suffixes() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("1500").unwrap(), 1500);
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert_eq!(parse_size("2m").unwrap(), 2 << 20);
+        assert_eq!(parse_size("1G").unwrap(), 1 << 30);
+        for s in &["", "K", "-1", "1.5M", "1T", "20000000000G"] {
+            assert!(parse_size(s).is_err(), "{}", s);
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn extract_files_in_size_range() {
+        let file = archive_file(&[
+            TestEntry::File(b"tiny", vec![0; 10]),
+            TestEntry::File(b"medium", vec![0; 100]),
+            TestEntry::File(b"big", vec![0; 1000]),
+            // Smaller than 100 bytes once compressed
+            TestEntry::Zlib(b"packed", vec![0; 500], 0x1000),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let mut options = ExtractOptions {
+            min_size: Some(100),
+            max_size: Some(999),
+            ..Default::default()
+        };
+
+        extract_archive(&archive, outdir.path().to_str().unwrap(), &mut options).unwrap();
+
+        assert!(outdir.path().join("medium").exists());
+        assert!(outdir.path().join("packed").exists());
+        assert!(!outdir.path().join("tiny").exists());
+        assert!(!outdir.path().join("big").exists());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn exclude_empty_files() {
+        let file = archive_file(&[
+            TestEntry::File(b"empty", Vec::new()),
+            TestEntry::File(b"small", vec![0; 10]),
+            TestEntry::Dir(
+                b"d",
+                vec![
+                    TestEntry::File(b"empty", Vec::new()),
+                    TestEntry::File(b"big", vec![0; 1000]),
+                    TestEntry::Zlib(b"packed", Vec::new(), 0x1000),
+                ],
+            ),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let extracted = |options: &mut ExtractOptions| {
+            let outdir = tempfile::tempdir().unwrap();
+            extract_archive(&archive, outdir.path().to_str().unwrap(), options).unwrap();
+            let mut names: Vec<&str> = ["empty", "small", "d/empty", "d/big", "d/packed"]
+                .iter()
+                .cloned()
+                .filter(|p| outdir.path().join(p).exists())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let mut options = ExtractOptions::default();
+        assert_eq!(
+            extracted(&mut options),
+            vec!["d/big", "d/empty", "d/packed", "empty", "small"]
+        );
+        options.exclude_empty = true;
+        assert_eq!(extracted(&mut options), vec!["d/big", "small"]);
+        options.max_size = Some(100);
+        assert_eq!(extracted(&mut options), vec!["small"]);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn extract_files_matching_pattern() {
+        let file = archive_file(&[
+            TestEntry::File(b"a.lua", b"a".to_vec()),
+            TestEntry::File(b"b.txt", b"b".to_vec()),
+            TestEntry::Dir(b"D", vec![TestEntry::File(b"c.LUA", b"c".to_vec())]),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        for &(sequential, case_insensitive, expected) in &[
+            (false, false, &["a.lua"][..]),
+            (true, false, &["a.lua"][..]),
+            (false, true, &["D/c.LUA", "a.lua"][..]),
+            (true, true, &["D/c.LUA", "a.lua"][..]),
+        ] {
+            let outdir = tempfile::tempdir().unwrap();
+            let mut options = ExtractOptions {
+                sequential: sequential,
+                include: Some(
+                    GlobPattern::new("**/*.lua")
+                        .unwrap()
+                        .case_insensitive(case_insensitive),
+                ),
+                ..Default::default()
+            };
+            extract_archive(&archive, outdir.path().to_str().unwrap(), &mut options).unwrap();
+            let extracted: Vec<&str> = ["D/c.LUA", "a.lua", "b.txt"]
+                .iter()
+                .cloned()
+                .filter(|p| outdir.path().join(p).exists())
+                .collect();
+            assert_eq!(extracted, expected);
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn check_extracted_tree() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"a".to_vec()),
+            TestEntry::File(b"b", b"b".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![TestEntry::File(
+                    b"c",
+                    write_zlib_file_data(&sample_data(0x300), 0x100),
+                )],
+            ),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let outpath = outdir.path().to_str().unwrap();
+        extract_archive(&archive, outpath, &mut ExtractOptions::default()).unwrap();
+        assert_eq!(check_extracted(&archive, outpath).unwrap(), vec![]);
+
+        let out = outdir.path();
+        ::std::fs::remove_file(out.join("a")).unwrap();
+        ::std::fs::write(out.join("b"), b"bb").unwrap();
+        ::std::fs::write(out.join("d/e"), b"").unwrap();
+        assert_eq!(
+            check_extracted(&archive, outpath).unwrap(),
+            vec![
+                CheckIssue::Missing("a".to_string()),
+                CheckIssue::SizeMismatch("b".to_string(), 1, 2),
+                CheckIssue::Extra("d/e".to_string()),
+            ]
+        );
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn extract_subtree() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"a".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![TestEntry::Dir(
+                    b"e",
+                    vec![
+                        TestEntry::File(b"b", b"b".to_vec()),
+                        TestEntry::Dir(b"f", vec![TestEntry::File(b"c", b"c".to_vec())]),
+                    ],
+                )],
+            ),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let e = &archive.root_directory().directories()[0].directories()[0];
+
+        archive
+            .extract_directory(e, "d", outdir.path().to_str().unwrap())
+            .unwrap();
+
+        let out = outdir.path();
+        assert_eq!(::std::fs::read(out.join("d/e/b")).unwrap(), b"b");
+        assert_eq!(::std::fs::read(out.join("d/e/f/c")).unwrap(), b"c");
+        assert!(!out.join("a").exists());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn extract_by_index() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"a".to_vec()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"b", b"b".to_vec())]),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let outpath = outdir.path().to_str().unwrap();
+
+        archive.extract_single_by_index(4, outpath).unwrap();
+        assert_eq!(::std::fs::read(outdir.path().join("d/b")).unwrap(), b"b");
+        assert!(!outdir.path().join("a").exists());
+        archive.extract_single_by_index(2, outpath).unwrap();
+        assert_eq!(::std::fs::read(outdir.path().join("a")).unwrap(), b"a");
+
+        for index in &[0, 1, 3, 5] {
+            assert!(archive.extract_single_by_index(*index, outpath).is_err());
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn extract_beyond_max_path() {
+        const NAME: &[u8] = b"a_directory_name_long_enough_to_overflow_max_path_quickly";
+        let mut entry = TestEntry::File(b"f", b"deep".to_vec());
+        for _ in 0..6 {
+            entry = TestEntry::Dir(NAME, vec![entry]);
+        }
+        let file = archive_file(&[entry]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let outdir = tempfile::tempdir().unwrap();
+        let outpath = outdir.path().to_str().unwrap();
+
+        extract_archive(&archive, outpath, &mut ExtractOptions::default()).unwrap();
+
+        let name = ::std::str::from_utf8(NAME).unwrap();
+        let mut path = String::from(outpath);
+        for _ in 0..6 {
+            path.push('\\');
+            path.push_str(name);
+        }
+        path.push_str("\\f");
+        assert!(path.len() > 260);
+        assert_eq!(::std::fs::read(long_path(&path)).unwrap(), b"deep");
+    }
+}
@@ -0,0 +1,297 @@
+use ::errors::*;
+
+/* Element of a path component pattern */
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Literal(char),
+    /* '?' */
+    AnyChar,
+    /* '*' */
+    AnySequence,
+    /* '[...]', with inclusive ranges, single characters being ranges of
+     * one */
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Component {
+    /* '**', matching any number of path components, including none */
+    AnyDepth,
+    Pattern(Vec<Token>),
+}
+
+/** Shell-like pattern matching '/' separated archive paths.
+ *
+ * `?` matches any character and `*` any sequence of characters, except '/'.
+ * `**` as a whole path component matches any number of components. `[abc]`,
+ * `[a-z]` and their negations `[!abc]` or `[^abc]` match a character of, or
+ * not of, a set; `]` is part of the set when it comes first. `\` makes the
+ * following character lose its special meaning. Leading and repeated '/' are
+ * ignored, so that "/a//b" is the same as "a/b". */
+#[derive(Clone, Debug)]
+pub struct GlobPattern {
+    components: Vec<Component>,
+    case_insensitive: bool,
+}
+
+fn parse_class(chars: &mut ::std::iter::Peekable<::std::str::Chars>) -> Result<Token> {
+    let negated = match chars.peek() {
+        Some(&'!') | Some(&'^') => {
+            chars.next();
+            true
+        }
+        _ => false,
+    };
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let c = match chars.next() {
+            None => bail!("Unterminated character class"),
+            Some(']') if !first => break,
+            Some('\\') => match chars.next() {
+                Some(c) => c,
+                None => bail!("Pattern ends with an escape character"),
+            },
+            Some(c) => c,
+        };
+        first = false;
+        if chars.peek() != Some(&'-') {
+            ranges.push((c, c));
+            continue;
+        }
+        chars.next();
+        let end = match chars.next() {
+            None => bail!("Unterminated character class"),
+            // "-" at the end of the class is a literal
+            Some(']') => {
+                ranges.push((c, c));
+                ranges.push(('-', '-'));
+                break;
+            }
+            Some('\\') => match chars.next() {
+                Some(c) => c,
+                None => bail!("Pattern ends with an escape character"),
+            },
+            Some(end) => end,
+        };
+        if end < c {
+            bail!("Invalid character range {}-{}", c, end);
+        }
+        ranges.push((c, end));
+    }
+    Ok(Token::Class {
+        negated: negated,
+        ranges: ranges,
+    })
+}
+
+fn parse_component(component: &str) -> Result<Component> {
+    if component == "**" {
+        return Ok(Component::AnyDepth);
+    }
+    if component.contains("**") {
+        bail!(
+            "\"**\" must be a whole path component, found \"{}\"",
+            component
+        );
+    }
+    let mut tokens = Vec::new();
+    let mut chars = component.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '?' => Token::AnyChar,
+            '*' => Token::AnySequence,
+            '[' => parse_class(&mut chars)?,
+            '\\' => match chars.next() {
+                Some(c) => Token::Literal(c),
+                None => bail!("Pattern ends with an escape character"),
+            },
+            c => Token::Literal(c),
+        };
+        tokens.push(token);
+    }
+    Ok(Component::Pattern(tokens))
+}
+
+impl GlobPattern {
+    /** Compile a pattern, failing if it is empty or malformed. */
+    pub fn new(pattern: &str) -> Result<GlobPattern> {
+        let mut components = Vec::new();
+        for c in pattern.split('/').filter(|c| !c.is_empty()) {
+            let component =
+                parse_component(c).chain_err(|| format!("Invalid glob pattern \"{}\"", pattern))?;
+            // Consecutive "**" are the same as a single one
+            if component == Component::AnyDepth && components.last() == Some(&Component::AnyDepth) {
+                continue;
+            }
+            components.push(component);
+        }
+        if components.is_empty() {
+            bail!("Invalid glob pattern \"{}\": no path component", pattern);
+        }
+        Ok(GlobPattern {
+            components: components,
+            case_insensitive: false,
+        })
+    }
+
+    /** Whether letters match regardless of their case. Off by default. */
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> GlobPattern {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /** Whether the pattern matches a '/' separated path. A trailing '/', as
+     * found in the paths of directories, is ignored. */
+    pub fn matches(&self, path: &str) -> bool {
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        self.matches_components(&self.components, &components)
+    }
+
+    /** Whether entries below the directory at a '/' separated path may
+     * match, so that directories which cannot hold matching entries need not
+     * be visited. */
+    pub fn may_match_in(&self, dir: &str) -> bool {
+        let components: Vec<&str> = dir.split('/').filter(|c| !c.is_empty()).collect();
+        self.may_match_below(&self.components, &components)
+    }
+
+    fn same_char(&self, a: char, b: char) -> bool {
+        a == b || (self.case_insensitive && a.to_lowercase().eq(b.to_lowercase()))
+    }
+
+    fn in_class(&self, ranges: &[(char, char)], c: char) -> bool {
+        let in_ranges = |c: char| ranges.iter().any(|&(start, end)| start <= c && c <= end);
+        if in_ranges(c) {
+            return true;
+        }
+        self.case_insensitive
+            && (c.to_lowercase().any(&in_ranges) || c.to_uppercase().any(&in_ranges))
+    }
+
+    fn token_matches(&self, token: &Token, c: char) -> bool {
+        match *token {
+            Token::Literal(l) => self.same_char(l, c),
+            Token::AnyChar => true,
+            Token::AnySequence => false,
+            Token::Class {
+                negated,
+                ref ranges,
+            } => self.in_class(ranges, c) != negated,
+        }
+    }
+
+    /* Match a single path component, backtracking to the last '*' seen on
+     * mismatches. */
+    fn component_matches(&self, tokens: &[Token], name: &str) -> bool {
+        let name: Vec<char> = name.chars().collect();
+        let (mut t, mut n) = (0, 0);
+        let mut backtrack: Option<(usize, usize)> = None;
+        while n < name.len() {
+            if t < tokens.len() && tokens[t] == Token::AnySequence {
+                backtrack = Some((t, n));
+                t += 1;
+            } else if t < tokens.len() && self.token_matches(&tokens[t], name[n]) {
+                t += 1;
+                n += 1;
+            } else if let Some((star_t, star_n)) = backtrack {
+                // Let the last '*' match one more character
+                backtrack = Some((star_t, star_n + 1));
+                t = star_t + 1;
+                n = star_n + 1;
+            } else {
+                return false;
+            }
+        }
+        tokens[t..].iter().all(|token| *token == Token::AnySequence)
+    }
+
+    fn matches_components(&self, pattern: &[Component], path: &[&str]) -> bool {
+        match pattern.first() {
+            None => path.is_empty(),
+            Some(&Component::AnyDepth) => {
+                (0..path.len() + 1).any(|i| self.matches_components(&pattern[1..], &path[i..]))
+            }
+            Some(Component::Pattern(tokens)) => {
+                !path.is_empty()
+                    && self.component_matches(tokens, path[0])
+                    && self.matches_components(&pattern[1..], &path[1..])
+            }
+        }
+    }
+
+    /* Whether a path below the directory with the specified components may
+     * match. */
+    fn may_match_below(&self, pattern: &[Component], dir: &[&str]) -> bool {
+        match pattern.first() {
+            None => false,
+            Some(&Component::AnyDepth) => true,
+            Some(Component::Pattern(tokens)) => {
+                dir.is_empty()
+                    || (self.component_matches(tokens, dir[0])
+                        && self.may_match_below(&pattern[1..], &dir[1..]))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        GlobPattern::new(pattern).unwrap().matches(path)
+    }
+
+    #[test]
+    fn wildcards_and_classes() {
+        assert!(matches("*.txt", "a.txt"));
+        assert!(!matches("*.txt", "d/a.txt"));
+        assert!(matches("*/*.txt", "d/a.txt"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(matches("**/*.txt", "a.txt"));
+        assert!(matches("**/*.txt", "d/e/a.txt"));
+        assert!(matches("d/**", "d/e/f"));
+        assert!(matches("d/**/f", "d/f"));
+        assert!(matches("/d//*", "d/x"));
+        assert!(matches("*a*b", "xaab"));
+        assert!(!matches("*a*b", "xaabc"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[^a-c]x", "dx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("d/", "d/"));
+
+        assert!(!matches("*.TXT", "a.txt"));
+        let insensitive = GlobPattern::new("[A-C]*.TXT")
+            .unwrap()
+            .case_insensitive(true);
+        assert!(insensitive.matches("b.txt"));
+        assert!(!insensitive.matches("d.txt"));
+
+        let nested = GlobPattern::new("d/*/x").unwrap();
+        assert!(nested.may_match_in("d/"));
+        assert!(nested.may_match_in("d/e/"));
+        assert!(!nested.may_match_in("d/e/f/"));
+        assert!(!nested.may_match_in("f/"));
+        assert!(GlobPattern::new("**/x").unwrap().may_match_in("a/b/"));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        for pattern in &["", "/", "[abc", "a\\", "[z-a]", "a**", "**b/c", "[]"] {
+            assert!(
+                GlobPattern::new(pattern).is_err(),
+                "\"{}\" should be invalid",
+                pattern
+            );
+        }
+    }
+}
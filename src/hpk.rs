@@ -1,60 +1,435 @@
 extern crate byteorder;
 extern crate libflate;
+#[cfg(feature = "fs")]
+extern crate memmap2;
+extern crate sha2;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 use self::byteorder::{ByteOrder, LittleEndian};
+use self::sha2::digest::{Digest, Output};
 use ::errors::*;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+#[cfg(feature = "fs")]
 use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::io::SeekFrom;
+use std::ops::{ControlFlow, Range};
+use std::path::Path;
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
 
-const FILE_ENTRY_SIZE: usize = 8;
-const NAME_ENTRY_MIN_SIZE: usize = 10;
+/** Magic bytes at the start of an archive */
+pub const MAGIC: &[u8; 4] = b"BPUL";
+/** Magic bytes at the start of the data of a ZLIB compressed file */
+pub const ZLIB_MAGIC: &[u8; 4] = b"ZLIB";
+
+/** Size of an entry of the file table: offset and size, both 32 bits */
+pub const FILE_ENTRY_SIZE: usize = 8;
+/** Size of an entry of a name table without its name: file table index,
+ * entry type and name length */
+pub const NAME_ENTRY_MIN_SIZE: usize = 10;
+/** Longest entry name, in bytes, as its length is stored on 16 bits */
+pub const MAX_NAME_LENGTH: usize = u16::MAX as usize;
 
 const ZLIB_BLOCKTBL_OFFSET: u64 = 0x0c;
 const ZLIB_MAX_CACHE_ENTRIES: usize = 2;
 const ZLIB_MAX_BLOCKSIZE: u64 = 0x1000000;
 
+/** Default maximum number of directory levels below the root directory */
+pub const MAX_DIRECTORY_DEPTH: usize = 128;
+const MAX_ENTRIES: usize = 4_000_000;
+/* Number of entries parsed between two calls of a progress callback */
+const PROGRESS_INTERVAL: usize = 1024;
+/* Number of file table entries read at once. Entries of a directory usually
+ * refer to consecutive indices. */
+const FILETBL_READAHEAD: u32 = 512;
+/* Size of the buffer through which file data is hashed */
+const DIGEST_BUFFER_SIZE: usize = 0x10000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum EntryType {
     File,
     Directory,
+    /** Type value not known to this parser. Such entries are skipped when
+     * `ArchiveOptions::strict_entry_types()` is unset. */
+    Unknown(u32),
+}
+
+impl EntryType {
+    pub fn is_file(&self) -> bool {
+        matches!(*self, EntryType::File)
+    }
+
+    pub fn is_directory(&self) -> bool {
+        matches!(*self, EntryType::Directory)
+    }
+}
+
+/** Entry skipped because of its unknown type, see
+ * `Archive::unknown_entries()`. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownEntry {
+    /** '/' separated path of the entry */
+    pub path: String,
+    pub entry_type: u32,
+    /** Offset of the name entry in the archive */
+    pub offset: u64,
+}
+
+/** Name table record as stored in the archive, see
+ * `Archive::raw_dir_entries()`. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawNameEntry {
+    pub file_index: u32,
+    pub entry_type: u32,
+    pub name_len: u16,
+    pub name: Vec<u8>,
+    /** Offset of the record in the archive */
+    pub offset: u64,
 }
 
+#[derive(Clone)]
 struct NameTableEntry {
     file_index: u32,
     entry_type: EntryType,
     entry_size: u32,
-    name: String,
+    /* Range of the name in the names of its directory, see File::names */
+    name: Range<u32>,
+    /* Offset of the entry in the archive */
+    offset: u64,
 }
 
+#[derive(Clone)]
 struct FileTableEntry {
     offset: u32,
     size: u32,
 }
 
+#[derive(Clone)]
 pub struct File {
     name_entry: NameTableEntry,
     file_entry: FileTableEntry,
+    /* Names of all the entries of the parent directory, stored back to back
+     * rather than allocated one by one */
+    names: Arc<str>,
+    /* Whether the data is ZLIB compressed, known once a reader was created */
+    compressed: OnceLock<bool>,
 }
 
-pub struct Directory {
+#[derive(Clone)]
+struct DirContents {
     files: Vec<File>,
     directories: Vec<Directory>,
+}
+
+/** What is needed to load the contents of a directory on demand. */
+#[derive(Clone)]
+struct LazyDirectory {
+    file: Arc<Mutex<ArchiveFile>>,
+    /* File table indices of the parent directories, used to enforce the
+     * depth limit and detect loops. */
+    stack: Vec<u32>,
+    /* Path of the directory, ending with '/' */
+    path: String,
+}
+
+#[derive(Clone)]
+pub struct Directory {
+    index: u32,
+    contents: OnceLock<DirContents>,
+    lazy: Option<LazyDirectory>,
     name_entry: Option<NameTableEntry>,
+    /* Names of the entries of the parent directory, see File::names */
+    names: Arc<str>,
     file_entry: FileTableEntry,
 }
 
 struct ArchiveFile {
-    filetbl_offset: u64,
-    reader: BufReader<fs::File>,
-    basefile: fs::File,
+    /* Size of the archive file, as of when it was opened */
+    file_len: u64,
+    header: HeaderInfo,
+    /* Number of entries in the file table */
+    filetbl_len: u32,
+    /* Number of entries in the file table, bounded by the data of the root
+     * directory, once computed by entry_count() */
+    entry_count: Option<usize>,
+    reader: BufReader<ArchiveSource>,
+    basefile: ArchiveSource,
+    /* File table entries read ahead, starting at the 0-based index
+     * filetbl_cache_start */
+    filetbl_cache: Vec<u8>,
+    filetbl_cache_start: u32,
+    options: ArchiveOptions,
+    /* Number of name entries parsed so far */
+    num_entries: usize,
+    /* Entries skipped because of their type */
+    unknown_entries: Vec<UnknownEntry>,
+    /* Errors skipped because of the recover_errors option */
+    recovered_errors: Vec<String>,
+    progress: ParseProgress,
+    progress_callback: Option<ProgressCallback>,
+    shared_cache: Option<Arc<SharedBlockCache>>,
+    metrics: MetricsHandle,
 }
 
 pub struct Archive {
-    file: ArchiveFile,
+    file: Arc<Mutex<ArchiveFile>>,
     rootdir: Directory,
+    header: HeaderInfo,
+    /* What is needed to reload the archive, no path if it was not read from
+     * a file */
+    path: Option<String>,
+    lazy: bool,
+}
+
+/** Two files whose data partially overlap, see
+ * `Archive::overlapping_files()`. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataOverlap {
+    /** Path and range of the data of the file starting first */
+    pub first: String,
+    pub first_range: Range<u64>,
+    pub second: String,
+    pub second_range: Range<u64>,
+}
+
+/** Problem found by `Archive::validate()`. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationIssue {
+    /** Entry which could not be parsed and was skipped, see
+     * `ArchiveOptions::recover_errors()` */
+    Unparsable(String),
+    UnknownEntry(UnknownEntry),
+    /** Path of a file whose data cannot be read, and why */
+    BadFileData(String, String),
+    Overlap(DataOverlap),
+    /** Paths of directories sharing their name table, and its offset, see
+     * `Archive::validate_no_loops()` */
+    SharedDirectory(u32, Vec<String>),
+    /** Path taken by several entries of a directory, of which extraction
+     * only keeps one */
+    DuplicateName(String),
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationIssue::Unparsable(ref e) => write!(f, "corrupt entry: {}", e),
+            ValidationIssue::UnknownEntry(ref e) => write!(
+                f,
+                "entry {} of unknown type 0x{:x} at offset 0x{:x}",
+                e.path, e.entry_type, e.offset
+            ),
+            ValidationIssue::BadFileData(ref path, ref e) => {
+                write!(f, "unreadable data of {}: {}", path, e)
+            }
+            ValidationIssue::Overlap(ref o) => write!(
+                f,
+                "data of {} [0x{:x}-0x{:x}) overlaps {} [0x{:x}-0x{:x})",
+                o.first,
+                o.first_range.start,
+                o.first_range.end,
+                o.second,
+                o.second_range.start,
+                o.second_range.end
+            ),
+            ValidationIssue::SharedDirectory(offset, ref paths) => write!(
+                f,
+                "directories {} share the name table at offset 0x{:x}",
+                paths.join(", "),
+                offset
+            ),
+            ValidationIssue::DuplicateName(ref path) => {
+                write!(f, "several entries are named {}", path)
+            }
+        }
+    }
+}
+
+/** Metadata of an entry, see `Archive::stat()`. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryStat {
+    pub kind: EntryType,
+    /** Size of the data in the archive. For a directory, this is the size of
+     * its name table. */
+    pub stored_size: u32,
+    /** Size of a file once decompressed, if requested */
+    pub unpacked_size: Option<u64>,
+    /** Offset of the data in the archive */
+    pub offset: u32,
+    /** File table index */
+    pub index: u32,
+}
+
+/** Order in which `Archive::walk_with()` visits files. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalkOrder {
+    /** Files of a directory, then each of its subdirectories recursively */
+    DepthFirst,
+    /** Files of the root directory, then of its subdirectories, then of
+     * theirs, and so on */
+    BreadthFirst,
+    /** Files by increasing offset of their data in the archive */
+    DataOffset,
+}
+
+/** How far the parsing of an archive went, see
+ * `Archive::open_with_progress()`. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseProgress {
+    pub directories: usize,
+    pub entries: usize,
+    /** Bytes of the name tables parsed */
+    pub name_table_bytes: u64,
+}
+
+type ProgressCallback = Box<dyn FnMut(ParseProgress) -> ControlFlow<()> + Send>;
+
+/** Fields of the archive header. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeaderInfo {
+    pub magic: [u8; 4],
+    pub header_size: u32,
+    /** Offset of the file table in the archive */
+    pub filetbl_offset: u32,
+}
+
+/* Directory being parsed, for error messages */
+struct ParseContext<'a> {
+    index: u32,
+    /* Path of the directory, ending with '/' unless it is the root */
+    path: &'a str,
+}
+
+/** Entry of an archive designated by a file table index, see
+ * `Archive::entry_by_index()`. */
+pub enum EntryRef<'a> {
+    /** A file, along with its '/' separated path */
+    File(String, &'a File),
+    /** A directory, along with its path, which ends with '/' unless it is
+     * the root directory */
+    Directory(String, &'a Directory),
+    /** The index is part of the file table, but no name entry refers to it */
+    Unreferenced,
+}
+
+/* Directory whose name table is being read by an ArchiveStream */
+struct StreamDir {
+    index: u32,
+    prefix: String,
+    /* Name table of the directory */
+    table: Vec<u8>,
+    cur_offset: u64,
+    max_offset: u64,
+}
+
+/** Iterator over the files of an archive and their '/' separated paths,
+ * parsing the directory tree as it goes and without keeping it in memory.
+ * Entries are visited depth first, in on-disk order. Iteration stops after
+ * the first error. */
+pub struct ArchiveStream {
+    file: ArchiveFile,
+    stack: Vec<StreamDir>,
+}
+
+/** Child of a directory, see `Directory::entries()`. */
+pub enum DirChild<'a> {
+    File(&'a File),
+    Directory(&'a Directory),
+}
+
+/** Iterator over the files of an archive and their '/' separated paths. See
+ * `Archive::files()` and `Archive::files_unsorted()`. */
+pub struct ArchiveFiles<'a> {
+    /* Path prefix of each directory being visited */
+    stack: DirStack<'a, String>,
+}
+
+/** Iterator over the files of an archive and their '/' separated paths,
+ * given by `for (path, file) in &archive`. */
+pub struct ArchiveIter<'a> {
+    files: ArchiveFiles<'a>,
+}
+
+/* Iterator over the files below a directory and their depth, see
+ * `Directory::iter_files_depth_first()`. */
+struct FilesWithDepth<'a> {
+    /* Depth of each directory being visited */
+    stack: DirStack<'a, usize>,
+}
+
+/* Directories being visited depth first by the iterators over files, with
+ * the remaining children of each one and what the iterator keeps for it.
+ * The first directory is only entered by the first call to next(), so that
+ * failing to load it is reported by the iterator. */
+struct DirStack<'a, T> {
+    stack: Vec<(T, ::std::vec::IntoIter<DirChild<'a>>)>,
+    root: Option<(&'a Directory, T)>,
+    sorted: bool,
+}
+
+/** Settings controlling how an archive is parsed. The defaults are used by
+ * `Archive::open()`. */
+#[derive(Clone, Debug)]
+pub struct ArchiveOptions {
+    max_depth: usize,
+    max_entries: usize,
+    max_block_size: u64,
+    shared_cache_bytes: Option<usize>,
+    block_cache_entries: usize,
+    strict_names: bool,
+    strict_entry_types: bool,
+    recover_errors: bool,
+}
+
+/** Reader an archive can be parsed from, see `Archive::from_boxed()`. */
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/* Contents of an archive kept in memory or mapped in memory. */
+#[derive(Clone)]
+enum SharedBytes {
+    Owned(Arc<[u8]>),
+    #[cfg(feature = "fs")]
+    Mapped(Arc<memmap2::Mmap>),
+}
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        match *self {
+            SharedBytes::Owned(ref b) => b,
+            #[cfg(feature = "fs")]
+            SharedBytes::Mapped(ref m) => m,
+        }
+    }
+}
+
+/* Where the contents of an archive are read from. Each handle has its own
+ * position, so that handles used by different threads do not disturb each
+ * other. */
+enum ArchiveSource {
+    /* File shared by all the handles, read with positioned reads */
+    #[cfg(all(feature = "fs", any(unix, windows)))]
+    File {
+        file: Arc<fs::File>,
+        pos: u64,
+    },
+    Memory(io::Cursor<SharedBytes>),
+    /* Reader shared by all the handles, each keeping its own position. The
+     * archive starts at offset base of the reader. */
+    Shared {
+        reader: Arc<Mutex<Box<dyn ReadSeek + Send>>>,
+        base: u64,
+        len: u64,
+        pos: u64,
+    },
 }
 
 enum FileDataEncoding {
@@ -63,10 +438,84 @@ enum FileDataEncoding {
 }
 
 struct FileDataPlain {
-    file: fs::File,
+    file: ArchiveSource,
     size: u64,
     base_offset: u64,
     cur_offset: u64,
+    metrics: MetricsHandle,
+}
+
+type BlockCache = HashMap<u32, Arc<Vec<u8>>>;
+
+/** Counters of the cache shared by the readers of an archive, see
+ * `ArchiveOptions::shared_cache_bytes()`. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /** Blocks currently in the cache, and their total size */
+    pub blocks: usize,
+    pub bytes: usize,
+}
+
+/** Counters of the work done by the readers of an archive, see
+ * `Archive::metrics()`. */
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /** Bytes read from the archive for file data, compressed or not */
+    pub bytes_read: u64,
+    /** Bytes produced by decompressing blocks */
+    pub bytes_decompressed: u64,
+    pub blocks_decompressed: u64,
+    /** Lookups of decompressed blocks in the block caches */
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /** Repositionings of readers, whether requested by the caller or needed
+     * to read a block */
+    pub seeks: u64,
+    /** Readers created, including clones */
+    pub file_data_created: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Counter {
+    BytesRead,
+    BytesDecompressed,
+    BlocksDecompressed,
+    CacheHits,
+    CacheMisses,
+    Seeks,
+    FileDataCreated,
+}
+
+#[cfg(feature = "metrics")]
+const NUM_COUNTERS: usize = 7;
+
+/* Counters shared by the readers of an archive. Without the metrics
+ * feature, this is empty and counting does nothing. */
+#[derive(Clone, Default)]
+struct MetricsHandle {
+    #[cfg(feature = "metrics")]
+    counters: Arc<[AtomicU64; NUM_COUNTERS]>,
+}
+
+/* Blocks are identified by the offset of the data of their file, which
+ * unlike the file index is known to the readers, and by their index. */
+type SharedBlockKey = (u64, u32);
+
+struct SharedCacheState {
+    /* Blocks along with the tick of their last use */
+    blocks: HashMap<SharedBlockKey, (Arc<Vec<u8>>, u64)>,
+    /* Blocks by tick of last use, least recently used first */
+    lru: BTreeMap<u64, SharedBlockKey>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+struct SharedBlockCache {
+    max_bytes: usize,
+    state: Mutex<SharedCacheState>,
 }
 
 struct FileDataZlib {
@@ -74,307 +523,866 @@ struct FileDataZlib {
     size: u64,
     cur_offset: u64,
     blocksize: u64,
-    cache: HashMap<u32, Vec<u8>>,
+    /* Offsets of the blocks, read once from the block table and shared with
+     * the clones of this reader */
+    block_offsets: Arc<Vec<u64>>,
+    /* Decompressed blocks, shared with the clones of this reader */
+    cache: Arc<Mutex<BlockCache>>,
+    max_cache_entries: usize,
+    /* Used instead of cache if set */
+    shared_cache: Option<Arc<SharedBlockCache>>,
 }
 
 pub struct FileData {
     fdata: FileDataEncoding,
 }
 
-impl File {
-    pub fn name(&self) -> &str {
-        &self.name_entry.name
-    }
+/** Reader over a file of an archive, which does not borrow the archive and
+ * can outlive it. See `Archive::open_path()`. */
+pub struct FileHandle {
+    path: String,
+    index: u32,
+    data: FileData,
+}
 
-    pub fn size(&self) -> u32 {
-        self.file_entry.size
+/** How a block of a ZLIB compressed file is stored, see
+ * `FileData::block_encoding()`. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockEncoding {
+    /** Deflated, with a ZLIB header */
+    Compressed,
+    /** Stored as is, because deflating did not make it smaller. Such a block
+     * is as large in the archive as it is once decompressed, which is how
+     * it is told apart: there is no flag. */
+    Uncompressed,
+}
+
+/** A block of a ZLIB compressed file, as stored in the archive. */
+pub struct RawBlock {
+    data: Vec<u8>,
+    offset: u64,
+    compressed: bool,
+    unpacked_size: u64,
+}
+
+/** Check whether a buffer starts with the given magic bytes. */
+fn starts_with_magic(buf: &[u8], magic: &[u8]) -> bool {
+    buf.len() >= magic.len() && &buf[..magic.len()] == magic
+}
+
+/** Read the 4 magic bytes located at the specified offset, then seek back to
+ * that offset. */
+fn peek_magic<T: Read + Seek>(reader: &mut T, offset: u64) -> io::Result<[u8; 4]> {
+    let mut magic = [0u8; 4];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut magic)?;
+    reader.seek(SeekFrom::Start(offset))?;
+    Ok(magic)
+}
+
+impl Default for ArchiveOptions {
+    fn default() -> ArchiveOptions {
+        ArchiveOptions {
+            max_depth: MAX_DIRECTORY_DEPTH,
+            max_entries: MAX_ENTRIES,
+            max_block_size: ZLIB_MAX_BLOCKSIZE,
+            shared_cache_bytes: None,
+            block_cache_entries: ZLIB_MAX_CACHE_ENTRIES,
+            strict_names: false,
+            strict_entry_types: true,
+            recover_errors: false,
+        }
     }
 }
 
-impl Directory {
-    pub fn files(&self) -> &Vec<File> {
-        &self.files
+impl ArchiveOptions {
+    pub fn new() -> ArchiveOptions {
+        Self::default()
     }
 
-    pub fn directories(&self) -> &Vec<Directory> {
-        &self.directories
+    /** Maximum number of directory levels below the root directory. */
+    pub fn max_depth(mut self, depth: usize) -> ArchiveOptions {
+        self.max_depth = depth;
+        self
     }
 
-    pub fn name(&self) -> Option<&str> {
-        return match self.name_entry {
-            None => None,
-            Some(ref ne) => Some(&ne.name),
-        };
+    /** Maximum number of name entries parsed in the whole archive, beyond
+     * which the archive is considered corrupt. */
+    pub fn max_entries(mut self, entries: usize) -> ArchiveOptions {
+        self.max_entries = entries;
+        self
     }
-}
 
-impl FileDataPlain {
-    fn from(mut file: fs::File, fentry: &FileTableEntry) -> Result<FileDataPlain> {
-        Ok(FileDataPlain {
-            file: file,
-            size: fentry.size as u64,
-            base_offset: fentry.offset as u64,
-            cur_offset: 0,
-        })
+    /** Maximum block size accepted in ZLIB compressed files. */
+    pub fn max_block_size(mut self, size: u64) -> ArchiveOptions {
+        self.max_block_size = size;
+        self
     }
 
-    fn size(&self) -> u64 {
-        return self.size;
+    /** Number of decompressed blocks kept in memory by each reader of a
+     * ZLIB compressed file. Must be at least 1. */
+    pub fn block_cache_entries(mut self, entries: usize) -> ArchiveOptions {
+        self.block_cache_entries = entries;
+        self
+    }
+
+    /** Keep decompressed blocks in a cache shared by all the readers of the
+     * archive, holding at most the specified number of bytes, instead of in a
+     * cache per reader. The least recently used blocks are evicted first.
+     * See `Archive::block_cache_stats()`. */
+    pub fn shared_cache_bytes(mut self, bytes: Option<usize>) -> ArchiveOptions {
+        self.shared_cache_bytes = bytes;
+        self
+    }
+
+    /** When set, entry names which are not valid UTF-8 are an error. They
+     * are otherwise decoded lossily. */
+    pub fn strict_names(mut self, strict: bool) -> ArchiveOptions {
+        self.strict_names = strict;
+        self
+    }
+
+    /** When set, entries of an unknown type are an error. They are otherwise
+     * ignored. */
+    pub fn strict_entry_types(mut self, strict: bool) -> ArchiveOptions {
+        self.strict_entry_types = strict;
+        self
+    }
+
+    /** When set, an entry which cannot be parsed is skipped, along with the
+     * rest of its directory if the extent of the entry is unknown, instead of
+     * failing to open the archive. See `Archive::is_partial()`. */
+    pub fn recover_errors(mut self, recover: bool) -> ArchiveOptions {
+        self.recover_errors = recover;
+        self
     }
 }
 
-impl Read for FileDataPlain {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut readable: usize = self.size as usize - self.cur_offset as usize;
-        if readable > buf.len() {
-            readable = buf.len();
+impl ArchiveStream {
+    #[cfg(feature = "fs")]
+    pub fn open(filename: &str) -> Result<ArchiveStream> {
+        Self::open_with(filename, &ArchiveOptions::default())
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn open_with(filename: &str, options: &ArchiveOptions) -> Result<ArchiveStream> {
+        let file = ArchiveFile::open(filename, options)?;
+        let mut stream = ArchiveStream {
+            file: file,
+            stack: Vec::new(),
         };
-        let readlen = self.file.read(&mut buf[..readable])?;
-        self.cur_offset += readlen as u64;
-        Ok(readlen)
+        stream.enter_directory(1, String::new())?;
+        Ok(stream)
     }
-}
 
-impl Seek for FileDataPlain {
-    fn seek(&mut self, style: SeekFrom) -> io::Result<u64> {
-        use std::io::{Error, ErrorKind};
-        match style {
-            SeekFrom::Start(o) => {
-                if o > self.size {
-                    Err(io::Error::new(
-                        ErrorKind::InvalidData,
-                        "Attempted to seek beyond EOF",
-                    ))
-                } else {
-                    let new_off = self.file.seek(SeekFrom::Start(self.base_offset + o))?;
-                    self.cur_offset = new_off - self.base_offset;
-                    Ok(self.cur_offset)
+    fn enter_directory(&mut self, index: u32, prefix: String) -> Result<()> {
+        let indices: Vec<u32> = self.stack.iter().map(|d| d.index).collect();
+        self.file.check_directory_stack(index, &indices)?;
+        let dentry = self.file.read_file_entry(index)?;
+        let table = self.file.read_name_table(&dentry)?;
+        self.stack.push(StreamDir {
+            index: index,
+            prefix: prefix,
+            table: table,
+            cur_offset: dentry.offset as u64,
+            max_offset: dentry.offset as u64 + dentry.size as u64,
+        });
+        Ok(())
+    }
+
+    /** Open the data of a file returned by this stream. */
+    pub fn file_data(&self, file: &File) -> Result<FileData> {
+        let f = self.file.basefile.try_clone()?;
+        FileData::new(
+            f,
+            &file.file_entry,
+            &self.file.options,
+            self.file.shared_cache.as_ref(),
+            &self.file.metrics,
+        )
+    }
+
+    fn next_file(&mut self) -> Result<Option<(String, File)>> {
+        loop {
+            let mut names = String::new();
+            let (nentry, fentry) = {
+                let dir = match self.stack.last() {
+                    None => return Ok(None),
+                    Some(d) => d,
+                };
+                if dir.cur_offset >= dir.max_offset {
+                    self.stack.pop();
+                    continue;
                 }
-            }
-            SeekFrom::End(o) => {
-                let wanted_off = (self.size as i64) + o;
-                if o > 0 {
-                    Err(Error::new(
-                        ErrorKind::InvalidData,
-                        "Attempted to seek beyond EOF",
-                    ))
-                } else if wanted_off < 0 {
-                    Err(Error::new(
-                        ErrorKind::InvalidData,
-                        "Seek resulted in negative offset",
-                    ))
-                } else {
-                    let new_off = self
-                        .file
-                        .seek(SeekFrom::Start(self.base_offset + wanted_off as u64))?;
-                    self.cur_offset = new_off - self.base_offset;
-                    Ok(self.cur_offset)
+                let ctx = ParseContext {
+                    index: dir.index,
+                    path: &dir.prefix,
+                };
+                let rest = &dir.table
+                    [(dir.table.len() as u64 - (dir.max_offset - dir.cur_offset)) as usize..];
+                self.file
+                    .read_directory_entry(&ctx, rest, dir.cur_offset, &mut names)?
+            };
+            let path = {
+                let dir = self.stack.last_mut().unwrap();
+                dir.cur_offset += nentry.entry_size() as u64;
+                format!("{}{}", dir.prefix, names)
+            };
+            match nentry.entry_type {
+                EntryType::Unknown(_) => {}
+                EntryType::File => {
+                    let file = File {
+                        name_entry: nentry,
+                        file_entry: fentry,
+                        names: Arc::from(names),
+                        compressed: OnceLock::new(),
+                    };
+                    return Ok(Some((path, file)));
                 }
-            }
-            SeekFrom::Current(o) => {
-                let cur = self.cur_offset as i64;
-                let wanted_off = cur + o;
-                if wanted_off < 0 {
-                    Err(Error::new(
-                        ErrorKind::InvalidData,
-                        "Seek resulted in negative offset",
-                    ))
-                } else if wanted_off > (self.size as i64) {
-                    Err(Error::new(
-                        ErrorKind::InvalidData,
-                        "Attempted to seek beyond EOF",
-                    ))
-                } else {
-                    let new_off = self
-                        .file
-                        .seek(SeekFrom::Start(self.base_offset + wanted_off as u64))?;
-                    self.cur_offset = new_off - self.base_offset;
-                    Ok(self.cur_offset)
+                EntryType::Directory => {
+                    self.enter_directory(nentry.file_index, format!("{}/", path))?;
                 }
             }
         }
     }
 }
 
-impl FileDataZlib {
-    fn parse_header(header: &[u8]) -> Result<(u64, u64)> {
-        let mut magic_iter = (&header[0..4]).into_iter();
-        if !"ZLIB".bytes().all(|i1| match magic_iter.next() {
-            Some(i2) => &i1 == i2,
-            None => false,
-        }) {
-            bail!("Invalid magic");
-        }
-        let size = LittleEndian::read_u32(&header[4..8]) as u64;
-        let blocksize = LittleEndian::read_u32(&header[8..0xc]) as u64;
-        if blocksize == 0 {
-            bail!("Block size is 0");
-        }
-        if blocksize > ZLIB_MAX_BLOCKSIZE {
-            bail!(
-                "Block size is exceeding the maximum allowed: {} > {}",
-                blocksize,
-                ZLIB_MAX_BLOCKSIZE
-            );
+impl Iterator for ArchiveStream {
+    type Item = Result<(String, File)>;
+
+    fn next(&mut self) -> Option<Result<(String, File)>> {
+        match self.next_file() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => None,
+            Err(e) => {
+                self.stack.clear();
+                Some(Err(e))
+            }
         }
-        Ok((size, blocksize))
     }
+}
 
-    fn from(mut file: fs::File, fentry: &FileTableEntry) -> Result<FileDataZlib> {
-        let mut plain = FileDataPlain::from(file, fentry)?;
-        let expanded_size: u64;
-        let blocksize: u64;
-        let (expanded_size, blocksize) = {
-            let mut header = [0u8; 0xc];
-            plain.read_exact(&mut header)?;
-            Self::parse_header(&header)?
-        };
-
-        Ok(FileDataZlib {
-            plain: plain,
-            size: expanded_size,
-            blocksize: blocksize,
-            cur_offset: 0u64,
-            cache: HashMap::new(),
-        })
+impl<'a> DirChild<'a> {
+    pub fn name(&self) -> &'a str {
+        match *self {
+            DirChild::File(f) => f.name(),
+            DirChild::Directory(d) => d.name().unwrap_or(""),
+        }
     }
 
-    fn size(&self) -> u64 {
-        return self.size;
+    /** Sorting by this key orders the full paths lexicographically, as a
+     * directory name is followed by '/' in the paths of its children. */
+    pub fn sort_key(&self) -> String {
+        match *self {
+            DirChild::File(f) => f.name().to_string(),
+            DirChild::Directory(d) => format!("{}/", d.name().unwrap_or("")),
+        }
     }
 
-    /** Evict one entry from the cache, provided that it is not idx.
-     * Panics if idx is the only entry in the cache or if no entry can be
-     * evicted. */
-    fn evict_another_entry(&mut self, idx: u32) {
-        if self.cache.len() == 0 {
-            panic!("Cannot evict an entry from an empty cache!");
-        }
-        if self.cache.len() == 1 && self.cache.contains_key(&idx) {
-            panic!("Cannot evict the only entry we try to keep in the cache!");
+    fn name_offset(&self) -> u64 {
+        match *self {
+            DirChild::File(f) => f.name_offset(),
+            DirChild::Directory(d) => d.name_offset().unwrap_or(0),
         }
-        let min = *self.cache.keys().min().unwrap();
-        if min == idx {
-            let max = *self.cache.keys().max().unwrap();
-            self.cache.remove(&max);
-        } else {
-            self.cache.remove(&min);
+    }
+}
+
+impl<'a, T> DirStack<'a, T> {
+    fn new(root: &'a Directory, state: T, sorted: bool) -> DirStack<'a, T> {
+        DirStack {
+            stack: Vec::new(),
+            root: Some((root, state)),
+            sorted: sorted,
         }
     }
 
-    fn read_block_offset_and_size(&mut self, idx: u32) -> io::Result<(u64, u64, u64)> {
-        let partial_block_size = (self.size % self.blocksize) as u64;
-        let num_blocks = if partial_block_size > 0 {
-            ((self.size / self.blocksize) as u32) + 1
+    /* Enter a directory, loading its contents. On error, the iteration
+     * ends. */
+    fn push(&mut self, dir: &'a Directory, state: T) -> Result<()> {
+        if let Err(e) = dir.load_children() {
+            self.stack.clear();
+            return Err(e);
+        }
+        let children = dir.entries();
+        let children = if self.sorted {
+            let mut children: Vec<DirChild<'a>> = children.collect();
+            children.sort_by_key(|c| c.sort_key());
+            children.into_iter()
         } else {
-            (self.size / self.blocksize) as u32
+            children
         };
-        if idx >= num_blocks {
-            panic!(
-                "idx {} is higher than the total number of blocks ({})",
-                idx, num_blocks
-            );
+        self.stack.push((state, children));
+        Ok(())
+    }
+
+    /* Next child of the directory visited last, along with the state of
+     * that directory. Directories are left once all their children were
+     * returned. */
+    fn next(&mut self) -> Option<Result<(&T, DirChild<'a>)>> {
+        if let Some((root, state)) = self.root.take() {
+            if let Err(e) = self.push(root, state) {
+                return Some(Err(e));
+            }
         }
-        if num_blocks == 0 {
-            return Ok((ZLIB_BLOCKTBL_OFFSET, 0u64, 0u64));
+        while self
+            .stack
+            .last()
+            .is_some_and(|(_, children)| children.len() == 0)
+        {
+            self.stack.pop();
         }
+        let (state, children) = self.stack.last_mut()?;
+        Some(Ok((state, children.next()?)))
+    }
+}
 
-        let last_block = num_blocks - 1;
-        let start_off = {
-            let mut buf = [0u8; 4];
-            let tbl_entry_off = ZLIB_BLOCKTBL_OFFSET + (idx as u64 * 4);
-            self.plain.seek(SeekFrom::Start(tbl_entry_off))?;
-            self.plain.read_exact(&mut buf)?;
-            LittleEndian::read_u32(&buf[..]) as u64
+impl<'a> ArchiveFiles<'a> {
+    fn new(root: &'a Directory, sorted: bool) -> ArchiveFiles<'a> {
+        ArchiveFiles {
+            stack: DirStack::new(root, String::new(), sorted),
+        }
+    }
+}
+
+impl<'a> Iterator for ArchiveFiles<'a> {
+    type Item = Result<(String, &'a File)>;
+
+    fn next(&mut self) -> Option<Result<(String, &'a File)>> {
+        loop {
+            let (path, dir) = match self.stack.next()? {
+                Ok((prefix, DirChild::File(f))) => {
+                    return Some(Ok((format!("{}{}", prefix, f.name()), f)))
+                }
+                Ok((prefix, DirChild::Directory(d))) => {
+                    (format!("{}{}/", prefix, d.name().unwrap_or("")), d)
+                }
+                Err(e) => return Some(Err(e)),
+            };
+            if let Err(e) = self.stack.push(dir, path) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for FilesWithDepth<'a> {
+    type Item = Result<(&'a File, usize)>;
+
+    fn next(&mut self) -> Option<Result<(&'a File, usize)>> {
+        loop {
+            let (depth, dir) = match self.stack.next()? {
+                Ok((&depth, DirChild::File(f))) => return Some(Ok((f, depth))),
+                Ok((&depth, DirChild::Directory(d))) => (depth + 1, d),
+                Err(e) => return Some(Err(e)),
+            };
+            if let Err(e) = self.stack.push(dir, depth) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for ArchiveIter<'a> {
+    type Item = (String, &'a File);
+
+    fn next(&mut self) -> Option<(String, &'a File)> {
+        // The stack is emptied on error, ending the iteration
+        loop {
+            if let Ok(entry) = self.files.next()? {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+/** `for (path, file) in &archive` visits the files in the same order as
+ * `Archive::files()`. Directories are only read as the iteration reaches
+ * them, and file data is not read at all. An archive not opened lazily is
+ * fully parsed when opened, so every file is visited. For a lazily opened
+ * archive, the iteration ends at the first directory which cannot be
+ * loaded: use `Archive::files()` to get the error. */
+impl<'a> IntoIterator for &'a Archive {
+    type Item = (String, &'a File);
+    type IntoIter = ArchiveIter<'a>;
+
+    fn into_iter(self) -> ArchiveIter<'a> {
+        ArchiveIter {
+            files: self.files(),
+        }
+    }
+}
+
+impl RawBlock {
+    /** Block contents, not decompressed. */
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /** Offset of the block from the start of the file data. */
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /** Whether the block is deflated, rather than stored as is. */
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /** Size of the block once decompressed. */
+    pub fn unpacked_size(&self) -> u64 {
+        self.unpacked_size
+    }
+}
+
+impl NameTableEntry {
+    /** Size of the entry in the name table. Entries are stored back to back,
+     * without any alignment: archives shipped with the game have entries
+     * starting at any offset, so padding is neither expected nor checked. A
+     * variant of the format padding entries would show up as entries with
+     * garbage fields, see `Archive::raw_dir_entries()`. */
+    pub fn entry_size(&self) -> u32 {
+        self.entry_size
+    }
+
+    /* Name of the entry, given the names of its directory. */
+    fn name_in<'a>(&self, names: &'a str) -> &'a str {
+        &names[self.name.start as usize..self.name.end as usize]
+    }
+}
+
+impl File {
+    pub fn name(&self) -> &str {
+        self.name_entry.name_in(&self.names)
+    }
+
+    pub fn size(&self) -> u32 {
+        self.file_entry.size
+    }
+
+    /** Offset of the file data in the archive. */
+    pub fn offset(&self) -> u32 {
+        self.file_entry.offset
+    }
+
+    /** Index of the file table entry of this file. Indices start at 1, as
+     * in the archive format, 0 being invalid. */
+    pub fn index(&self) -> u32 {
+        self.name_entry.file_index
+    }
+
+    /** Offset of the entry of this file in the name table of its parent. */
+    pub fn name_offset(&self) -> u64 {
+        self.name_entry.offset
+    }
+}
+
+fn lock_archive_file(file: &Mutex<ArchiveFile>) -> Result<MutexGuard<'_, ArchiveFile>> {
+    match file.lock() {
+        Ok(guard) => Ok(guard),
+        Err(_) => bail!("Archive file lock is poisoned"),
+    }
+}
+
+impl Directory {
+    /** Files of this directory. For a directory of a lazily opened archive,
+     * the contents are loaded on first access. Contents which cannot be
+     * parsed are seen as empty by this function and the others giving the
+     * children of a directory: call `load_children()` first to get the
+     * error. */
+    pub fn files(&self) -> &Vec<File> {
+        &self.loaded_contents().files
+    }
+
+    /** Files of this directory sorted by name, files with the same name
+     * keeping their order in the archive. */
+    pub fn files_sorted_by_name(&self) -> Vec<&File> {
+        let mut files: Vec<&File> = self.files().iter().collect();
+        files.sort_by(|a, b| a.name().cmp(b.name()));
+        files
+    }
+
+    /** Files of this directory sorted by their size in the archive, largest
+     * first, files of the same size keeping their order in the archive. */
+    pub fn files_sorted_by_size(&self) -> Vec<&File> {
+        let mut files: Vec<&File> = self.files().iter().collect();
+        files.sort_by_key(|f| cmp::Reverse(f.size()));
+        files
+    }
+
+    /** Subdirectories of this directory. */
+    pub fn directories(&self) -> &Vec<Directory> {
+        &self.loaded_contents().directories
+    }
+
+    /** Number of direct children of this directory, files and
+     * subdirectories alike. */
+    pub fn child_count(&self) -> usize {
+        let contents = self.loaded_contents();
+        contents.files.len() + contents.directories.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.child_count() == 0
+    }
+
+    pub fn has_files(&self) -> bool {
+        !self.files().is_empty()
+    }
+
+    pub fn has_subdirectories(&self) -> bool {
+        !self.directories().is_empty()
+    }
+
+    /** Names of the files of this directory. As with `File::name()`, names
+     * which are not valid UTF-8 are decoded lossily. */
+    pub fn file_names(&self) -> impl Iterator<Item = &str> {
+        self.files().iter().map(|f| f.name())
+    }
+
+    /** Names of the subdirectories of this directory. */
+    pub fn dir_names(&self) -> impl Iterator<Item = &str> {
+        self.directories().iter().map(|d| d.name().unwrap_or(""))
+    }
+
+    /** Iterate over the files below this directory, along with their depth:
+     * 0 for the files of this directory, 1 for the files of its
+     * subdirectories, and so on. Files are visited in the same order as with
+     * `Archive::files()`, without building their paths. For a lazily opened
+     * archive, the iteration ends with an error if a directory cannot be
+     * loaded. */
+    pub fn iter_files_depth_first(&self) -> impl Iterator<Item = Result<(&File, usize)>> {
+        FilesWithDepth {
+            stack: DirStack::new(self, 0, true),
+        }
+    }
+
+    /** Files and subdirectories of this directory, in the order in which
+     * they are stored in the archive. */
+    pub fn entries(&self) -> ::std::vec::IntoIter<DirChild<'_>> {
+        let contents = self.loaded_contents();
+        let mut children: Vec<DirChild> = contents
+            .files
+            .iter()
+            .map(DirChild::File)
+            .chain(contents.directories.iter().map(DirChild::Directory))
+            .collect();
+        children.sort_by_key(|c| c.name_offset());
+        children.into_iter()
+    }
+
+    /** Whether the contents of this directory have been parsed yet. Always
+     * true for an archive which was not opened lazily. */
+    pub fn is_loaded(&self) -> bool {
+        self.contents.get().is_some()
+    }
+
+    /** Parse the contents of this directory if not done already. Only the
+     * direct children are loaded: subdirectories are still loaded lazily. */
+    pub fn load_children(&self) -> Result<()> {
+        if self.is_loaded() {
+            return Ok(());
+        }
+        let lazy = match self.lazy {
+            Some(ref l) => l,
+            None => bail!("Directory with index {} has no contents", self.index),
         };
-        let (end_off, unpacked_size) = if idx == last_block {
-            (self.plain.size(), partial_block_size)
-        } else {
-            let mut buf = [0u8; 4];
-            self.plain.read_exact(&mut buf)?;
-            (LittleEndian::read_u32(&buf[..]) as u64, self.blocksize)
+        let contents = {
+            let mut file = lock_archive_file(&lazy.file)?;
+            let mut stack = lazy.stack.clone();
+            file.read_directory_contents(
+                self.index,
+                &self.file_entry,
+                &lazy.path,
+                &mut stack,
+                Some(&lazy.file),
+            )?
         };
-        let size = end_off - start_off;
-        if size > self.blocksize {
-            use std::io::ErrorKind;
-            let err = io::Error::new(
-                ErrorKind::InvalidData,
-                format!(
-                    "Block at index {} is larger than block size ({} > {})",
-                    idx, size, self.blocksize
-                ),
-            );
-            return Err(err);
-        }
-        Ok((start_off, size, unpacked_size))
+        // Another thread may have loaded the directory in the meantime, in
+        // which case both results are identical and ours can be dropped.
+        let _ = self.contents.set(contents);
+        Ok(())
     }
 
-    /** Read and decompress a block. */
-    fn read_block(&mut self, idx: u32) -> io::Result<Vec<u8>> {
-        let (pack_start, pack_size, unpack_size) = self.read_block_offset_and_size(idx)?;
-        let mut plain_block = vec![0u8; pack_size as usize];
-        self.plain.seek(SeekFrom::Start(pack_start))?;
-        self.plain.read_exact(&mut plain_block)?;
-        if pack_size == unpack_size {
-            return Ok(plain_block);
+    fn contents(&self) -> Result<&DirContents> {
+        self.load_children()?;
+        Ok(self.contents.get().unwrap())
+    }
+
+    /* Contents for the infallible accessors, empty if they cannot be
+     * loaded */
+    fn loaded_contents(&self) -> &DirContents {
+        static NO_CONTENTS: DirContents = DirContents {
+            files: Vec::new(),
+            directories: Vec::new(),
         };
-        /* Pack size is lower than block size => pack is compressed */
-        use self::libflate::zlib::Decoder;
-        let mut decoder = Decoder::new(&plain_block[..])?;
-        let mut inflated_block = vec![0u8; unpack_size as usize];
-        decoder.read_exact(&mut inflated_block)?;
-        Ok(inflated_block)
+        self.contents().unwrap_or(&NO_CONTENTS)
     }
 
-    /** Get a block from the cache. If none exist, read the requested block and
-     * add it into the cache. */
-    fn get_block(&mut self, idx: u32) -> io::Result<&Vec<u8>> {
-        if self.cache.contains_key(&idx) {
-            return Ok(self.cache.get(&idx).unwrap());
+    /** Index of the file table entry of this directory, starting at 1. The
+     * root directory has index 1. */
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /** Offset of the entry of this directory in the name table of its
+     * parent. The root directory has no such entry. */
+    pub fn name_offset(&self) -> Option<u64> {
+        self.name_entry.as_ref().map(|n| n.offset)
+    }
+
+    /** Offset of the name table of this directory in the archive. */
+    pub fn offset(&self) -> u32 {
+        self.file_entry.offset
+    }
+
+    /** Size of the name table of this directory. */
+    pub fn size(&self) -> u32 {
+        self.file_entry.size
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        return match self.name_entry {
+            None => None,
+            Some(ref ne) => Some(ne.name_in(&self.names)),
+        };
+    }
+}
+
+impl ArchiveSource {
+    fn len(&self) -> io::Result<u64> {
+        match *self {
+            #[cfg(all(feature = "fs", any(unix, windows)))]
+            ArchiveSource::File { ref file, .. } => Ok(file.metadata()?.len()),
+            ArchiveSource::Memory(ref c) => Ok(c.get_ref().as_ref().len() as u64),
+            ArchiveSource::Shared { len, .. } => Ok(len),
         }
+    }
 
-        let block = self.read_block(idx)?;
-        while self.cache.len() >= ZLIB_MAX_CACHE_ENTRIES {
-            self.evict_another_entry(idx);
+    /** Create a handle over the same contents, with its own position,
+     * starting at the position of this handle. */
+    fn try_clone(&self) -> io::Result<ArchiveSource> {
+        match *self {
+            #[cfg(all(feature = "fs", any(unix, windows)))]
+            ArchiveSource::File { ref file, pos } => Ok(ArchiveSource::File {
+                file: file.clone(),
+                pos: pos,
+            }),
+            ArchiveSource::Memory(ref c) => {
+                let mut cursor = io::Cursor::new(c.get_ref().clone());
+                cursor.set_position(c.position());
+                Ok(ArchiveSource::Memory(cursor))
+            }
+            ArchiveSource::Shared {
+                ref reader,
+                base,
+                len,
+                pos,
+            } => Ok(ArchiveSource::Shared {
+                reader: reader.clone(),
+                base: base,
+                len: len,
+                pos: pos,
+            }),
         }
-        self.cache.insert(idx, block);
-        Ok(self.cache.get(&idx).unwrap())
     }
 }
 
-impl Read for FileDataZlib {
+impl ArchiveSource {
+    /* Contents of the archive, when it is in memory. */
+    fn in_memory(&self) -> Option<&[u8]> {
+        match *self {
+            ArchiveSource::Memory(ref c) => Some(c.get_ref().as_ref()),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    fn is_mapped(&self) -> bool {
+        match *self {
+            ArchiveSource::Memory(ref c) => matches!(*c.get_ref(), SharedBytes::Mapped(_)),
+            _ => false,
+        }
+    }
+}
+
+impl Read for ArchiveSource {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut out_pos = 0u64;
-        let mut size_left = buf.len() as u64;
-        if size_left > (self.size - self.cur_offset) {
-            self.size - self.cur_offset;
-        };
-        while size_left > 0 && self.cur_offset < self.size {
-            let idx = (self.cur_offset / self.blocksize) as u32;
-            let block_offset = self.cur_offset % self.blocksize;
-            let to_copy;
-            {
-                let blockdata = self.get_block(idx)?;
-                to_copy = if size_left < (blockdata.len() as u64 - block_offset) {
-                    size_left
-                } else {
-                    blockdata.len() as u64 - block_offset
+        match *self {
+            #[cfg(all(feature = "fs", any(unix, windows)))]
+            ArchiveSource::File {
+                ref file,
+                ref mut pos,
+            } => {
+                let len = read_at(file, buf, *pos)?;
+                *pos += len as u64;
+                Ok(len)
+            }
+            ArchiveSource::Memory(ref mut c) => c.read(buf),
+            ArchiveSource::Shared {
+                ref reader,
+                base,
+                ref mut pos,
+                ..
+            } => {
+                let mut reader = reader
+                    .lock()
+                    .map_err(|_| io::Error::other("Reader lock is poisoned"))?;
+                reader.seek(SeekFrom::Start(base + *pos))?;
+                let len = reader.read(buf)?;
+                *pos += len as u64;
+                Ok(len)
+            }
+        }
+    }
+}
+
+impl Seek for ArchiveSource {
+    fn seek(&mut self, style: SeekFrom) -> io::Result<u64> {
+        match *self {
+            #[cfg(all(feature = "fs", any(unix, windows)))]
+            ArchiveSource::File {
+                ref file,
+                ref mut pos,
+            } => {
+                let len = match style {
+                    SeekFrom::End(_) => file.metadata()?.len(),
+                    _ => 0,
                 };
-                &mut buf[out_pos as usize..(out_pos + to_copy) as usize].copy_from_slice(
-                    &blockdata[block_offset as usize..(block_offset + to_copy) as usize],
-                );
+                seek_position(pos, len, style)
             }
-            out_pos += to_copy;
-            size_left -= to_copy;
-            self.cur_offset += to_copy;
+            ArchiveSource::Memory(ref mut c) => c.seek(style),
+            ArchiveSource::Shared {
+                len, ref mut pos, ..
+            } => seek_position(pos, len, style),
         }
-        Ok(out_pos as usize)
     }
 }
 
-impl Seek for FileDataZlib {
+/* Move pos, the position of a handle over len bytes, as requested by style. */
+fn seek_position(pos: &mut u64, len: u64, style: SeekFrom) -> io::Result<u64> {
+    let new_pos = match style {
+        SeekFrom::Start(o) => Some(o),
+        SeekFrom::End(o) => len.checked_add_signed(o),
+        SeekFrom::Current(o) => pos.checked_add_signed(o),
+    };
+    match new_pos {
+        Some(p) => {
+            *pos = p;
+            Ok(p)
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Seek resulted in negative offset",
+        )),
+    }
+}
+
+/* Read from file at offset, without using or moving the position of the
+ * file, which is shared with its duplicates. */
+#[cfg(all(feature = "fs", unix))]
+fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(all(feature = "fs", windows))]
+fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+impl MetricsHandle {
+    #[cfg(feature = "metrics")]
+    fn add(&self, counter: Counter, n: u64) {
+        self.counters[counter as usize].fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    #[inline(always)]
+    fn add(&self, _counter: Counter, _n: u64) {}
+
+    #[cfg(feature = "metrics")]
+    fn get(&self) -> Metrics {
+        let get = |counter: Counter| self.counters[counter as usize].load(Ordering::Relaxed);
+        Metrics {
+            bytes_read: get(Counter::BytesRead),
+            bytes_decompressed: get(Counter::BytesDecompressed),
+            blocks_decompressed: get(Counter::BlocksDecompressed),
+            cache_hits: get(Counter::CacheHits),
+            cache_misses: get(Counter::CacheMisses),
+            seeks: get(Counter::Seeks),
+            file_data_created: get(Counter::FileDataCreated),
+        }
+    }
+}
+
+impl FileDataPlain {
+    fn from(
+        file: ArchiveSource,
+        fentry: &FileTableEntry,
+        metrics: &MetricsHandle,
+    ) -> Result<FileDataPlain> {
+        Ok(FileDataPlain {
+            file: file,
+            size: fentry.size as u64,
+            base_offset: fentry.offset as u64,
+            cur_offset: 0,
+            metrics: metrics.clone(),
+        })
+    }
+
+    fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    /** Offset of the first byte of the file in the archive. */
+    pub fn base_offset(&self) -> u64 {
+        self.base_offset
+    }
+
+    /** Offset following the last byte of the file in the archive. */
+    pub fn end_offset(&self) -> u64 {
+        self.base_offset + self.size
+    }
+
+    /** Create a reader over the same data, with its own position. */
+    fn try_clone(&self) -> io::Result<FileDataPlain> {
+        Ok(FileDataPlain {
+            file: self.file.try_clone()?,
+            size: self.size,
+            base_offset: self.base_offset,
+            cur_offset: self.cur_offset,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+impl Read for FileDataPlain {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut readable: usize = self.size as usize - self.cur_offset as usize;
+        if readable > buf.len() {
+            readable = buf.len();
+        };
+        // The source handed over by the archive may be anywhere, so always
+        // move it to our position before reading.
+        self.file
+            .seek(SeekFrom::Start(self.base_offset + self.cur_offset))?;
+        let readlen = self.file.read(&mut buf[..readable])?;
+        self.cur_offset += readlen as u64;
+        self.metrics.add(Counter::BytesRead, readlen as u64);
+        Ok(readlen)
+    }
+}
+
+/* Error of a seek whose target offset does not fit in an i64 */
+fn seek_overflow() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Seek offset overflows")
+}
+
+impl Seek for FileDataPlain {
     fn seek(&mut self, style: SeekFrom) -> io::Result<u64> {
         use std::io::{Error, ErrorKind};
+        self.metrics.add(Counter::Seeks, 1);
         match style {
             SeekFrom::Start(o) => {
                 if o > self.size {
@@ -383,12 +1391,16 @@ impl Seek for FileDataZlib {
                         "Attempted to seek beyond EOF",
                     ))
                 } else {
-                    self.cur_offset = o;
+                    let new_off = self.file.seek(SeekFrom::Start(self.base_offset + o))?;
+                    self.cur_offset = new_off - self.base_offset;
                     Ok(self.cur_offset)
                 }
             }
             SeekFrom::End(o) => {
-                let wanted_off = (self.size as i64) + o;
+                let wanted_off = match (self.size as i64).checked_add(o) {
+                    Some(off) => off,
+                    None => return Err(seek_overflow()),
+                };
                 if o > 0 {
                     Err(Error::new(
                         ErrorKind::InvalidData,
@@ -400,13 +1412,18 @@ impl Seek for FileDataZlib {
                         "Seek resulted in negative offset",
                     ))
                 } else {
-                    self.cur_offset = wanted_off as u64;
+                    let new_off = self
+                        .file
+                        .seek(SeekFrom::Start(self.base_offset + wanted_off as u64))?;
+                    self.cur_offset = new_off - self.base_offset;
                     Ok(self.cur_offset)
                 }
             }
             SeekFrom::Current(o) => {
-                let cur = self.cur_offset as i64;
-                let wanted_off = cur + o;
+                let wanted_off = match (self.cur_offset as i64).checked_add(o) {
+                    Some(off) => off,
+                    None => return Err(seek_overflow()),
+                };
                 if wanted_off < 0 {
                     Err(Error::new(
                         ErrorKind::InvalidData,
@@ -418,7 +1435,10 @@ impl Seek for FileDataZlib {
                         "Attempted to seek beyond EOF",
                     ))
                 } else {
-                    self.cur_offset = wanted_off as u64;
+                    let new_off = self
+                        .file
+                        .seek(SeekFrom::Start(self.base_offset + wanted_off as u64))?;
+                    self.cur_offset = new_off - self.base_offset;
                     Ok(self.cur_offset)
                 }
             }
@@ -426,236 +1446,4071 @@ impl Seek for FileDataZlib {
     }
 }
 
-impl FileData {
-    fn new(mut file: fs::File, fentry: &FileTableEntry) -> Result<FileData> {
-        file.seek(SeekFrom::Start(fentry.offset as u64))?;
-        let is_zlib = {
-            let mut magic = [0u8; 4];
-            file.read_exact(&mut magic)?;
-            file.seek(SeekFrom::Start(fentry.offset as u64))?;
-            let mut magic_iter = magic.into_iter();
-            "ZLIB".bytes().all(|i1| match magic_iter.next() {
-                Some(i2) => &i1 == i2,
-                None => false,
-            })
-        };
-        if is_zlib {
-            Ok(FileData {
-                fdata: FileDataEncoding::Zlib(FileDataZlib::from(file, fentry)?),
-            })
-        } else {
-            Ok(FileData {
-                fdata: FileDataEncoding::Plain(FileDataPlain::from(file, fentry)?),
-            })
+impl SharedBlockCache {
+    fn new(max_bytes: usize) -> SharedBlockCache {
+        SharedBlockCache {
+            max_bytes: max_bytes,
+            state: Mutex::new(SharedCacheState {
+                blocks: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                stats: CacheStats::default(),
+            }),
         }
     }
 
-    pub fn size(&self) -> u64 {
-        match &self.fdata {
-            &FileDataEncoding::Plain(ref plain) => plain.size(),
-            &FileDataEncoding::Zlib(ref zlib) => zlib.size(),
+    fn lock(&self) -> io::Result<MutexGuard<'_, SharedCacheState>> {
+        match self.state.lock() {
+            Ok(guard) => Ok(guard),
+            Err(_) => Err(io::Error::other("Block cache lock is poisoned")),
         }
     }
-}
 
-impl Read for FileData {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match &mut self.fdata {
-            &mut FileDataEncoding::Plain(ref mut plain) => plain.read(buf),
-            &mut FileDataEncoding::Zlib(ref mut zlib) => zlib.read(buf),
+    /* Look up a block, marking it as the most recently used one. */
+    fn get(&self, key: SharedBlockKey) -> io::Result<Option<Arc<Vec<u8>>>> {
+        let mut guard = self.lock()?;
+        let state = &mut *guard;
+        state.tick += 1;
+        match state.blocks.get_mut(&key) {
+            Some(&mut (ref block, ref mut last_use)) => {
+                state.lru.remove(last_use);
+                *last_use = state.tick;
+                state.lru.insert(state.tick, key);
+                state.stats.hits += 1;
+                Ok(Some(block.clone()))
+            }
+            None => {
+                state.stats.misses += 1;
+                Ok(None)
+            }
         }
     }
-}
 
-impl Seek for FileData {
-    fn seek(&mut self, style: SeekFrom) -> io::Result<u64> {
-        match &mut self.fdata {
-            &mut FileDataEncoding::Plain(ref mut plain) => plain.seek(style),
-            &mut FileDataEncoding::Zlib(ref mut zlib) => zlib.seek(style),
+    /* Add a block, evicting the least recently used ones to stay within the
+     * budget. Blocks larger than the whole budget are not kept. */
+    fn insert(&self, key: SharedBlockKey, block: Arc<Vec<u8>>) -> io::Result<()> {
+        let mut guard = self.lock()?;
+        let state = &mut *guard;
+        if block.len() > self.max_bytes || state.blocks.contains_key(&key) {
+            return Ok(());
+        }
+        while state.stats.bytes + block.len() > self.max_bytes {
+            let (&tick, &evicted) = state.lru.iter().next().unwrap();
+            state.lru.remove(&tick);
+            let (old, _) = state.blocks.remove(&evicted).unwrap();
+            state.stats.bytes -= old.len();
         }
+        state.tick += 1;
+        state.stats.bytes += block.len();
+        state.lru.insert(state.tick, key);
+        state.blocks.insert(key, (block, state.tick));
+        Ok(())
+    }
+
+    fn stats(&self) -> io::Result<CacheStats> {
+        let state = self.lock()?;
+        Ok(CacheStats {
+            blocks: state.blocks.len(),
+            ..state.stats
+        })
     }
 }
 
-impl ArchiveFile {
-    fn read_header<T: Read + Seek>(reader: &mut T) -> Result<u32> {
-        let header_size;
-        let magic;
-        let filetbl_offset;
-        reader.seek(SeekFrom::Start(0))?;
-        {
-            let mut buf = [0u8; 0x20];
-            reader.read_exact(&mut buf)?;
-            magic = LittleEndian::read_u32(&buf[0..4]);
-            header_size = LittleEndian::read_u32(&buf[4..8]);
-            filetbl_offset = LittleEndian::read_u32(&buf[0x1c..0x20]);
-        }
-        if magic != 0x4c555042 {
+impl FileDataZlib {
+    fn parse_header(header: &[u8], max_blocksize: u64) -> Result<(u64, u64)> {
+        if !starts_with_magic(header, ZLIB_MAGIC) {
             bail!("Invalid magic");
         }
-        if header_size < 0x20 {
-            bail!("Header size too short");
+        if header.len() < ZLIB_BLOCKTBL_OFFSET as usize {
+            bail!("Header too short");
         }
-        if header_size > 0x24 {
-            bail!("Unsupported format variant: 0x{:x}", header_size);
+        let size = LittleEndian::read_u32(&header[4..8]) as u64;
+        let blocksize = LittleEndian::read_u32(&header[8..0xc]) as u64;
+        if blocksize == 0 {
+            bail!("Block size is 0");
         }
-        if filetbl_offset < header_size {
-            bail!("File table and file header are overlapping");
+        if blocksize > max_blocksize {
+            bail!(
+                "Block size is exceeding the maximum allowed: {} > {}",
+                blocksize,
+                max_blocksize
+            );
         }
-        Ok(filetbl_offset)
+        Ok((size, blocksize))
     }
 
-    fn read_file_entry(&mut self, mut index: u32) -> Result<FileTableEntry> {
-        let offset;
-        let size;
-        if index == 0 {
-            bail!("Index cannot be 0");
+    fn from(
+        file: ArchiveSource,
+        fentry: &FileTableEntry,
+        options: &ArchiveOptions,
+        shared_cache: Option<&Arc<SharedBlockCache>>,
+        metrics: &MetricsHandle,
+    ) -> Result<FileDataZlib> {
+        let mut plain = FileDataPlain::from(file, fentry, metrics)?;
+        let expanded_size: u64;
+        let blocksize: u64;
+        let (expanded_size, blocksize) = {
+            let mut header = [0u8; 0xc];
+            plain.read_exact(&mut header)?;
+            Self::parse_header(&header, options.max_block_size)?
+        };
+        // An empty file has a header but no blocks, and is valid: this is
+        // how ArchiveBuilder compresses empty files. Reads then return
+        // nothing and block accessors report that there is no such block.
+        let num_blocks = if expanded_size == 0 {
+            0
+        } else {
+            expanded_size.div_ceil(blocksize)
+        };
+        if ZLIB_BLOCKTBL_OFFSET + num_blocks * 4 > plain.size() {
+            bail!(
+                "Block table of {} entries does not fit in the file ({} bytes)",
+                num_blocks,
+                plain.size()
+            );
         }
-        // Index is 1 based
-        index = index - 1;
-        let entry_offset = self.filetbl_offset + (index as u64 * FILE_ENTRY_SIZE as u64);
-        self.reader.seek(SeekFrom::Start(entry_offset))?;
-        {
-            let mut buf = [0; FILE_ENTRY_SIZE];
-            self.reader.read_exact(&mut buf)?;
-            offset = LittleEndian::read_u32(&buf[0..4]);
-            size = LittleEndian::read_u32(&buf[4..8]);
+        let block_offsets: Vec<u64> = {
+            let mut table = vec![0u8; num_blocks as usize * 4];
+            plain.read_exact(&mut table)?;
+            table
+                .chunks(4)
+                .map(|e| LittleEndian::read_u32(e) as u64)
+                .collect()
+        };
+        for (idx, pair) in block_offsets.windows(2).enumerate() {
+            if pair[1] < pair[0] {
+                bail!(
+                    "Block table is not sorted: block {} at offset 0x{:x} follows \
+                       block {} at offset 0x{:x}",
+                    idx + 1,
+                    pair[1],
+                    idx,
+                    pair[0]
+                );
+            }
         }
-        Ok(FileTableEntry {
-            offset: offset,
-            size: size,
+
+        Ok(FileDataZlib {
+            plain: plain,
+            size: expanded_size,
+            blocksize: blocksize,
+            block_offsets: Arc::new(block_offsets),
+            cur_offset: 0u64,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            max_cache_entries: cmp::max(options.block_cache_entries, 1),
+            shared_cache: shared_cache.cloned(),
         })
     }
 
-    fn read_name_entry(&mut self, offset: u64) -> Result<NameTableEntry> {
-        let index;
-        let entry_type;
-        let name;
-        let name_len: u16;
-        self.reader.seek(SeekFrom::Start(offset))?;
-        {
-            let mut buf = [0; NAME_ENTRY_MIN_SIZE];
-            self.reader.read_exact(&mut buf)?;
-            index = LittleEndian::read_u32(&buf[0..4]);
-            if index == 0 {
-                bail!("Invalid entry index: 0");
-            }
-            entry_type = match LittleEndian::read_u32(&buf[4..8]) {
-                0 => EntryType::File,
-                1 => EntryType::Directory,
-                v @ _ => bail!("Unknown entry type: 0x{:x}", v),
-            };
-            name_len = LittleEndian::read_u16(&buf[8..10]);
+    fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    /** Evict one entry from the cache, provided that it is not idx.
+     * Panics if idx is the only entry in the cache or if no entry can be
+     * evicted. */
+    fn evict_another_entry(cache: &mut BlockCache, idx: u32) {
+        if cache.len() == 0 {
+            panic!("Cannot evict an entry from an empty cache!");
         }
-        {
-            let mut v = vec![0u8; name_len as usize];
-            self.reader.read_exact(&mut v)?;
-            name = String::from_utf8_lossy(&v).into_owned();
+        if cache.len() == 1 && cache.contains_key(&idx) {
+            panic!("Cannot evict the only entry we try to keep in the cache!");
         }
-        Ok(NameTableEntry {
-            file_index: index,
-            entry_type: entry_type,
-            entry_size: NAME_ENTRY_MIN_SIZE as u32 + name_len as u32,
-            name: name,
+        let min = *cache.keys().min().unwrap();
+        if min == idx {
+            let max = *cache.keys().max().unwrap();
+            cache.remove(&max);
+        } else {
+            cache.remove(&min);
+        }
+    }
+
+    fn lock_cache(&self) -> io::Result<MutexGuard<'_, BlockCache>> {
+        match self.cache.lock() {
+            Ok(guard) => Ok(guard),
+            Err(_) => Err(io::Error::other("Block cache lock is poisoned")),
+        }
+    }
+
+    /** Create a reader over the same data, with its own position but sharing
+     * the cache of decompressed blocks. */
+    fn try_clone(&self) -> io::Result<FileDataZlib> {
+        Ok(FileDataZlib {
+            plain: self.plain.try_clone()?,
+            size: self.size,
+            cur_offset: self.cur_offset,
+            blocksize: self.blocksize,
+            block_offsets: self.block_offsets.clone(),
+            cache: self.cache.clone(),
+            max_cache_entries: self.max_cache_entries,
+            shared_cache: self.shared_cache.clone(),
         })
     }
 
-    // FIXME: We might want to avoid recursive calls even if their number is limited
-    fn read_directory_loop(&mut self, index: u32, stack: &mut Vec<u32>) -> Result<Directory> {
-        let dentry = self.read_file_entry(index)?;
-        let max_offset = dentry.offset as u64 + dentry.size as u64;
-        let mut cur_offset = dentry.offset as u64;
-        let mut dirs: Vec<Directory> = Vec::new();
-        let mut files: Vec<File> = Vec::new();
+    /** Number of blocks, the last one being possibly partial. */
+    fn num_blocks(&self) -> u32 {
+        self.size.div_ceil(self.blocksize) as u32
+    }
 
-        if stack.len() > 128 {
-            bail!("Directory hierarchy is too deep (> 128 levels)");
-        }
-        if stack.contains(&index) {
-            bail!("Directory loop detected for index 0x{:x}", index);
+    /* Offset, packed size and unpacked size of a block. */
+    fn block_offset_and_size(&self, idx: u32) -> io::Result<(u64, u64, u64)> {
+        use std::io::ErrorKind;
+        let num_blocks = self.num_blocks();
+        if idx >= num_blocks {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Block index {} is out of range ({} blocks)",
+                    idx, num_blocks
+                ),
+            ));
         }
-        stack.push(index);
 
-        while cur_offset < max_offset {
-            let nentry = self.read_name_entry(cur_offset)?;
-            let nentry_size = nentry.entry_size as u64;
-            if cur_offset + nentry_size > max_offset {
-                bail!(
-                    "Name entry at offset 0x{:x} spans outside of directory \
-                       with index {}",
-                    cur_offset,
-                    index
-                );
+        let last_block = num_blocks - 1;
+        let start_off = self.block_offsets[idx as usize];
+        let (end_off, unpacked_size) = if idx == last_block {
+            // The last block is a full one when the size is a multiple of
+            // the block size.
+            (
+                self.plain.size(),
+                self.size - last_block as u64 * self.blocksize,
+            )
+        } else {
+            (self.block_offsets[idx as usize + 1], self.blocksize)
+        };
+        let size = match end_off.checked_sub(start_off) {
+            Some(s) => s,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Block at index {} ends before it starts", idx),
+                ))
             }
-            let fentry = self.read_file_entry(nentry.file_index)?;
-            match nentry.entry_type {
-                EntryType::File => {
-                    files.push(File {
-                        name_entry: nentry,
-                        file_entry: fentry,
-                    });
-                }
-                EntryType::Directory => {
-                    let undir = self.read_directory_loop(nentry.file_index, stack)?;
-                    let dir = Directory {
-                        files: undir.files,
-                        directories: undir.directories,
-                        file_entry: undir.file_entry,
-                        name_entry: Some(nentry),
-                    };
-                    dirs.push(dir);
-                }
-            };
-            cur_offset += nentry_size;
+        };
+        if size > self.blocksize {
+            let err = io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Block at index {} is larger than block size ({} > {})",
+                    idx, size, self.blocksize
+                ),
+            );
+            return Err(err);
         }
+        Ok((start_off, size, unpacked_size))
+    }
 
-        stack.pop();
-
-        Ok(Directory {
-            file_entry: dentry,
-            name_entry: None,
-            files: files,
-            directories: dirs,
+    /** How a block is stored, found from its size alone. */
+    fn block_encoding(&self, idx: u32) -> io::Result<BlockEncoding> {
+        let (_, pack_size, unpack_size) = self.block_offset_and_size(idx)?;
+        Ok(if pack_size == unpack_size {
+            BlockEncoding::Uncompressed
+        } else {
+            BlockEncoding::Compressed
         })
     }
 
-    fn read_directory(&mut self, index: u32) -> Result<Directory> {
-        let mut stack: Vec<u32> = Vec::new();
-        return self.read_directory_loop(index, &mut stack);
+    /** Read a block as it is stored in the archive. */
+    fn read_raw_block(&mut self, idx: u32) -> io::Result<RawBlock> {
+        let (pack_start, pack_size, unpack_size) = self.block_offset_and_size(idx)?;
+        let mut data = vec![0u8; pack_size as usize];
+        self.plain.seek(SeekFrom::Start(pack_start))?;
+        self.plain.read_exact(&mut data)?;
+        Ok(RawBlock {
+            data: data,
+            offset: pack_start,
+            compressed: pack_size != unpack_size,
+            unpacked_size: unpack_size,
+        })
     }
 
-    fn read_rootdir(&mut self) -> Result<Directory> {
-        self.read_directory(1)
+    /** Read and decompress a block. */
+    fn read_block(&mut self, idx: u32) -> io::Result<Vec<u8>> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!(
+            "read_block",
+            index = idx,
+            offset = tracing::field::Empty,
+            size = tracing::field::Empty,
+            unpacked_size = tracing::field::Empty
+        )
+        .entered();
+        let raw = self.read_raw_block(idx)?;
+        let unpack_size = raw.unpacked_size;
+        #[cfg(feature = "tracing")]
+        {
+            span.record("offset", self.plain.base_offset() + raw.offset);
+            span.record("size", raw.data.len());
+            span.record("unpacked_size", unpack_size);
+        }
+        if raw.data.is_empty() && unpack_size > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Block at index {} is empty, but should unpack to {} bytes",
+                    idx, unpack_size
+                ),
+            ));
+        }
+        if !raw.compressed {
+            return Ok(raw.data);
+        };
+        let plain_block = raw.data;
+        /* Pack size is lower than block size => pack is compressed */
+        use self::libflate::zlib::Decoder;
+        let mut decoder = Decoder::new(&plain_block[..])?;
+        let mut inflated_block = vec![0u8; unpack_size as usize];
+        decoder.read_exact(&mut inflated_block)?;
+        let metrics = &self.plain.metrics;
+        metrics.add(Counter::BlocksDecompressed, 1);
+        metrics.add(Counter::BytesDecompressed, unpack_size);
+        Ok(inflated_block)
     }
 
-    fn open(filename: &str) -> Result<ArchiveFile> {
-        let file = fs::File::open(filename)?;
-        let basefile = file.try_clone()?;
-        let mut filereader = BufReader::new(file);
-        let filetbl_offset = ArchiveFile::read_header(&mut filereader)?;
-        Ok(ArchiveFile {
-            basefile: basefile,
-            reader: filereader,
-            filetbl_offset: filetbl_offset as u64,
-        })
+    /** Get a block from the cache. If none exist, read the requested block and
+     * add it into the cache. */
+    fn get_block(&mut self, idx: u32) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(shared) = self.shared_cache.clone() {
+            let key = (self.plain.base_offset(), idx);
+            if let Some(block) = shared.get(key)? {
+                self.plain.metrics.add(Counter::CacheHits, 1);
+                return Ok(block);
+            }
+            self.plain.metrics.add(Counter::CacheMisses, 1);
+            let block = Arc::new(self.read_block(idx)?);
+            shared.insert(key, block.clone())?;
+            return Ok(block);
+        }
+        if let Some(block) = self.lock_cache()?.get(&idx) {
+            self.plain.metrics.add(Counter::CacheHits, 1);
+            return Ok(block.clone());
+        }
+        self.plain.metrics.add(Counter::CacheMisses, 1);
+
+        let block = Arc::new(self.read_block(idx)?);
+        let mut cache = self.lock_cache()?;
+        // A clone of this reader may have inserted the block in the meantime
+        if let Some(cached) = cache.get(&idx) {
+            return Ok(cached.clone());
+        }
+        while cache.len() >= self.max_cache_entries {
+            Self::evict_another_entry(&mut cache, idx);
+        }
+        cache.insert(idx, block.clone());
+        Ok(block)
     }
-}
 
-impl Archive {
-    pub fn open(filename: &str) -> Result<Archive> {
-        let mut file = ArchiveFile::open(filename)?;
-        let rootdir = file.read_rootdir()?;
-        Ok(Archive {
-            file: file,
-            rootdir: rootdir,
-        })
+    /** Decompress all the blocks at once, bypassing the block cache, and
+     * return a reader over the result. */
+    fn into_plain_reader(mut self) -> io::Result<io::Cursor<Vec<u8>>> {
+        // The size comes from the file header, do not trust it too much
+        let mut data = Vec::with_capacity(cmp::min(self.size, ZLIB_MAX_BLOCKSIZE) as usize);
+        for idx in 0..self.num_blocks() {
+            data.extend_from_slice(&self.read_block(idx)?);
+        }
+        if data.len() as u64 != self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Blocks unpack to {} bytes instead of {}",
+                    data.len(),
+                    self.size
+                ),
+            ));
+        }
+        Ok(io::Cursor::new(data))
     }
+}
 
-    pub fn file_data(&self, file: &File) -> Result<FileData> {
-        let f = self.file.basefile.try_clone()?;
-        FileData::new(f, &file.file_entry)
+impl Read for FileDataZlib {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut out_pos = 0u64;
+        let mut size_left = buf.len() as u64;
+        if size_left > (self.size - self.cur_offset) {
+            size_left = self.size - self.cur_offset;
+        };
+        while size_left > 0 && self.cur_offset < self.size {
+            let idx = (self.cur_offset / self.blocksize) as u32;
+            let block_offset = self.cur_offset % self.blocksize;
+            let to_copy;
+            {
+                let blockdata = self.get_block(idx)?;
+                to_copy = if size_left < (blockdata.len() as u64 - block_offset) {
+                    size_left
+                } else {
+                    blockdata.len() as u64 - block_offset
+                };
+                &mut buf[out_pos as usize..(out_pos + to_copy) as usize].copy_from_slice(
+                    &blockdata[block_offset as usize..(block_offset + to_copy) as usize],
+                );
+            }
+            out_pos += to_copy;
+            size_left -= to_copy;
+            self.cur_offset += to_copy;
+        }
+        Ok(out_pos as usize)
     }
+}
 
-    pub fn root_directory(&self) -> &Directory {
-        &self.rootdir
+impl Seek for FileDataZlib {
+    fn seek(&mut self, style: SeekFrom) -> io::Result<u64> {
+        use std::io::{Error, ErrorKind};
+        self.plain.metrics.add(Counter::Seeks, 1);
+        match style {
+            SeekFrom::Start(o) => {
+                if o > self.size {
+                    Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "Attempted to seek beyond EOF",
+                    ))
+                } else {
+                    self.cur_offset = o;
+                    Ok(self.cur_offset)
+                }
+            }
+            SeekFrom::End(o) => {
+                let wanted_off = match (self.size as i64).checked_add(o) {
+                    Some(off) => off,
+                    None => return Err(seek_overflow()),
+                };
+                if o > 0 {
+                    Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Attempted to seek beyond EOF",
+                    ))
+                } else if wanted_off < 0 {
+                    Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Seek resulted in negative offset",
+                    ))
+                } else {
+                    self.cur_offset = wanted_off as u64;
+                    Ok(self.cur_offset)
+                }
+            }
+            SeekFrom::Current(o) => {
+                let wanted_off = match (self.cur_offset as i64).checked_add(o) {
+                    Some(off) => off,
+                    None => return Err(seek_overflow()),
+                };
+                if wanted_off < 0 {
+                    Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Seek resulted in negative offset",
+                    ))
+                } else if wanted_off > (self.size as i64) {
+                    Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Attempted to seek beyond EOF",
+                    ))
+                } else {
+                    self.cur_offset = wanted_off as u64;
+                    Ok(self.cur_offset)
+                }
+            }
+        }
+    }
+}
+
+impl FileData {
+    fn new(
+        mut file: ArchiveSource,
+        fentry: &FileTableEntry,
+        options: &ArchiveOptions,
+        shared_cache: Option<&Arc<SharedBlockCache>>,
+        metrics: &MetricsHandle,
+    ) -> Result<FileData> {
+        // Data too short to hold the magic is plain, whatever follows it
+        let compressed = fentry.size as usize >= ZLIB_MAGIC.len()
+            && starts_with_magic(&peek_magic(&mut file, fentry.offset as u64)?, ZLIB_MAGIC);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            offset = fentry.offset,
+            size = fentry.size,
+            compressed = compressed,
+            "file data"
+        );
+        let fdata = if compressed {
+            FileDataEncoding::Zlib(FileDataZlib::from(
+                file,
+                fentry,
+                options,
+                shared_cache,
+                metrics,
+            )?)
+        } else {
+            FileDataEncoding::Plain(FileDataPlain::from(file, fentry, metrics)?)
+        };
+        metrics.add(Counter::FileDataCreated, 1);
+        Ok(FileData { fdata: fdata })
+    }
+
+    /** Create a reader over an empty file, not backed by any archive.
+     * `size()` returns 0 and all reads return `Ok(0)`. Mostly useful for
+     * tests. */
+    pub fn empty() -> FileData {
+        FileData {
+            fdata: FileDataEncoding::Plain(FileDataPlain {
+                file: ArchiveSource::Memory(io::Cursor::new(SharedBytes::Owned(Arc::from(
+                    Vec::new(),
+                )))),
+                size: 0,
+                base_offset: 0,
+                cur_offset: 0,
+                metrics: MetricsHandle::default(),
+            }),
+        }
+    }
+
+    /** Create an independent reader over the same file. Seeking or reading
+     * with one reader does not affect the position of the other, and both
+     * can be used at the same time from different threads. A compressed
+     * file shares its cache of decompressed blocks with its clones. */
+    pub fn try_clone(&self) -> Result<FileData> {
+        let fdata = match self.fdata {
+            FileDataEncoding::Plain(ref plain) => {
+                plain.metrics.add(Counter::FileDataCreated, 1);
+                FileDataEncoding::Plain(plain.try_clone()?)
+            }
+            FileDataEncoding::Zlib(ref zlib) => {
+                zlib.plain.metrics.add(Counter::FileDataCreated, 1);
+                FileDataEncoding::Zlib(zlib.try_clone()?)
+            }
+        };
+        Ok(FileData { fdata: fdata })
+    }
+
+    /** Move the position by offset bytes, returning the new position from
+     * the start of the file. Unlike `Seek::seek_relative()`, the new
+     * position is returned. */
+    pub fn seek_relative(&mut self, offset: i64) -> io::Result<u64> {
+        self.seek(SeekFrom::Current(offset))
+    }
+
+    /** Whether the file is stored ZLIB compressed in the archive. */
+    pub fn is_compressed(&self) -> bool {
+        matches!(self.fdata, FileDataEncoding::Zlib(_))
+    }
+
+    /** Size of the decompressed blocks of a compressed file. */
+    pub fn block_size(&self) -> Option<u64> {
+        match self.fdata {
+            FileDataEncoding::Zlib(ref zlib) => Some(zlib.blocksize),
+            _ => None,
+        }
+    }
+
+    /** Number of blocks of a compressed file. */
+    pub fn num_blocks(&self) -> Option<u32> {
+        match self.fdata {
+            FileDataEncoding::Zlib(ref zlib) => Some(zlib.num_blocks()),
+            _ => None,
+        }
+    }
+
+    /** Index of the block of a compressed file holding the byte at offset,
+     * along with the range of the file this block covers once decompressed.
+     * Returns None for an uncompressed file, which has no blocks, and for an
+     * offset beyond the end of the file. */
+    pub fn block_of(&self, offset: u64) -> Option<(u32, Range<u64>)> {
+        match self.fdata {
+            FileDataEncoding::Zlib(ref zlib) if offset < zlib.size => {
+                let idx = offset / zlib.blocksize;
+                let start = idx * zlib.blocksize;
+                let end = cmp::min(start + zlib.blocksize, zlib.size);
+                Some((idx as u32, start..end))
+            }
+            _ => None,
+        }
+    }
+
+    /** Contents of an uncompressed file of an archive which is in memory,
+     * such as one opened with `Archive::open_mmap()`, borrowed without any
+     * copy. Returns None for a compressed file, which has to be inflated, or
+     * for an archive read from a file. */
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        match self.fdata {
+            FileDataEncoding::Plain(ref plain) => {
+                let bytes = plain.file.in_memory()?;
+                bytes.get(plain.base_offset() as usize..plain.end_offset() as usize)
+            }
+            FileDataEncoding::Zlib(_) => None,
+        }
+    }
+
+    /** Range of bytes of the archive holding the contents of an
+     * uncompressed file, as start and end offsets. Those bytes can be
+     * accessed directly instead of through this reader. */
+    pub fn byte_range(&self) -> Option<(u64, u64)> {
+        match self.fdata {
+            FileDataEncoding::Plain(ref plain) => Some((plain.base_offset(), plain.end_offset())),
+            _ => None,
+        }
+    }
+
+    /** How block idx of a compressed file is stored. Returns None for an
+     * uncompressed file, which has no blocks. */
+    pub fn block_encoding(&self, idx: u32) -> Option<io::Result<BlockEncoding>> {
+        match self.fdata {
+            FileDataEncoding::Zlib(ref zlib) => Some(zlib.block_encoding(idx)),
+            _ => None,
+        }
+    }
+
+    /** Read block idx of a compressed file without decompressing it. */
+    pub fn raw_block(&mut self, idx: u32) -> Result<RawBlock> {
+        match self.fdata {
+            FileDataEncoding::Zlib(ref mut zlib) => Ok(zlib.read_raw_block(idx)?),
+            _ => bail!("File is not compressed"),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match &self.fdata {
+            &FileDataEncoding::Plain(ref plain) => plain.size(),
+            &FileDataEncoding::Zlib(ref zlib) => zlib.size(),
+        }
+    }
+
+    /** Hash the decompressed contents of the file with D, such as
+     * `sha2::Sha256`. The whole file is hashed whatever the current
+     * position, which is restored afterwards. Fails if the data ends before
+     * `size()` bytes could be read. */
+    pub fn digest<D: Digest>(&mut self) -> Result<Output<D>> {
+        let pos = self.stream_position()?;
+        let res = (|| -> Result<Output<D>> {
+            self.seek(SeekFrom::Start(0))?;
+            let mut hasher = D::new();
+            let mut buf = vec![0u8; DIGEST_BUFFER_SIZE];
+            let mut hashed = 0u64;
+            loop {
+                let len = match self.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                hasher.update(&buf[..len]);
+                hashed += len as u64;
+            }
+            if hashed != self.size() {
+                bail!("File data ended after {} of {} bytes", hashed, self.size());
+            }
+            Ok(hasher.finalize())
+        })();
+        // Also on failure, so that the reader can still be used
+        self.seek(SeekFrom::Start(pos))?;
+        res
+    }
+
+    /** Read the whole file in memory, decompressing all its blocks at once,
+     * and return a reader over it. Seeking anywhere in the result is free,
+     * unlike with this reader, which only keeps a few decompressed blocks
+     * around and decompresses a block again when seeking back to it. This
+     * suits small files needing random access, at the cost of keeping their
+     * whole contents in memory. For large files read mostly sequentially,
+     * keep this reader, possibly with a bigger block cache, see
+     * `ArchiveOptions::block_cache_entries()` and
+     * `ArchiveOptions::shared_cache_bytes()`. */
+    pub fn into_plain_reader(self) -> Result<io::Cursor<Vec<u8>>> {
+        match self.fdata {
+            FileDataEncoding::Zlib(zlib) => Ok(zlib.into_plain_reader()?),
+            FileDataEncoding::Plain(mut plain) => {
+                let mut data = Vec::new();
+                plain.seek(SeekFrom::Start(0))?;
+                plain.read_to_end(&mut data)?;
+                Ok(io::Cursor::new(data))
+            }
+        }
+    }
+
+    /** SHA-256 of the decompressed contents of the file, see `digest()`. */
+    pub fn sha256(&mut self) -> Result<[u8; 32]> {
+        Ok(self.digest::<sha2::Sha256>()?.into())
+    }
+}
+
+impl Read for FileData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.fdata {
+            &mut FileDataEncoding::Plain(ref mut plain) => plain.read(buf),
+            &mut FileDataEncoding::Zlib(ref mut zlib) => zlib.read(buf),
+        }
+    }
+}
+
+impl FileHandle {
+    /** Path of the file in the archive, '/' separated. */
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /** File table index of the file, see `File::index()`. */
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /** Size of the file once decompressed. */
+    pub fn size(&self) -> u64 {
+        self.data.size()
+    }
+
+    /** Create an independent handle on the same file, see
+     * `FileData::try_clone()`. */
+    pub fn try_clone(&self) -> Result<FileHandle> {
+        Ok(FileHandle {
+            path: self.path.clone(),
+            index: self.index,
+            data: self.data.try_clone()?,
+        })
+    }
+}
+
+impl Read for FileHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Seek for FileHandle {
+    fn seek(&mut self, style: SeekFrom) -> io::Result<u64> {
+        self.data.seek(style)
+    }
+}
+
+impl Seek for FileData {
+    fn seek(&mut self, style: SeekFrom) -> io::Result<u64> {
+        match &mut self.fdata {
+            &mut FileDataEncoding::Plain(ref mut plain) => plain.seek(style),
+            &mut FileDataEncoding::Zlib(ref mut zlib) => zlib.seek(style),
+        }
+    }
+}
+
+impl ArchiveFile {
+    fn read_header<T: Read + Seek>(reader: &mut T) -> Result<HeaderInfo> {
+        let header_size;
+        let magic;
+        let filetbl_offset;
+        reader.seek(SeekFrom::Start(0))?;
+        {
+            let mut buf = [0u8; 0x20];
+            reader.read_exact(&mut buf)?;
+            magic = [buf[0], buf[1], buf[2], buf[3]];
+            header_size = LittleEndian::read_u32(&buf[4..8]);
+            filetbl_offset = LittleEndian::read_u32(&buf[0x1c..0x20]);
+        }
+        if !starts_with_magic(&magic, MAGIC) {
+            bail!("Invalid magic");
+        }
+        if header_size < 0x20 {
+            bail!("Header size too short");
+        }
+        if header_size > 0x24 {
+            bail!("Unsupported format variant: 0x{:x}", header_size);
+        }
+        if filetbl_offset < header_size {
+            bail!("File table and file header are overlapping");
+        }
+        Ok(HeaderInfo {
+            magic: magic,
+            header_size: header_size,
+            filetbl_offset: filetbl_offset,
+        })
+    }
+
+    fn read_file_entry(&mut self, mut index: u32) -> Result<FileTableEntry> {
+        let offset;
+        let size;
+        if index == 0 {
+            bail!("Index cannot be 0");
+        }
+        // Index is 1 based
+        index = index - 1;
+        let entry_offset =
+            self.header.filetbl_offset as u64 + (index as u64 * FILE_ENTRY_SIZE as u64);
+        {
+            let buf = self.read_file_table(index)?;
+            offset = LittleEndian::read_u32(&buf[0..4]);
+            size = LittleEndian::read_u32(&buf[4..8]);
+        }
+        if offset as u64 + size as u64 > self.file_len {
+            bail!(
+                "File table entry {} at offset 0x{:x} spans outside of the archive: \
+                   offset 0x{:x}, size 0x{:x}, archive size 0x{:x}",
+                index + 1,
+                entry_offset,
+                offset,
+                size,
+                self.file_len
+            );
+        }
+        Ok(FileTableEntry {
+            offset: offset,
+            size: size,
+        })
+    }
+
+    /* Number of entries in the file table, which the header does not store.
+     * The table extends to the end of the archive, unless the data of the
+     * root directory (index 1) comes after it, in which case the table ends
+     * there. */
+    fn entry_count(&mut self) -> Result<usize> {
+        if let Some(count) = self.entry_count {
+            return Ok(count);
+        }
+        let root = self
+            .read_file_entry(1)
+            .chain_err(|| "Unable to read the root directory entry")?;
+        let start = self.header.filetbl_offset as u64;
+        let mut end = self.file_len;
+        if root.offset as u64 > start {
+            end = cmp::min(end, root.offset as u64);
+        }
+        let count = ((end - start) / FILE_ENTRY_SIZE as u64) as usize;
+        self.entry_count = Some(count);
+        Ok(count)
+    }
+
+    /* Raw file table entry with the specified 0-based index, read along with
+     * the following ones unless it was read already. */
+    fn read_file_table(&mut self, index: u32) -> io::Result<&[u8]> {
+        let start = self.filetbl_cache_start;
+        let cached = (self.filetbl_cache.len() / FILE_ENTRY_SIZE) as u32;
+        if index < start || index - start >= cached {
+            // Entries past the end of the table are read one at a time, and
+            // fail to be read
+            let count = self
+                .filetbl_len
+                .saturating_sub(index)
+                .clamp(1, FILETBL_READAHEAD);
+            let offset = self.header.filetbl_offset as u64 + index as u64 * FILE_ENTRY_SIZE as u64;
+            self.filetbl_cache.clear();
+            self.filetbl_cache
+                .resize(count as usize * FILE_ENTRY_SIZE, 0);
+            self.filetbl_cache_start = index;
+            self.reader.seek(SeekFrom::Start(offset))?;
+            if let Err(e) = self.reader.read_exact(&mut self.filetbl_cache) {
+                self.filetbl_cache.clear();
+                return Err(e);
+            }
+        }
+        let pos = (index - self.filetbl_cache_start) as usize * FILE_ENTRY_SIZE;
+        Ok(&self.filetbl_cache[pos..pos + FILE_ENTRY_SIZE])
+    }
+
+    /* Size of a file once decompressed, found by only reading the header of
+     * ZLIB compressed files. */
+    fn read_unpacked_size(&mut self, fentry: &FileTableEntry) -> Result<u64> {
+        if (fentry.size as u64) < ZLIB_BLOCKTBL_OFFSET {
+            return Ok(fentry.size as u64);
+        }
+        let mut header = [0u8; ZLIB_BLOCKTBL_OFFSET as usize];
+        self.reader.seek(SeekFrom::Start(fentry.offset as u64))?;
+        self.reader.read_exact(&mut header)?;
+        if !starts_with_magic(&header, ZLIB_MAGIC) {
+            return Ok(fentry.size as u64);
+        }
+        let (size, _) = FileDataZlib::parse_header(&header, self.options.max_block_size)?;
+        Ok(size)
+    }
+
+    /* Split the name table stored in the specified extent into records,
+     * without validating their contents. Parsing stops at the first record
+     * which does not fit in the extent. */
+    fn read_raw_name_entries(
+        &mut self,
+        fentry: &FileTableEntry,
+    ) -> Result<Vec<Result<RawNameEntry>>> {
+        let offset = fentry.offset as u64;
+        let mut buf = vec![0u8; fentry.size as usize];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut buf)?;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let rest = &buf[pos..];
+            let entry_offset = offset + pos as u64;
+            if rest.len() < NAME_ENTRY_MIN_SIZE {
+                entries.push(Err(format!(
+                    "Trailing {} bytes at offset 0x{:x}",
+                    rest.len(),
+                    entry_offset
+                )
+                .into()));
+                break;
+            }
+            let name_len = LittleEndian::read_u16(&rest[8..10]);
+            let entry_size = NAME_ENTRY_MIN_SIZE + name_len as usize;
+            if entry_size > rest.len() {
+                entries.push(Err(format!(
+                    "Name entry at offset 0x{:x} extends beyond its directory",
+                    entry_offset
+                )
+                .into()));
+                break;
+            }
+            entries.push(Ok(RawNameEntry {
+                file_index: LittleEndian::read_u32(&rest[0..4]),
+                entry_type: LittleEndian::read_u32(&rest[4..8]),
+                name_len: name_len,
+                name: rest[NAME_ENTRY_MIN_SIZE..entry_size].to_vec(),
+                offset: entry_offset,
+            }));
+            pos += entry_size;
+        }
+        Ok(entries)
+    }
+
+    /* Whole name table of a directory. */
+    fn read_name_table(&mut self, dentry: &FileTableEntry) -> Result<Vec<u8>> {
+        let mut names = vec![0u8; dentry.size as usize];
+        self.reader.seek(SeekFrom::Start(dentry.offset as u64))?;
+        self.reader.read_exact(&mut names)?;
+        Ok(names)
+    }
+
+    /* Parse the name entry at the start of buf, the remainder of the name
+     * table of the directory with the specified index, found at offset in
+     * the archive. Its name is appended to names. */
+    fn parse_name_entry(
+        &self,
+        buf: &[u8],
+        offset: u64,
+        dir_index: u32,
+        names: &mut String,
+    ) -> Result<NameTableEntry> {
+        if buf.len() < NAME_ENTRY_MIN_SIZE {
+            bail!(
+                "Name entry at offset 0x{:x} spans outside of directory \
+                   with index {}",
+                offset,
+                dir_index
+            );
+        }
+        let index = LittleEndian::read_u32(&buf[0..4]);
+        if index == 0 {
+            bail!("Invalid entry index: 0");
+        }
+        let entry_type = match LittleEndian::read_u32(&buf[4..8]) {
+            0 => EntryType::File,
+            1 => EntryType::Directory,
+            v @ _ => {
+                if self.options.strict_entry_types {
+                    bail!("Unknown entry type: 0x{:x}", v);
+                }
+                EntryType::Unknown(v)
+            }
+        };
+        let name_len = LittleEndian::read_u16(&buf[8..10]);
+        let raw_name = match buf.get(NAME_ENTRY_MIN_SIZE..NAME_ENTRY_MIN_SIZE + name_len as usize) {
+            Some(n) => n,
+            None => bail!(
+                "Name entry at offset 0x{:x} spans outside of directory \
+                   with index {}",
+                offset,
+                dir_index
+            ),
+        };
+        let name_start = names.len() as u32;
+        match ::std::str::from_utf8(raw_name) {
+            Ok(n) => names.push_str(n),
+            Err(_) if self.options.strict_names => bail!(
+                "Entry name at offset 0x{:x} is not valid UTF-8: \"{}\"",
+                offset,
+                raw_name.escape_ascii()
+            ),
+            Err(_) => names.push_str(&String::from_utf8_lossy(raw_name)),
+        };
+        Ok(NameTableEntry {
+            file_index: index,
+            entry_type: entry_type,
+            entry_size: NAME_ENTRY_MIN_SIZE as u32 + name_len as u32,
+            name: name_start..names.len() as u32,
+            offset: offset,
+        })
+    }
+
+    /* Make sure that entering the directory with the specified index, given
+     * the indices of its parent directories, neither exceeds the depth limit
+     * nor creates a loop. */
+    fn check_directory_stack(&self, index: u32, stack: &[u32]) -> Result<()> {
+        if stack.len() > self.options.max_depth {
+            bail!(
+                "Directory hierarchy is too deep (> {} levels)",
+                self.options.max_depth
+            );
+        }
+        if stack.contains(&index) {
+            bail!("Directory loop detected for index 0x{:x}", index);
+        }
+        Ok(())
+    }
+
+    /* Parse the name entry at cur_offset in the name table of a directory,
+     * rest being the remainder of that table, and read the file table entry
+     * it refers to. The name of the entry is appended to names. */
+    fn read_directory_entry(
+        &mut self,
+        ctx: &ParseContext,
+        rest: &[u8],
+        cur_offset: u64,
+        names: &mut String,
+    ) -> Result<(NameTableEntry, FileTableEntry)> {
+        if self.num_entries >= self.options.max_entries {
+            bail!(ErrorKind::TooManyEntries(self.options.max_entries));
+        }
+        self.num_entries += 1;
+        let res = self
+            .parse_name_entry(rest, cur_offset, ctx.index, names)
+            .and_then(|nentry| {
+                let fentry = self.read_file_entry(nentry.file_index)?;
+                Ok((nentry, fentry))
+            });
+        res.chain_err(|| {
+            format!(
+                "Unable to parse the entry at offset 0x{:x} of directory /{} (index {})",
+                cur_offset, ctx.path, ctx.index
+            )
+        })
+    }
+
+    /* Pass the progress to the callback, if any. Unless forced, this is only
+     * done every PROGRESS_INTERVAL entries. */
+    fn report_progress(&mut self, force: bool) -> Result<()> {
+        self.progress.entries = self.num_entries;
+        if !force && !self.num_entries.is_multiple_of(PROGRESS_INTERVAL) {
+            return Ok(());
+        }
+        let progress = self.progress;
+        if let Some(ref mut callback) = self.progress_callback {
+            if callback(progress).is_break() {
+                bail!(ErrorKind::Cancelled);
+            }
+        }
+        Ok(())
+    }
+
+    /* Deal with an error which occurred while parsing a directory: keep it
+     * for later if errors are to be recovered from, or return it. The limit
+     * on the number of entries and cancellations are never bypassed. */
+    fn recover(&mut self, e: Error) -> Result<()> {
+        if !self.options.recover_errors {
+            return Err(e);
+        }
+        match *e.kind() {
+            ErrorKind::TooManyEntries(_) | ErrorKind::Cancelled => return Err(e),
+            _ => {}
+        }
+        let messages: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+        self.recovered_errors.push(messages.join(": "));
+        Ok(())
+    }
+
+    /* Parse the name table of the directory with the specified index and
+     * path. When lazy is set, subdirectories are not
+     * parsed, but are set up to be loaded on demand from that archive file
+     * instead. */
+    // FIXME: We might want to avoid recursive calls even if their number is limited
+    fn read_directory_contents(
+        &mut self,
+        index: u32,
+        dentry: &FileTableEntry,
+        path: &str,
+        stack: &mut Vec<u32>,
+        lazy: Option<&Arc<Mutex<ArchiveFile>>>,
+    ) -> Result<DirContents> {
+        let max_offset = dentry.offset as u64 + dentry.size as u64;
+        let mut cur_offset = dentry.offset as u64;
+        let mut dirs: Vec<Directory> = Vec::new();
+        let mut file_entries: Vec<(NameTableEntry, FileTableEntry)> = Vec::new();
+        let mut names = String::new();
+
+        self.check_directory_stack(index, stack)?;
+        let table = self.read_name_table(dentry).chain_err(|| {
+            format!(
+                "Unable to read the name table of directory /{} (index {})",
+                path, index
+            )
+        })?;
+        stack.push(index);
+        self.progress.directories += 1;
+
+        let ctx = ParseContext {
+            index: index,
+            path: path,
+        };
+        while cur_offset < max_offset {
+            let rest = &table[(cur_offset - dentry.offset as u64) as usize..];
+            let names_len = names.len();
+            let (nentry, fentry) =
+                match self.read_directory_entry(&ctx, rest, cur_offset, &mut names) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        self.recover(e)?;
+                        // Skip the entry if at least its extent is known
+                        let skipped = self.parse_name_entry(rest, cur_offset, index, &mut names);
+                        names.truncate(names_len);
+                        match skipped {
+                            Ok(ref n) => {
+                                cur_offset += n.entry_size() as u64;
+                                continue;
+                            }
+                            _ => break,
+                        }
+                    }
+                };
+            let nentry_size = nentry.entry_size() as u64;
+            self.progress.name_table_bytes += nentry_size;
+            self.report_progress(false)?;
+            let name = nentry.name_in(&names);
+            match nentry.entry_type {
+                EntryType::Unknown(v) => {
+                    self.unknown_entries.push(UnknownEntry {
+                        path: format!("{}{}", path, name),
+                        entry_type: v,
+                        offset: nentry.offset,
+                    });
+                }
+                EntryType::File => {
+                    file_entries.push((nentry, fentry));
+                }
+                EntryType::Directory => {
+                    let subpath = format!("{}{}/", path, name);
+                    let dir = match lazy {
+                        Some(file) => Directory {
+                            index: nentry.file_index,
+                            contents: OnceLock::new(),
+                            lazy: Some(LazyDirectory {
+                                file: file.clone(),
+                                stack: stack.clone(),
+                                path: subpath,
+                            }),
+                            file_entry: fentry,
+                            name_entry: Some(nentry),
+                            names: Arc::from(""),
+                        },
+                        None => {
+                            match self.read_directory_loop(nentry.file_index, &subpath, stack, None)
+                            {
+                                Ok(mut undir) => {
+                                    undir.name_entry = Some(nentry);
+                                    undir
+                                }
+                                Err(e) => {
+                                    self.recover(e)?;
+                                    cur_offset += nentry_size;
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    dirs.push(dir);
+                }
+            };
+            cur_offset += nentry_size;
+        }
+
+        stack.pop();
+
+        let names: Arc<str> = Arc::from(names);
+        for dir in &mut dirs {
+            dir.names = names.clone();
+        }
+        let files = file_entries
+            .into_iter()
+            .map(|(nentry, fentry)| File {
+                name_entry: nentry,
+                file_entry: fentry,
+                names: names.clone(),
+                compressed: OnceLock::new(),
+            })
+            .collect();
+        Ok(DirContents {
+            files: files,
+            directories: dirs,
+        })
+    }
+
+    fn read_directory_loop(
+        &mut self,
+        index: u32,
+        path: &str,
+        stack: &mut Vec<u32>,
+        lazy: Option<&Arc<Mutex<ArchiveFile>>>,
+    ) -> Result<Directory> {
+        let dentry = self.read_file_entry(index).chain_err(|| {
+            format!(
+                "Unable to read the file table entry of directory /{} (index {})",
+                path, index
+            )
+        })?;
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!(
+            "read_directory",
+            index = index,
+            offset = dentry.offset,
+            size = dentry.size
+        )
+        .entered();
+        let contents = self.read_directory_contents(index, &dentry, path, stack, lazy)?;
+        Ok(Directory {
+            index: index,
+            contents: OnceLock::from(contents),
+            lazy: None,
+            file_entry: dentry,
+            name_entry: None,
+            names: Arc::from(""),
+        })
+    }
+
+    fn read_directory(
+        &mut self,
+        index: u32,
+        lazy: Option<&Arc<Mutex<ArchiveFile>>>,
+    ) -> Result<Directory> {
+        let mut stack: Vec<u32> = Vec::new();
+        return self.read_directory_loop(index, "", &mut stack, lazy);
+    }
+
+    fn read_rootdir(&mut self) -> Result<Directory> {
+        self.read_directory(1, None)
+    }
+
+    #[cfg(feature = "fs")]
+    fn open(filename: &str, options: &ArchiveOptions) -> Result<ArchiveFile> {
+        let file = fs::File::open(filename)?;
+        Self::from_source(Self::file_source(file)?, options)
+    }
+
+    /* Source reading file. Without positioned reads, the file is shared
+     * like the readers of Archive::from_boxed(). */
+    #[cfg(all(feature = "fs", any(unix, windows)))]
+    fn file_source(file: fs::File) -> Result<ArchiveSource> {
+        Ok(ArchiveSource::File {
+            file: Arc::new(file),
+            pos: 0,
+        })
+    }
+
+    #[cfg(all(feature = "fs", not(any(unix, windows))))]
+    fn file_source(file: fs::File) -> Result<ArchiveSource> {
+        let len = file.metadata()?.len();
+        Ok(ArchiveSource::Shared {
+            reader: Arc::new(Mutex::new(Box::new(file))),
+            base: 0,
+            len: len,
+            pos: 0,
+        })
+    }
+
+    fn from_source(source: ArchiveSource, options: &ArchiveOptions) -> Result<ArchiveFile> {
+        let basefile = source.try_clone()?;
+        let mut filereader = BufReader::new(source);
+        let header =
+            ArchiveFile::read_header(&mut filereader).chain_err(|| "Unable to parse the header")?;
+        let file_len = basefile.len()?;
+        if file_len < header.filetbl_offset as u64 {
+            bail!(ErrorKind::ArchiveTruncated(
+                file_len,
+                header.filetbl_offset as u64
+            ));
+        }
+        let filetbl_len =
+            file_len.saturating_sub(header.filetbl_offset as u64) / FILE_ENTRY_SIZE as u64;
+        Ok(ArchiveFile {
+            file_len: file_len,
+            basefile: basefile,
+            reader: filereader,
+            filetbl_cache: Vec::new(),
+            filetbl_cache_start: 0,
+            header: header,
+            filetbl_len: cmp::min(filetbl_len, u32::MAX as u64) as u32,
+            entry_count: None,
+            options: options.clone(),
+            num_entries: 0,
+            unknown_entries: Vec::new(),
+            recovered_errors: Vec::new(),
+            progress: ParseProgress::default(),
+            progress_callback: None,
+            shared_cache: options
+                .shared_cache_bytes
+                .map(|bytes| Arc::new(SharedBlockCache::new(bytes))),
+            metrics: MetricsHandle::default(),
+        })
+    }
+}
+
+impl ArchiveFile {
+    /* Create an archive file reading the same archive with its own handles,
+     * and knowing the entries parsed so far. */
+    fn try_clone(&self) -> Result<ArchiveFile> {
+        Ok(ArchiveFile {
+            file_len: self.file_len,
+            basefile: self.basefile.try_clone()?,
+            reader: BufReader::new(self.basefile.try_clone()?),
+            filetbl_cache: Vec::new(),
+            filetbl_cache_start: 0,
+            header: self.header,
+            filetbl_len: self.filetbl_len,
+            entry_count: self.entry_count,
+            options: self.options.clone(),
+            num_entries: self.num_entries,
+            unknown_entries: self.unknown_entries.clone(),
+            recovered_errors: self.recovered_errors.clone(),
+            progress: self.progress,
+            progress_callback: None,
+            shared_cache: self
+                .options
+                .shared_cache_bytes
+                .map(|bytes| Arc::new(SharedBlockCache::new(bytes))),
+            metrics: MetricsHandle::default(),
+        })
+    }
+}
+
+impl Directory {
+    /* Make this directory and its loaded subdirectories load their contents
+     * from another archive file. */
+    fn rebind(&mut self, file: &Arc<Mutex<ArchiveFile>>) {
+        if let Some(ref mut lazy) = self.lazy {
+            lazy.file = file.clone();
+        }
+        if let Some(contents) = self.contents.get_mut() {
+            for dir in &mut contents.directories {
+                dir.rebind(file);
+            }
+        }
+    }
+}
+
+impl Archive {
+    #[cfg(feature = "fs")]
+    pub fn open(filename: &str) -> Result<Archive> {
+        Self::open_with(filename, &ArchiveOptions::default())
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn open_with(filename: &str, options: &ArchiveOptions) -> Result<Archive> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("open", path = filename).entered();
+        let file = ArchiveFile::open(filename, options)?;
+        Self::from_archive_file(file, Some(filename.to_string()))
+    }
+
+    /** Same as `open_with()`, passing the progress of the parsing to
+     * callback every few entries, and once done. The opening is aborted with
+     * `ErrorKind::Cancelled` if it returns `ControlFlow::Break`. */
+    #[cfg(feature = "fs")]
+    pub fn open_with_progress<F>(
+        filename: &str,
+        options: &ArchiveOptions,
+        callback: F,
+    ) -> Result<Archive>
+    where
+        F: FnMut(ParseProgress) -> ControlFlow<()> + Send + 'static,
+    {
+        let mut file = ArchiveFile::open(filename, options)?;
+        file.progress_callback = Some(Box::new(callback));
+        Self::from_archive_file(file, Some(filename.to_string()))
+    }
+
+    /** Open an archive by mapping it in memory, read-only, so that the
+     * contents of uncompressed files can be accessed without copies with
+     * `FileData::as_slice()`. The archive file must not be modified while
+     * it is mapped. */
+    #[cfg(feature = "fs")]
+    pub fn open_mmap(filename: &str) -> Result<Archive> {
+        Self::open_mmap_with(filename, &ArchiveOptions::default())
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn open_mmap_with(filename: &str, options: &ArchiveOptions) -> Result<Archive> {
+        let f = fs::File::open(filename)?;
+        // The mapping is read-only, and never handed out past the lifetime of
+        // the archive and its readers, which hold a reference to it. Like
+        // for any mapping, changes made to the file by other processes are
+        // not guarded against.
+        let map = unsafe { memmap2::Mmap::map(&f) }
+            .chain_err(|| format!("Unable to map {} in memory", filename))?;
+        let source = ArchiveSource::Memory(io::Cursor::new(SharedBytes::Mapped(Arc::new(map))));
+        let file = ArchiveFile::from_source(source, options)?;
+        Self::from_archive_file(file, Some(filename.to_string()))
+    }
+
+    /** Read a whole archive from reader, and keep it in memory. Such an
+     * archive cannot be reloaded. */
+    pub fn open_from_reader<R: Read>(reader: R) -> Result<Archive> {
+        Self::open_from_reader_with(reader, &ArchiveOptions::default())
+    }
+
+    pub fn open_from_reader_with<R: Read>(
+        mut reader: R,
+        options: &ArchiveOptions,
+    ) -> Result<Archive> {
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .chain_err(|| "Unable to read the archive")?;
+        let source = ArchiveSource::Memory(io::Cursor::new(SharedBytes::Owned(Arc::from(data))));
+        let file = ArchiveFile::from_source(source, options)?;
+        Self::from_archive_file(file, None)
+    }
+
+    /** Parse the archive starting at the current position of reader, which
+     * may for instance be a section of a larger file. The reader is shared
+     * by all the readers of file data, which take turns using it. */
+    pub fn from_boxed(reader: Box<dyn ReadSeek + Send>) -> Result<Archive> {
+        Self::from_boxed_with(reader, &ArchiveOptions::default())
+    }
+
+    pub fn from_boxed_with(
+        mut reader: Box<dyn ReadSeek + Send>,
+        options: &ArchiveOptions,
+    ) -> Result<Archive> {
+        let base = reader.stream_position()?;
+        let end = reader.seek(SeekFrom::End(0))?;
+        let source = ArchiveSource::Shared {
+            reader: Arc::new(Mutex::new(reader)),
+            base: base,
+            len: end.saturating_sub(base),
+            pos: 0,
+        };
+        let file = ArchiveFile::from_source(source, options)?;
+        Self::from_archive_file(file, None)
+    }
+
+    fn from_archive_file(mut file: ArchiveFile, path: Option<String>) -> Result<Archive> {
+        let rootdir = file.read_rootdir()?;
+        file.report_progress(true)?;
+        file.progress_callback = None;
+        Ok(Archive {
+            header: file.header,
+            file: Arc::new(Mutex::new(file)),
+            rootdir: rootdir,
+            path: path,
+            lazy: false,
+        })
+    }
+
+    /** Open an archive, parsing only the contents of the root directory.
+     * Subdirectories are parsed when first accessed, see
+     * `Directory::load_children()`. */
+    #[cfg(feature = "fs")]
+    pub fn open_lazy(filename: &str) -> Result<Archive> {
+        Self::open_lazy_with(filename, &ArchiveOptions::default())
+    }
+
+    #[cfg(feature = "fs")]
+    pub fn open_lazy_with(filename: &str, options: &ArchiveOptions) -> Result<Archive> {
+        let file = Arc::new(Mutex::new(ArchiveFile::open(filename, options)?));
+        let (rootdir, header) = {
+            let mut archive_file = lock_archive_file(&file)?;
+            let rootdir = archive_file.read_directory(1, Some(&file))?;
+            (rootdir, archive_file.header)
+        };
+        Ok(Archive {
+            file: file,
+            rootdir: rootdir,
+            header: header,
+            path: Some(filename.to_string()),
+            lazy: true,
+        })
+    }
+
+    /** Parse the archive file again, to pick up changes made to it since it
+     * was opened. The archive is left untouched if that fails. Readers
+     * obtained before, such as `FileData` or `FileHandle`, are not affected:
+     * they keep their own position and block cache, and are not meant to be
+     * used after the file has changed. */
+    #[cfg(feature = "fs")]
+    pub fn reload(&mut self) -> Result<()> {
+        let (options, mapped) = {
+            let file = lock_archive_file(&self.file)?;
+            (file.options.clone(), file.basefile.is_mapped())
+        };
+        let path = match self.path {
+            Some(ref p) => p.clone(),
+            None => bail!("Archive was not read from a file"),
+        };
+        *self = if self.lazy {
+            Archive::open_lazy_with(&path, &options)?
+        } else if mapped {
+            Archive::open_mmap_with(&path, &options)?
+        } else {
+            Archive::open_with(&path, &options)?
+        };
+        Ok(())
+    }
+
+    /** Create a copy of this archive with its own lock and read positions,
+     * which can be used from another thread without contending with this
+     * one, except for archives read with `from_boxed()`, whose reader is
+     * still shared. The directory tree is copied rather than parsed again. */
+    pub fn deep_clone(&self) -> Result<Archive> {
+        let file = Arc::new(Mutex::new(lock_archive_file(&self.file)?.try_clone()?));
+        let mut rootdir = self.rootdir.clone();
+        rootdir.rebind(&file);
+        Ok(Archive {
+            file: file,
+            rootdir: rootdir,
+            header: self.header,
+            path: self.path.clone(),
+            lazy: self.lazy,
+        })
+    }
+
+    pub fn file_data(&self, file: &File) -> Result<FileData> {
+        let archive_file = lock_archive_file(&self.file)?;
+        let f = archive_file.basefile.try_clone()?;
+        let data = FileData::new(
+            f,
+            &file.file_entry,
+            &archive_file.options,
+            archive_file.shared_cache.as_ref(),
+            &archive_file.metrics,
+        )?;
+        let _ = file.compressed.set(data.is_compressed());
+        Ok(data)
+    }
+
+    /** Whether a file is stored ZLIB compressed. This needs a reader for the
+     * file the first time, the answer is then kept in the file. */
+    pub fn is_compressed(&self, file: &File) -> Result<bool> {
+        match file.compressed.get() {
+            Some(&compressed) => Ok(compressed),
+            None => Ok(self.file_data(file)?.is_compressed()),
+        }
+    }
+
+    fn files_by_compression(&self, compressed: bool) -> Result<Vec<(String, &File)>> {
+        let mut files = Vec::new();
+        for entry in self.files() {
+            let (path, file) = entry?;
+            if self.is_compressed(file)? == compressed {
+                files.push((path, file));
+            }
+        }
+        Ok(files)
+    }
+
+    /** List the files stored ZLIB compressed along with their '/' separated
+     * paths, in lexicographical order of the paths. */
+    pub fn list_compressed_files(&self) -> Result<Vec<(String, &File)>> {
+        self.files_by_compression(true)
+    }
+
+    /** Same as `list_compressed_files()`, for the files stored as is. */
+    pub fn list_plain_files(&self) -> Result<Vec<(String, &File)>> {
+        self.files_by_compression(false)
+    }
+
+    /** Open the file at the specified '/' separated path, returning a
+     * handle which can be kept or moved independently of the archive. */
+    pub fn open_path(&self, path: &str) -> Result<FileHandle> {
+        let file = match self.find(path)? {
+            Some(f) => f,
+            None => bail!("No such file in archive: {}", path),
+        };
+        Ok(FileHandle {
+            path: path.trim_matches('/').to_string(),
+            index: file.index(),
+            data: self.file_data(file)?,
+        })
+    }
+
+    pub fn root_directory(&self) -> &Directory {
+        &self.rootdir
+    }
+
+    pub fn header(&self) -> HeaderInfo {
+        self.header
+    }
+
+    /** Path of the file the archive was opened from, None if it was read
+     * from memory or from a reader. */
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_ref().map(Path::new)
+    }
+
+    /** Call f for each file of the archive along with its path, made of the
+     * names of its parent directories and its own name separated by '/'.
+     * Directories are visited depth first, in on-disk order. */
+    pub fn walk<'a, F>(&'a self, f: F) -> Result<()>
+    where
+        F: FnMut(&str, &'a File) -> Result<()>,
+    {
+        self.walk_with(WalkOrder::DepthFirst, f)
+    }
+
+    /** Same as `walk()`, visiting the files in the specified order. */
+    pub fn walk_with<'a, F>(&'a self, order: WalkOrder, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &'a File) -> Result<()>,
+    {
+        fn walk_dir<'a, F>(dir: &'a Directory, prefix: &str, f: &mut F) -> Result<()>
+        where
+            F: FnMut(&str, &'a File) -> Result<()>,
+        {
+            for file in &dir.contents()?.files {
+                f(&format!("{}{}", prefix, file.name()), file)?;
+            }
+            for subdir in &dir.contents()?.directories {
+                let name = subdir.name().unwrap_or("");
+                walk_dir(subdir, &format!("{}{}/", prefix, name), f)?;
+            }
+            Ok(())
+        }
+        match order {
+            WalkOrder::DepthFirst => walk_dir(&self.rootdir, "", &mut f),
+            WalkOrder::BreadthFirst => {
+                let mut queue = VecDeque::new();
+                queue.push_back((String::new(), &self.rootdir));
+                while let Some((prefix, dir)) = queue.pop_front() {
+                    for file in &dir.contents()?.files {
+                        f(&format!("{}{}", prefix, file.name()), file)?;
+                    }
+                    for subdir in &dir.contents()?.directories {
+                        let name = subdir.name().unwrap_or("");
+                        queue.push_back((format!("{}{}/", prefix, name), subdir));
+                    }
+                }
+                Ok(())
+            }
+            WalkOrder::DataOffset => {
+                let mut files = Vec::new();
+                walk_dir(&self.rootdir, "", &mut |path: &str, file: &'a File| {
+                    files.push((path.to_string(), file));
+                    Ok(())
+                })?;
+                files.sort_by_key(|&(_, file)| file.offset());
+                for (path, file) in files {
+                    f(&path, file)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /** Iterate over all the files of the archive along with their '/'
+     * separated paths, in lexicographical order of the paths. Directories are
+     * only sorted when the iteration reaches them. For a lazily opened
+     * archive, the iteration ends with an error if a directory cannot be
+     * loaded. */
+    pub fn files(&self) -> ArchiveFiles<'_> {
+        ArchiveFiles::new(&self.rootdir, true)
+    }
+
+    /** Same as `files()`, except that the entries of each directory are
+     * visited in the order in which they are stored in the archive. */
+    pub fn files_unsorted(&self) -> ArchiveFiles<'_> {
+        ArchiveFiles::new(&self.rootdir, false)
+    }
+
+    /** List all the files of the archive along with their '/' separated
+     * paths, ordered by the offset of their data in the archive. Reading
+     * files in this order minimizes seeking. */
+    pub fn sort_entries_by_offset(&self) -> Result<Vec<(String, &File)>> {
+        let mut entries: Vec<(String, &File)> = self.files_unsorted().collect::<Result<_>>()?;
+        entries.sort_by_key(|&(_, f)| f.offset());
+        Ok(entries)
+    }
+
+    /** Find the files whose data partially overlap, which extracting would
+     * not detect. Files sharing the exact same data, which is how
+     * identical files are stored, and empty files are not reported. A file
+     * overlapping several others is reported once, along with the one
+     * starting before it and ending the furthest. */
+    pub fn overlapping_files(&self) -> Result<Vec<DataOverlap>> {
+        let mut ranges: Vec<(Range<u64>, String)> = Vec::new();
+        for entry in self.files() {
+            let (path, f) = entry?;
+            if f.size() > 0 {
+                let start = f.offset() as u64;
+                ranges.push((start..start + f.size() as u64, path));
+            }
+        }
+        // Stable, so that the first of several aliases in path order is kept
+        ranges.sort_by_key(|(r, _)| (r.start, r.end));
+        ranges.dedup_by(|b, a| a.0 == b.0);
+        let mut overlaps = Vec::new();
+        let mut furthest = 0;
+        for i in 1..ranges.len() {
+            let (ref range, ref path) = ranges[i];
+            let (ref prev_range, ref prev_path) = ranges[furthest];
+            if range.start < prev_range.end {
+                overlaps.push(DataOverlap {
+                    first: prev_path.clone(),
+                    first_range: prev_range.clone(),
+                    second: path.clone(),
+                    second_range: range.clone(),
+                });
+            }
+            if range.end > prev_range.end {
+                furthest = i;
+            }
+        }
+        Ok(overlaps)
+    }
+
+    /** Check the whole archive without extracting it, returning all the
+     * problems found: entries skipped while parsing, the data of every file
+     * including the block tables of compressed files, and overlapping data.
+     * The archive should be opened with `ArchiveOptions::recover_errors()`
+     * and without `strict_entry_types()`, so that structural problems show
+     * up here rather than make opening fail. */
+    pub fn validate(&self) -> Result<Vec<ValidationIssue>> {
+        let mut issues: Vec<ValidationIssue> = self
+            .recovered_errors()?
+            .into_iter()
+            .map(ValidationIssue::Unparsable)
+            .collect();
+        issues.extend(
+            self.unknown_entries()?
+                .into_iter()
+                .map(ValidationIssue::UnknownEntry),
+        );
+        self.walk(|path, file| {
+            if let Err(e) = self.file_data(file) {
+                let messages: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+                issues.push(ValidationIssue::BadFileData(
+                    path.to_string(),
+                    messages.join(": "),
+                ));
+            }
+            Ok(())
+        })?;
+        issues.extend(
+            self.duplicate_names()?
+                .into_iter()
+                .map(ValidationIssue::DuplicateName),
+        );
+        issues.extend(
+            self.overlapping_files()?
+                .into_iter()
+                .map(ValidationIssue::Overlap),
+        );
+        issues.extend(
+            self.shared_directories()?
+                .into_iter()
+                .map(|(offset, paths)| ValidationIssue::SharedDirectory(offset, paths)),
+        );
+        Ok(issues)
+    }
+
+    /** Check that no two directories of the parsed tree share their name
+     * table. The parser rejects a directory containing itself, but not one
+     * reachable through several paths, such as two sibling entries referring
+     * to the same name table, which can hide a loop deeper than the depth
+     * limit. Fails with the offsets of all the shared name tables. */
+    pub fn validate_no_loops(&self) -> Result<()> {
+        let shared = self.shared_directories()?;
+        if shared.is_empty() {
+            return Ok(());
+        }
+        let list: Vec<String> = shared
+            .iter()
+            .map(|(offset, paths)| format!("0x{:x} ({})", offset, paths.join(", ")))
+            .collect();
+        bail!("Directories share their name table: {}", list.join("; "))
+    }
+
+    /* Paths of the directories sharing their name table, by offset of the
+     * name table, the root directory being "/". Only the directories loaded
+     * so far are visited. Empty directories are ignored: there is nothing
+     * in their name table, wherever it is. */
+    fn shared_directories(&self) -> Result<BTreeMap<u32, Vec<String>>> {
+        let mut by_offset: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+        let mut stack = vec![("/".to_string(), &self.rootdir)];
+        while let Some((path, dir)) = stack.pop() {
+            if !dir.is_loaded() {
+                continue;
+            }
+            if dir.file_entry.size > 0 {
+                by_offset
+                    .entry(dir.file_entry.offset)
+                    .or_default()
+                    .push(path.clone());
+            }
+            for d in &dir.contents()?.directories {
+                let prefix = if path == "/" { "" } else { &path };
+                stack.push((format!("{}{}/", prefix, d.name().unwrap_or("")), d));
+            }
+        }
+        by_offset.retain(|_, paths| paths.len() > 1);
+        for paths in by_offset.values_mut() {
+            paths.sort();
+        }
+        Ok(by_offset)
+    }
+
+    /* Paths taken by several entries of their directory, be they files or
+     * directories, sorted. */
+    fn duplicate_names(&self) -> Result<Vec<String>> {
+        let mut duplicates = BTreeSet::new();
+        let mut stack = vec![(String::new(), &self.rootdir)];
+        while let Some((prefix, dir)) = stack.pop() {
+            let mut names = HashSet::new();
+            let subdirs = dir
+                .contents()?
+                .directories
+                .iter()
+                .map(|d| d.name().unwrap_or(""));
+            for name in dir
+                .contents()?
+                .files
+                .iter()
+                .map(|f| f.name())
+                .chain(subdirs)
+            {
+                if !names.insert(name) {
+                    duplicates.insert(format!("{}{}", prefix, name));
+                }
+            }
+            for d in &dir.contents()?.directories {
+                stack.push((format!("{}{}/", prefix, d.name().unwrap_or("")), d));
+            }
+        }
+        Ok(duplicates.into_iter().collect())
+    }
+
+    /** Look up a file by its '/' separated path. */
+    pub fn find(&self, path: &str) -> Result<Option<&File>> {
+        let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let filename = match components.pop() {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        let dir = match self.find_directory(&components)? {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+        Ok(dir.contents()?.files.iter().find(|f| f.name() == filename))
+    }
+
+    /** Open the data of the file at the specified path, in which both '/'
+     * and '\\' are accepted as separators. A leading separator is ignored.
+     * Fails with `ErrorKind::NoSuchPath` or `ErrorKind::IsADirectory` if the
+     * path does not designate a file. */
+    pub fn file_data_at(&self, path: &str) -> Result<FileData> {
+        let components: Vec<&str> = path.split(['/', '\\']).filter(|c| !c.is_empty()).collect();
+        let (name, parents) = match components.split_last() {
+            Some(c) => c,
+            None => bail!(ErrorKind::IsADirectory(path.to_string())),
+        };
+        let dir = match self.find_directory(parents)? {
+            Some(d) => d,
+            None => bail!(ErrorKind::NoSuchPath(path.to_string())),
+        };
+        if let Some(file) = dir.contents()?.files.iter().find(|f| f.name() == *name) {
+            return self
+                .file_data(file)
+                .chain_err(|| format!("Unable to open the data of {}", path));
+        }
+        if dir
+            .contents()?
+            .directories
+            .iter()
+            .any(|d| d.name() == Some(*name))
+        {
+            bail!(ErrorKind::IsADirectory(path.to_string()));
+        }
+        bail!(ErrorKind::NoSuchPath(path.to_string()))
+    }
+
+    /** Open data stored at the specified offset of the archive, as if a
+     * file table entry referred to it, without going through the directory
+     * tree. ZLIB compressed data is detected like for files. This is meant
+     * for carving data no directory refers to, such as in an archive whose
+     * tree is damaged. The range must be within the archive. */
+    pub fn file_data_at_offset(&self, offset: u32, size: u32) -> Result<FileData> {
+        let archive_file = lock_archive_file(&self.file)?;
+        if offset as u64 + size as u64 > archive_file.file_len {
+            bail!(
+                "Data at offset 0x{:x}, size 0x{:x} spans outside of the archive of size 0x{:x}",
+                offset,
+                size,
+                archive_file.file_len
+            );
+        }
+        let entry = FileTableEntry {
+            offset: offset,
+            size: size,
+        };
+        FileData::new(
+            archive_file.basefile.try_clone()?,
+            &entry,
+            &archive_file.options,
+            archive_file.shared_cache.as_ref(),
+            &archive_file.metrics,
+        )
+    }
+
+    /* Look up a directory by the components of its path, and load its
+     * contents. */
+    fn find_directory(&self, components: &[&str]) -> Result<Option<&Directory>> {
+        let mut dir = &self.rootdir;
+        for component in components {
+            dir = match dir
+                .contents()?
+                .directories
+                .iter()
+                .find(|d| d.name() == Some(*component))
+            {
+                Some(d) => d,
+                None => return Ok(None),
+            };
+        }
+        dir.load_children()?;
+        Ok(Some(dir))
+    }
+
+    /** Read the name table of a directory again, and return its records
+     * as stored, including those the parser skips or rejects. A record which
+     * does not fit in the directory, such as trailing padding, is returned
+     * as an error, and ends the list. */
+    pub fn raw_dir_entries(&self, dir: &Directory) -> Result<Vec<Result<RawNameEntry>>> {
+        lock_archive_file(&self.file)?.read_raw_name_entries(&dir.file_entry)
+    }
+
+    /** Counters of the cache of decompressed blocks shared by the readers of
+     * the archive, if enabled with `ArchiveOptions::shared_cache_bytes()`. */
+    pub fn block_cache_stats(&self) -> Result<Option<CacheStats>> {
+        match lock_archive_file(&self.file)?.shared_cache {
+            Some(ref cache) => Ok(Some(cache.stats()?)),
+            None => Ok(None),
+        }
+    }
+
+    /** Counters of the work done by the readers of this archive since it
+     * was opened. Copies made with `deep_clone()` count separately. */
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Result<Metrics> {
+        Ok(lock_archive_file(&self.file)?.metrics.get())
+    }
+
+    /** Whether some entries were skipped because they could not be parsed,
+     * see `ArchiveOptions::recover_errors()`. */
+    pub fn is_partial(&self) -> bool {
+        self.recovered_errors()
+            .map(|e| !e.is_empty())
+            .unwrap_or(true)
+    }
+
+    /** Messages of the errors skipped while parsing the archive, see
+     * `ArchiveOptions::recover_errors()`. For a lazily opened archive, only
+     * the loaded directories are taken into account. */
+    pub fn recovered_errors(&self) -> Result<Vec<String>> {
+        Ok(lock_archive_file(&self.file)?.recovered_errors.clone())
+    }
+
+    /** Entries of an unknown type skipped so far, which only happens if
+     * `ArchiveOptions::strict_entry_types()` is unset. For a lazily opened
+     * archive, only the loaded directories are taken into account. */
+    pub fn unknown_entries(&self) -> Result<Vec<UnknownEntry>> {
+        Ok(lock_archive_file(&self.file)?.unknown_entries.clone())
+    }
+
+    /** Get the metadata of the file or directory at the specified '/'
+     * separated path, without setting up a reader for its data. The size of
+     * a file once decompressed is only looked up when peek_size is set, as
+     * it requires reading the header of its data. */
+    pub fn stat(&self, path: &str, peek_size: bool) -> Result<EntryStat> {
+        if let Some(file) = self.find(path)? {
+            let unpacked_size = if peek_size {
+                Some(lock_archive_file(&self.file)?.read_unpacked_size(&file.file_entry)?)
+            } else {
+                None
+            };
+            return Ok(EntryStat {
+                kind: EntryType::File,
+                stored_size: file.size(),
+                unpacked_size: unpacked_size,
+                offset: file.offset(),
+                index: file.index(),
+            });
+        }
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        match self.find_directory(&components)? {
+            Some(dir) => Ok(EntryStat {
+                kind: EntryType::Directory,
+                stored_size: dir.file_entry.size,
+                unpacked_size: None,
+                offset: dir.file_entry.offset,
+                index: dir.index,
+            }),
+            None => bail!("No such file or directory in archive: {}", path),
+        }
+    }
+
+    /** Stored size of a compressed file divided by its size once
+     * decompressed. Returns None for a file which is not compressed, or is
+     * empty. */
+    pub fn file_compression_ratio(&self, file: &File) -> Result<Option<f64>> {
+        let data = self.file_data(file)?;
+        if !data.is_compressed() || data.size() == 0 {
+            return Ok(None);
+        }
+        Ok(Some(file.size() as f64 / data.size() as f64))
+    }
+
+    /** Path and compression ratio of the compressed file which has the
+     * highest ratio, that is which compresses the least. Returns None if no
+     * file is compressed. */
+    pub fn worst_compression_ratio(&self) -> Result<Option<(String, f64)>> {
+        let mut worst: Option<(String, f64)> = None;
+        self.walk(|path, file| {
+            if let Some(ratio) = self.file_compression_ratio(file)? {
+                if worst.as_ref().is_none_or(|&(_, r)| ratio > r) {
+                    worst = Some((path.to_string(), ratio));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(worst)
+    }
+
+    /** Number of entries in the file table, including the root directory
+     * and entries no directory refers to. */
+    pub fn entry_count(&self) -> Result<usize> {
+        lock_archive_file(&self.file)?.entry_count()
+    }
+
+    /** Find the entry which refers to the file table entry with the
+     * specified 1-based index. Returns None if the index is outside of the
+     * file table. If several entries refer to it, the last one parsed is
+     * returned. The loaded directories are searched, so the lookup takes
+     * time in proportion to the number of entries, and for a lazily opened
+     * archive, only the entries of the directories loaded so far are
+     * known. */
+    pub fn entry_by_index(&self, idx: u32) -> Option<EntryRef<'_>> {
+        {
+            let mut archive_file = lock_archive_file(&self.file).ok()?;
+            if idx == 0 || idx as usize > archive_file.entry_count().ok()? {
+                return None;
+            }
+        }
+        let mut found = None;
+        if idx == self.rootdir.index {
+            found = Some(EntryRef::Directory(String::new(), &self.rootdir));
+        }
+        // Visit the entries in the order in which they are parsed: each
+        // subdirectory right after its entry
+        let mut stack = vec![(String::new(), self.rootdir.entries())];
+        while let Some((prefix, children)) = stack.last_mut() {
+            match children.next() {
+                None => {
+                    stack.pop();
+                }
+                Some(DirChild::File(f)) => {
+                    if f.index() == idx {
+                        found = Some(EntryRef::File(format!("{}{}", prefix, f.name()), f));
+                    }
+                }
+                Some(DirChild::Directory(d)) => {
+                    let path = format!("{}{}/", prefix, d.name().unwrap_or(""));
+                    if d.index == idx {
+                        found = Some(EntryRef::Directory(path.clone(), d));
+                    }
+                    if d.is_loaded() {
+                        stack.push((path, d.entries()));
+                    }
+                }
+            }
+        }
+        Some(found.unwrap_or(EntryRef::Unreferenced))
+    }
+}
+
+/** Entry points for the fuzz targets of the fuzz/ directory, which build
+ * this module on its own. */
+#[cfg(fuzzing)]
+pub mod fuzzing {
+    use super::*;
+
+    pub fn read_header(data: &[u8]) -> Result<HeaderInfo> {
+        ArchiveFile::read_header(&mut io::Cursor::new(data))
+    }
+
+    pub fn parse_zlib_header(data: &[u8]) -> Result<(u64, u64)> {
+        FileDataZlib::parse_header(data, ZLIB_MAX_BLOCKSIZE)
+    }
+
+    /* Read data as the contents of a ZLIB compressed file, sequentially and
+     * up to limit bytes, so that huge sizes in the header do not make a run
+     * last forever. Returns the number of bytes read. */
+    pub fn read_zlib_file(data: &[u8], limit: u64) -> Result<u64> {
+        let fentry = FileTableEntry {
+            offset: 0,
+            size: cmp::min(data.len(), u32::MAX as usize) as u32,
+        };
+        let source = ArchiveSource::Memory(io::Cursor::new(SharedBytes::Owned(Arc::from(data))));
+        let mut zlib = FileDataZlib::from(
+            source,
+            &fentry,
+            &ArchiveOptions::default(),
+            None,
+            &MetricsHandle::default(),
+        )?;
+        let mut buf = [0u8; 0x1000];
+        let mut total = 0u64;
+        while total < limit {
+            let n = zlib.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            total += n as u64;
+        }
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate proptest;
+    extern crate tempfile;
+
+    use self::proptest::prelude::*;
+    use super::*;
+    use testutil::*;
+
+    fn zlib_file_data(data: &[u8], blocksize: usize) -> FileDataZlib {
+        zlib_file_data_from(&write_zlib_file_data(data, blocksize)).unwrap()
+    }
+
+    fn zlib_file_data_from(packed: &[u8]) -> Result<FileDataZlib> {
+        let source = ArchiveSource::Memory(io::Cursor::new(SharedBytes::Owned(Arc::from(packed))));
+        let fentry = FileTableEntry {
+            offset: 0,
+            size: packed.len() as u32,
+        };
+        FileDataZlib::from(
+            source,
+            &fentry,
+            &ArchiveOptions::default(),
+            None,
+            &MetricsHandle::default(),
+        )
+    }
+
+    /* The message of an error followed by those of its causes */
+    fn render_error(e: &Error) -> String {
+        let messages: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+        messages.join(": ")
+    }
+
+    #[cfg(feature = "fs")]
+    fn open_archive_with(
+        file: &tempfile::NamedTempFile,
+        options: &ArchiveOptions,
+    ) -> Result<Archive> {
+        Archive::open_with(file.path().to_str().unwrap(), options)
+    }
+
+    #[test]
+    fn zlib_read_exact_oversized_and_tiny_buffers() {
+        let data = sample_data(1000);
+        let mut zlib = zlib_file_data(&data, 0x100);
+
+        let mut whole = vec![0u8; data.len()];
+        let mut oversized = vec![0u8; data.len() * 2];
+        let len = zlib.read(&mut oversized).unwrap();
+        whole.copy_from_slice(&oversized[..len]);
+        assert_eq!(len, data.len());
+        assert_eq!(whole, data);
+
+        zlib.seek(SeekFrom::Start(0)).unwrap();
+        let mut pieces = Vec::new();
+        let mut tiny = [0u8; 3];
+        while pieces.len() + tiny.len() <= data.len() {
+            zlib.read_exact(&mut tiny).unwrap();
+            pieces.extend_from_slice(&tiny);
+        }
+        let rest = data.len() - pieces.len();
+        zlib.read_exact(&mut tiny[..rest]).unwrap();
+        pieces.extend_from_slice(&tiny[..rest]);
+        assert_eq!(pieces, whole);
+    }
+
+    #[test]
+    fn zlib_read_larger_buffer_than_file() {
+        let data = sample_data(0x280);
+        let mut zlib = zlib_file_data(&data, 0x100);
+        let mut buf = vec![0u8; 0x1000];
+        assert_eq!(zlib.read(&mut buf).unwrap(), data.len());
+        assert_eq!(&buf[..data.len()], &data[..]);
+
+        zlib.seek(SeekFrom::Start(0x1f0)).unwrap();
+        assert_eq!(zlib.read(&mut buf).unwrap(), data.len() - 0x1f0);
+        assert_eq!(&buf[..data.len() - 0x1f0], &data[0x1f0..]);
+    }
+
+    #[test]
+    fn zlib_read_at_eof_returns_zero() {
+        let data = sample_data(0x300);
+        let mut zlib = zlib_file_data(&data, 0x100);
+        zlib.seek(SeekFrom::End(0)).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(zlib.read(&mut buf).unwrap(), 0);
+        assert!(zlib.read_exact(&mut buf).is_err());
+    }
+
+    #[cfg(feature = "fs")]
+    fn nested_dirs(depth: usize) -> Vec<TestEntry> {
+        let mut entries = vec![TestEntry::File(b"leaf.txt", b"leaf".to_vec())];
+        for _ in 0..depth {
+            entries = vec![TestEntry::Dir(b"dir", entries)];
+        }
+        entries
+    }
+
+    #[test]
+    fn zlib_unsorted_block_table() {
+        let mut packed = write_zlib_file_data(&sample_data(0x300), 0x100);
+        let tbl = ZLIB_BLOCKTBL_OFFSET as usize;
+        let second = LittleEndian::read_u32(&packed[tbl + 4..tbl + 8]);
+        let third = LittleEndian::read_u32(&packed[tbl + 8..tbl + 12]);
+        LittleEndian::write_u32(&mut packed[tbl + 4..tbl + 8], third);
+        LittleEndian::write_u32(&mut packed[tbl + 8..tbl + 12], second);
+        let err = zlib_file_data_from(&packed).err().unwrap();
+        assert!(err.to_string().contains("not sorted"));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn open_from_reader() {
+        let data = write_archive(&[
+            TestEntry::File(b"a", b"first".to_vec()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"b", sample_data(0x100))]),
+        ]);
+        let mut archive = Archive::open_from_reader(&data[..]).unwrap();
+        let mut contents = Vec::new();
+        archive
+            .open_path("d/b")
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, sample_data(0x100));
+        assert!(archive.reload().is_err());
+        assert!(Archive::open_from_reader(&data[..0x30]).is_err());
+        assert!(FileDataZlib::parse_header(b"ZLIB\0\0", 0x10000).is_err());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn file_data_at_separators_and_errors() {
+        let file = archive_file(&[TestEntry::Dir(
+            b"Scripts",
+            vec![
+                TestEntry::File(b"init.lua", b"print()".to_vec()),
+                TestEntry::Dir(b"lib", vec![]),
+            ],
+        )]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        for path in &[
+            "Scripts/init.lua",
+            "/Scripts/init.lua",
+            "\\Scripts\\init.lua",
+        ] {
+            let mut contents = Vec::new();
+            archive
+                .file_data_at(path)
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            assert_eq!(contents, b"print()");
+        }
+        for &(path, is_dir) in &[
+            ("Scripts/lib", true),
+            ("/", true),
+            ("Scripts/main.lua", false),
+            ("Other/init.lua", false),
+        ] {
+            match *archive.file_data_at(path).err().unwrap().kind() {
+                ErrorKind::IsADirectory(_) => assert!(is_dir, "{}", path),
+                ErrorKind::NoSuchPath(_) => assert!(!is_dir, "{}", path),
+                ref k => panic!("unexpected error for {}: {}", path, k),
+            }
+        }
+    }
+
+    #[test]
+    fn open_embedded_archive() {
+        let data = sample_data(0x300);
+        let mut container = b"not an archive".repeat(20);
+        let base = container.len() as u64;
+        container.extend_from_slice(&write_archive(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::Dir(
+                b"d",
+                vec![TestEntry::File(b"zlib", write_zlib_file_data(&data, 0x100))],
+            ),
+        ]));
+        container.extend_from_slice(b"trailer");
+        let mut reader = io::Cursor::new(container);
+        reader.seek(SeekFrom::Start(base)).unwrap();
+
+        let archive = Archive::from_boxed(Box::new(reader)).unwrap();
+        for path in &["plain", "d/zlib"] {
+            let mut first = archive.file_data_at(path).unwrap();
+            first.seek(SeekFrom::Start(0x100)).unwrap();
+            let mut second = first.try_clone().unwrap();
+            second.seek(SeekFrom::Start(0x10)).unwrap();
+            let mut buf = [0u8; 0x20];
+            first.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[0x100..0x120]);
+            second.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[0x10..0x30]);
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn deep_clones_read_concurrently() {
+        let data = sample_data(0x8000);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::Zlib(b"zlib", data.clone(), 0x1000),
+        ]);
+        let path = file.path().to_str().unwrap();
+        for archive in &[
+            Archive::open(path).unwrap(),
+            Archive::open_mmap(path).unwrap(),
+        ] {
+            let start = Arc::new(::std::sync::Barrier::new(4));
+            let threads: Vec<_> = (0..4)
+                .map(|t| {
+                    let clone = archive.deep_clone().unwrap();
+                    let expected = data.clone();
+                    let start = start.clone();
+                    ::std::thread::spawn(move || {
+                        start.wait();
+                        for i in 0..100 {
+                            let path = if (t + i) % 2 == 0 { "plain" } else { "zlib" };
+                            // Small reads, for the threads to interleave
+                            let mut contents = Vec::new();
+                            let mut reader = clone.file_data_at(path).unwrap();
+                            let mut buf = [0u8; 0x100];
+                            loop {
+                                match reader.read(&mut buf).unwrap() {
+                                    0 => break,
+                                    n => contents.extend_from_slice(&buf[..n]),
+                                }
+                            }
+                            assert!(contents == expected, "{} differs", path);
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn file_source_handles_read_concurrently() {
+        let data = sample_data(0x10000);
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&data).unwrap();
+        let source = ArchiveFile::file_source(file).unwrap();
+        let data = Arc::new(data);
+        let start = Arc::new(::std::sync::Barrier::new(4));
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let mut handle = source.try_clone().unwrap();
+                let data = data.clone();
+                let start = start.clone();
+                ::std::thread::spawn(move || {
+                    let mut buf = [0u8; 0x40];
+                    start.wait();
+                    for i in 0..5000 {
+                        let offset = (t * 0x3d1 + i * 0x83) % (data.len() - buf.len());
+                        handle.seek(SeekFrom::Start(offset as u64)).unwrap();
+                        handle.read_exact(&mut buf).unwrap();
+                        assert_eq!(&buf[..], &data[offset..offset + buf.len()]);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn zlib_block_of_offset() {
+        let data = sample_data(0x280);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::File(b"zlib", write_zlib_file_data(&data, 0x100)),
+        ]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        let zlib = archive.file_data_at("zlib").unwrap();
+        assert_eq!(zlib.block_of(0), Some((0, 0..0x100)));
+        assert_eq!(zlib.block_of(0x1ff), Some((1, 0x100..0x200)));
+        assert_eq!(zlib.block_of(0x27f), Some((2, 0x200..0x280)));
+        assert_eq!(zlib.block_of(0x280), None);
+        assert_eq!(archive.file_data_at("plain").unwrap().block_of(0), None);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn compression_ratios() {
+        let text = b"El Presidente ".repeat(0x40);
+        let noise = sample_data(0x300);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", text.clone()),
+            TestEntry::Dir(
+                b"d",
+                vec![
+                    TestEntry::File(b"text", write_zlib_file_data(&text, 0x100)),
+                    TestEntry::File(b"noise", write_zlib_file_data(&noise, 0x100)),
+                ],
+            ),
+        ]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        let plain = archive.find("plain").unwrap().unwrap();
+        assert_eq!(archive.file_compression_ratio(plain).unwrap(), None);
+        let text_file = archive.find("d/text").unwrap().unwrap();
+        let ratio = archive.file_compression_ratio(text_file).unwrap().unwrap();
+        assert_eq!(ratio, text_file.size() as f64 / text.len() as f64);
+        let (path, worst) = archive.worst_compression_ratio().unwrap().unwrap();
+        assert_eq!(path, "d/noise");
+        assert!(worst > ratio);
+
+        let file = archive_file(&[TestEntry::File(b"plain", text)]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        assert_eq!(archive.worst_compression_ratio().unwrap(), None);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn open_with_progress_and_cancel() {
+        let entries: Vec<TestEntry> = (0..3000)
+            .map(|_| TestEntry::Dir(b"d", vec![TestEntry::File(b"f", Vec::new())]))
+            .collect();
+        let file = archive_file(&entries);
+        let path = file.path().to_str().unwrap();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        Archive::open_with_progress(path, &ArchiveOptions::default(), move |p| {
+            sink.lock().unwrap().push(p);
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        let reports = reports.lock().unwrap();
+        let entries: Vec<usize> = reports.iter().map(|p| p.entries).collect();
+        assert_eq!(entries, vec![1024, 2048, 3072, 4096, 5120, 6000]);
+        assert_eq!(reports.last().unwrap().directories, 3001);
+        assert_eq!(
+            reports.last().unwrap().name_table_bytes,
+            6000 * (NAME_ENTRY_MIN_SIZE as u64 + 1)
+        );
+
+        let options = ArchiveOptions::new().recover_errors(true);
+        let err = Archive::open_with_progress(path, &options, |p| {
+            if p.entries >= 2048 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .err()
+        .unwrap();
+        match *err.kind() {
+            ErrorKind::Cancelled => {}
+            ref k => panic!("unexpected error: {}", k),
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn shared_block_cache() {
+        let data = sample_data(0x400);
+        let file = archive_file(&[
+            TestEntry::File(b"a", write_zlib_file_data(&data, 0x100)),
+            TestEntry::File(b"b", write_zlib_file_data(&data, 0x100)),
+        ]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        assert_eq!(archive.block_cache_stats().unwrap(), None);
+
+        // Room for 3 blocks
+        let options = ArchiveOptions::new().shared_cache_bytes(Some(0x300));
+        let archive = open_archive_with(&file, &options).unwrap();
+        let read_at = |path: &str, offset: u64| {
+            let mut reader = archive.file_data_at(path).unwrap();
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            let mut buf = [0u8; 0x10];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[offset as usize..offset as usize + 0x10]);
+        };
+        let stats = |hits, misses, blocks| CacheStats {
+            hits: hits,
+            misses: misses,
+            blocks: blocks,
+            bytes: blocks * 0x100,
+        };
+        read_at("a", 0);
+        read_at("a", 0x100);
+        read_at("b", 0);
+        assert_eq!(archive.block_cache_stats().unwrap(), Some(stats(0, 3, 3)));
+        // Readers of the same file share blocks
+        read_at("a", 0x10);
+        assert_eq!(archive.block_cache_stats().unwrap(), Some(stats(1, 3, 3)));
+        // Block 1 of a is the least recently used one
+        read_at("b", 0x100);
+        read_at("a", 0);
+        read_at("a", 0x100);
+        assert_eq!(archive.block_cache_stats().unwrap(), Some(stats(2, 5, 3)));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn deep_clone_reads_independently() {
+        let data = sample_data(0x300);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::Dir(
+                b"d",
+                vec![TestEntry::File(b"zlib", write_zlib_file_data(&data, 0x100))],
+            ),
+        ]);
+        let path = file.path().to_str().unwrap();
+        for archive in &[
+            Archive::open(path).unwrap(),
+            Archive::open_lazy(path).unwrap(),
+        ] {
+            let clone = archive.deep_clone().unwrap();
+            let mut first = archive.file_data_at("d/zlib").unwrap();
+            first.seek(SeekFrom::Start(0x80)).unwrap();
+            let expected = data.clone();
+            let handle = ::std::thread::spawn(move || {
+                for path in &["plain", "d/zlib"] {
+                    let mut contents = Vec::new();
+                    clone
+                        .file_data_at(path)
+                        .unwrap()
+                        .read_to_end(&mut contents)
+                        .unwrap();
+                    assert_eq!(contents, expected);
+                }
+                clone
+            });
+            let mut rest = Vec::new();
+            first.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, &data[0x80..]);
+            let clone = handle.join().unwrap();
+            assert!(!Arc::ptr_eq(&archive.file, &clone.file));
+            let lazy = clone.root_directory().directories()[0].lazy.as_ref();
+            assert!(lazy.is_none_or(|l| Arc::ptr_eq(&l.file, &clone.file)));
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn mapped_archive_slices() {
+        let data = sample_data(0x300);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::File(b"zlib", write_zlib_file_data(&data, 0x100)),
+        ]);
+        let path = file.path().to_str().unwrap();
+        let mut archive = Archive::open_mmap(path).unwrap();
+        assert_eq!(
+            archive.file_data_at("plain").unwrap().as_slice(),
+            Some(&data[..])
+        );
+        let mut zlib = archive.file_data_at("zlib").unwrap();
+        assert_eq!(zlib.as_slice(), None);
+        let mut contents = Vec::new();
+        zlib.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+        archive.reload().unwrap();
+        assert!(archive.file_data_at("plain").unwrap().as_slice().is_some());
+
+        let archive = Archive::open(path).unwrap();
+        assert_eq!(archive.file_data_at("plain").unwrap().as_slice(), None);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn plain_readers() {
+        let data = sample_data(0x2345);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::File(b"zlib", write_zlib_file_data(&data, 0x1000)),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        for path in &["plain", "zlib"] {
+            let fdata = archive.file_data_at(path).unwrap();
+            let mut reader = fdata.into_plain_reader().unwrap();
+            assert_eq!(reader.get_ref(), &data);
+            reader.seek(SeekFrom::Start(0x2000)).unwrap();
+            let mut buf = [0u8; 0x10];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, &data[0x2000..0x2010]);
+        }
+        assert!(FileData::empty()
+            .into_plain_reader()
+            .unwrap()
+            .get_ref()
+            .is_empty());
+
+        // A block table claiming more data than the blocks hold
+        let mut packed = write_zlib_file_data(&data, 0x1000);
+        LittleEndian::write_u32(&mut packed[4..8], data.len() as u32 + 1);
+        let zlib = zlib_file_data_from(&packed).unwrap();
+        assert!(zlib.into_plain_reader().is_err());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn file_data_digests() {
+        let data = sample_data(0x25000);
+        let mut corrupted = write_zlib_file_data(&data, 0x1000);
+        let len = corrupted.len();
+        for b in &mut corrupted[len - 0x10..] {
+            *b = 0xff;
+        }
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::File(b"zlib", write_zlib_file_data(&data, 0x1000)),
+            TestEntry::File(b"empty", Vec::new()),
+            TestEntry::File(b"corrupted", corrupted),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let expected: [u8; 32] = sha2::Sha256::digest(&data).into();
+        for path in &["plain", "zlib"] {
+            let mut fdata = archive.file_data_at(path).unwrap();
+            fdata.seek(SeekFrom::Start(0x123)).unwrap();
+            assert_eq!(fdata.sha256().unwrap(), expected);
+            assert_eq!(fdata.stream_position().unwrap(), 0x123);
+            assert_eq!(
+                &fdata.digest::<sha2::Sha512>().unwrap()[..],
+                &sha2::Sha512::digest(&data)[..]
+            );
+        }
+        let expected: [u8; 32] = sha2::Sha256::digest(b"").into();
+        assert_eq!(
+            archive.file_data_at("empty").unwrap().sha256().unwrap(),
+            expected
+        );
+
+        // The position is restored when the data cannot be read either
+        let mut fdata = archive.file_data_at("corrupted").unwrap();
+        fdata.seek(SeekFrom::Start(0x123)).unwrap();
+        assert!(fdata.sha256().is_err());
+        assert_eq!(fdata.stream_position().unwrap(), 0x123);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_count_reads() {
+        let packed = write_zlib_file_data(&[b'a'; 0x3000], 0x1000);
+        let file = archive_file(&[TestEntry::File(b"zlib", packed.clone())]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(archive.metrics().unwrap(), Metrics::default());
+
+        let mut fdata = archive.file_data_at("zlib").unwrap();
+        let mut buf = vec![0u8; 0x3000];
+        fdata.read_exact(&mut buf).unwrap();
+        fdata.seek(SeekFrom::Start(0x2000)).unwrap();
+        fdata.read_exact(&mut buf[..0x10]).unwrap();
+        let _clone = fdata.try_clone().unwrap();
+        assert_eq!(
+            archive.metrics().unwrap(),
+            Metrics {
+                bytes_read: packed.len() as u64,
+                bytes_decompressed: 0x3000,
+                blocks_decompressed: 3,
+                cache_hits: 1,
+                cache_misses: 3,
+                // One per block read, then the explicit one
+                seeks: 4,
+                file_data_created: 2,
+            }
+        );
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn zlib_empty_file() {
+        let packed = write_zlib_file_data(b"", 0x100);
+        assert_eq!(packed.len() as u64, ZLIB_BLOCKTBL_OFFSET);
+        let file = archive_file(&[TestEntry::File(b"empty", packed)]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let mut fdata = archive.file_data_at("empty").unwrap();
+        assert!(fdata.is_compressed());
+        assert_eq!(fdata.size(), 0);
+        assert_eq!(fdata.num_blocks(), Some(0));
+        assert_eq!(fdata.block_of(0), None);
+        assert!(fdata.raw_block(0).is_err());
+        assert_eq!(fdata.seek(SeekFrom::End(0)).unwrap(), 0);
+        assert!(fdata.seek(SeekFrom::Start(1)).is_err());
+        let mut buf = [0u8; 4];
+        assert_eq!(fdata.read(&mut buf).unwrap(), 0);
+        let expected: [u8; 32] = sha2::Sha256::digest(b"").into();
+        assert_eq!(fdata.sha256().unwrap(), expected);
+        assert!(fdata.into_plain_reader().unwrap().get_ref().is_empty());
+
+        // A header alone is not enough for a non empty file
+        let mut packed = write_zlib_file_data(b"", 0x100);
+        LittleEndian::write_u32(&mut packed[4..8], 1);
+        assert!(zlib_file_data_from(&packed).is_err());
+    }
+
+    #[test]
+    fn zlib_empty_block() {
+        let mut packed = write_zlib_file_data(&sample_data(0x300), 0x100);
+        let tbl = ZLIB_BLOCKTBL_OFFSET as usize;
+        let first = LittleEndian::read_u32(&packed[tbl..tbl + 4]);
+        LittleEndian::write_u32(&mut packed[tbl + 4..tbl + 8], first);
+        let mut zlib = zlib_file_data_from(&packed).unwrap();
+        let err = zlib.read_block(0).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("is empty"));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn options_default_accepts_archive() {
+        let file = archive_file(&nested_dirs(3));
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        let mut dir = archive.root_directory();
+        for _ in 0..3 {
+            assert_eq!(dir.directories().len(), 1);
+            dir = &dir.directories()[0];
+        }
+        assert_eq!(dir.files()[0].name(), "leaf.txt");
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn options_max_depth() {
+        let file = archive_file(&nested_dirs(3));
+        let options = ArchiveOptions::new().max_depth(2);
+        assert!(open_archive_with(&file, &options).is_err());
+        let options = ArchiveOptions::new().max_depth(3);
+        assert!(open_archive_with(&file, &options).is_ok());
+
+        let lazy = Archive::open_lazy_with(
+            file.path().to_str().unwrap(),
+            &ArchiveOptions::new().max_depth(2),
+        )
+        .unwrap();
+        let level2 = &lazy.root_directory().directories()[0].directories()[0];
+        assert!(level2.load_children().is_ok());
+        assert!(level2.directories()[0].load_children().is_err());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn lazy_load_errors_are_returned() {
+        let file = archive_file(&nested_dirs(3));
+        let lazy = Archive::open_lazy_with(
+            file.path().to_str().unwrap(),
+            &ArchiveOptions::new().max_depth(2),
+        )
+        .unwrap();
+        let root = lazy.root_directory();
+        let level2 = &root.directories()[0].directories()[0];
+        let level3 = &level2.directories()[0];
+        assert!(level3.load_children().is_err());
+        // The accessors see no contents
+        assert!(level3.files().is_empty());
+        assert_eq!(level3.child_count(), 0);
+        assert!(level3.files_sorted_by_name().is_empty());
+        assert_eq!(level3.entries().count(), 0);
+
+        // Iterations end with the error
+        let depth_first: Vec<_> = root.iter_files_depth_first().collect();
+        assert_eq!(depth_first.len(), 1);
+        assert!(depth_first[0].is_err());
+        let files: Vec<_> = lazy.files().collect();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].is_err());
+        assert!(lazy.overlapping_files().is_err());
+        assert_eq!((&lazy).into_iter().count(), 0);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn corrupt_table_entries() {
+        // The longest name the format can hold is accepted
+        let file = archive_file(&[TestEntry::File(&[b'n'; MAX_NAME_LENGTH], Vec::new())]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        assert_eq!(
+            archive.root_directory().files()[0].name().len(),
+            MAX_NAME_LENGTH
+        );
+
+        let root = [TestEntry::File(b"f", b"data".to_vec())];
+        let file = TestArchiveBuilder::new(&root)
+            .corrupt(Corruption::DataPastEnd("f"))
+            .build_file();
+        let err = open_archive_with(&file, &ArchiveOptions::default())
+            .err()
+            .unwrap();
+        assert!(render_error(&err).contains("spans outside of the archive"));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn parse_errors_carry_offsets() {
+        let root = [
+            TestEntry::File(b"a", Vec::new()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"b", Vec::new())]),
+        ];
+        let data = write_archive(&root);
+        // Name table of d, the directory with index 3
+        let archive = Archive::open_from_reader(io::Cursor::new(&data)).unwrap();
+        let names_offset = archive.stat("d", false).unwrap().offset as usize;
+
+        // Same layout, with an entry of an unknown type in d
+        let garbage = archive_file(&[
+            TestEntry::File(b"a", Vec::new()),
+            TestEntry::Dir(b"d", vec![TestEntry::Typed(b"b", 0x62017c4a)]),
+        ]);
+        let truncated = TestArchiveBuilder::new(&root)
+            .corrupt(Corruption::LastBytes("d"))
+            .build_file();
+
+        for &(ref file, offset) in &[(garbage, names_offset), (truncated, data.len() - 4)] {
+            let path = file.path().to_str().unwrap();
+            let expected = format!("offset 0x{:x} of directory /d/ (index 3)", offset);
+            let err = Archive::open(path).err().unwrap();
+            assert!(
+                render_error(&err).contains(&expected),
+                "{}",
+                render_error(&err)
+            );
+            let err = ArchiveStream::open(path)
+                .unwrap()
+                .find(|r| r.is_err())
+                .unwrap()
+                .err()
+                .unwrap();
+            assert!(
+                render_error(&err).contains(&expected),
+                "{}",
+                render_error(&err)
+            );
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn options_strict_names() {
+        let file = archive_file(&[TestEntry::File(b"bad\xff.txt", Vec::new())]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        assert_eq!(
+            archive.root_directory().files()[0].name(),
+            "bad\u{fffd}.txt"
+        );
+        let options = ArchiveOptions::new().strict_names(true);
+        let err = open_archive_with(&file, &options).err().unwrap();
+        assert!(render_error(&err).contains("is not valid UTF-8: \"bad\\xff.txt\""));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn options_strict_entry_types() {
+        let file = archive_file(&[
+            TestEntry::Typed(b"unknown", 7),
+            TestEntry::File(b"known", Vec::new()),
+        ]);
+        assert!(open_archive_with(&file, &ArchiveOptions::default()).is_err());
+        let options = ArchiveOptions::new().strict_entry_types(false);
+        let archive = open_archive_with(&file, &options).unwrap();
+        let root = archive.root_directory();
+        assert_eq!(root.files().len(), 1);
+        assert_eq!(root.files()[0].name(), "known");
+        assert!(!root.has_subdirectories());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn recover_from_corrupt_entries() {
+        let root = [
+            TestEntry::File(b"a", b"a".to_vec()),
+            TestEntry::File(b"b", b"b".to_vec()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"c", b"c".to_vec())]),
+            TestEntry::Dir(
+                b"e",
+                vec![
+                    TestEntry::File(b"f", b"f".to_vec()),
+                    TestEntry::File(b"g", b"g".to_vec()),
+                ],
+            ),
+        ];
+        // The name of f is too long, which hides g
+        let file = TestArchiveBuilder::new(&root)
+            .corrupt(Corruption::DataPastEnd("b"))
+            .corrupt(Corruption::NameLength("e/f", 0x1000))
+            .build_file();
+
+        assert!(open_archive_with(&file, &ArchiveOptions::default()).is_err());
+        let options = ArchiveOptions::new().recover_errors(true);
+        let archive = open_archive_with(&file, &options).unwrap();
+        let paths: Vec<String> = archive.files().map(|e| e.unwrap().0).collect();
+        assert_eq!(paths, vec!["a", "d/c"]);
+        assert!(archive.is_partial());
+        let errors = archive.recovered_errors().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("spans outside of the archive"));
+        assert!(errors[1].contains("of directory /e/"));
+
+        let options = options.max_entries(2);
+        assert!(open_archive_with(&file, &options).is_err());
+        let file = archive_file(&[TestEntry::File(b"a", Vec::new())]);
+        let archive =
+            open_archive_with(&file, &ArchiveOptions::new().recover_errors(true)).unwrap();
+        assert!(!archive.is_partial());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn raw_dir_entries_keep_padding() {
+        let root = [
+            TestEntry::Typed(b"future", 7),
+            TestEntry::File(b"a", b"a".to_vec()),
+        ];
+        // Extend the root directory over the start of the file table
+        let file = TestArchiveBuilder::new(&root)
+            .corrupt(Corruption::PaddedNameTable("", 3))
+            .build_file();
+        let options = ArchiveOptions::new()
+            .strict_entry_types(false)
+            .recover_errors(true);
+        let archive = open_archive_with(&file, &options).unwrap();
+
+        let entries = archive.raw_dir_entries(archive.root_directory()).unwrap();
+        assert_eq!(entries.len(), 3);
+        let future = entries[0].as_ref().unwrap();
+        assert_eq!(
+            (future.entry_type, future.name_len, &future.name[..]),
+            (7, 6, &b"future"[..])
+        );
+        let a = entries[1].as_ref().unwrap();
+        assert_eq!(a.offset, future.offset + NAME_ENTRY_MIN_SIZE as u64 + 6);
+        assert_eq!(&a.name[..], b"a");
+        assert!(entries[2]
+            .as_ref()
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("Trailing 3 bytes"));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn unknown_entries_are_reported() {
+        let file = archive_file(&[TestEntry::Dir(
+            b"d",
+            vec![
+                TestEntry::Typed(b"future", 2),
+                TestEntry::File(b"f", b"f".to_vec()),
+            ],
+        )]);
+        assert!(open_archive_with(&file, &ArchiveOptions::default()).is_err());
+        let options = ArchiveOptions::new().strict_entry_types(false);
+        let archive = open_archive_with(&file, &options).unwrap();
+        assert!(archive.find("d/f").unwrap().is_some());
+        let unknown = archive.unknown_entries().unwrap();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].path, "d/future");
+        assert_eq!(unknown[0].entry_type, 2);
+        let names_offset = archive.stat("d", false).unwrap().offset as u64;
+        assert_eq!(unknown[0].offset, names_offset);
+    }
+
+    #[test]
+    fn entry_referring_to_its_parent() {
+        // Make the entry of d/f refer to the file table entry of d
+        let root = [TestEntry::Dir(
+            b"d",
+            vec![TestEntry::File(b"f", Vec::new())],
+        )];
+        let data = TestArchiveBuilder::new(&root)
+            .corrupt(Corruption::ParentIndex("d/f"))
+            .build();
+        let archive = Archive::open_from_reader(&data[..]).unwrap();
+        match archive.entry_by_index(2) {
+            Some(EntryRef::File(ref p, f)) => {
+                assert_eq!(p, "d/f");
+                assert_eq!(f.name(), "f");
+            }
+            _ => panic!("index 2 should now be the file d/f"),
+        }
+        assert_eq!(archive.root_directory().directories()[0].name(), Some("d"));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn entries_by_index() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", Vec::new()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"b", Vec::new())]),
+            TestEntry::Typed(b"unknown", 7),
+        ]);
+        let path = file.path().to_str().unwrap();
+        let options = ArchiveOptions::new().strict_entry_types(false);
+        let archive = Archive::open_with(path, &options).unwrap();
+        let lazy = Archive::open_lazy_with(path, &options).unwrap();
+        for archive in &[archive, lazy] {
+            match archive.entry_by_index(1) {
+                Some(EntryRef::Directory(ref p, d)) => {
+                    assert_eq!(p, "");
+                    assert!(d.name().is_none());
+                }
+                _ => panic!("index 1 should be the root directory"),
+            }
+            match archive.entry_by_index(2) {
+                Some(EntryRef::File(ref p, f)) => {
+                    assert_eq!(p, "a");
+                    assert_eq!(f.name(), "a");
+                }
+                _ => panic!("index 2 should be a file"),
+            }
+            match archive.entry_by_index(3) {
+                Some(EntryRef::Directory(ref p, d)) => {
+                    assert_eq!(p, "d/");
+                    assert_eq!(d.name(), Some("d"));
+                    assert_eq!(d.index(), 3);
+                }
+                _ => panic!("index 3 should be a directory"),
+            }
+            archive.find("d/b").unwrap().unwrap();
+            match archive.entry_by_index(4) {
+                Some(EntryRef::File(ref p, f)) => {
+                    assert_eq!(p, "d/b");
+                    assert_eq!(f.index(), 4);
+                }
+                _ => panic!("index 4 should be a file"),
+            }
+            assert!(matches!(
+                archive.entry_by_index(5),
+                Some(EntryRef::Unreferenced)
+            ));
+            assert!(archive.entry_by_index(0).is_none());
+            assert!(archive.entry_by_index(6).is_none());
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn file_handle_outlives_archive() {
+        let data = sample_data(0x300);
+        let file = archive_file(&[TestEntry::Dir(
+            b"d",
+            vec![TestEntry::File(b"z", write_zlib_file_data(&data, 0x100))],
+        )]);
+        let mut handle = {
+            let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+            assert!(archive.open_path("d/missing").is_err());
+            archive.open_path("/d/z").unwrap()
+        };
+        assert_eq!(handle.path(), "d/z");
+        assert_eq!(handle.index(), 3);
+        assert_eq!(handle.size(), data.len() as u64);
+        handle.seek(SeekFrom::Start(0x180)).unwrap();
+        let read = ::std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            handle.read_to_end(&mut buf).unwrap();
+            buf
+        })
+        .join()
+        .unwrap();
+        assert_eq!(read, &data[0x180..]);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn reload_picks_up_changes() {
+        let file = archive_file(&[TestEntry::File(b"a", b"first".to_vec())]);
+        let path = file.path().to_str().unwrap();
+        let mut archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+
+        let data = write_archive(&[
+            TestEntry::File(b"a", b"second".to_vec()),
+            TestEntry::File(b"b", Vec::new()),
+        ]);
+        fs::write(path, &data).unwrap();
+        archive.reload().unwrap();
+        assert_eq!(archive.path(), Some(file.path()));
+        assert!(Archive::open_from_reader(&data[..])
+            .unwrap()
+            .path()
+            .is_none());
+        let names: Vec<&str> = archive.root_directory().file_names().collect();
+        assert_eq!(names, vec!["a", "b"]);
+        let mut contents = Vec::new();
+        archive
+            .open_path("a")
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, b"second");
+
+        let filetbl_offset = archive.header().filetbl_offset as usize;
+        fs::write(path, &data[..filetbl_offset - 1]).unwrap();
+        match archive.reload() {
+            Err(Error(ErrorKind::ArchiveTruncated(len, offset), _)) => {
+                assert_eq!(len, filetbl_offset as u64 - 1);
+                assert_eq!(offset, filetbl_offset as u64);
+            }
+            _ => panic!("reloading a truncated archive should fail"),
+        }
+        assert_eq!(archive.root_directory().child_count(), 2);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn stat_entries() {
+        let data = sample_data(0x300);
+        let packed = write_zlib_file_data(&data, 0x100);
+        let packed_len = packed.len() as u32;
+        let file = archive_file(&[
+            TestEntry::File(b"plain", b"plain".to_vec()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"z", packed)]),
+        ]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+
+        let stat = archive.stat("d/z", false).unwrap();
+        assert_eq!(stat.kind, EntryType::File);
+        assert!(stat.kind.is_file() && !stat.kind.is_directory());
+        assert_eq!(stat.stored_size, packed_len);
+        assert_eq!(stat.unpacked_size, None);
+        assert_eq!(stat.index, 4);
+        assert_eq!(stat.offset, archive.find("d/z").unwrap().unwrap().offset());
+        let stat = archive.stat("d/z", true).unwrap();
+        assert_eq!(stat.unpacked_size, Some(data.len() as u64));
+        assert_eq!(archive.stat("plain", true).unwrap().unpacked_size, Some(5));
+
+        let stat = archive.stat("d", true).unwrap();
+        assert_eq!(stat.kind, EntryType::Directory);
+        assert!(stat.kind.is_directory() && !stat.kind.is_file());
+        assert!(!EntryType::Unknown(2).is_file());
+        assert_eq!(stat.index, 3);
+        assert_eq!(stat.unpacked_size, None);
+        assert_eq!(archive.stat("", false).unwrap().index, 1);
+        assert!(archive.stat("missing", false).is_err());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn header_fields() {
+        let file = archive_file(&[TestEntry::File(b"f", b"data".to_vec())]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        assert_eq!(
+            archive.header(),
+            HeaderInfo {
+                magic: *b"BPUL",
+                header_size: 0x24,
+                // Header, file data, then the root name table of 11 bytes
+                filetbl_offset: 0x24 + 4 + 11,
+            }
+        );
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn missing_table_offset() {
+        let file = TestArchiveBuilder::new(&[TestEntry::File(b"a", b"data".to_vec())])
+            .corrupt(Corruption::NoTableOffset)
+            .build_file();
+        let err = open_archive_with(&file, &ArchiveOptions::default())
+            .err()
+            .unwrap();
+        assert!(render_error(&err).contains("File table and file header are overlapping"));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn entry_count_from_table_size() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"data".to_vec()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"b", b"more".to_vec())]),
+        ]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        assert_eq!(archive.entry_count().unwrap(), 4);
+
+        // File table right after the header, followed by the root name table
+        // and the data of the file
+        let mut data = write_archive(&[])[..0x24].to_vec();
+        LittleEndian::write_u32(&mut data[0x1c..0x20], 0x24);
+        let mut entry = [0u8; 8];
+        for &(offset, size) in &[(0x34, 11), (0x3f, 1)] {
+            LittleEndian::write_u32(&mut entry[0..4], offset);
+            LittleEndian::write_u32(&mut entry[4..8], size);
+            data.extend_from_slice(&entry);
+        }
+        data.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 1, 0, b'a']);
+        data.push(b'x');
+        let archive = Archive::open_from_reader(io::Cursor::new(data)).unwrap();
+        assert_eq!(archive.entry_count().unwrap(), 2);
+        assert!(archive.find("a").unwrap().is_some());
+        assert!(archive.entry_by_index(3).is_none());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn files_with_depth() {
+        let file = archive_file(&[
+            TestEntry::File(b"b", Vec::new()),
+            TestEntry::Dir(
+                b"d",
+                vec![
+                    TestEntry::Dir(b"e", vec![TestEntry::File(b"f", Vec::new())]),
+                    TestEntry::File(b"c", Vec::new()),
+                ],
+            ),
+            TestEntry::File(b"a", Vec::new()),
+        ]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        let root = archive.root_directory();
+        let with_depth: Vec<(&str, usize)> = root
+            .iter_files_depth_first()
+            .map(|e| e.unwrap())
+            .map(|(f, depth)| (f.name(), depth))
+            .collect();
+        assert_eq!(with_depth, vec![("a", 0), ("b", 0), ("c", 1), ("f", 2)]);
+        let paths: Vec<String> = archive.files().map(|e| e.unwrap().0).collect();
+        assert_eq!(paths, vec!["a", "b", "d/c", "d/e/f"]);
+
+        let d = &root.directories()[0];
+        let with_depth: Vec<(&str, usize)> = d
+            .iter_files_depth_first()
+            .map(|e| e.unwrap())
+            .map(|(f, depth)| (f.name(), depth))
+            .collect();
+        assert_eq!(with_depth, vec![("c", 0), ("f", 1)]);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn data_at_offset() {
+        let data = sample_data(0x1800);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", b"plain data".to_vec()),
+            TestEntry::File(b"z", write_zlib_file_data(&data, 0x800)),
+        ]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        let read = |offset, size| {
+            let mut contents = Vec::new();
+            archive
+                .file_data_at_offset(offset, size)
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            contents
+        };
+        let z = archive.find("z").unwrap().unwrap();
+        assert!(read(z.offset(), z.size()) == data);
+        let plain = archive.find("plain").unwrap().unwrap();
+        assert_eq!(read(plain.offset() + 6, 4), b"data");
+
+        let len = file.as_file().metadata().unwrap().len() as u32;
+        assert_eq!(read(len, 0), b"");
+        assert!(archive.file_data_at_offset(len - 4, 5).is_err());
+        assert!(archive
+            .file_data_at_offset(0xffff_ffff, 0xffff_ffff)
+            .is_err());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn directory_child_counts() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", Vec::new()),
+            TestEntry::Dir(b"d", vec![TestEntry::Dir(b"e", Vec::new())]),
+        ]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        let root = archive.root_directory();
+        assert_eq!(root.child_count(), 2);
+        assert!(root.has_files() && root.has_subdirectories());
+        let d = &root.directories()[0];
+        assert_eq!(d.child_count(), 1);
+        assert!(!d.has_files() && d.has_subdirectories());
+        let e = &d.directories()[0];
+        assert!(e.is_empty());
+        assert!(!e.has_files() && !e.has_subdirectories());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn directory_names_and_entries() {
+        let file = archive_file(&[
+            TestEntry::File(b"b", Vec::new()),
+            TestEntry::Dir(b"d", Vec::new()),
+            TestEntry::File(b"a", Vec::new()),
+            TestEntry::Dir(b"c", Vec::new()),
+        ]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        let root = archive.root_directory();
+        assert_eq!(root.file_names().collect::<Vec<_>>(), vec!["b", "a"]);
+        assert_eq!(root.dir_names().collect::<Vec<_>>(), vec!["d", "c"]);
+        let entries: Vec<(&str, bool)> = root
+            .entries()
+            .map(|e| match e {
+                DirChild::File(f) => (f.name(), false),
+                DirChild::Directory(d) => (d.name().unwrap(), true),
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![("b", false), ("d", true), ("a", false), ("c", true)]
+        );
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn options_max_entries() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", Vec::new()),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"b", Vec::new())]),
+        ]);
+        let options = ArchiveOptions::new().max_entries(2);
+        assert!(open_archive_with(&file, &options).is_err());
+        let options = ArchiveOptions::new().max_entries(3);
+        assert!(open_archive_with(&file, &options).is_ok());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn options_max_block_size() {
+        let data = sample_data(0x300);
+        let file = archive_file(&[TestEntry::File(b"z", write_zlib_file_data(&data, 0x200))]);
+        let options = ArchiveOptions::new().max_block_size(0x100);
+        let archive = open_archive_with(&file, &options).unwrap();
+        assert!(archive
+            .file_data(&archive.root_directory().files()[0])
+            .is_err());
+
+        let options = ArchiveOptions::new().block_cache_entries(1);
+        let archive = open_archive_with(&file, &options).unwrap();
+        let mut fdata = archive
+            .file_data(&archive.root_directory().files()[0])
+            .unwrap();
+        let mut out = Vec::new();
+        fdata.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    proptest! {
+        #[test]
+        fn zlib_seek_read_matches_data(
+            data in prop::collection::vec(0u8..4, 0..0x1000),
+            blocksize in 1usize..0x200,
+            ops in prop::collection::vec((0usize..0x1100, 0usize..0x300), 1..32),
+        ) {
+            let mut zlib = zlib_file_data(&data, blocksize);
+            prop_assert_eq!(zlib.size(), data.len() as u64);
+            for (offset, len) in ops {
+                let seek_res = zlib.seek(SeekFrom::Start(offset as u64));
+                if offset > data.len() {
+                    prop_assert!(seek_res.is_err());
+                    continue;
+                }
+                prop_assert_eq!(seek_res.unwrap(), offset as u64);
+                let mut buf = vec![0u8; len];
+                let readlen = zlib.read(&mut buf).unwrap();
+                let expected = &data[offset..cmp::min(offset + len, data.len())];
+                prop_assert_eq!(&buf[..readlen], expected);
+            }
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn walk_orders() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"a".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![TestEntry::Dir(
+                    b"e",
+                    vec![TestEntry::File(b"c", b"c".to_vec())],
+                )],
+            ),
+            TestEntry::Dir(b"f", vec![TestEntry::File(b"g", b"g".to_vec())]),
+            TestEntry::File(b"z", b"z".to_vec()),
+        ]);
+        let archive = open_archive_with(&file, &ArchiveOptions::default()).unwrap();
+        let walk = |order| {
+            let mut paths = Vec::new();
+            archive
+                .walk_with(order, |path, _file| {
+                    paths.push(path.to_string());
+                    Ok(())
+                })
+                .unwrap();
+            paths
+        };
+        assert_eq!(walk(WalkOrder::DepthFirst), vec!["a", "z", "d/e/c", "f/g"]);
+        assert_eq!(
+            walk(WalkOrder::BreadthFirst),
+            vec!["a", "z", "f/g", "d/e/c"]
+        );
+        assert_eq!(walk(WalkOrder::DataOffset), vec!["a", "d/e/c", "f/g", "z"]);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn walk_and_find() {
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"a".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![
+                    TestEntry::Dir(b"e", vec![TestEntry::File(b"c", b"c".to_vec())]),
+                    TestEntry::File(b"b", b"b".to_vec()),
+                ],
+            ),
+        ]);
+        for &lazy in &[false, true] {
+            let path = file.path().to_str().unwrap();
+            let archive = if lazy {
+                Archive::open_lazy(path).unwrap()
+            } else {
+                Archive::open(path).unwrap()
+            };
+            let mut paths = Vec::new();
+            archive
+                .walk(|path, _file| {
+                    paths.push(path.to_string());
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(paths, vec!["a", "d/b", "d/e/c"]);
+            assert_eq!(archive.find("d/e/c").unwrap().unwrap().name(), "c");
+            assert_eq!(archive.find("/d/b").unwrap().unwrap().name(), "b");
+            assert!(archive.find("d/e").unwrap().is_none());
+            assert!(archive.find("x/c").unwrap().is_none());
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn file_data_clones_are_independent() {
+        let data = sample_data(0x300);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::File(b"zlib", write_zlib_file_data(&data, 0x100)),
+        ]);
+        let in_memory = Archive::open_from_reader(fs::File::open(file.path()).unwrap()).unwrap();
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        for &archive in &[&archive, &in_memory] {
+            for f in archive.root_directory().files() {
+                let mut first = archive.file_data(f).unwrap();
+                first.seek(SeekFrom::Start(0x10)).unwrap();
+                let mut second = first.try_clone().unwrap();
+                first.seek(SeekFrom::Start(0x200)).unwrap();
+
+                let mut buf = [0u8; 0x20];
+                second.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf[..], &data[0x10..0x30]);
+                first.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf[..], &data[0x200..0x220]);
+                second.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf[..], &data[0x30..0x50]);
+
+                let handle = ::std::thread::spawn(move || {
+                    let mut rest = Vec::new();
+                    second.read_to_end(&mut rest).unwrap();
+                    rest
+                });
+                assert_eq!(handle.join().unwrap(), &data[0x50..]);
+            }
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn file_data_clones_read_concurrently() {
+        let data = sample_data(0x8000);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::Zlib(b"zlib", data.clone(), 0x1000),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        for path in &["plain", "zlib"] {
+            let fdata = archive.file_data_at(path).unwrap();
+            let start = Arc::new(::std::sync::Barrier::new(4));
+            let threads: Vec<_> = (0..4)
+                .map(|t| {
+                    let mut reader = fdata.try_clone().unwrap();
+                    let expected = data.clone();
+                    let start = start.clone();
+                    ::std::thread::spawn(move || {
+                        let mut buf = [0u8; 0x40];
+                        start.wait();
+                        for i in 0..5000 {
+                            let offset = (t * 0x3d1 + i * 0x83) % (expected.len() - buf.len());
+                            reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+                            reader.read_exact(&mut buf).unwrap();
+                            assert!(buf[..] == expected[offset..offset + buf.len()]);
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn files_iteration_order() {
+        let file = archive_file(&[
+            TestEntry::File(b"b", Vec::new()),
+            TestEntry::Dir(
+                b"a",
+                vec![
+                    TestEntry::File(b"z", Vec::new()),
+                    TestEntry::Dir(b"d", vec![TestEntry::File(b"x", Vec::new())]),
+                    TestEntry::File(b"c", Vec::new()),
+                ],
+            ),
+            TestEntry::File(b"a.txt", Vec::new()),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let sorted: Vec<String> = archive.files().map(|e| e.unwrap().0).collect();
+        assert_eq!(sorted, vec!["a.txt", "a/c", "a/d/x", "a/z", "b"]);
+        let unsorted: Vec<String> = archive.files_unsorted().map(|e| e.unwrap().0).collect();
+        assert_eq!(unsorted, vec!["b", "a/z", "a/d/x", "a/c", "a.txt"]);
+        let mut names = Vec::new();
+        for (path, file) in &archive {
+            assert!(path.ends_with(file.name()));
+            names.push(path);
+        }
+        assert_eq!(names, sorted);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn file_data_block_info() {
+        let file = archive_file(&[
+            TestEntry::File(b"plain", sample_data(0x200)),
+            TestEntry::File(b"full", write_zlib_file_data(&sample_data(0x200), 0x100)),
+            TestEntry::File(b"partial", write_zlib_file_data(&sample_data(0x201), 0x100)),
+            TestEntry::File(b"empty", write_zlib_file_data(&[], 0x100)),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let info = |path: &str| {
+            let fdata = archive
+                .file_data(archive.find(path).unwrap().unwrap())
+                .unwrap();
+            (
+                fdata.is_compressed(),
+                fdata.block_size(),
+                fdata.num_blocks(),
+            )
+        };
+        assert_eq!(info("plain"), (false, None, None));
+        assert_eq!(info("full"), (true, Some(0x100), Some(2)));
+        assert_eq!(info("partial"), (true, Some(0x100), Some(3)));
+        assert_eq!(info("empty"), (true, Some(0x100), Some(0)));
+
+        let plain = archive.find("plain").unwrap().unwrap();
+        let start = plain.offset() as u64;
+        assert_eq!(
+            archive.file_data(plain).unwrap().byte_range(),
+            Some((start, start + 0x200))
+        );
+        let full = archive.find("full").unwrap().unwrap();
+        assert_eq!(archive.file_data(full).unwrap().byte_range(), None);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn stream_matches_tree() {
+        let file = archive_file(&[
+            TestEntry::File(b"b", b"b".to_vec()),
+            TestEntry::Dir(
+                b"a",
+                vec![
+                    TestEntry::Dir(b"d", vec![TestEntry::File(b"x", b"x".to_vec())]),
+                    TestEntry::File(b"c", b"c".to_vec()),
+                ],
+            ),
+        ]);
+        let path = file.path().to_str().unwrap();
+        let stream = ArchiveStream::open(path).unwrap();
+        let mut entries = Vec::new();
+        for entry in ArchiveStream::open(path).unwrap() {
+            let (path, file) = entry.unwrap();
+            let mut data = Vec::new();
+            stream
+                .file_data(&file)
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            assert_eq!(data, file.name().as_bytes());
+            entries.push(path);
+        }
+        assert_eq!(entries, vec!["b", "a/d/x", "a/c"]);
+
+        let deep = archive_file(&nested_dirs(3));
+        let options = ArchiveOptions::new().max_depth(2);
+        let mut stream = ArchiveStream::open_with(deep.path().to_str().unwrap(), &options).unwrap();
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn entries_sorted_by_offset() {
+        let file = archive_file(&[
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"first", b"1".to_vec())]),
+            TestEntry::File(b"second", b"2".to_vec()),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let paths: Vec<String> = archive
+            .sort_entries_by_offset()
+            .unwrap()
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect();
+        assert_eq!(paths, vec!["d/first", "second"]);
+        let sorted: Vec<String> = archive.files().map(|e| e.unwrap().0).collect();
+        assert_eq!(sorted, vec!["d/first", "second"]);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn overlapping_files() {
+        let root = [
+            TestEntry::File(b"a", b"0123456789".to_vec()),
+            TestEntry::File(b"b", b"abcdef".to_vec()),
+            TestEntry::File(b"c", b"0123456789".to_vec()),
+            TestEntry::File(b"e", Vec::new()),
+        ];
+        // b starts within a, c shares the data of a
+        let file = TestArchiveBuilder::new(&root)
+            .corrupt(Corruption::SharedData("b", "a", 8))
+            .corrupt(Corruption::SharedData("c", "a", 0))
+            .build_file();
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let a = archive.stat("a", false).unwrap().offset as u64;
+        assert_eq!(
+            archive.overlapping_files().unwrap(),
+            vec![DataOverlap {
+                first: "a".to_string(),
+                first_range: a..a + 10,
+                second: "b".to_string(),
+                second_range: a + 8..a + 14,
+            }]
+        );
+
+        let file = archive_file(&[
+            TestEntry::File(b"a", b"0123456789".to_vec()),
+            TestEntry::File(b"b", b"abcdef".to_vec()),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        assert!(archive.overlapping_files().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn seek_overflow_is_an_error() {
+        let data = sample_data(0x100);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::File(b"zlib", write_zlib_file_data(&data, 0x80)),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        for path in &["plain", "zlib"] {
+            let mut fdata = archive.file_data_at(path).unwrap();
+            assert_eq!(fdata.seek_relative(0x10).unwrap(), 0x10);
+            assert_eq!(fdata.seek_relative(-8).unwrap(), 8);
+            for &style in &[SeekFrom::Current(i64::MAX), SeekFrom::End(i64::MAX)] {
+                let err = fdata.seek(style).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            }
+            assert!(fdata.seek(SeekFrom::Current(i64::MIN)).is_err());
+            assert_eq!(fdata.stream_position().unwrap(), 8);
+            let mut buf = [0u8; 4];
+            fdata.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, &data[8..12]);
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn validate_reports_all_issues() {
+        let root = [
+            TestEntry::File(b"a", b"0123456789".to_vec()),
+            TestEntry::File(b"b", b"abcdef".to_vec()),
+            TestEntry::Zlib(b"z", sample_data(0x200), 0x80),
+            TestEntry::Typed(b"u", 7),
+        ];
+        let file = TestArchiveBuilder::new(&root)
+            .corrupt(Corruption::UnsortedBlockTable("z"))
+            .corrupt(Corruption::SharedData("b", "a", 8))
+            .build_file();
+        let options = ArchiveOptions::new()
+            .strict_entry_types(false)
+            .recover_errors(true);
+        let archive = open_archive_with(&file, &options).unwrap();
+
+        let issues = archive.validate().unwrap();
+        assert_eq!(issues.len(), 3, "{:?}", issues);
+        match issues[0] {
+            ValidationIssue::UnknownEntry(ref e) => assert_eq!(e.path, "u"),
+            ref i => panic!("unexpected issue: {}", i),
+        }
+        match issues[1] {
+            ValidationIssue::BadFileData(ref path, ref e) => {
+                assert_eq!(path, "z");
+                assert!(e.contains("not sorted"), "{}", e);
+            }
+            ref i => panic!("unexpected issue: {}", i),
+        }
+        match issues[2] {
+            ValidationIssue::Overlap(ref o) => assert_eq!((&*o.first, &*o.second), ("a", "b")),
+            ref i => panic!("unexpected issue: {}", i),
+        }
+
+        let file = archive_file(&[TestEntry::File(b"a", b"data".to_vec())]);
+        let archive = open_archive_with(&file, &options).unwrap();
+        assert!(archive.validate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn directories_sharing_a_name_table() {
+        let root = [
+            TestEntry::Dir(b"d1", vec![TestEntry::File(b"a", b"a".to_vec())]),
+            TestEntry::Dir(
+                b"d2",
+                vec![TestEntry::Dir(
+                    b"e",
+                    vec![TestEntry::File(b"b", b"b".to_vec())],
+                )],
+            ),
+            TestEntry::Dir(b"empty1", Vec::new()),
+            TestEntry::Dir(b"empty2", Vec::new()),
+        ];
+        let archive = Archive::open_from_reader(io::Cursor::new(write_archive(&root))).unwrap();
+        archive.validate_no_loops().unwrap();
+        let d1_offset = archive.stat("d1", false).unwrap().offset;
+
+        // Make d2/e point to the name table of d1
+        let data = TestArchiveBuilder::new(&root)
+            .corrupt(Corruption::SharedData("d2/e", "d1", 0))
+            .build();
+        let archive = Archive::open_from_reader(io::Cursor::new(data)).unwrap();
+        let e = archive.validate_no_loops().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "Directories share their name table: 0x{:x} (d1/, d2/e/)",
+                d1_offset
+            )
+        );
+        let issues = archive.validate().unwrap();
+        assert_eq!(
+            issues,
+            vec![ValidationIssue::SharedDirectory(
+                d1_offset,
+                vec!["d1/".to_string(), "d2/e/".to_string()]
+            )]
+        );
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn validate_reports_duplicate_names() {
+        let file = archive_file(&[
+            TestEntry::File(b"same", b"first".to_vec()),
+            TestEntry::File(b"same", b"second".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![
+                    TestEntry::File(b"x", b"file".to_vec()),
+                    TestEntry::Dir(b"x", vec![TestEntry::File(b"y", b"y".to_vec())]),
+                    TestEntry::File(b"z", b"z".to_vec()),
+                ],
+            ),
+        ]);
+        for archive in &[
+            open_archive_with(&file, &ArchiveOptions::default()).unwrap(),
+            Archive::open_lazy(file.path().to_str().unwrap()).unwrap(),
+        ] {
+            assert_eq!(
+                archive.validate().unwrap(),
+                vec![
+                    ValidationIssue::DuplicateName("d/x".to_string()),
+                    ValidationIssue::DuplicateName("same".to_string()),
+                ]
+            );
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn compressed_and_plain_files() {
+        let file = archive_file(&[
+            TestEntry::File(b"plain", b"data".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![TestEntry::File(
+                    b"zlib",
+                    write_zlib_file_data(&sample_data(0x100), 0x80),
+                )],
+            ),
+            TestEntry::File(b"empty", Vec::new()),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let paths = |files: Vec<(String, &File)>| -> Vec<String> {
+            files.into_iter().map(|(p, _)| p).collect()
+        };
+        assert_eq!(
+            paths(archive.list_compressed_files().unwrap()),
+            vec!["d/zlib"]
+        );
+        assert_eq!(
+            paths(archive.list_plain_files().unwrap()),
+            vec!["empty", "plain"]
+        );
+        let zlib = archive.find("d/zlib").unwrap().unwrap();
+        assert_eq!(zlib.compressed.get(), Some(&true));
+        assert!(archive.is_compressed(zlib).unwrap());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn directory_files_sorted() {
+        let file = archive_file(&[
+            TestEntry::File(b"b", b"12".to_vec()),
+            TestEntry::File(b"c", b"1".to_vec()),
+            TestEntry::File(b"a", b"123".to_vec()),
+            TestEntry::File(b"b", b"3".to_vec()),
+            TestEntry::Dir(b"0", Vec::new()),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let root = archive.root_directory();
+        let indices = |files: Vec<&File>| -> Vec<u32> { files.iter().map(|f| f.index()).collect() };
+        assert_eq!(indices(root.files_sorted_by_name()), vec![4, 2, 5, 3]);
+        assert_eq!(indices(root.files_sorted_by_size()), vec![4, 2, 3, 5]);
+        let stored: Vec<u32> = root.files().iter().map(|f| f.index()).collect();
+        assert_eq!(stored, vec![2, 3, 4, 5]);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn raw_blocks() {
+        let data = sample_data(0x180);
+        let mut stored = vec![0x55u8; 0x100];
+        stored.extend_from_slice(&data[..0x80]);
+        let file = archive_file(&[
+            TestEntry::File(b"plain", data.clone()),
+            TestEntry::File(b"zlib", write_zlib_file_data(&stored, 0x100)),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let mut plain = archive
+            .file_data(archive.find("plain").unwrap().unwrap())
+            .unwrap();
+        assert!(plain.raw_block(0).is_err());
+        assert!(plain.block_encoding(0).is_none());
+
+        let mut zlib = archive
+            .file_data(archive.find("zlib").unwrap().unwrap())
+            .unwrap();
+        let first = zlib.raw_block(0).unwrap();
+        assert!(first.is_compressed());
+        assert_eq!(first.offset(), 0xc + 2 * 4);
+        let second = zlib.raw_block(1).unwrap();
+        assert!(!second.is_compressed());
+        assert_eq!(second.offset(), first.offset() + first.data().len() as u64);
+        assert_eq!(second.data(), &data[..0x80]);
+        assert!(zlib.raw_block(2).is_err());
+        let encoding = |idx| zlib.block_encoding(idx).unwrap();
+        assert_eq!(encoding(0).unwrap(), BlockEncoding::Compressed);
+        assert_eq!(encoding(1).unwrap(), BlockEncoding::Uncompressed);
+        assert!(encoding(2).is_err());
+    }
+
+    #[test]
+    fn corrupted_test_archives() {
+        let root = vec![
+            TestEntry::File(b"a", b"a".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![
+                    TestEntry::Zlib(b"z", sample_data(0x300), 0x100),
+                    TestEntry::Dir(b"e", vec![TestEntry::File(b"b", b"b".to_vec())]),
+                ],
+            ),
+        ];
+        let open = |builder: TestArchiveBuilder| {
+            Archive::open_from_reader(io::Cursor::new(builder.build()))
+        };
+        let error = |builder| match open(builder) {
+            Ok(_) => panic!("The corrupted archive was opened"),
+            Err(e) => e
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join(": "),
+        };
+
+        let archive = open(TestArchiveBuilder::new(&root)).unwrap();
+        let mut data = archive
+            .file_data(archive.find("d/z").unwrap().unwrap())
+            .unwrap();
+        let mut out = Vec::new();
+        data.read_to_end(&mut out).unwrap();
+        assert_eq!(out, sample_data(0x300));
+
+        let bad_magic = error(TestArchiveBuilder::new(&root).corrupt(Corruption::BadMagic));
+        assert!(bad_magic.ends_with("Invalid magic"), "{}", bad_magic);
+        // The entry past the end of the file table cannot be read
+        let out_of_range =
+            open(TestArchiveBuilder::new(&root).corrupt(Corruption::IndexOutOfRange("d/e/b")))
+                .err()
+                .unwrap();
+        let cause = out_of_range
+            .1
+            .next_error
+            .as_ref()
+            .and_then(|e| e.downcast_ref::<Error>());
+        match cause.map(|e| e.kind()) {
+            Some(ErrorKind::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            _ => panic!("unexpected error: {}", render_error(&out_of_range)),
+        }
+        let looped = error(TestArchiveBuilder::new(&root).corrupt(Corruption::ParentIndex("d/e")));
+        assert!(looped.contains("Directory loop detected"), "{}", looped);
+
+        let archive =
+            open(TestArchiveBuilder::new(&root).corrupt(Corruption::UnsortedBlockTable("d/z")))
+                .unwrap();
+        let err = archive
+            .file_data(archive.find("d/z").unwrap().unwrap())
+            .err()
+            .unwrap();
+        assert!(err
+            .iter()
+            .any(|e| e.to_string().starts_with("Block table is not sorted")));
+    }
+
+    /* Shape of a random archive tree; names are given by position */
+    #[derive(Clone, Debug)]
+    enum TreeSpec {
+        /* Contents, and block size if stored ZLIB compressed */
+        File(Vec<u8>, Option<usize>),
+        Dir(Vec<TreeSpec>),
+    }
+
+    const TREE_NAMES: [&[u8]; 6] = [b"n0", b"n1", b"n2", b"n3", b"n4", b"n5"];
+
+    fn tree_spec() -> BoxedStrategy<Vec<TreeSpec>> {
+        let file = (
+            prop::collection::vec(0u8..16, 0..0x300),
+            prop::option::of(1usize..0x200),
+        )
+            .prop_map(|(data, blocksize)| TreeSpec::File(data, blocksize));
+        let tree = file.prop_recursive(4, 32, 6, |inner| {
+            prop::collection::vec(inner, 0..6).prop_map(TreeSpec::Dir)
+        });
+        prop::collection::vec(tree, 0..6).boxed()
+    }
+
+    /* Convert a tree to test entries, collecting the expected files and
+     * directories with their paths */
+    fn tree_entries(
+        specs: &[TreeSpec],
+        prefix: &str,
+        files: &mut Vec<(String, Vec<u8>)>,
+        dirs: &mut Vec<String>,
+    ) -> Vec<TestEntry> {
+        let mut entries = Vec::new();
+        for (spec, &name) in specs.iter().zip(TREE_NAMES.iter()) {
+            let path = format!("{}{}", prefix, String::from_utf8_lossy(name));
+            entries.push(match *spec {
+                TreeSpec::File(ref data, blocksize) => {
+                    files.push((path, data.clone()));
+                    match blocksize {
+                        Some(blocksize) => TestEntry::Zlib(name, data.clone(), blocksize),
+                        None => TestEntry::File(name, data.clone()),
+                    }
+                }
+                TreeSpec::Dir(ref children) => {
+                    let children = tree_entries(children, &format!("{}/", path), files, dirs);
+                    dirs.push(path);
+                    TestEntry::Dir(name, children)
+                }
+            });
+        }
+        entries
+    }
+
+    fn directory_paths(dir: &Directory, prefix: &str, paths: &mut Vec<String>) {
+        for d in dir.directories() {
+            let path = format!("{}{}", prefix, d.name().unwrap());
+            directory_paths(d, &format!("{}/", path), paths);
+            paths.push(path);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_archive_round_trip(specs in tree_spec()) {
+            let mut expected_files = Vec::new();
+            let mut expected_dirs = Vec::new();
+            let root = tree_entries(&specs, "", &mut expected_files, &mut expected_dirs);
+            let data = TestArchiveBuilder::new(&root).build();
+            let archive = Archive::open_from_reader(io::Cursor::new(data)).unwrap();
+
+            let mut files = Vec::new();
+            archive.walk(|path, file| {
+                let mut contents = Vec::new();
+                archive.file_data(file)?.read_to_end(&mut contents)?;
+                files.push((path.to_string(), contents));
+                Ok(())
+            }).unwrap();
+            let mut dirs = Vec::new();
+            directory_paths(archive.root_directory(), "", &mut dirs);
+            files.sort();
+            dirs.sort();
+            expected_files.sort();
+            expected_dirs.sort();
+            prop_assert_eq!(files, expected_files);
+            prop_assert_eq!(dirs, expected_dirs);
+        }
+    }
+
+    /* Benchmark of the parsing of an archive with 100k entries, run it with
+     * `cargo test --release -- --ignored --nocapture parse_large_archive` */
+    #[cfg(feature = "fs")]
+    #[test]
+    #[ignore]
+    fn parse_large_archive() {
+        let mut builder = ::builder::ArchiveBuilder::new();
+        for d in 0..100 {
+            let dir = format!("directory_{:04}", d);
+            builder.add_directory("", &dir).unwrap();
+            for f in 0..1000 {
+                let name = format!("some_file_name_{:06}.dat", f);
+                builder.add_file(&dir, &name, Vec::new()).unwrap();
+            }
+        }
+        let outdir = tempfile::tempdir().unwrap();
+        let output = outdir.path().join("large.hpk");
+        let output = output.to_str().unwrap();
+        builder.finish(output).unwrap();
+
+        let mut best = ::std::time::Duration::MAX;
+        for _ in 0..5 {
+            let start = ::std::time::Instant::now();
+            let archive = Archive::open(output).unwrap();
+            best = cmp::min(best, start.elapsed());
+            assert_eq!(archive.files_unsorted().count(), 100_000);
+        }
+        println!("Parsed 100100 entries in {:?}", best);
     }
 }
@@ -0,0 +1,188 @@
+/* Read-only filesystem of the vfs crate backed by an archive. */
+
+extern crate vfs;
+
+use self::vfs::error::VfsErrorKind;
+use self::vfs::{FileSystem, SeekAndRead, VfsError, VfsFileType, VfsMetadata, VfsResult};
+use ::errors::*;
+use hpk::{Archive, Directory, File};
+use std::fmt;
+use std::io::Write;
+
+/** Filesystem of the `vfs` crate exposing the files and directories of an
+ * archive. It is read-only: operations modifying it fail with
+ * `VfsErrorKind::NotSupported`. */
+pub struct HpkFS {
+    archive: Archive,
+}
+
+impl fmt::Debug for HpkFS {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HpkFS")
+    }
+}
+
+/* Report the errors of the archive with their causes, except for missing
+ * paths which vfs has a kind for. */
+fn vfs_error(e: Error) -> VfsError {
+    if let ErrorKind::NoSuchPath(_) = *e.kind() {
+        return VfsErrorKind::FileNotFound.into();
+    }
+    let messages: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+    VfsErrorKind::Other(messages.join(": ")).into()
+}
+
+fn not_supported<T>() -> VfsResult<T> {
+    Err(VfsErrorKind::NotSupported.into())
+}
+
+impl HpkFS {
+    pub fn new(archive: Archive) -> HpkFS {
+        HpkFS { archive: archive }
+    }
+
+    /* Look up the directory at a '/' separated path, loading its contents. */
+    fn directory(&self, path: &str) -> Result<Option<&Directory>> {
+        let mut dir = self.archive.root_directory();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            dir.load_children()?;
+            dir = match dir
+                .directories()
+                .iter()
+                .find(|d| d.name() == Some(component))
+            {
+                Some(d) => d,
+                None => return Ok(None),
+            };
+        }
+        dir.load_children()?;
+        Ok(Some(dir))
+    }
+
+    fn file(&self, path: &str) -> VfsResult<&File> {
+        match self.archive.find(path).map_err(vfs_error)? {
+            Some(f) => Ok(f),
+            None => Err(VfsErrorKind::FileNotFound.into()),
+        }
+    }
+}
+
+impl FileSystem for HpkFS {
+    fn read_dir(&self, path: &str) -> VfsResult<Box<dyn Iterator<Item = String> + Send>> {
+        let dir = match self.directory(path).map_err(vfs_error)? {
+            Some(d) => d,
+            None => return Err(VfsErrorKind::FileNotFound.into()),
+        };
+        let mut names: Vec<String> = dir.dir_names().map(|n| n.to_string()).collect();
+        names.extend(dir.files().iter().map(|f| f.name().to_string()));
+        Ok(Box::new(names.into_iter()))
+    }
+
+    fn open_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndRead + Send>> {
+        let file = self.file(path)?;
+        let data = self.archive.file_data(file).map_err(vfs_error)?;
+        Ok(Box::new(data))
+    }
+
+    fn metadata(&self, path: &str) -> VfsResult<VfsMetadata> {
+        if let Some(file) = self.archive.find(path).map_err(vfs_error)? {
+            let data = self.archive.file_data(file).map_err(vfs_error)?;
+            return Ok(VfsMetadata {
+                file_type: VfsFileType::File,
+                len: data.size(),
+            });
+        }
+        match self.directory(path).map_err(vfs_error)? {
+            Some(_) => Ok(VfsMetadata {
+                file_type: VfsFileType::Directory,
+                len: 0,
+            }),
+            None => Err(VfsErrorKind::FileNotFound.into()),
+        }
+    }
+
+    fn exists(&self, path: &str) -> VfsResult<bool> {
+        if self.archive.find(path).map_err(vfs_error)?.is_some() {
+            return Ok(true);
+        }
+        Ok(self.directory(path).map_err(vfs_error)?.is_some())
+    }
+
+    fn create_dir(&self, _path: &str) -> VfsResult<()> {
+        not_supported()
+    }
+
+    fn create_file(&self, _path: &str) -> VfsResult<Box<dyn Write + Send>> {
+        not_supported()
+    }
+
+    fn append_file(&self, _path: &str) -> VfsResult<Box<dyn Write + Send>> {
+        not_supported()
+    }
+
+    fn remove_file(&self, _path: &str) -> VfsResult<()> {
+        not_supported()
+    }
+
+    fn remove_dir(&self, _path: &str) -> VfsResult<()> {
+        not_supported()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::vfs::VfsPath;
+    use super::*;
+    use std::io::Cursor;
+    use testutil::*;
+
+    #[test]
+    fn walk_archive_through_vfs() {
+        let data = sample_data(0x2000);
+        let archive = Archive::open_from_reader(Cursor::new(write_archive(&[
+            TestEntry::File(b"a.txt", b"hello".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![
+                    TestEntry::File(b"z", write_zlib_file_data(&data, 0x800)),
+                    TestEntry::Dir(b"e", Vec::new()),
+                ],
+            ),
+        ])))
+        .unwrap();
+        let root: VfsPath = HpkFS::new(archive).into();
+
+        let mut paths: Vec<String> = root
+            .walk_dir()
+            .unwrap()
+            .map(|p| p.unwrap().as_str().to_string())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["/a.txt", "/d", "/d/e", "/d/z"]);
+
+        assert_eq!(
+            root.join("a.txt").unwrap().read_to_string().unwrap(),
+            "hello"
+        );
+        let z = root.join("d/z").unwrap();
+        let mut contents = Vec::new();
+        z.open_file().unwrap().read_to_end(&mut contents).unwrap();
+        assert!(contents == data);
+        assert_eq!(z.metadata().unwrap().len, data.len() as u64);
+        assert_eq!(z.metadata().unwrap().file_type, VfsFileType::File);
+        assert!(root.join("d/e").unwrap().is_dir().unwrap());
+        assert!(!root.join("missing").unwrap().exists().unwrap());
+        let kind = |res: VfsResult<()>| match res {
+            Err(e) => e.kind().to_string(),
+            Ok(()) => "success".to_string(),
+        };
+        assert_eq!(
+            kind(root.join("missing").unwrap().open_file().map(|_| ())),
+            VfsErrorKind::FileNotFound.to_string()
+        );
+        assert_eq!(
+            kind(root.join("new").unwrap().create_file().map(|_| ())),
+            VfsErrorKind::NotSupported.to_string()
+        );
+    }
+}
@@ -0,0 +1,37 @@
+/* Reading, writing and extracting HPK archives, as done by the unpacker in
+ * main.rs. The modules are public for the benchmarks and the integration
+ * tests, and for other programs handling archives. */
+
+// `error_chain!` can recurse deeply
+#![recursion_limit = "1024"]
+
+#[macro_use]
+extern crate error_chain;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+#[cfg(feature = "tokio")]
+pub mod async_data;
+pub mod builder;
+pub mod cache;
+pub mod diff;
+pub mod extract;
+pub mod glob;
+pub mod hpk;
+#[cfg(feature = "vfs")]
+pub mod hpkfs;
+mod json;
+pub mod list;
+pub mod merged;
+pub mod resume;
+#[cfg(test)]
+// Most of the helpers are only used by the tests opening archive files
+#[cfg_attr(not(feature = "fs"), allow(dead_code))]
+mod testutil;
+
+// We'll put our errors in an `errors` module, and other modules in
+// this crate will `use errors::*;` to get access to everything
+// `error_chain!` creates.
+pub mod errors;
@@ -0,0 +1,321 @@
+use ::errors::*;
+use glob::GlobPattern;
+use hpk::{Archive, DirChild, Directory, File};
+use json::json_string;
+use std::io::Write;
+
+/* ANSI escape sequences of the colors of the long listing */
+const COLOR_DIRECTORY: &str = "\x1b[1;34m";
+const COLOR_RESET: &str = "\x1b[0m";
+
+/** How `write_listing()` formats the files of an archive. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListFormat {
+    /** One path per line */
+    Text,
+    /** One JSON object per line, with the path, size, compressed size and
+     * whether the file is compressed */
+    Ndjson,
+    /** Size and path of each file and directory, see `ListStyle` */
+    Long,
+}
+
+impl ListFormat {
+    pub fn parse(s: &str) -> Result<ListFormat> {
+        match s {
+            "text" => Ok(ListFormat::Text),
+            "ndjson" => Ok(ListFormat::Ndjson),
+            "long" => Ok(ListFormat::Long),
+            _ => bail!(
+                "Invalid list format \"{}\", expected text, ndjson or long",
+                s
+            ),
+        }
+    }
+}
+
+/** When to color the long listing. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    /** Only when the output is a terminal */
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(s: &str) -> Result<ColorChoice> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => bail!(
+                "Invalid color setting \"{}\", expected auto, always or never",
+                s
+            ),
+        }
+    }
+}
+
+/** How the long listing is rendered. The default is plain output, suitable
+ * for scripts. */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ListStyle {
+    /** Show directories in color, with ANSI escape sequences */
+    pub color: bool,
+    /** Show sizes with binary suffixes, such as 1.5 MiB, instead of exact
+     * byte counts */
+    pub human_sizes: bool,
+}
+
+/** Render a size with the largest binary suffix keeping it at least 1, with
+ * one decimal. Sizes below 1 KiB are exact. */
+pub fn human_size(bytes: u64) -> String {
+    const SUFFIXES: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut suffix = 0;
+    while value >= 1024.0 && suffix + 1 < SUFFIXES.len() {
+        value /= 1024.0;
+        suffix += 1;
+    }
+    format!("{:.1} {}", value, SUFFIXES[suffix])
+}
+
+/* Write the entries of a directory and of its subdirectories, in
+ * lexicographical order of their paths. Only the entries matching the
+ * pattern are written, and the directories which cannot hold any are not
+ * visited. */
+fn write_long_dir<W: Write>(
+    archive: &Archive,
+    dir: &Directory,
+    prefix: &str,
+    style: &ListStyle,
+    include: Option<&GlobPattern>,
+    out: &mut W,
+) -> Result<()> {
+    dir.load_children()?;
+    let mut children: Vec<DirChild> = dir.entries().collect();
+    children.sort_by_key(|c| c.sort_key());
+    for child in children {
+        match child {
+            DirChild::File(f) => {
+                let path = format!("{}{}", prefix, f.name());
+                if let Some(pattern) = include {
+                    if !pattern.matches(&path) {
+                        continue;
+                    }
+                }
+                let size = archive
+                    .file_data(f)
+                    .chain_err(|| format!("Unable to read {}", path))?
+                    .size();
+                let size = if style.human_sizes {
+                    human_size(size)
+                } else {
+                    size.to_string()
+                };
+                writeln!(out, "{:>10}  {}", size, path)?;
+            }
+            DirChild::Directory(d) => {
+                let path = format!("{}{}/", prefix, d.name().unwrap_or(""));
+                if let Some(pattern) = include {
+                    if !pattern.matches(&path) {
+                        if pattern.may_match_in(&path) {
+                            write_long_dir(archive, d, &path, style, include, out)?;
+                        }
+                        continue;
+                    }
+                }
+                if style.color {
+                    writeln!(
+                        out,
+                        "{:>10}  {}{}{}",
+                        "-", COLOR_DIRECTORY, path, COLOR_RESET
+                    )?;
+                } else {
+                    writeln!(out, "{:>10}  {}", "-", path)?;
+                }
+                write_long_dir(archive, d, &path, style, include, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/* Call f with the path of each file of the archive matching the pattern,
+ * in the order of `Archive::files()`. */
+fn for_each_file<F>(archive: &Archive, include: Option<&GlobPattern>, mut f: F) -> Result<()>
+where
+    F: FnMut(&str, &File) -> Result<()>,
+{
+    for entry in archive.files() {
+        let (path, file) = entry?;
+        if let Some(pattern) = include {
+            if !pattern.matches(&path) {
+                continue;
+            }
+        }
+        f(&path, file)?;
+    }
+    Ok(())
+}
+
+/** Write the files of an archive to out, one per line. Each line is written
+ * as soon as its file is visited, so that consumers can process the output
+ * as a stream. The style only applies to `ListFormat::Long`. With a
+ * pattern, only the entries whose path matches it are written. */
+pub fn write_listing<W: Write>(
+    archive: &Archive,
+    format: ListFormat,
+    style: &ListStyle,
+    include: Option<&GlobPattern>,
+    out: &mut W,
+) -> Result<()> {
+    match format {
+        ListFormat::Text => for_each_file(archive, include, |path, _| {
+            writeln!(out, "{}", path)?;
+            Ok(())
+        }),
+        ListFormat::Ndjson => for_each_file(archive, include, |path, file| {
+            let data = archive
+                .file_data(file)
+                .chain_err(|| format!("Unable to read {}", path))?;
+            writeln!(
+                out,
+                "{{\"path\":{},\"size\":{},\"compressed_size\":{},\"is_compressed\":{}}}",
+                json_string(path),
+                data.size(),
+                file.size(),
+                data.is_compressed()
+            )?;
+            Ok(())
+        }),
+        ListFormat::Long => {
+            write_long_dir(archive, archive.root_directory(), "", style, include, out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "fs")]
+    use testutil::*;
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn list_archive_as_ndjson() {
+        let data = sample_data(0x3000);
+        let file = archive_file(&[
+            TestEntry::File(b"a\"b", b"plain".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![TestEntry::File(b"z", write_zlib_file_data(&data, 0x1000))],
+            ),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let zlib_size = archive.files().nth(1).unwrap().unwrap().1.size();
+        let render = |format| {
+            let mut out = Vec::new();
+            write_listing(&archive, format, &ListStyle::default(), None, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(render(ListFormat::Text), "a\"b\nd/z\n");
+        assert_eq!(
+            render(ListFormat::Ndjson),
+            format!(
+                "{{\"path\":\"a\\\"b\",\"size\":5,\"compressed_size\":5,\"is_compressed\":false}}\n\
+                 {{\"path\":\"d/z\",\"size\":12288,\"compressed_size\":{},\"is_compressed\":true}}\n",
+                zlib_size
+            )
+        );
+        assert!(ListFormat::parse("json").is_err());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn long_listing_styles() {
+        let file = archive_file(&[
+            TestEntry::File(b"big", vec![0; 0x18000]),
+            TestEntry::Dir(b"d", vec![TestEntry::File(b"small", vec![0; 10])]),
+        ]);
+        let archive = Archive::open(file.path().to_str().unwrap()).unwrap();
+        let render = |color, human_sizes| {
+            let style = ListStyle {
+                color: color,
+                human_sizes: human_sizes,
+            };
+            let mut out = Vec::new();
+            write_listing(&archive, ListFormat::Long, &style, None, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            render(false, false),
+            "     98304  big\n         -  d/\n        10  d/small\n"
+        );
+        assert_eq!(
+            render(true, true),
+            "  96.0 KiB  big\n         -  \x1b[1;34md/\x1b[0m\n      10 B  d/small\n"
+        );
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn list_matching_entries() {
+        let file = archive_file(&[
+            TestEntry::File(b"a.txt", vec![0; 1]),
+            TestEntry::Dir(
+                b"d",
+                vec![
+                    TestEntry::File(b"b.txt", vec![0; 2]),
+                    TestEntry::File(b"c.bin", vec![0; 3]),
+                ],
+            ),
+            TestEntry::Dir(b"e", vec![TestEntry::File(b"f.txt", vec![0; 4])]),
+        ]);
+        let archive = Archive::open_lazy(file.path().to_str().unwrap()).unwrap();
+        let render = |format, pattern| {
+            let pattern = GlobPattern::new(pattern).unwrap();
+            let mut out = Vec::new();
+            write_listing(
+                &archive,
+                format,
+                &ListStyle::default(),
+                Some(&pattern),
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            render(ListFormat::Long, "d/*"),
+            "         2  d/b.txt\n         3  d/c.bin\n"
+        );
+        // Only d was needed for this listing
+        let root = archive.root_directory();
+        assert!(!root.directories()[1].is_loaded());
+        assert_eq!(
+            render(ListFormat::Long, "?"),
+            "         -  d/\n         -  e/\n"
+        );
+        assert_eq!(
+            render(ListFormat::Text, "**/*.txt"),
+            "a.txt\nd/b.txt\ne/f.txt\n"
+        );
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(3 << 20), "3.0 MiB");
+        assert_eq!(human_size(5 << 40), "5.0 TiB");
+        assert_eq!(human_size(2048 << 40), "2048.0 TiB");
+        assert_eq!(ColorChoice::parse("always").unwrap(), ColorChoice::Always);
+        assert!(ColorChoice::parse("yes").is_err());
+    }
+}
@@ -4,195 +4,815 @@
 #[macro_use]
 extern crate error_chain;
 extern crate getopts;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "tracing")]
+extern crate tracing_subscriber;
+extern crate tropico5_hpk_unpacker;
 
-mod hpk;
+use tropico5_hpk_unpacker::errors::*;
+use tropico5_hpk_unpacker::hpk;
 
-// We'll put our errors in an `errors` module, and other modules in
-// this crate will `use errors::*;` to get access to everything
-// `error_chain!` creates.
-mod errors {
-    // Create the Error, ErrorKind, ResultExt, and Result types
-    error_chain! {
-        foreign_links {
-            Fmt(::std::fmt::Error);
-            Io(::std::io::Error) #[cfg(unix)];
-        }
-    }
-}
+use tropico5_hpk_unpacker::builder::{
+    pack_directory, ArchiveBuilder, MergePolicy, DEFAULT_COMPRESSION_LEVEL,
+};
+use tropico5_hpk_unpacker::cache::ExtractionCache;
+use tropico5_hpk_unpacker::diff::{write_diff, DiffFormat};
+use tropico5_hpk_unpacker::extract::{
+    check_extracted, extract_archive, extract_stream, parse_mode, parse_size, read_all_files,
+    ExtractOptions,
+};
+use tropico5_hpk_unpacker::glob::GlobPattern;
+use tropico5_hpk_unpacker::hpk::{Archive, ArchiveOptions, ArchiveStream, BlockEncoding};
+use tropico5_hpk_unpacker::list::{write_listing, ColorChoice, ListFormat, ListStyle};
+use tropico5_hpk_unpacker::merged::MergedArchive;
+use tropico5_hpk_unpacker::resume::ResumeState;
 
-use errors::*;
-
-use hpk::Archive;
-use hpk::Directory;
-use std::iter::Peekable;
-use std::slice::Iter;
-
-struct DirCtx<'a> {
-    dir: &'a Directory,
-    iter: Peekable<Iter<'a, Directory>>,
-}
+use std::io::IsTerminal;
 
 fn main() {
-    if let Err(ref e) = run() {
-        use std::io::Write;
-        let stderr = &mut ::std::io::stderr();
-        let errmsg = "Error writing to stderr";
+    match run() {
+        Ok(code) => {
+            if code != 0 {
+                ::std::process::exit(code);
+            }
+        }
+        Err(ref e) => {
+            use std::io::Write;
+            let stderr = &mut ::std::io::stderr();
+            let errmsg = "Error writing to stderr";
 
-        writeln!(stderr, "error: {}", e).expect(errmsg);
+            writeln!(stderr, "error: {}", e).expect(errmsg);
 
-        for e in e.iter().skip(1) {
-            writeln!(stderr, "caused by: {}", e).expect(errmsg);
-        }
+            for e in e.iter().skip(1) {
+                writeln!(stderr, "caused by: {}", e).expect(errmsg);
+            }
 
-        if let Some(backtrace) = e.backtrace() {
-            writeln!(stderr, "backtrace: {:?}", backtrace).expect(errmsg);
-        }
+            if let Some(backtrace) = e.backtrace() {
+                writeln!(stderr, "backtrace: {:?}", backtrace).expect(errmsg);
+            }
 
-        ::std::process::exit(1);
+            ::std::process::exit(1);
+        }
     }
 }
 
-fn build_path(dir: &Directory, dirstack: &Vec<DirCtx>) -> String {
-    let mut path = String::new();
-    for ctx in dirstack {
-        if let Some(n) = ctx.dir.name() {
-            path.push_str(n);
-            path.push(::std::path::MAIN_SEPARATOR);
-        };
-    }
-    if let Some(n) = dir.name() {
-        path.push_str(n);
-        path.push(::std::path::MAIN_SEPARATOR);
+/* Write the raw contents of a block of a compressed file to stdout. The
+ * block is designated by the path of the file in the archive and the index of
+ * the block, separated by a colon. */
+fn dump_block(archive: &Archive, spec: &str) -> Result<()> {
+    use std::io::Write;
+    let (path, idx) = match spec.rfind(':') {
+        Some(pos) => (&spec[..pos], &spec[pos + 1..]),
+        None => bail!("Invalid block specification \"{}\", expected PATH:N", spec),
     };
-    path
+    let idx: u32 = idx
+        .parse()
+        .chain_err(|| format!("Invalid block index \"{}\"", idx))?;
+    let block = archive.file_data_at(path)?.raw_block(idx)?;
+    eprintln!(
+        "block {} of {}: offset 0x{:x}, {} bytes, {} ({} bytes unpacked)",
+        idx,
+        path,
+        block.offset(),
+        block.data().len(),
+        if block.is_compressed() {
+            "deflated"
+        } else {
+            "stored"
+        },
+        block.unpacked_size()
+    );
+    let stdout = ::std::io::stdout();
+    let mut out = stdout.lock();
+    out.write_all(block.data())?;
+    Ok(())
 }
 
-fn foreach_dir_in_dir<F>(_archive: &Archive, dir: &Directory, closure: F) -> Result<()>
-where
-    F: Fn(&Directory, &str, u16) -> Result<()>,
-{
-    // Initial state
-    let mut dirstack: Vec<DirCtx> = Vec::new();
-    let mut ctx = DirCtx {
-        dir: dir,
-        iter: dir.directories().iter().peekable(),
-    };
-
-    // Process root directory
-    closure(
-        ctx.dir,
-        &build_path(ctx.dir, &dirstack),
-        dirstack.len() as u16,
-    )?;
-
-    while !dirstack.is_empty() || !ctx.iter.peek().is_none() {
-        let next_dir = ctx.iter.next();
-        match next_dir {
-            None => {
-                /* Last directory for this level processed, resume to where we left off in
-                 * the parent directory. */
-                ctx = dirstack.pop().unwrap();
-            }
-            Some(d) => {
-                dirstack.push(ctx);
-                ctx = DirCtx {
-                    dir: d,
-                    iter: d.directories().iter().peekable(),
-                };
-                closure(
-                    ctx.dir,
-                    &build_path(ctx.dir, &dirstack),
-                    dirstack.len() as u16,
-                )?;
-            }
-        };
+/* Print which entry of the archive refers to a file table index. */
+fn dump_index(archive: &Archive, idx: &str) -> Result<()> {
+    use hpk::EntryRef;
+    let idx: u32 = idx
+        .parse()
+        .chain_err(|| format!("Invalid index \"{}\"", idx))?;
+    match archive.entry_by_index(idx) {
+        Some(EntryRef::File(path, file)) => println!(
+            "index {}: file {} (offset 0x{:x}, {} bytes)",
+            idx,
+            path,
+            file.offset(),
+            file.size()
+        ),
+        Some(EntryRef::Directory(path, dir)) => {
+            dir.load_children()?;
+            println!(
+                "index {}: directory /{} ({} entries)",
+                idx,
+                path,
+                dir.child_count()
+            )
+        }
+        Some(EntryRef::Unreferenced) => {
+            println!("index {}: not referenced by any directory", idx)
+        }
+        None => bail!("Index {} is outside of the file table", idx),
     }
     Ok(())
 }
 
-fn foreach_file_in_dir<F>(archive: &Archive, dir: &Directory, closure: F) -> Result<()>
-where
-    F: Fn(&hpk::File, &str, u16) -> Result<()>,
-{
-    foreach_dir_in_dir(archive, dir, |dir, path, level| {
-        for f in dir.files() {
-            closure(f, path, level)?;
+/* Print the structure of the archive as stored: for each entry, the offset
+ * of its name table entry, its file table index, the offset and size it
+ * refers to, and its kind, followed by its path. With show_aliases, files
+ * whose data is at the same offset as that of a file listed before are
+ * followed by the path of that first file. */
+fn list_offsets(archive: &Archive, show_aliases: bool) -> Result<()> {
+    use hpk::{DirChild, Directory};
+    use std::collections::HashMap;
+    fn list_dir(
+        archive: &Archive,
+        dir: &Directory,
+        prefix: &str,
+        aliases: &mut Option<HashMap<u32, String>>,
+    ) -> Result<()> {
+        dir.load_children()?;
+        for child in dir.entries() {
+            match child {
+                DirChild::File(f) => {
+                    let path = format!("{}{}", prefix, f.name());
+                    let mut alias_of = String::new();
+                    /* An unreadable file is reported in its line, so that the
+                     * other files are still listed */
+                    let (kind, unreadable) = match archive.file_data(f) {
+                        Ok(ref data) if data.is_compressed() => ("zlib", String::new()),
+                        Ok(_) => ("plain", String::new()),
+                        Err(e) => ("error", format!(" (unreadable: {})", e)),
+                    };
+                    if let Some(ref mut aliases) = *aliases {
+                        // Empty files share their offset without sharing any data
+                        if f.size() > 0 {
+                            match aliases.get(&f.offset()) {
+                                Some(first) => alias_of = format!(" (alias of {})", first),
+                                None => {
+                                    aliases.insert(f.offset(), path.clone());
+                                }
+                            }
+                        }
+                    }
+                    println!(
+                        "0x{:08x} {:>8} 0x{:08x} {:>10} {:<5} {}{}{}",
+                        f.name_offset(),
+                        f.index(),
+                        f.offset(),
+                        f.size(),
+                        kind,
+                        path,
+                        alias_of,
+                        unreadable
+                    );
+                }
+                DirChild::Directory(d) => {
+                    let path = format!("{}{}/", prefix, d.name().unwrap_or(""));
+                    println!(
+                        "0x{:08x} {:>8} 0x{:08x} {:>10} {:<5} {}",
+                        d.name_offset().unwrap_or(0),
+                        d.index(),
+                        d.offset(),
+                        d.size(),
+                        "dir",
+                        path
+                    );
+                    list_dir(archive, d, &path, aliases)?;
+                }
+            }
         }
         Ok(())
-    })
-}
-
-fn list_archive(archive: &Archive) -> Result<()> {
-    foreach_file_in_dir(archive, archive.root_directory(), |file, path, _level| {
-        let mut display_path = String::new();
-        println!("{}{}", path, file.name());
-        unimplemented!();
-        Ok(())
-    })
+    }
+    let root = archive.root_directory();
+    println!(
+        "{:<10} {:>8} {:<10} {:>10} {:<5} path",
+        "name_off", "index", "offset", "size", "kind"
+    );
+    println!(
+        "{:<10} {:>8} 0x{:08x} {:>10} {:<5} /",
+        "-",
+        root.index(),
+        root.offset(),
+        root.size(),
+        "dir"
+    );
+    let mut aliases = if show_aliases {
+        Some(HashMap::new())
+    } else {
+        None
+    };
+    list_dir(archive, root, "", &mut aliases)
 }
 
-/* Create all the output directory hiererchy under a specified path. */
-fn create_dirs(archive: &Archive, directory: &Directory, outpath: &str) -> Result<()> {
-    use std::fs::DirBuilder;
-    let mut builder = DirBuilder::new();
-    builder.recursive(true);
-    foreach_dir_in_dir(archive, directory, |_dir, path, _level| {
-        let mut dirpath = String::from(outpath);
-        dirpath.push(std::path::MAIN_SEPARATOR);
-        dirpath.push_str(path);
-        builder.create(dirpath)?;
-        Ok(())
-    })?;
+/* Print the number of files of the archive, by storage format. */
+fn print_stats(archive: &Archive) -> Result<()> {
+    let compressed = archive.list_compressed_files()?;
+    let plain = archive.list_plain_files()?;
+    let (mut deflated_blocks, mut stored_blocks) = (0, 0);
+    for &(ref path, file) in &compressed {
+        let data = archive.file_data(file)?;
+        for idx in 0..data.num_blocks().unwrap_or(0) {
+            match data.block_encoding(idx) {
+                Some(Ok(BlockEncoding::Compressed)) => deflated_blocks += 1,
+                Some(Ok(BlockEncoding::Uncompressed)) => stored_blocks += 1,
+                Some(Err(e)) => {
+                    return Err(e).chain_err(|| format!("Unable to read the blocks of {}", path))
+                }
+                None => {}
+            }
+        }
+    }
+    /* On the decompressed size, as --exclude-empty */
+    let mut empty_files = 0;
+    for (path, file) in compressed.iter().chain(&plain) {
+        let data = archive
+            .file_data(file)
+            .chain_err(|| format!("Unable to read {}", path))?;
+        if data.size() == 0 {
+            empty_files += 1;
+        }
+    }
+    println!("files: {}", compressed.len() + plain.len());
+    println!("compressed files: {}", compressed.len());
+    println!("plain files: {}", plain.len());
+    println!("empty files: {}", empty_files);
+    println!("compressed blocks: {}", deflated_blocks);
+    println!("blocks stored as is: {}", stored_blocks);
     Ok(())
 }
 
-/* Extract a single file to a specified output directory */
-fn extract_file(archive: &Archive, file: &hpk::File, outpath: &str) -> Result<()> {
-    let mut data = archive.file_data(file)?;
-    let mut out;
-    let mut remain = data.size() as usize;
-    {
-        use std::fs::File;
-        let mut filepath = String::new();
-        filepath.push_str(outpath);
-        filepath.push_str(file.name());
-        out = File::create(filepath)?;
-    }
-
-    while remain > 0 {
-        use std::io::Read;
-        use std::io::Write;
-        // XXX: There must be a faster way
-        let mut buf = vec![0; 0x100000];
-        let buflen = buf.len();
-        let size = if remain > buflen { buflen } else { remain };
-        data.read_exact(&mut buf[0..size])?;
-        out.write(&buf[0..size])?;
-        remain -= size;
-    }
-    Ok(())
+#[cfg(feature = "metrics")]
+fn print_metrics(metrics: &hpk::Metrics) {
+    eprintln!("bytes read: {}", metrics.bytes_read);
+    eprintln!("bytes decompressed: {}", metrics.bytes_decompressed);
+    eprintln!("blocks decompressed: {}", metrics.blocks_decompressed);
+    eprintln!("block cache hits: {}", metrics.cache_hits);
+    eprintln!("block cache misses: {}", metrics.cache_misses);
+    eprintln!("seeks: {}", metrics.seeks);
+    eprintln!("readers created: {}", metrics.file_data_created);
 }
 
-fn extract_archive(archive: &Archive, outpath: &str) -> Result<()> {
-    let rootdir = archive.root_directory();
-    create_dirs(archive, rootdir, outpath)?;
-    foreach_file_in_dir(archive, archive.root_directory(), |file, path, _level| {
-        let mut filepath = String::new();
-        filepath.push_str(outpath);
-        filepath.push(std::path::MAIN_SEPARATOR);
-        filepath.push_str(path);
-        println!("{}{}", filepath, file.name());
-        extract_file(archive, file, &filepath)?;
-        Ok(())
-    })
+/* Open an archive, warning about the entries skipped because of their
+ * type or because they could not be parsed. */
+fn open_archive(path: &str, options: &ArchiveOptions) -> Result<Archive> {
+    let archive = Archive::open_with(path, options)?;
+    for entry in archive.unknown_entries()? {
+        eprintln!(
+            "warning: skipped entry {} of unknown type 0x{:x} at offset 0x{:x}",
+            entry.path, entry.entry_type, entry.offset
+        );
+    }
+    let recovered = archive.recovered_errors()?;
+    for e in &recovered {
+        eprintln!("warning: skipped corrupt entry: {}", e);
+    }
+    if !recovered.is_empty() {
+        eprintln!("warning: {} is only partially readable", path);
+    }
+    Ok(archive)
 }
 
-fn run() -> Result<()> {
+/* Returns the exit code of the program. */
+fn run() -> Result<i32> {
     use getopts::Options;
 
     let args: Vec<String> = std::env::args().collect();
     let mut opts = Options::new();
+    opts.optflag("", "skip-errors", "Continue extraction after errors");
+    opts.optflag(
+        "",
+        "sequential",
+        "Extract files in the order in which they are stored in the archive",
+    );
+    opts.optopt(
+        "",
+        "cache",
+        "Keep an index of extracted files in DIR, and reuse them in later runs",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "min-size",
+        "Only extract files of at least SIZE bytes once decompressed, which may have a K, M or G suffix",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "max-size",
+        "Only extract files of at most SIZE bytes once decompressed, which may have a K, M or G suffix",
+        "SIZE",
+    );
+    opts.optflag("", "exclude-empty", "Do not extract the files of 0 bytes");
+    opts.optopt(
+        "",
+        "include",
+        "Only extract or list the files whose path matches PATTERN, such as **/*.lua",
+        "PATTERN",
+    );
+    opts.optflag(
+        "",
+        "ignore-case",
+        "Match the --include pattern regardless of case",
+    );
+    opts.optopt(
+        "",
+        "dump-block",
+        "Write block N of the compressed file at PATH to stdout, without decompressing it",
+        "PATH:N",
+    );
+    opts.optopt(
+        "",
+        "dump-index",
+        "Show the entry referring to file table index N",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "stats",
+        "Show how many files of ARCHIVE are stored compressed or as is, or are empty",
+    );
+    opts.optflag(
+        "",
+        "list-offsets",
+        "List the offsets, indices and sizes of all the entries, as stored in the archive",
+    );
+    opts.optflag(
+        "",
+        "show-aliases",
+        "With --list-offsets, show which files share their data with a file listed before",
+    );
+    opts.optopt(
+        "",
+        "num-files-threshold",
+        "Refuse archives with more than N entries (default: 4000000)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "pack",
+        "Create an archive from the contents of a directory, such as one extracted beforehand, instead of extracting one. Files of already compressed formats are stored as is",
+    );
+    opts.optflag(
+        "",
+        "merge",
+        "Extract several archives to the last argument, files of later archives overriding those of earlier ones. With --pack, write them to a new archive instead",
+    );
+    opts.optflag(
+        "",
+        "keep-first",
+        "With --merge, keep the files of the earliest archive containing them instead of the latest",
+    );
+    opts.optflag(
+        "",
+        "stream",
+        "Extract the files as the directory tree is read, without holding it in memory, for huge archives",
+    );
+    opts.optopt(
+        "",
+        "compression-level",
+        &format!(
+            "zlib compression level used by --pack, from 0 (none) to 9 (default: {})",
+            DEFAULT_COMPRESSION_LEVEL
+        ),
+        "LEVEL",
+    );
+    opts.optopt(
+        "",
+        "repack-dir",
+        "Same as --pack with the default compression level, DIR being the directory and the argument the archive to write",
+        "DIR",
+    );
+    opts.optflag(
+        "",
+        "checkdir",
+        "Check that OUTDIR holds the files of ARCHIVE with the right sizes, instead of extracting",
+    );
+    opts.optflag(
+        "",
+        "validate",
+        "Check the structure and the data of all the files of ARCHIVE, instead of extracting",
+    );
+    opts.optflag(
+        "",
+        "check-overlap",
+        "Report the files of ARCHIVE whose data partially overlap, instead of extracting",
+    );
+    opts.optflag(
+        "",
+        "diff",
+        "List the files added, removed or changed between OLD and NEW archives, instead of extracting",
+    );
+    opts.optopt(
+        "",
+        "diff-format",
+        "Output format of --diff: text (default), json or csv",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "file-index",
+        "Only extract the file with this 1-based file table index, keeping its path",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "chmod",
+        "Give the extracted files these permissions, as an octal mode (Unix only)",
+        "MODE",
+    );
+    opts.optflag(
+        "",
+        "in-place",
+        "Write extracted files directly at their final path, instead of renaming them there once complete",
+    );
+    opts.optflag(
+        "",
+        "resume",
+        "Record the extracted files in OUTDIR, and skip the files completed by a previous interrupted --resume run",
+    );
+    #[cfg(feature = "tracing")]
+    opts.optflag(
+        "",
+        "trace",
+        "Print a trace of the parsing and extraction steps, with their timing, to stderr",
+    );
+    #[cfg(feature = "metrics")]
+    opts.optflag(
+        "",
+        "metrics",
+        "Print counters of the reads and decompressions done once extraction is over",
+    );
+    opts.optflag("", "benchmark", "Measure extraction speed without writing");
+    opts.optflag(
+        "",
+        "list",
+        "List the files of the archive instead of extracting it",
+    );
+    opts.optopt(
+        "",
+        "list-format",
+        "Output format of --list: text (default), ndjson or long, also showing sizes and directories",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "color",
+        "Color directories in the long listing: auto (default, when writing to a terminal), always or never",
+        "WHEN",
+    );
+    opts.optflag(
+        "",
+        "bytes",
+        "Show exact byte counts in the long listing, instead of sizes such as 1.5 MiB on a terminal",
+    );
+    opts.optflag(
+        "",
+        "strict-names",
+        "Refuse archives with entry names which are not valid UTF-8, instead of replacing the invalid bytes",
+    );
+    opts.optflag(
+        "",
+        "lenient",
+        "Skip entries of an unknown type or which cannot be parsed instead of refusing the archive",
+    );
     let matches = opts.parse(&args[1..]).unwrap();
+    #[cfg(feature = "tracing")]
+    {
+        if matches.opt_present("trace") {
+            use tracing_subscriber::fmt::format::FmtSpan;
+            tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::TRACE)
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(::std::io::stderr)
+                .init();
+        }
+    }
+    let skip_errors = matches.opt_present("skip-errors");
+    let mut archive_options = ArchiveOptions::new();
+    if let Some(n) = matches.opt_str("num-files-threshold") {
+        let n = n
+            .parse()
+            .chain_err(|| format!("Invalid number of files \"{}\"", n))?;
+        archive_options = archive_options.max_entries(n);
+    }
+    if matches.opt_present("strict-names") {
+        archive_options = archive_options.strict_names(true);
+    }
+    if matches.opt_present("lenient") {
+        archive_options = archive_options
+            .strict_entry_types(false)
+            .recover_errors(true);
+    }
+    let include = match matches.opt_str("include") {
+        Some(p) => Some(
+            GlobPattern::new(&p)
+                .chain_err(|| format!("Invalid pattern \"{}\"", p))?
+                .case_insensitive(matches.opt_present("ignore-case")),
+        ),
+        None => None,
+    };
+
+    if matches.opt_present("pack") {
+        let merge = matches.opt_present("merge");
+        if merge && matches.free.len() < 2 {
+            bail!(
+                "Incorrect number of arguments. Expected at least 2, got {}.",
+                matches.free.len()
+            );
+        }
+        if !merge && matches.free.len() != 2 {
+            bail!(
+                "Incorrect number of arguments. Expected 2, got {}.",
+                matches.free.len()
+            );
+        }
+        let level = match matches.opt_str("compression-level") {
+            Some(l) => l
+                .parse()
+                .chain_err(|| format!("Invalid compression level \"{}\"", l))?,
+            None => DEFAULT_COMPRESSION_LEVEL,
+        };
+        let (outpath, inputs) = matches.free.split_last().unwrap();
+        if !merge {
+            pack_directory(std::path::Path::new(&inputs[0]), outpath, Some(level))
+                .chain_err(|| "Unable to write archive")?;
+            return Ok(0);
+        }
+        let mut builder = ArchiveBuilder::new();
+        builder.set_compression_level(Some(level))?;
+        if matches.opt_present("keep-first") {
+            builder.set_merge_policy(MergePolicy::Skip);
+        }
+        for path in inputs {
+            let archive = open_archive(path, &archive_options)
+                .chain_err(|| format!("Unable to open archive {}", path))?;
+            builder.merge_archive(&archive)?;
+        }
+        builder
+            .finish(outpath)
+            .chain_err(|| "Unable to write archive")?;
+        return Ok(0);
+    }
+
+    if let Some(dir) = matches.opt_str("repack-dir") {
+        if matches.free.len() != 1 {
+            bail!(
+                "Incorrect number of arguments. Expected 1, got {}.",
+                matches.free.len()
+            );
+        }
+        Archive::rebuild_from_extracted(&dir, &matches.free[0])
+            .chain_err(|| "Unable to write archive")?;
+        return Ok(0);
+    }
+
+    if let Some(spec) = matches.opt_str("dump-block") {
+        if matches.free.len() != 1 {
+            bail!(
+                "Incorrect number of arguments. Expected 1, got {}.",
+                matches.free.len()
+            );
+        }
+        let archive = open_archive(&matches.free[0], &archive_options)
+            .chain_err(|| "Unable to open archive")?;
+        dump_block(&archive, &spec)?;
+        return Ok(0);
+    }
+
+    if matches.opt_present("list") {
+        if matches.free.len() != 1 {
+            bail!(
+                "Incorrect number of arguments. Expected 1, got {}.",
+                matches.free.len()
+            );
+        }
+        let format = match matches.opt_str("list-format") {
+            Some(f) => ListFormat::parse(&f)?,
+            None => ListFormat::Text,
+        };
+        let color = match matches.opt_str("color") {
+            Some(c) => ColorChoice::parse(&c)?,
+            None => ColorChoice::Auto,
+        };
+        let archive = open_archive(&matches.free[0], &archive_options)
+            .chain_err(|| "Unable to open archive")?;
+        let stdout = ::std::io::stdout();
+        let is_terminal = stdout.is_terminal();
+        let style = ListStyle {
+            color: match color {
+                ColorChoice::Auto => is_terminal,
+                ColorChoice::Always => true,
+                ColorChoice::Never => false,
+            },
+            human_sizes: is_terminal && !matches.opt_present("bytes"),
+        };
+        write_listing(
+            &archive,
+            format,
+            &style,
+            include.as_ref(),
+            &mut stdout.lock(),
+        )?;
+        return Ok(0);
+    }
+
+    if matches.opt_present("benchmark") {
+        if matches.free.len() != 1 {
+            bail!(
+                "Incorrect number of arguments. Expected 1, got {}.",
+                matches.free.len()
+            );
+        }
+        let archive = open_archive(&matches.free[0], &archive_options)
+            .chain_err(|| "Unable to open archive")?;
+        let start = ::std::time::Instant::now();
+        let (num_files, num_bytes) = read_all_files(&archive)?;
+        let seconds = start.elapsed().as_secs_f64();
+        let megabytes = num_bytes as f64 / (1024.0 * 1024.0);
+        // No throughput when the clock could not measure anything
+        let throughput = if seconds > 0.0 {
+            format!(" ({:.1} MB/s)", megabytes / seconds)
+        } else {
+            String::new()
+        };
+        println!(
+            "Extracted {} files, {:.1} MB in {:.1} seconds{}",
+            num_files, megabytes, seconds, throughput
+        );
+        return Ok(0);
+    }
+
+    if matches.opt_present("stats") {
+        if matches.free.len() != 1 {
+            bail!(
+                "Incorrect number of arguments. Expected 1, got {}.",
+                matches.free.len()
+            );
+        }
+        let archive = open_archive(&matches.free[0], &archive_options)
+            .chain_err(|| "Unable to open archive")?;
+        print_stats(&archive)?;
+        return Ok(0);
+    }
+
+    if matches.opt_present("list-offsets") {
+        if matches.free.len() != 1 {
+            bail!(
+                "Incorrect number of arguments. Expected 1, got {}.",
+                matches.free.len()
+            );
+        }
+        let archive = open_archive(&matches.free[0], &archive_options)
+            .chain_err(|| "Unable to open archive")?;
+        list_offsets(&archive, matches.opt_present("show-aliases"))?;
+        return Ok(0);
+    }
+
+    if let Some(idx) = matches.opt_str("dump-index") {
+        if matches.free.len() != 1 {
+            bail!(
+                "Incorrect number of arguments. Expected 1, got {}.",
+                matches.free.len()
+            );
+        }
+        let archive = open_archive(&matches.free[0], &archive_options)
+            .chain_err(|| "Unable to open archive")?;
+        dump_index(&archive, &idx)?;
+        return Ok(0);
+    }
+
+    if matches.opt_present("validate") {
+        if matches.free.len() != 1 {
+            bail!(
+                "Incorrect number of arguments. Expected 1, got {}.",
+                matches.free.len()
+            );
+        }
+        /* Report what can be recovered from rather than stop at it */
+        let options = archive_options
+            .clone()
+            .strict_entry_types(false)
+            .recover_errors(true);
+        let archive = Archive::open_with(&matches.free[0], &options)
+            .chain_err(|| "Unable to open archive")?;
+        let issues = archive.validate()?;
+        for issue in &issues {
+            println!("{}", issue);
+        }
+        return Ok(if issues.is_empty() { 0 } else { 2 });
+    }
+
+    if matches.opt_present("check-overlap") {
+        if matches.free.len() != 1 {
+            bail!(
+                "Incorrect number of arguments. Expected 1, got {}.",
+                matches.free.len()
+            );
+        }
+        let archive = open_archive(&matches.free[0], &archive_options)
+            .chain_err(|| "Unable to open archive")?;
+        let overlaps = archive.overlapping_files()?;
+        for o in &overlaps {
+            println!(
+                "overlap: {} [0x{:x}-0x{:x}) and {} [0x{:x}-0x{:x})",
+                o.first,
+                o.first_range.start,
+                o.first_range.end,
+                o.second,
+                o.second_range.start,
+                o.second_range.end
+            );
+        }
+        return Ok(if overlaps.is_empty() { 0 } else { 2 });
+    }
+
+    if matches.free.len() < 2 {
+        bail!(
+            "Incorrect number of arguments. Expected at least 2, got {}.",
+            matches.free.len()
+        );
+    }
+
+    if let Some(idx) = matches.opt_str("file-index") {
+        if matches.free.len() != 2 {
+            bail!(
+                "Incorrect number of arguments. Expected 2, got {}.",
+                matches.free.len()
+            );
+        }
+        let idx: u32 = idx
+            .parse()
+            .chain_err(|| format!("Invalid index \"{}\"", idx))?;
+        let archive = open_archive(&matches.free[0], &archive_options)
+            .chain_err(|| "Unable to open archive")?;
+        archive.extract_single_by_index(idx, &matches.free[1])?;
+        return Ok(0);
+    }
+
+    if matches.opt_present("diff") {
+        if matches.free.len() != 2 {
+            bail!(
+                "Incorrect number of arguments. Expected 2, got {}.",
+                matches.free.len()
+            );
+        }
+        let format = match matches.opt_str("diff-format") {
+            Some(f) => DiffFormat::parse(&f)?,
+            None => DiffFormat::Text,
+        };
+        let old = open_archive(&matches.free[0], &archive_options)
+            .chain_err(|| format!("Unable to open archive {}", matches.free[0]))?;
+        let new = open_archive(&matches.free[1], &archive_options)
+            .chain_err(|| format!("Unable to open archive {}", matches.free[1]))?;
+        let diff = old.diff(&new)?;
+        if format == DiffFormat::Text {
+            println!(
+                "diff {} {}",
+                old.path().unwrap().display(),
+                new.path().unwrap().display()
+            );
+        }
+        let stdout = ::std::io::stdout();
+        write_diff(&diff, format, &mut stdout.lock())?;
+        return Ok(if diff.is_empty() { 0 } else { 2 });
+    }
+
+    if matches.opt_present("checkdir") {
+        if matches.free.len() != 2 {
+            bail!(
+                "Incorrect number of arguments. Expected 2, got {}.",
+                matches.free.len()
+            );
+        }
+        let archive = open_archive(&matches.free[0], &archive_options)
+            .chain_err(|| "Unable to open archive")?;
+        let issues = check_extracted(&archive, &matches.free[1])?;
+        for issue in &issues {
+            println!("{}", issue);
+        }
+        return Ok(if issues.is_empty() { 0 } else { 2 });
+    }
+
+    if matches.opt_present("merge") {
+        /* Later archives override files from earlier ones */
+        let (outpath, paths) = matches.free.split_last().unwrap();
+        let mut archives = Vec::new();
+        for path in paths {
+            let archive = open_archive(path, &archive_options)
+                .chain_err(|| format!("Unable to open archive {}", path))?;
+            archives.push(archive);
+        }
+        if matches.opt_present("keep-first") {
+            archives.reverse();
+        }
+        MergedArchive::new(archives).extract_all(outpath)?;
+        return Ok(0);
+    }
+
     if matches.free.len() != 2 {
         bail!(
             "Incorrect number of arguments. Expected 2, got {}.",
@@ -200,13 +820,100 @@ fn run() -> Result<()> {
         );
     }
 
-    let archive = Archive::open(&matches.free[0]).chain_err(|| "Unable to open archive")?;
+    if matches.opt_present("stream") {
+        for opt in &[
+            "sequential",
+            "cache",
+            "resume",
+            "in-place",
+            "chmod",
+            "min-size",
+            "max-size",
+            "exclude-empty",
+            "include",
+            "skip-errors",
+        ] {
+            if matches.opt_present(opt) {
+                bail!("--{} cannot be used with --stream", opt);
+            }
+        }
+        let mut stream = ArchiveStream::open_with(&matches.free[0], &archive_options)
+            .chain_err(|| "Unable to open archive")?;
+        extract_stream(&mut stream, &matches.free[1])?;
+        return Ok(0);
+    }
+
+    let archive =
+        open_archive(&matches.free[0], &archive_options).chain_err(|| "Unable to open archive")?;
     let rootdir = archive.root_directory();
     println!("Num files: {}", rootdir.files().len());
     println!("Num directories: {}", rootdir.directories().len());
 
-    //list_archive(&archive);
-    extract_archive(&archive, &matches.free[1])?;
+    let min_size = match matches.opt_str("min-size") {
+        Some(s) => Some(parse_size(&s)?),
+        None => None,
+    };
+    let max_size = match matches.opt_str("max-size") {
+        Some(s) => Some(parse_size(&s)?),
+        None => None,
+    };
+    let mode = match matches.opt_str("chmod") {
+        Some(m) => Some(parse_mode(&m)?),
+        None => None,
+    };
+    if mode.is_some() && cfg!(not(unix)) {
+        eprintln!("warning: --chmod is only supported on Unix, ignoring it");
+    }
+    let mut cache = match matches.opt_str("cache") {
+        Some(dir) => Some(ExtractionCache::open(&dir, &matches.free[0])?),
+        None => None,
+    };
+    let mut resume = if matches.opt_present("resume") {
+        Some(ResumeState::open(&matches.free[1])?)
+    } else {
+        None
+    };
+    let num_errors = {
+        let stdout = ::std::io::stdout();
+        let stderr = ::std::io::stderr();
+        let mut out = stdout.lock();
+        let mut err = stderr.lock();
+        let mut extract_options = ExtractOptions {
+            skip_errors: skip_errors,
+            sequential: matches.opt_present("sequential"),
+            in_place: matches.opt_present("in-place"),
+            mode: mode,
+            cache: cache.as_mut(),
+            min_size: min_size,
+            max_size: max_size,
+            exclude_empty: matches.opt_present("exclude-empty"),
+            include: include,
+            output: Some(&mut out),
+            errors: Some(&mut err),
+            resume: resume.as_mut(),
+        };
+        extract_archive(&archive, &matches.free[1], &mut extract_options)?
+    };
+    if let Some(c) = cache {
+        c.save().chain_err(|| "Unable to save the cache index")?;
+    }
+    if let Some(r) = resume {
+        /* Keep the state while some files are missing, so that they can be
+         * retried */
+        if num_errors == 0 {
+            r.finish()?;
+        }
+    }
+    #[cfg(feature = "metrics")]
+    {
+        if matches.opt_present("metrics") {
+            print_metrics(&archive.metrics()?);
+        }
+    }
+    if num_errors > 0 {
+        eprintln!("{} file(s) could not be extracted", num_errors);
+        return Ok(2);
+    }
 
-    Ok(())
+    Ok(0)
 }
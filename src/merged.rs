@@ -0,0 +1,103 @@
+use ::errors::*;
+use extract::write_atomically;
+use hpk::{Archive, File};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/** A view over several archives layered on top of each other, such as a game
+ * archive and mods overriding some of its files. When several archives
+ * contain a file with the same path, the one from the archive coming last
+ * wins. */
+pub struct MergedArchive {
+    archives: Vec<Archive>,
+}
+
+impl MergedArchive {
+    /** Archives are listed by increasing priority. */
+    pub fn new(archives: Vec<Archive>) -> MergedArchive {
+        MergedArchive { archives: archives }
+    }
+
+    /** Call f once for each path present in any of the archives, along with
+     * the archive and file it resolves to. Paths are visited in
+     * lexicographical order. */
+    pub fn walk<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &Archive, &File) -> Result<()>,
+    {
+        let mut entries: BTreeMap<String, (&Archive, &File)> = BTreeMap::new();
+        for archive in &self.archives {
+            archive.walk(|path, file| {
+                entries.insert(path.to_string(), (archive, file));
+                Ok(())
+            })?;
+        }
+        for (path, &(archive, file)) in &entries {
+            f(path, archive, file)?;
+        }
+        Ok(())
+    }
+
+    /** Extract every file of the merged view under outpath. */
+    pub fn extract_all(&self, outpath: &str) -> Result<()> {
+        self.walk(|path, archive, file| {
+            let mut filepath = PathBuf::from(outpath);
+            for component in path.split('/') {
+                filepath.push(component);
+            }
+            if let Some(parent) = filepath.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut data = archive.file_data(file)?;
+            let size = data.size();
+            write_atomically(&mut data, size, &filepath)
+        })
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use testutil::*;
+
+    #[test]
+    fn later_archive_overrides_earlier() {
+        let base = archive_file(&[
+            TestEntry::File(b"keep.txt", b"base keep".to_vec()),
+            TestEntry::Dir(
+                b"dir",
+                vec![TestEntry::File(b"override.txt", b"base".to_vec())],
+            ),
+        ]);
+        let overlay = archive_file(&[TestEntry::Dir(
+            b"dir",
+            vec![
+                TestEntry::File(b"override.txt", b"overlay".to_vec()),
+                TestEntry::File(b"new.txt", b"overlay new".to_vec()),
+            ],
+        )]);
+        let merged = MergedArchive::new(vec![
+            Archive::open(base.path().to_str().unwrap()).unwrap(),
+            Archive::open(overlay.path().to_str().unwrap()).unwrap(),
+        ]);
+
+        let mut paths = Vec::new();
+        merged
+            .walk(|path, _archive, _file| {
+                paths.push(path.to_string());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(paths, vec!["dir/new.txt", "dir/override.txt", "keep.txt"]);
+
+        let outdir = tempfile::tempdir().unwrap();
+        merged.extract_all(outdir.path().to_str().unwrap()).unwrap();
+        let read = |p: &str| fs::read(outdir.path().join(p)).unwrap();
+        assert_eq!(read("keep.txt"), b"base keep");
+        assert_eq!(read("dir/override.txt"), b"overlay");
+        assert_eq!(read("dir/new.txt"), b"overlay new");
+    }
+}
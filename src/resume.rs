@@ -0,0 +1,100 @@
+use ::errors::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+const STATE_FILENAME: &str = ".hpk-resume";
+
+/** Files completed by an extraction, recorded as soon as each of them is
+ * written, so that an interrupted extraction can be resumed without
+ * extracting them again.
+ *
+ * The state is a text file in the output directory, with one tab separated
+ * entry per line: extracted size and path relative to the output directory.
+ * Since files are renamed into place once complete, a file recorded in the
+ * state and having the recorded size on disk is trusted to be complete. */
+pub struct ResumeState {
+    outdir: PathBuf,
+    done: HashMap<String, u64>,
+    state: fs::File,
+}
+
+fn parse_line(line: &str) -> Option<(String, u64)> {
+    let mut fields = line.splitn(2, '\t');
+    let size = fields.next()?.parse().ok()?;
+    let path = fields.next()?;
+    Some((path.to_string(), size))
+}
+
+impl ResumeState {
+    /** Open the state of the extraction to outdir, creating the directory
+     * if needed, and load the files completed by previous runs. Malformed
+     * lines, such as one cut short by an interruption, are ignored. */
+    pub fn open(outdir: &str) -> Result<ResumeState> {
+        fs::create_dir_all(outdir).chain_err(|| "Unable to create output directory")?;
+        let outdir = PathBuf::from(outdir);
+        let path = outdir.join(STATE_FILENAME);
+        let mut done = HashMap::new();
+        match fs::File::open(&path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    if let Some((path, size)) = parse_line(&line?) {
+                        done.insert(path, size);
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).chain_err(|| "Unable to read resume state"),
+        }
+        let state = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .chain_err(|| "Unable to open resume state")?;
+        Ok(ResumeState {
+            outdir: outdir,
+            done: done,
+            state: state,
+        })
+    }
+
+    /** Whether the file at path, relative to the output directory, was
+     * completed by a previous run and still has the expected size. */
+    pub fn is_done(&self, path: &str, size: u64) -> bool {
+        if self.done.get(path) != Some(&size) {
+            return false;
+        }
+        match fs::metadata(self.outdir.join(path)) {
+            Ok(ref m) => m.is_file() && m.len() == size,
+            Err(_) => false,
+        }
+    }
+
+    /** Record that the file at path, relative to the output directory, was
+     * completely extracted. The state file is updated immediately. */
+    pub fn record(&mut self, path: &str, size: u64) -> Result<()> {
+        /* A path with a line break cannot be stored; it will simply be
+         * extracted again */
+        if path.contains('\n') {
+            return Ok(());
+        }
+        writeln!(self.state, "{}\t{}", size, path)
+            .and_then(|_| self.state.flush())
+            .chain_err(|| "Unable to update resume state")?;
+        self.done.insert(path.to_string(), size);
+        Ok(())
+    }
+
+    fn path(&self) -> PathBuf {
+        self.outdir.join(STATE_FILENAME)
+    }
+
+    /** Delete the state once the extraction completed. */
+    pub fn finish(self) -> Result<()> {
+        let path = self.path();
+        drop(self.state);
+        fs::remove_file(&path).chain_err(|| "Unable to remove resume state")
+    }
+}
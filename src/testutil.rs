@@ -0,0 +1,264 @@
+/* Helpers building archives and compressed files for the tests. */
+
+extern crate byteorder;
+extern crate tempfile;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+use builder::zlib_file_data;
+use hpk::MAGIC;
+use std::collections::HashMap;
+use std::io::Write;
+
+/* Build the contents of a ZLIB compressed file, storing blocks which do
+ * not compress well as is. */
+pub fn write_zlib_file_data(data: &[u8], blocksize: usize) -> Vec<u8> {
+    zlib_file_data(data, blocksize, 9).unwrap()
+}
+
+#[derive(Clone, Debug)]
+pub enum TestEntry {
+    File(&'static [u8], Vec<u8>),
+    /* File stored ZLIB compressed, given its contents and block size */
+    Zlib(&'static [u8], Vec<u8>, usize),
+    Dir(&'static [u8], Vec<TestEntry>),
+    /* Entry with an arbitrary type value, pointing to an empty file */
+    Typed(&'static [u8], u32),
+}
+
+/* Deliberate damage done to an archive by TestArchiveBuilder. Paths are '/'
+ * separated, without trailing '/'. */
+#[derive(Clone, Debug)]
+pub enum Corruption {
+    /* Magic other than "BPUL" */
+    BadMagic,
+    /* The name entry of the entry at path refers to the first index past
+     * the end of the file table */
+    IndexOutOfRange(&'static str),
+    /* The first two entries of the block table of the ZLIB file at path,
+     * which must have at least two blocks, are swapped */
+    UnsortedBlockTable(&'static str),
+    /* The name entry of the entry at path refers to the index of its
+     * parent, which then contains itself */
+    ParentIndex(&'static str),
+    /* The header has no file table offset, as in archives whose table is
+     * appended to them */
+    NoTableOffset,
+    /* The data of the entry at path extends past the end of the archive */
+    DataPastEnd(&'static str),
+    /* The file table entry of the entry at path refers to the last 4 bytes
+     * of the archive, which belong to the file table */
+    LastBytes(&'static str),
+    /* The name length in the name entry of the entry at path */
+    NameLength(&'static str, u16),
+    /* The data of the directory at path extends over that many bytes past
+     * its name table */
+    PaddedNameTable(&'static str, u32),
+    /* The data of the entry at the first path starts that many bytes into
+     * the data of the entry at the second path, keeping its size */
+    SharedData(&'static str, &'static str, u32),
+}
+
+/* Where an entry was laid out */
+struct Placement {
+    index: u32,
+    /* Offset of the name entry referring to the entry, none for the root
+     * directory */
+    name_entry: Option<usize>,
+    parent: u32,
+}
+
+/* Build the contents of a whole HPK archive in memory, possibly damaged on
+ * purpose, to check how the reader copes with it. */
+pub struct TestArchiveBuilder<'a> {
+    root: &'a [TestEntry],
+    corruptions: Vec<Corruption>,
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    let mut buf = [0u8; 4];
+    LittleEndian::write_u32(&mut buf, value);
+    out.extend_from_slice(&buf);
+}
+
+/* Lay out the contents of a directory, returning its file table index.
+ * File table entries are stored in table, indexed by file index - 1, and
+ * the placement of each entry in placements, by path. */
+fn layout_directory(
+    entries: &[TestEntry],
+    prefix: &str,
+    data: &mut Vec<u8>,
+    table: &mut Vec<(u32, u32)>,
+    placements: &mut HashMap<String, Placement>,
+) -> u32 {
+    table.push((0, 0));
+    let index = table.len() as u32;
+    let mut names = Vec::new();
+    let mut children = Vec::new();
+    for entry in entries {
+        let (name, entry_type, child) = match *entry {
+            TestEntry::File(name, ref contents) => {
+                table.push((data.len() as u32, contents.len() as u32));
+                data.extend_from_slice(contents);
+                (name, 0, table.len() as u32)
+            }
+            TestEntry::Zlib(name, ref contents, blocksize) => {
+                let packed = write_zlib_file_data(contents, blocksize);
+                table.push((data.len() as u32, packed.len() as u32));
+                data.extend_from_slice(&packed);
+                (name, 0, table.len() as u32)
+            }
+            TestEntry::Dir(name, ref dir_entries) => {
+                let path = format!("{}{}/", prefix, String::from_utf8_lossy(name));
+                let child = layout_directory(dir_entries, &path, data, table, placements);
+                (name, 1, child)
+            }
+            TestEntry::Typed(name, entry_type) => {
+                table.push((data.len() as u32, 0));
+                (name, entry_type, table.len() as u32)
+            }
+        };
+        children.push((
+            format!("{}{}", prefix, String::from_utf8_lossy(name)),
+            child,
+            names.len(),
+        ));
+        push_u32(&mut names, child);
+        push_u32(&mut names, entry_type);
+        names.push(name.len() as u8);
+        names.push((name.len() >> 8) as u8);
+        names.extend_from_slice(name);
+    }
+    for (path, child, pos) in children {
+        placements.insert(
+            path,
+            Placement {
+                index: child,
+                name_entry: Some(data.len() + pos),
+                parent: index,
+            },
+        );
+    }
+    table[index as usize - 1] = (data.len() as u32, names.len() as u32);
+    data.extend_from_slice(&names);
+    index
+}
+
+impl<'a> TestArchiveBuilder<'a> {
+    pub fn new(root: &'a [TestEntry]) -> TestArchiveBuilder<'a> {
+        TestArchiveBuilder {
+            root: root,
+            corruptions: Vec::new(),
+        }
+    }
+
+    pub fn corrupt(mut self, corruption: Corruption) -> TestArchiveBuilder<'a> {
+        self.corruptions.push(corruption);
+        self
+    }
+
+    /* Panics if a corruption refers to a missing path, or cannot be done
+     * on the entry at its path. */
+    pub fn build(&self) -> Vec<u8> {
+        let mut data = vec![0u8; 0x24];
+        let mut table = Vec::new();
+        let mut placements = HashMap::new();
+        let root = layout_directory(self.root, "", &mut data, &mut table, &mut placements);
+        placements.insert(
+            String::new(),
+            Placement {
+                index: root,
+                name_entry: None,
+                parent: 0,
+            },
+        );
+        let filetbl_offset = data.len() as u32;
+        for &(offset, size) in &table {
+            push_u32(&mut data, offset);
+            push_u32(&mut data, size);
+        }
+        let mut header = Vec::new();
+        header.extend_from_slice(MAGIC);
+        for &v in &[0x24, 1, 0xffffffff, 0, 0, 1, filetbl_offset, 0] {
+            push_u32(&mut header, v);
+        }
+        data[..0x24].copy_from_slice(&header);
+
+        let len = data.len() as u32;
+        for corruption in &self.corruptions {
+            let placement = |path: &str| match placements.get(path) {
+                Some(p) => p,
+                None => panic!("No entry at {}", path),
+            };
+            let table_entry =
+                |path: &str| filetbl_offset as usize + (placement(path).index as usize - 1) * 8;
+            match *corruption {
+                Corruption::BadMagic => data[..4].copy_from_slice(b"LUPB"),
+                Corruption::IndexOutOfRange(path) => {
+                    let pos = placement(path).name_entry.unwrap();
+                    LittleEndian::write_u32(&mut data[pos..pos + 4], table.len() as u32 + 1);
+                }
+                Corruption::UnsortedBlockTable(path) => {
+                    let (offset, _) = table[placement(path).index as usize - 1];
+                    let blocktbl = offset as usize + 0xc;
+                    let first = LittleEndian::read_u32(&data[blocktbl..]);
+                    let second = LittleEndian::read_u32(&data[blocktbl + 4..]);
+                    LittleEndian::write_u32(&mut data[blocktbl..], second);
+                    LittleEndian::write_u32(&mut data[blocktbl + 4..], first);
+                }
+                Corruption::ParentIndex(path) => {
+                    let placement = placement(path);
+                    let pos = placement.name_entry.unwrap();
+                    LittleEndian::write_u32(&mut data[pos..pos + 4], placement.parent);
+                }
+                Corruption::NoTableOffset => LittleEndian::write_u32(&mut data[0x1c..0x20], 0),
+                Corruption::DataPastEnd(path) => {
+                    let pos = table_entry(path);
+                    LittleEndian::write_u32(&mut data[pos + 4..pos + 8], len);
+                }
+                Corruption::LastBytes(path) => {
+                    let pos = table_entry(path);
+                    LittleEndian::write_u32(&mut data[pos..pos + 4], len - 4);
+                    LittleEndian::write_u32(&mut data[pos + 4..pos + 8], 4);
+                }
+                Corruption::NameLength(path, name_len) => {
+                    let pos = placement(path).name_entry.unwrap() + 8;
+                    LittleEndian::write_u16(&mut data[pos..pos + 2], name_len);
+                }
+                Corruption::PaddedNameTable(path, padding) => {
+                    let pos = table_entry(path);
+                    let size = LittleEndian::read_u32(&data[pos + 4..pos + 8]);
+                    LittleEndian::write_u32(&mut data[pos + 4..pos + 8], size + padding);
+                }
+                Corruption::SharedData(path, other, skip) => {
+                    let (offset, _) = table[placement(other).index as usize - 1];
+                    let pos = table_entry(path);
+                    LittleEndian::write_u32(&mut data[pos..pos + 4], offset + skip);
+                }
+            }
+        }
+        data
+    }
+
+    /* Same as build(), writing the archive to a temporary file. */
+    pub fn build_file(&self) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&self.build()).unwrap();
+        file.flush().unwrap();
+        file
+    }
+}
+
+/* Build a whole HPK archive with the specified root directory contents. */
+pub fn write_archive(root: &[TestEntry]) -> Vec<u8> {
+    TestArchiveBuilder::new(root).build()
+}
+
+pub fn archive_file(root: &[TestEntry]) -> tempfile::NamedTempFile {
+    TestArchiveBuilder::new(root).build_file()
+}
+
+pub fn sample_data(len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| ((i * 7) % 251) as u8 ^ ((i / 100) as u8))
+        .collect()
+}
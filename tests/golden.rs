@@ -0,0 +1,216 @@
+/* Run the unpacker on archives generated at test time, and compare what it
+ * prints with the expectations of tests/golden/, named after the archive
+ * and the command. Once a change of the output is deemed right, write the
+ * new expectations with `UPDATE_GOLDEN=1 cargo test --test golden`. */
+
+extern crate tempfile;
+extern crate tropico5_hpk_unpacker;
+
+#[allow(dead_code)]
+#[path = "../src/testutil.rs"]
+mod testutil;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use testutil::*;
+use tropico5_hpk_unpacker::hpk::MAX_DIRECTORY_DEPTH;
+// Modules testutil refers to from the crate root
+use tropico5_hpk_unpacker::{builder, hpk};
+
+/* Directories nested depth levels deep, the last one holding a file */
+fn nested(depth: usize) -> Vec<TestEntry> {
+    let mut entries = vec![TestEntry::File(b"leaf", b"bottom".to_vec())];
+    for _ in 0..depth {
+        entries = vec![TestEntry::Dir(b"d", entries)];
+    }
+    entries
+}
+
+/* Run the unpacker, returning its exit status followed by what it printed
+ * on stdout. */
+fn run(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_tropico5-hpk-unpacker"))
+        .args(args)
+        .output()
+        .unwrap();
+    format!(
+        "exit status: {}\n{}",
+        output.status.code().unwrap_or(-1),
+        String::from_utf8_lossy(&output.stdout)
+    )
+}
+
+/* Size and '/' separated path of each file under dir, sorted by path */
+fn tree(dir: &Path, prefix: &str, out: &mut Vec<String>) {
+    let mut entries: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap()).collect();
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type().unwrap().is_dir() {
+            tree(&entry.path(), &format!("{}/", path), out);
+        } else {
+            out.push(format!("{} {}", entry.metadata().unwrap().len(), path));
+        }
+    }
+}
+
+fn check_golden(name: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(name);
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Unable to read {}: {}", path.display(), e));
+    assert!(
+        actual == expected,
+        "Output differs from {}:\n--- expected\n{}--- actual\n{}",
+        path.display(),
+        expected,
+        actual
+    );
+}
+
+/* Generate the archive name.hpk, then list it, validate it, extract it and
+ * check the extracted files, comparing each output with its expectation.
+ * The extracted files are expected along with the exit status of the
+ * extraction. */
+fn check_archive(name: &str, root: &[TestEntry]) {
+    let tmp = tempfile::tempdir().unwrap();
+    let archive = tmp.path().join(format!("{}.hpk", name));
+    fs::write(&archive, write_archive(root)).unwrap();
+    let archive = archive.to_str().unwrap();
+    let outdir = tmp.path().join("out");
+    let outdir = outdir.to_str().unwrap();
+
+    check_golden(&format!("{}.list", name), &run(&["--list", archive]));
+    check_golden(
+        &format!("{}.long", name),
+        &run(&[
+            "--list",
+            "--list-format",
+            "long",
+            "--color",
+            "never",
+            "--bytes",
+            archive,
+        ]),
+    );
+    check_golden(
+        &format!("{}.validate", name),
+        &run(&["--validate", archive]),
+    );
+    // The output of the extraction holds temporary paths
+    let status = run(&[archive, outdir]);
+    let mut extracted = format!("{}\n", status.lines().next().unwrap());
+    let mut files = Vec::new();
+    tree(Path::new(outdir), "", &mut files);
+    for file in files {
+        extracted.push_str(&file);
+        extracted.push('\n');
+    }
+    check_golden(&format!("{}.tree", name), &extracted);
+    check_golden(
+        &format!("{}.checkdir", name),
+        &run(&["--checkdir", archive, outdir]),
+    );
+}
+
+#[test]
+fn empty_archive() {
+    check_archive("empty", &[]);
+}
+
+#[test]
+fn large_zlib_file() {
+    // The last block is partial
+    let data = sample_data(0x8000 * 3 + 0x123);
+    check_archive(
+        "large_zlib",
+        &[TestEntry::Zlib(b"large.bin", data.clone(), 0x8000)],
+    );
+
+    let tmp = tempfile::tempdir().unwrap();
+    let archive = tmp.path().join("large.hpk");
+    fs::write(
+        &archive,
+        write_archive(&[TestEntry::Zlib(b"large.bin", data.clone(), 0x8000)]),
+    )
+    .unwrap();
+    let outdir = tmp.path().join("out");
+    run(&[archive.to_str().unwrap(), outdir.to_str().unwrap()]);
+    assert_eq!(fs::read(outdir.join("large.bin")).unwrap(), data);
+}
+
+#[test]
+fn deepest_directories() {
+    check_archive("deep", &nested(MAX_DIRECTORY_DEPTH));
+
+    let tmp = tempfile::tempdir().unwrap();
+    let archive = tmp.path().join("too_deep.hpk");
+    fs::write(&archive, write_archive(&nested(MAX_DIRECTORY_DEPTH + 1))).unwrap();
+    assert!(run(&["--list", archive.to_str().unwrap()]).starts_with("exit status: 1\n"));
+}
+
+#[test]
+fn non_ascii_names() {
+    check_archive(
+        "non_ascii",
+        &[
+            TestEntry::File("café.txt".as_bytes(), b"utf-8".to_vec()),
+            TestEntry::Dir(
+                "日本".as_bytes(),
+                vec![TestEntry::File("ñ".as_bytes(), b"n".to_vec())],
+            ),
+            // Latin-1, which is not valid UTF-8
+            TestEntry::File(b"caf\xe9", b"latin-1".to_vec()),
+        ],
+    );
+}
+
+#[test]
+fn duplicate_names() {
+    // Extraction only keeps one of the entries sharing a path, which makes
+    // it fail, and --validate reports them
+    check_archive(
+        "duplicates",
+        &[
+            TestEntry::File(b"same", b"first".to_vec()),
+            TestEntry::File(b"same", b"second one".to_vec()),
+            TestEntry::Dir(
+                b"d",
+                vec![
+                    TestEntry::File(b"x", b"file".to_vec()),
+                    TestEntry::Dir(b"x", vec![TestEntry::File(b"y", b"y".to_vec())]),
+                ],
+            ),
+        ],
+    );
+}
+
+#[test]
+fn unreadable_file_offsets() {
+    // A ZLIB header announcing more blocks than the file holds
+    let mut bad = b"ZLIB".to_vec();
+    bad.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00]);
+    let tmp = tempfile::tempdir().unwrap();
+    let archive = tmp.path().join("unreadable.hpk");
+    fs::write(
+        &archive,
+        write_archive(&[
+            TestEntry::File(b"bad", bad),
+            TestEntry::File(b"good", b"good".to_vec()),
+        ]),
+    )
+    .unwrap();
+    check_golden(
+        "unreadable.offsets",
+        &run(&["--list-offsets", archive.to_str().unwrap()]),
+    );
+}
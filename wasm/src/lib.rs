@@ -0,0 +1,41 @@
+/* Archive inspector for web browsers: the parser compiled to WebAssembly,
+ * reading archives from bytes handed over by JavaScript, such as a file
+ * dropped on a page. It is built without the fs feature of the parser, which
+ * has no files to open there. See www/index.html for an example, built
+ * with:
+ *
+ *   cargo build --release --target wasm32-unknown-unknown
+ *   wasm-bindgen --target web --out-dir www/pkg \
+ *       target/wasm32-unknown-unknown/release/tropico5_hpk_wasm.wasm
+ */
+
+#![allow(dead_code)]
+#![recursion_limit = "1024"]
+
+#[macro_use]
+extern crate error_chain;
+extern crate wasm_bindgen;
+
+#[path = "../../src/errors.rs"]
+mod errors;
+#[path = "../../src/hpk.rs"]
+mod hpk;
+
+use wasm_bindgen::prelude::*;
+
+fn js_error(e: errors::Error) -> JsValue {
+    let messages: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+    JsValue::from_str(&messages.join(": "))
+}
+
+/** List the files of the archive held in data, a Uint8Array on the
+ * JavaScript side, as '/' separated paths in lexicographical order. Throws
+ * the error message if the archive cannot be parsed. */
+#[wasm_bindgen]
+pub fn list_files(data: &[u8]) -> Result<Vec<String>, JsValue> {
+    let archive = hpk::Archive::open_from_reader(data).map_err(js_error)?;
+    archive
+        .files()
+        .map(|entry| entry.map(|(path, _)| path).map_err(js_error))
+        .collect()
+}